@@ -1,12 +1,16 @@
-use crate::dependencies::{has_circular_dependency, recalculate_dependents};
-use crate::types::CellDependencies;
-use crate::types::{DependencyType, Sheet};
+use crate::dependencies::{
+    formula_dependencies_at, has_circular_dependency, range_stats, recalculate_after_change,
+    recalculate_dependents, set_dependencies,
+};
+use crate::parser::{parse, Expr};
+use crate::types::{Cell, DataKind, DependencyType, ErrorKind, Sheet};
 use crate::utils::{
-    calculate_range_function, evaluate_arithmetic, is_valid_formula, parse_cell_reference,
-    parse_range,
+    calculate_range_function, calculate_sumif, cell_text, days_from_civil, is_valid_formula,
+    is_valid_list_arg, matches_criterion, parse_cell_reference, parse_external_reference,
+    parse_range, percent_literal, reference_arg, resolve_dynamic_bounds,
 };
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Updates a cell with a new formula and recalculates dependencies.
 ///
@@ -31,6 +35,7 @@ pub fn update_cell(sheet: &mut Sheet, row: i32, col: i32, formula: &str) {
         || row >= sheet.rows
         || col < 0
         || col >= sheet.cols
+        || sheet.cells[row as usize][col as usize].is_locked
         || !is_valid_formula(sheet, formula)
     {
         return;
@@ -41,100 +46,71 @@ pub fn update_cell(sheet: &mut Sheet, row: i32, col: i32, formula: &str) {
         cell.formula = Some(formula.to_string());
         cell.is_formula = true;
         cell.has_circular = true;
+        cell.error_kind = Some(ErrorKind::Circular);
+        cell.is_set = true;
+        record_history(sheet, row, col);
         recalculate_dependents(sheet, row, col);
         return;
     }
 
     // Parse new dependencies
-    let mut new_dependencies = Vec::new();
-    let tokens: Vec<&str> = formula
-        .split(&['+', '-', '*', '/', '(', ')', ' '][..])
-        .collect();
-    for token in tokens {
-        if token.contains(':') {
-            if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, token) {
-                new_dependencies.push(DependencyType::Range {
-                    start_row,
-                    start_col,
-                    end_row,
-                    end_col,
-                });
-            }
-        } else if token.chars().next().is_some_and(|c| c.is_alphabetic()) {
-            if let Some((dep_row, dep_col)) = parse_cell_reference(sheet, token) {
-                new_dependencies.push(DependencyType::Single {
-                    row: dep_row,
-                    col: dep_col,
-                });
-            }
-        }
-    }
-
-    let (value, is_error) = evaluate_expression(sheet, formula, row, col);
+    let new_dependencies = formula_dependencies_at(sheet, formula, row, col);
+
+    let (value, error) = evaluate_expression(sheet, formula, row, col);
+    let stats = range_stats(sheet, formula);
+    let previous = sheet.cells[row as usize][col as usize].clone();
     {
         let cell = &mut sheet.cells[row as usize][col as usize];
+        cell.stats = stats;
         cell.formula = Some(formula.to_string());
         cell.is_formula = true;
         cell.value = value;
-        cell.is_error = is_error;
+        cell.is_error = error.is_some();
+        cell.error_kind = error;
+        cell.kind = formula_kind(formula);
+        cell.is_set = true;
     }
+    record_history(sheet, row, col);
 
-    // Get and remove old dependencies
-    let old_cell_deps = sheet.dependency_graph.remove(&(row, col));
+    set_dependencies(sheet, row, col, new_dependencies);
 
-    // Remove this cell from dependents of old Single dependencies
-    if let Some(old_cell_deps) = &old_cell_deps {
-        for dep in &old_cell_deps.dependencies {
-            if let DependencyType::Single { row: r, col: c } = dep {
-                if let Some(dep_cell_deps) = sheet.dependency_graph.get_mut(&(*r, *c)) {
-                    dep_cell_deps.dependents.retain(|d| !matches!(d, DependencyType::Single { row: r2, col: c2 } if *r2 == row && *c2 == col));
-                }
-            }
-            // Range dependencies are handled in BFS, so no need to update dependents here
-        }
-    }
-
-    // Create new cell dependencies, preserving existing dependents if any
-    let new_cell_deps = CellDependencies {
-        dependencies: new_dependencies.clone(),
-        dependents: old_cell_deps.map_or(Vec::new(), |d| d.dependents),
-    };
+    recalculate_after_change(sheet, row, col, &previous);
+    crate::dependencies::reset_circular_dependency_flag(sheet);
+}
 
-    // Add this cell to dependents of new Single dependencies
-    for dep in &new_cell_deps.dependencies {
-        if let DependencyType::Single { row: r, col: c } = dep {
-            let dep_cell_deps =
-                sheet
-                    .dependency_graph
-                    .entry((*r, *c))
-                    .or_insert_with(|| CellDependencies {
-                        dependencies: Vec::new(),
-                        dependents: Vec::new(),
-                    });
-            dep_cell_deps
-                .dependents
-                .push(DependencyType::Single { row, col });
-        }
-        // Range dependencies will be detected dynamically in BFS
+/// Appends a cell's current value and formula to its history.
+///
+/// The history is only kept in extension mode, where the HISTORY command reports it. Like the
+/// undo stack, it holds at most `max_history_size` entries per cell; the oldest entry is dropped
+/// first.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `row` - The row index of the cell.
+/// * `col` - The column index of the cell.
+fn record_history(sheet: &mut Sheet, row: i32, col: i32) {
+    if !sheet.extension_enabled {
+        return;
     }
-
-    // Insert the updated dependencies into the graph
-    sheet.dependency_graph.insert((row, col), new_cell_deps);
-
-    // Clean up if both dependencies and dependents are empty
-    if new_dependencies.is_empty() && sheet.dependency_graph[&(row, col)].dependents.is_empty() {
-        sheet.dependency_graph.remove(&(row, col));
+    let cell = &sheet.cells[row as usize][col as usize];
+    let entry = (cell.value, cell.formula.clone().unwrap_or_default());
+    let history = sheet.cell_history.entry((row, col)).or_default();
+    history.push(entry);
+    if history.len() > sheet.max_history_size {
+        history.remove(0);
     }
-
-    recalculate_dependents(sheet, row, col);
-    crate::dependencies::reset_circular_dependency_flag(sheet);
 }
 
 /// Evaluates an expression to compute a cell's value.
 ///
 /// This function evaluates an expression, which can be a numeric literal, cell reference,
-/// arithmetic expression, or function (e.g., SUM, SLEEP). It handles errors and returns
-/// the computed value and an error flag.
+/// arithmetic expression, or function (e.g., SUM, SLEEP). Expressions other than a plain literal
+/// or cell reference are parsed into an expression tree by `parser::parse`, so operator
+/// precedence, unary minus and functions nested inside arithmetic (e.g., `SUM(A1:A2)*2+1`) are
+/// all supported. It handles errors and returns the computed value together with the kind of
+/// error, if any: division by zero, a bad reference, an overflowing result, or any other failure.
+/// A reference to an error cell carries that cell's error kind along. A result outside the range
+/// allowed for the cell by a VALIDATE rule is an `Invalid` error.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
@@ -143,112 +119,574 @@ pub fn update_cell(sheet: &mut Sheet, row: i32, col: i32, formula: &str) {
 /// * `col` - The column index of the cell (for context).
 ///
 /// # Returns
-/// A tuple `(i32, bool)` containing the computed value and a boolean indicating if an error occurred.
+/// A tuple `(i32, Option<ErrorKind>)` containing the computed value and the kind of error, or
+/// `None` if the evaluation succeeded.
 ///
 /// # Example
 /// ```
 /// let mut sheet = create_sheet(10, 10, false).unwrap();
 /// sheet.cells[0][0].value = 5;
-/// let (value, is_error) = evaluate_expression(&mut sheet, "=A1+5", 0, 0);
-/// assert_eq!(value, 10);
-/// assert!(!is_error);
+/// assert_eq!(evaluate_expression(&mut sheet, "A1+5*2", 0, 0), (15, None));
+/// assert_eq!(evaluate_expression(&mut sheet, "A1/0", 0, 1), (0, Some(ErrorKind::DivByZero)));
 /// ```
-pub fn evaluate_expression(sheet: &mut Sheet, expr: &str, _row: i32, _col: i32) -> (i32, bool) {
-    let mut is_error = false;
+pub fn evaluate_expression(
+    sheet: &mut Sheet,
+    expr: &str,
+    row: i32,
+    col: i32,
+) -> (i32, Option<ErrorKind>) {
+    let (value, error) = evaluate_unchecked(sheet, expr, row, col);
+    // A value outside the cell's VALIDATE rule is an error
+    match sheet.validation_at(row, col) {
+        Some((min, max)) if error.is_none() && !(min..=max).contains(&value) => {
+            (0, Some(ErrorKind::Invalid))
+        }
+        _ => (value, error),
+    }
+}
 
-    // Handle numeric literals
-    if let Ok(value) = expr.parse::<i32>() {
-        return (value, false);
+/// Evaluates an expression without applying the cell's validation rule.
+/// See `evaluate_expression`.
+fn evaluate_unchecked(
+    sheet: &mut Sheet,
+    expr: &str,
+    row: i32,
+    col: i32,
+) -> (i32, Option<ErrorKind>) {
+    // A row given as a cell in braces, as in `SUM(A1:A{B1})`, takes that cell's current value
+    if expr.contains(['{', '}']) {
+        return match resolve_dynamic_bounds(sheet, expr) {
+            Some(resolved) => evaluate_unchecked(sheet, &resolved, row, col),
+            None => (0, Some(ErrorKind::BadReference)),
+        };
+    }
+
+    // Handle numeric literals, and percentages like `50%` that hold their number. Rust accepts
+    // `+5` as a number but the formula grammar has no unary plus, so leave that to the parser.
+    let literal = expr.parse::<i32>().ok().filter(|_| !expr.starts_with('+'));
+    if let Some(value) = literal.or_else(|| percent_literal(expr)) {
+        return (value, None);
     }
 
     // Handle single cell reference
     if expr.chars().next().is_some_and(|c| c.is_alphabetic())
         && !expr.contains(&['+', '-', '*', '/', '('][..])
     {
-        if let Some((r, c)) = parse_cell_reference(sheet, expr) {
+        if let Ok((r, c)) = parse_cell_reference(sheet, expr) {
             let cell = &sheet.cells[r as usize][c as usize];
-            return (cell.value, cell.is_error);
+            if cell.kind == DataKind::Text && !cell.is_error {
+                return (0, Some(ErrorKind::Invalid));
+            }
+            return (cell.value, cell.is_error.then(|| error_of(cell)));
+        }
+    }
+
+    // Handle arithmetic expressions and functions like SLEEP, SUM, AVG, etc.
+    match parse(expr) {
+        // Quoted text makes a text cell
+        Some(Expr::Text(_)) => (0, None),
+        Some(ast) => match evaluate_ast(sheet, &ast, row, col) {
+            Ok(value) => (value, None),
+            Err(kind) => (0, Some(kind)),
+        },
+        None => (0, Some(ErrorKind::Invalid)),
+    }
+}
+
+/// Collects the cells read by the OFFSET calls in an expression as dependencies.
+///
+/// The target of each call is resolved with the offsets as they are when the formula is
+/// assigned. Calls whose target cannot be resolved add nothing.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `expr` - The parsed formula.
+/// * `row` - The row index of the formula's cell.
+/// * `col` - The column index of the formula's cell.
+/// * `dependencies` - The list the resolved cells are added to.
+pub fn offset_dependencies(
+    sheet: &mut Sheet,
+    expr: &Expr,
+    row: i32,
+    col: i32,
+    dependencies: &mut Vec<DependencyType>,
+) {
+    match expr {
+        Expr::Call { name, args } => {
+            if name == "OFFSET" {
+                if let Ok((dep_row, dep_col)) = offset_target(sheet, args, row, col) {
+                    dependencies.push(DependencyType::Single {
+                        row: dep_row,
+                        col: dep_col,
+                    });
+                }
+            }
+            for arg in args {
+                offset_dependencies(sheet, arg, row, col, dependencies);
+            }
+        }
+        Expr::Neg(inner) | Expr::Criterion { operand: inner, .. } => {
+            offset_dependencies(sheet, inner, row, col, dependencies)
+        }
+        Expr::Binary { left, right, .. } | Expr::Comparison { left, right, .. } => {
+            offset_dependencies(sheet, left, row, col, dependencies);
+            offset_dependencies(sheet, right, row, col, dependencies);
         }
+        _ => {}
     }
+}
 
-    // Handle functions like SLEEP, SUM, AVG, etc.
-    if let Some((function, args)) = expr.split_once('(').map(|(f, a)| (f, &a[..a.len() - 1])) {
-        let function = function.trim().to_uppercase();
-        if function == "SLEEP" {
-            let (duration, error) = evaluate_expression(sheet, args, _row, _col);
-            if error {
-                return (0, true);
+/// Resolves the cell an `OFFSET(cell, rows, cols)` call points at.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `args` - The call's arguments: a cell reference and the row and column offsets.
+/// * `row` - The row index of the formula's cell (for context).
+/// * `col` - The column index of the formula's cell (for context).
+///
+/// # Returns
+/// A `Result<(i32, i32), ErrorKind>` with the target cell, or `BadReference` if it lies outside
+/// the spreadsheet.
+fn offset_target(
+    sheet: &mut Sheet,
+    args: &[Expr],
+    row: i32,
+    col: i32,
+) -> Result<(i32, i32), ErrorKind> {
+    let [Expr::CellRef(base), row_offset, col_offset] = args else {
+        return Err(ErrorKind::Invalid);
+    };
+    let (base_row, base_col) =
+        parse_cell_reference(sheet, base).map_err(|_| ErrorKind::BadReference)?;
+    let row_offset = evaluate_ast(sheet, row_offset, row, col)?;
+    let col_offset = evaluate_ast(sheet, col_offset, row, col)?;
+    let target_row = base_row
+        .checked_add(row_offset)
+        .ok_or(ErrorKind::BadReference)?;
+    let target_col = base_col
+        .checked_add(col_offset)
+        .ok_or(ErrorKind::BadReference)?;
+    if target_row < 0 || target_row >= sheet.rows || target_col < 0 || target_col >= sheet.cols {
+        return Err(ErrorKind::BadReference);
+    }
+    Ok((target_row, target_col))
+}
+
+/// The error kind a reference to an error cell evaluates to.
+fn error_of(cell: &Cell) -> ErrorKind {
+    cell.error_kind.unwrap_or(ErrorKind::Invalid)
+}
+
+/// Finds why a range function failed.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `args` - The function's arguments; only ranges and cell references among them are inspected.
+///
+/// # Returns
+/// The error kind of the first error cell in the ranges, `BadReference` if a range is invalid,
+/// or `Invalid` if the ranges hold no error.
+fn range_error(sheet: &mut Sheet, args: &[Expr]) -> ErrorKind {
+    let Some(ranges) = args
+        .iter()
+        .filter(|arg| matches!(arg, Expr::Range(..) | Expr::CellRef(_)))
+        .map(|arg| reference_arg(sheet, arg))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return ErrorKind::BadReference;
+    };
+    for (sheet_name, start_row, start_col, end_row, end_col) in ranges {
+        let cells = match &sheet_name {
+            Some(name) => &sheet.workbook.sheets[name].cells,
+            None => &sheet.cells,
+        };
+        for row in &cells[start_row as usize..=end_row as usize] {
+            if let Some(cell) = row[start_col as usize..=end_col as usize]
+                .iter()
+                .find(|cell| cell.is_error)
+            {
+                return error_of(cell);
             }
-            sleep(Duration::from_secs(duration as u64));
-            return (duration, false);
         }
+    }
+    ErrorKind::Invalid
+}
 
-        if parse_range(sheet, args).is_some() {
-            match calculate_range_function(sheet, &function, args) {
-                Ok(result) => {
-                    if result.is_nan() || result.is_infinite() {
-                        return (0, true);
+/// Evaluates a parsed expression tree.
+///
+/// Cell references holding an error, division by zero, overflow, unknown functions and
+/// malformed function arguments all evaluate to an `Err` with the matching `ErrorKind`.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `expr` - The expression tree to evaluate.
+/// * `row` - The row index of the cell (for context).
+/// * `col` - The column index of the cell (for context).
+///
+/// # Returns
+/// A `Result<i32, ErrorKind>` containing the computed value or the kind of error.
+fn evaluate_ast(sheet: &mut Sheet, expr: &Expr, row: i32, col: i32) -> Result<i32, ErrorKind> {
+    match expr {
+        Expr::Number(value) => Ok(*value),
+        Expr::Bool(value) => Ok(*value as i32),
+        Expr::CellRef(cell_ref) => {
+            let cell = referenced_cell(sheet, cell_ref)?;
+            if cell.is_error {
+                return Err(error_of(cell));
+            }
+            // Text has to be converted with VALUE before it can be used as a number
+            if cell.kind == DataKind::Text {
+                return Err(ErrorKind::Invalid);
+            }
+            Ok(cell.value)
+        }
+        Expr::Neg(inner) => evaluate_ast(sheet, inner, row, col)?
+            .checked_neg()
+            .ok_or(ErrorKind::Overflow),
+        Expr::Binary { op, left, right } => {
+            let a = evaluate_ast(sheet, left, row, col)?;
+            let b = evaluate_ast(sheet, right, row, col)?;
+            let result = match op {
+                '+' => a.checked_add(b),
+                '-' => a.checked_sub(b),
+                '*' => a.checked_mul(b),
+                '/' => {
+                    if b == 0 {
+                        return Err(ErrorKind::DivByZero);
                     }
-                    return (result as i32, false);
+                    a.checked_div(b)
                 }
-                Err(()) => return (0, true),
-            }
+                _ => return Err(ErrorKind::Invalid),
+            };
+            result.ok_or(ErrorKind::Overflow)
+        }
+        Expr::Comparison { op, left, right } => {
+            let a = evaluate_ast(sheet, left, row, col)?;
+            let b = evaluate_ast(sheet, right, row, col)?;
+            Ok(matches_criterion(a, op, b) as i32)
         }
+        Expr::Call { name, args } => evaluate_function(sheet, name, args, row, col),
+        Expr::Text(_) | Expr::Range(..) | Expr::Criterion { .. } => Err(ErrorKind::Invalid),
     }
+}
 
-    // Handle arithmetic expressions
-    let mut final_expr = String::new();
-    let mut pos = 0;
-    while pos < expr.len() {
-        let c = expr.chars().nth(pos).unwrap();
-        if c.is_alphabetic() {
-            let mut token_end = pos;
-            while token_end < expr.len()
-                && expr
-                    .chars()
-                    .nth(token_end)
-                    .is_some_and(|c| c.is_alphanumeric())
+/// Looks up the cell a reference points to, on the active sheet or another sheet.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the active spreadsheet.
+/// * `cell_ref` - The cell reference (e.g., "A1" or "Sheet2!A1").
+///
+/// # Returns
+/// A `Result<&Cell, ErrorKind>` with the cell, or `BadReference` if there is no such cell.
+fn referenced_cell<'a>(sheet: &'a mut Sheet, cell_ref: &str) -> Result<&'a Cell, ErrorKind> {
+    match parse_cell_reference(sheet, cell_ref) {
+        Ok((r, c)) => Ok(&sheet.cells[r as usize][c as usize]),
+        Err(_) => {
+            let (name, r, c, _, _) =
+                parse_external_reference(sheet, cell_ref).ok_or(ErrorKind::BadReference)?;
+            Ok(&sheet.workbook.sheets[&name].cells[r as usize][c as usize])
+        }
+    }
+}
+
+/// Draws the next pseudo-random number for RANDBETWEEN.
+///
+/// This is the SplitMix64 generator: the state advances by a fixed odd constant and is then
+/// scrambled, so every seed, including 0, gives a well-mixed sequence. The same seed always
+/// gives the same sequence, see the SEED command.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet, whose `rng_state` is advanced.
+///
+/// # Returns
+/// A `u64` with the next number of the sequence.
+fn next_random(sheet: &mut Sheet) -> u64 {
+    sheet.rng_state = sheet.rng_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = sheet.rng_state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Evaluates a function call such as `SUM(A1:A3)` or `SLEEP(2)`.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `name` - The upper-case function name.
+/// * `args` - The parsed argument expressions.
+/// * `row` - The row index of the cell (for context).
+/// * `col` - The column index of the cell (for context).
+///
+/// # Returns
+/// A `Result<i32, ErrorKind>` containing the function result or the kind of error.
+fn evaluate_function(
+    sheet: &mut Sheet,
+    name: &str,
+    args: &[Expr],
+    row: i32,
+    col: i32,
+) -> Result<i32, ErrorKind> {
+    match name {
+        "SLEEP" => {
+            if args.len() != 1 {
+                return Err(ErrorKind::Invalid);
+            }
+            let duration = evaluate_ast(sheet, &args[0], row, col)?;
+            // A recalculation only sleeps again if the duration itself changed
+            if !sheet.recalculating || sheet.sleep_cache.get(&(row, col)) != Some(&duration) {
+                sleep(Duration::from_secs(duration as u64));
+                sheet.sleep_cache.insert((row, col), duration);
+            }
+            Ok(duration)
+        }
+        "SUMIF" => match calculate_sumif(sheet, args) {
+            Ok(result) => i32::try_from(result).map_err(|_| ErrorKind::Overflow),
+            Err(()) => Err(range_error(sheet, args)),
+        },
+        "SUM" | "AVG" | "AVGOK" | "MIN" | "MAX" | "STDEV" | "MODE" => {
+            if args.is_empty() || !args.iter().all(|arg| is_valid_list_arg(sheet, arg)) {
+                return Err(ErrorKind::Invalid);
+            }
+            let result = match calculate_range_function(sheet, name, args) {
+                Ok(result) => result,
+                Err(()) => return Err(range_error(sheet, args)),
+            };
+            if result.is_nan() || result.is_infinite() {
+                return Err(ErrorKind::Invalid);
+            }
+            if result < i32::MIN as f64 || result > i32::MAX as f64 {
+                return Err(ErrorKind::Overflow);
+            }
+            Ok(result as i32)
+        }
+        "AND" | "OR" => {
+            if args.is_empty() {
+                return Err(ErrorKind::Invalid);
+            }
+            // Every argument is evaluated, so an error in any of them is reported
+            let mut truths = Vec::with_capacity(args.len());
+            for arg in args {
+                truths.push(evaluate_ast(sheet, arg, row, col)? != 0);
+            }
+            let result = if name == "AND" {
+                truths.iter().all(|&truth| truth)
+            } else {
+                truths.iter().any(|&truth| truth)
+            };
+            Ok(result as i32)
+        }
+        "NOT" => {
+            if args.len() != 1 {
+                return Err(ErrorKind::Invalid);
+            }
+            Ok((evaluate_ast(sheet, &args[0], row, col)? == 0) as i32)
+        }
+        "DATE" => {
+            if args.len() != 3 {
+                return Err(ErrorKind::Invalid);
+            }
+            let year = evaluate_ast(sheet, &args[0], row, col)?;
+            let month = evaluate_ast(sheet, &args[1], row, col)?;
+            let day = evaluate_ast(sheet, &args[2], row, col)?;
+            days_from_civil(year, month, day).ok_or(ErrorKind::Invalid)
+        }
+        "TODAY" => {
+            if !args.is_empty() {
+                return Err(ErrorKind::Invalid);
+            }
+            let elapsed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| ErrorKind::Invalid)?;
+            Ok((elapsed.as_secs() / 86400) as i32)
+        }
+        "RANDBETWEEN" => {
+            if args.len() != 2 {
+                return Err(ErrorKind::Invalid);
+            }
+            let low = evaluate_ast(sheet, &args[0], row, col)?;
+            let high = evaluate_ast(sheet, &args[1], row, col)?;
+            if low > high {
+                return Err(ErrorKind::Invalid);
+            }
+            // A recalculation keeps the number drawn before, unless the bounds changed
+            if let Some(&(cached_low, cached_high, value)) = sheet.random_cache.get(&(row, col)) {
+                if sheet.recalculating && (cached_low, cached_high) == (low, high) {
+                    return Ok(value);
+                }
+            }
+            let span = (high as i64 - low as i64 + 1) as u64;
+            let value = (low as i64 + (next_random(sheet) % span) as i64) as i32;
+            sheet.random_cache.insert((row, col), (low, high, value));
+            Ok(value)
+        }
+        "ELAPSED" => {
+            if !args.is_empty() || !sheet.extension_enabled {
+                return Err(ErrorKind::Invalid);
+            }
+            Ok(sheet.last_elapsed as i32)
+        }
+        "DATEDIFF" => {
+            if args.len() != 2 {
+                return Err(ErrorKind::Invalid);
+            }
+            let start = evaluate_ast(sheet, &args[0], row, col)?;
+            let end = evaluate_ast(sheet, &args[1], row, col)?;
+            end.checked_sub(start).ok_or(ErrorKind::Overflow)
+        }
+        "VLOOKUP" => {
+            if args.len() != 3 {
+                return Err(ErrorKind::Invalid);
+            }
+            let key = evaluate_ast(sheet, &args[0], row, col)?;
+            let (start_row, start_col, end_row, end_col) = match &args[1] {
+                Expr::Range(start, end) => parse_range(sheet, &format!("{}:{}", start, end))
+                    .ok_or(ErrorKind::BadReference)?,
+                _ => return Err(ErrorKind::Invalid),
+            };
+            let offset = evaluate_ast(sheet, &args[2], row, col)?;
+            if offset < 1 || offset > end_col - start_col + 1 {
+                return Err(ErrorKind::BadReference);
+            }
+
+            // Find the first row whose key column matches, ignoring error, text and empty cells,
+            // whose stored 0 is not a number to match
+            for r in start_row..=end_row {
+                let key_cell = &sheet.cells[r as usize][start_col as usize];
+                if !key_cell.is_error
+                    && key_cell.is_set
+                    && key_cell.kind != DataKind::Text
+                    && key_cell.value == key
+                {
+                    let result = &sheet.cells[r as usize][(start_col + offset - 1) as usize];
+                    return if result.is_error {
+                        Err(error_of(result))
+                    } else {
+                        Ok(result.value)
+                    };
+                }
+            }
+            Err(ErrorKind::Invalid)
+        }
+        "INDEX" => {
+            if args.len() != 3 {
+                return Err(ErrorKind::Invalid);
+            }
+            let (start_row, start_col, end_row, end_col) = match &args[0] {
+                Expr::Range(start, end) => parse_range(sheet, &format!("{}:{}", start, end))
+                    .ok_or(ErrorKind::BadReference)?,
+                _ => return Err(ErrorKind::Invalid),
+            };
+            let row_offset = evaluate_ast(sheet, &args[1], row, col)?;
+            let col_offset = evaluate_ast(sheet, &args[2], row, col)?;
+            if row_offset < 1
+                || row_offset > end_row - start_row + 1
+                || col_offset < 1
+                || col_offset > end_col - start_col + 1
             {
-                token_end += 1;
+                return Err(ErrorKind::BadReference);
+            }
+            let cell = &sheet.cells[(start_row + row_offset - 1) as usize]
+                [(start_col + col_offset - 1) as usize];
+            if cell.is_error {
+                Err(error_of(cell))
+            } else {
+                Ok(cell.value)
             }
-            let token = &expr[pos..token_end];
-            if let Some((r, c)) = parse_cell_reference(sheet, token) {
-                let cell = &sheet.cells[r as usize][c as usize];
+        }
+        "SUMDIAG" => {
+            let (start_row, start_col, end_row, end_col) = match args {
+                [Expr::Range(start, end)] => parse_range(sheet, &format!("{}:{}", start, end))
+                    .ok_or(ErrorKind::BadReference)?,
+                _ => return Err(ErrorKind::Invalid),
+            };
+            // Only a square range has a main diagonal
+            if end_row - start_row != end_col - start_col {
+                return Err(ErrorKind::Invalid);
+            }
+            let mut sum: i64 = 0;
+            for offset in 0..=end_row - start_row {
+                let cell =
+                    &sheet.cells[(start_row + offset) as usize][(start_col + offset) as usize];
                 if cell.is_error {
-                    return (0, true);
+                    return Err(error_of(cell));
                 }
-                final_expr.push_str(&cell.value.to_string());
-            } else {
-                return (0, true); // Invalid cell reference
-            }
-            pos = token_end;
-        } else if c.is_ascii_digit() {
-            let mut token_end = pos;
-            while token_end < expr.len() && expr.chars().nth(token_end).unwrap().is_ascii_digit() {
-                token_end += 1;
-            }
-            final_expr.push_str(&expr[pos..token_end]);
-            pos = token_end;
-        } else if c == '-'
-            && (pos == 0 || "+-*/(".contains(expr.chars().nth(pos - 1).unwrap_or(' ')))
-        {
-            let mut token_end = pos + 1;
-            while token_end < expr.len() && expr.chars().nth(token_end).unwrap().is_ascii_digit() {
-                token_end += 1;
-            }
-            final_expr.push_str(&expr[pos..token_end]);
-            pos = token_end;
-        } else if "+-*/()".contains(c) {
-            final_expr.push(' ');
-            final_expr.push(c);
-            final_expr.push(' ');
-            pos += 1;
-        } else {
-            pos += 1;
-        }
-    }
-
-    // Evaluate the constructed arithmetic expression
-    let result = evaluate_arithmetic(&final_expr, &mut is_error);
-    (result, is_error)
+                if cell.kind != DataKind::Text {
+                    sum += cell.value as i64;
+                }
+            }
+            i32::try_from(sum).map_err(|_| ErrorKind::Overflow)
+        }
+        "OFFSET" => {
+            let (target_row, target_col) = offset_target(sheet, args, row, col)?;
+            let cell = &sheet.cells[target_row as usize][target_col as usize];
+            if cell.is_error {
+                return Err(error_of(cell));
+            }
+            if cell.kind == DataKind::Text {
+                return Err(ErrorKind::Invalid);
+            }
+            Ok(cell.value)
+        }
+        "VALUE" => {
+            if args.len() != 1 {
+                return Err(ErrorKind::Invalid);
+            }
+            let text = match &args[0] {
+                Expr::Text(text) => Some(text.clone()),
+                Expr::CellRef(cell_ref) => {
+                    let cell = referenced_cell(sheet, cell_ref)?;
+                    if cell.is_error {
+                        return Err(error_of(cell));
+                    }
+                    cell_text(cell)
+                }
+                _ => None,
+            };
+            match text {
+                Some(text) => text.trim().parse().map_err(|_| ErrorKind::Invalid),
+                None => evaluate_ast(sheet, &args[0], row, col),
+            }
+        }
+        "TEXT" => match args {
+            // The pattern only decides how the value is shown, see `cell_text`
+            [value, Expr::Text(pattern)]
+                if !pattern.is_empty() && pattern.chars().all(|c| c == '0') =>
+            {
+                evaluate_ast(sheet, value, row, col)
+            }
+            _ => Err(ErrorKind::Invalid),
+        },
+        _ => Err(ErrorKind::Invalid),
+    }
+}
+
+/// Determines how a formula's result should be displayed.
+///
+/// A formula whose outermost expression is a call to DATE or TODAY produces a date, quoted text
+/// or a call to TEXT produces text, a percentage literal such as `50%` produces a percentage,
+/// and every other formula produces a plain number.
+///
+/// # Arguments
+/// * `formula` - The formula string.
+///
+/// # Returns
+/// The `DataKind` of the formula's result.
+///
+/// # Example
+/// ```
+/// assert_eq!(formula_kind("DATE(2024, 1, 1)"), DataKind::Date);
+/// assert_eq!(formula_kind("\"abc\""), DataKind::Text);
+/// assert_eq!(formula_kind("50%"), DataKind::Percent);
+/// assert_eq!(formula_kind("A1+1"), DataKind::Number);
+/// ```
+pub fn formula_kind(formula: &str) -> DataKind {
+    if percent_literal(formula).is_some() {
+        return DataKind::Percent;
+    }
+    match parse(formula) {
+        Some(Expr::Call { name, .. }) if name == "DATE" || name == "TODAY" => DataKind::Date,
+        Some(Expr::Call { name, .. }) if name == "TEXT" => DataKind::Text,
+        Some(Expr::Text(_)) => DataKind::Text,
+        _ => DataKind::Number,
+    }
 }
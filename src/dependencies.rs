@@ -1,9 +1,15 @@
-use crate::cell::evaluate_expression;
+use crate::cell::{evaluate_expression, offset_dependencies};
+use crate::parser::{parse, RefPosition};
 use crate::types::CellDependencies;
-use crate::types::{DependencyType, Sheet};
-use crate::utils::{parse_cell_reference, parse_range};
+use crate::types::{Cell, DataKind, DependencyType, ErrorKind, RangeStats, Sheet};
+use crate::utils::{
+    parse_cell_reference, parse_range, replace_dynamic_bounds, resolve_dynamic_bounds,
+};
 use std::collections::{HashMap, HashSet, VecDeque};
 
+/// A changed cell, with the value it added to a range before and after the change.
+type CellChange = ((i32, i32), Option<i32>, Option<i32>);
+
 /// Removes a specific dependency or dependent relationship from the dependency graph for a given cell.
 ///
 /// This function updates the dependency graph by removing a single cell dependency (or dependent)
@@ -51,6 +57,40 @@ pub fn remove_dependency(
     }
 }
 
+/// Removes all of a cell's own dependencies from the dependency graph.
+///
+/// This function is used when a cell's formula is replaced by a plain value without going through
+/// `update_cell`. The cell is removed from the dependents of every cell it referenced, while cells
+/// that depend on it keep their links.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `row` - The row index of the cell.
+/// * `col` - The column index of the cell.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// update_cell(&mut sheet, 0, 1, "A1+1");
+/// clear_cell_dependencies(&mut sheet, 0, 1);
+/// assert!(sheet.dependency_graph.get(&(0, 1)).is_none());
+/// assert!(sheet.dependency_graph.get(&(0, 0)).is_none());
+/// ```
+pub fn clear_cell_dependencies(sheet: &mut Sheet, row: i32, col: i32) {
+    let Some(cell_deps) = sheet.dependency_graph.get_mut(&(row, col)) else {
+        return;
+    };
+    let dependencies = std::mem::take(&mut cell_deps.dependencies);
+    if cell_deps.dependents.is_empty() {
+        sheet.dependency_graph.remove(&(row, col));
+    }
+    for dep in dependencies {
+        if let DependencyType::Single { row: r, col: c } = dep {
+            remove_dependency(sheet, r, c, row, col, true);
+        }
+    }
+}
+
 /// Checks if a formula in a cell introduces a circular dependency.
 ///
 /// This function evaluates the formula at the specified cell `(start_row, start_col)` to determine
@@ -71,7 +111,7 @@ pub fn remove_dependency(
 /// ```
 /// let mut sheet = create_sheet(10, 10, false).unwrap();
 /// // A1 references A1 itself
-/// let has_circular = has_circular_dependency(&mut sheet, 0, 0, "=A1");
+/// let has_circular = has_circular_dependency(&mut sheet, 0, 0, "A1");
 /// assert!(has_circular);
 /// assert!(sheet.cells[0][0].has_circular);
 /// ```
@@ -85,31 +125,7 @@ pub fn has_circular_dependency(
         return false;
     }
 
-    let mut new_deps = Vec::new();
-    if !formula.is_empty() {
-        let tokens: Vec<&str> = formula
-            .split(&['+', '-', '*', '/', '(', ')', ' '][..])
-            .collect();
-        for token in tokens {
-            if token.contains(':') {
-                if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, token) {
-                    new_deps.push(DependencyType::Range {
-                        start_row,
-                        start_col,
-                        end_row,
-                        end_col,
-                    });
-                }
-            } else if token.chars().next().is_some_and(|c| c.is_alphabetic()) {
-                if let Some((dep_row, dep_col)) = parse_cell_reference(sheet, token) {
-                    new_deps.push(DependencyType::Single {
-                        row: dep_row,
-                        col: dep_col,
-                    });
-                }
-            }
-        }
-    }
+    let new_deps = formula_dependencies_at(sheet, formula, start_row, start_col);
 
     // Temporarily add new dependencies
     let old_deps = sheet.dependency_graph.remove(&(start_row, start_col));
@@ -125,34 +141,23 @@ pub fn has_circular_dependency(
 
     let mut visited = HashSet::new();
 
-    fn dfs(
+    // Lists the cells to visit from `cell`, or returns `None` if `cell` depends on the start cell
+    // directly. Range dependencies are followed through their corner cells.
+    fn dfs_children(
         sheet: &Sheet,
-        curr_row: i32,
-        curr_col: i32,
+        cell: (i32, i32),
         start_row: i32,
         start_col: i32,
-        path: &mut HashSet<(i32, i32)>,
-        visited: &mut HashSet<(i32, i32)>,
-    ) -> bool {
-        if path.contains(&(curr_row, curr_col)) {
-            return true;
-        }
-
-        if visited.contains(&(curr_row, curr_col)) {
-            return false;
-        }
-
-        path.insert((curr_row, curr_col));
-
-        if let Some(cell_deps) = sheet.dependency_graph.get(&(curr_row, curr_col)) {
+    ) -> Option<Vec<(i32, i32)>> {
+        let mut children = Vec::new();
+        if let Some(cell_deps) = sheet.dependency_graph.get(&cell) {
             for dep in &cell_deps.dependencies {
                 match dep {
                     DependencyType::Single { row, col } => {
-                        if (*row == start_row && *col == start_col)
-                            || dfs(sheet, *row, *col, start_row, start_col, path, visited)
-                        {
-                            return true;
+                        if *row == start_row && *col == start_col {
+                            return None;
                         }
+                        children.push((*row, *col));
                     }
                     DependencyType::Range {
                         start_row: s_row,
@@ -165,28 +170,62 @@ pub fn has_circular_dependency(
                             && start_col >= *s_col
                             && start_col <= *e_col
                         {
-                            return true;
+                            return None;
                         }
-                        let corners = [
+                        children.extend([
                             (*s_row, *s_col),
                             (*s_row, *e_col),
                             (*e_row, *s_col),
                             (*e_row, *e_col),
-                        ];
-                        for (i, j) in corners.iter() {
-                            if dfs(sheet, *i, *j, start_row, start_col, path, visited) {
-                                return true;
-                            }
-                        }
+                        ]);
                     }
                 }
             }
         }
+        Some(children)
+    }
+
+    // Depth-first search with an explicit stack, so long dependency chains cannot overflow the
+    // call stack. Each stack entry holds a cell on the current path and the children still to visit.
+    fn dfs(
+        sheet: &Sheet,
+        curr_row: i32,
+        curr_col: i32,
+        start_row: i32,
+        start_col: i32,
+        path: &mut HashSet<(i32, i32)>,
+        visited: &mut HashSet<(i32, i32)>,
+    ) -> bool {
+        let mut stack = Vec::new();
+        let mut next = Some((curr_row, curr_col));
 
-        path.remove(&(curr_row, curr_col));
-        visited.insert((curr_row, curr_col));
+        loop {
+            if let Some(cell) = next.take() {
+                if path.contains(&cell) {
+                    return true;
+                }
+                if !visited.contains(&cell) {
+                    let Some(mut children) = dfs_children(sheet, cell, start_row, start_col) else {
+                        return true;
+                    };
+                    children.reverse();
+                    path.insert(cell);
+                    stack.push((cell, children));
+                }
+            }
 
-        false
+            let Some((cell, children)) = stack.last_mut() else {
+                return false;
+            };
+            if let Some(child) = children.pop() {
+                next = Some(child);
+            } else {
+                let cell = *cell;
+                stack.pop();
+                path.remove(&cell);
+                visited.insert(cell);
+            }
+        }
     }
 
     let mut path = HashSet::new();
@@ -280,7 +319,8 @@ pub fn has_circular_dependency(
 /// This function uses a breadth-first search (BFS) to identify all cells that depend on the cell
 /// at `(start_row, start_col)`, either directly or indirectly. It then performs a topological sort
 /// to determine the order of recalculation and updates the values of dependent cells based on their
-/// formulas. The starting cell itself is not recalculated.
+/// formulas. The starting cell itself is not recalculated. A `SLEEP` whose duration is unchanged
+/// since it last ran reuses its value instead of sleeping again.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
@@ -300,6 +340,45 @@ pub fn has_circular_dependency(
 /// assert_eq!(sheet.cells[0][1].value, 11);
 /// ```
 pub fn recalculate_dependents(sheet: &mut Sheet, start_row: i32, start_col: i32) {
+    recalculate(sheet, start_row, start_col, None);
+}
+
+/// Recalculates the cells that depend on a cell whose previous state is known.
+///
+/// This works like `recalculate_dependents`, but knowing what the cell held before lets SUM,
+/// AVG and STDEV cells over a single range update their running statistics for the change
+/// instead of reading their whole range again. `update_cell` uses it after every assignment.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `start_row` - The row index of the changed cell.
+/// * `start_col` - The column index of the changed cell.
+/// * `previous` - The changed cell as it was before the change.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// update_cell(&mut sheet, 0, 0, "5");
+/// update_cell(&mut sheet, 0, 1, "SUM(A1:A3)");
+/// let previous = sheet.cells[0][0].clone();
+/// sheet.cells[0][0].value = 8;
+/// recalculate_after_change(&mut sheet, 0, 0, &previous);
+/// assert_eq!(sheet.cells[0][1].value, 8);
+/// ```
+pub fn recalculate_after_change(
+    sheet: &mut Sheet,
+    start_row: i32,
+    start_col: i32,
+    previous: &Cell,
+) {
+    recalculate(sheet, start_row, start_col, Some(previous));
+}
+
+/// Recalculates the dependents of a cell, see `recalculate_dependents`. With the cell's previous
+/// state, range statistics are updated in place where possible. Each formula evaluated uses up one
+/// cell of `recalc_remaining`, the budget left for the current command; once it runs out, the
+/// others keep their old values and are added to `deferred_cells`.
+fn recalculate(sheet: &mut Sheet, start_row: i32, start_col: i32, previous: Option<&Cell>) {
     if start_row < 0 || start_row >= sheet.rows || start_col < 0 || start_col >= sheet.cols {
         return;
     }
@@ -349,11 +428,204 @@ pub fn recalculate_dependents(sheet: &mut Sheet, start_row: i32, start_col: i32)
         }
     }
 
-    // Topological sort
+    let topo_order = topological_order(sheet, &dependents);
+
+    // How each changed cell counted in a range before and after its change. A change to the
+    // start cell is only known if its previous state was given.
+    let start_cell = &sheet.cells[start_row as usize][start_col as usize];
+    let mut changes: Vec<CellChange> = Vec::new();
+    if let Some(previous) = previous {
+        changes.push((
+            (start_row, start_col),
+            counted_value(previous),
+            counted_value(start_cell),
+        ));
+    }
+    let unknown_change = previous.is_none().then_some((start_row, start_col));
+
+    // Recalculate cells, up to the budget; the rest wait for a RECOMPUTE
+    sheet.recalculating = true;
+    for (position, &(row, col)) in topo_order.iter().enumerate() {
+        if row != start_row || col != start_col {
+            if let Some(formula) = sheet.cells[row as usize][col as usize].formula.clone() {
+                if sheet.recalc_remaining == Some(0) {
+                    let deferred: Vec<(i32, i32)> = topo_order[position..]
+                        .iter()
+                        .copied()
+                        .filter(|&(r, c)| {
+                            (r, c) != (start_row, start_col)
+                                && sheet.cells[r as usize][c as usize].formula.is_some()
+                        })
+                        .collect();
+                    sheet.deferred_cells.extend(deferred);
+                    break;
+                }
+                if let Some(remaining) = sheet.recalc_remaining.as_mut() {
+                    *remaining -= 1;
+                }
+                // A `{cell}` row bound or an OFFSET target may have moved, so the cells read are
+                // found again
+                if has_moving_references(&formula) {
+                    let dependencies = formula_dependencies_at(sheet, &formula, row, col);
+                    set_dependencies(sheet, row, col, dependencies);
+                }
+                let before = counted_value(&sheet.cells[row as usize][col as usize]);
+                let updated = updated_stats(sheet, row, col, &formula, &changes, unknown_change);
+                let cell = &mut sheet.cells[row as usize][col as usize];
+                if let Some((new_value, stats)) = updated {
+                    cell.value = new_value;
+                    cell.is_error = false;
+                    cell.error_kind = None;
+                    cell.stats = Some(Box::new(stats));
+                } else {
+                    let (new_value, error) = evaluate_expression(sheet, &formula, row, col);
+                    let stats = range_stats(sheet, &formula);
+                    let cell = &mut sheet.cells[row as usize][col as usize];
+                    cell.value = new_value;
+                    cell.is_error = error.is_some();
+                    cell.error_kind = error;
+                    cell.stats = stats;
+                }
+                let after = counted_value(&sheet.cells[row as usize][col as usize]);
+                if before != after {
+                    changes.push(((row, col), before, after));
+                }
+            }
+        }
+    }
+    sheet.recalculating = false;
+}
+
+/// The value a cell adds to SUM, AVG and STDEV, or `None` if it makes them fail (an error) or
+/// is skipped by them (text).
+fn counted_value(cell: &Cell) -> Option<i32> {
+    (!cell.is_error && cell.kind != DataKind::Text).then_some(cell.value)
+}
+
+/// Splits a formula of the form `SUM(range)`, `AVG(range)` or `STDEV(range)` into the function
+/// name and the range, for the formulas that keep running statistics.
+fn aggregate_range(sheet: &mut Sheet, formula: &str) -> Option<(String, (i32, i32, i32, i32))> {
+    let (name, args) = formula.trim().trim_start_matches('=').split_once('(')?;
+    let name = name.trim().to_uppercase();
+    let args = args.trim_end().strip_suffix(')')?;
+    if !["SUM", "AVG", "STDEV"].contains(&name.as_str()) || args.contains([',', '(', '!']) {
+        return None;
+    }
+    Some((name, parse_range(sheet, args.trim())?))
+}
+
+/// Computes the running statistics of a SUM, AVG or STDEV formula over a single range by reading
+/// the whole range.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `formula` - The formula of the cell.
+///
+/// # Returns
+/// The statistics, or `None` if the formula is not of that form or the range holds an error or
+/// text cell, in which case the formula is always evaluated in full.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// sheet.cells[0][0].value = 2;
+/// sheet.cells[1][0].value = 4;
+/// let stats = range_stats(&mut sheet, "AVG(A1:A2)").unwrap();
+/// assert_eq!((stats.count, stats.sum, stats.mean, stats.m2), (2, 6, 3.0, 2.0));
+/// ```
+pub fn range_stats(sheet: &mut Sheet, formula: &str) -> Option<Box<RangeStats>> {
+    let (_, (start_row, start_col, end_row, end_col)) = aggregate_range(sheet, formula)?;
+    let mut stats = RangeStats {
+        count: 0,
+        sum: 0,
+        mean: 0.0,
+        m2: 0.0,
+    };
+    for row in &sheet.cells[start_row as usize..=end_row as usize] {
+        for cell in &row[start_col as usize..=end_col as usize] {
+            let value = counted_value(cell)?;
+            stats.count += 1;
+            stats.sum += i64::from(value);
+            let delta = value as f64 - stats.mean;
+            stats.mean += delta / stats.count as f64;
+            stats.m2 += delta * (value as f64 - stats.mean);
+        }
+    }
+    Some(Box::new(stats))
+}
+
+/// Updates the running statistics of a SUM, AVG or STDEV cell for the changes made so far in a
+/// recalculation, and computes its new value from them.
+///
+/// Returns `None` when the cell has no statistics, when a change in its range is not known or
+/// turns a cell into an error or text, or when the result does not fit a cell; the formula is
+/// then evaluated in full instead.
+fn updated_stats(
+    sheet: &mut Sheet,
+    row: i32,
+    col: i32,
+    formula: &str,
+    changes: &[CellChange],
+    unknown_change: Option<(i32, i32)>,
+) -> Option<(i32, RangeStats)> {
+    let mut stats = **sheet.cells[row as usize][col as usize].stats.as_ref()?;
+    let (name, (start_row, start_col, end_row, end_col)) = aggregate_range(sheet, formula)?;
+    let in_range = |(r, c): (i32, i32)| {
+        (start_row..=end_row).contains(&r) && (start_col..=end_col).contains(&c)
+    };
+    if unknown_change.is_some_and(in_range) {
+        return None;
+    }
+    for &(cell, before, after) in changes {
+        if !in_range(cell) {
+            continue;
+        }
+        // Replacing one value keeps the count; see Welford's algorithm for the update of m2
+        let (before, after) = (f64::from(before?), f64::from(after?));
+        let old_mean = stats.mean;
+        stats.sum += (after - before) as i64;
+        stats.mean += (after - before) / stats.count as f64;
+        stats.m2 += (after - before) * (after - stats.mean + before - old_mean);
+    }
+
+    let result = match name.as_str() {
+        "SUM" => stats.sum as f64,
+        "AVG" => stats.sum as f64 / stats.count as f64,
+        _ if stats.count <= 1 => 0.0,
+        _ => (stats.m2 / stats.count as f64).sqrt().round(),
+    };
+    if !result.is_finite() || result < i32::MIN as f64 || result > i32::MAX as f64 {
+        return None;
+    }
+    Some((result as i32, stats))
+}
+
+/// Orders a set of cells so that every cell comes after the cells it depends on.
+///
+/// This function runs Kahn's algorithm over the dependency graph, considering only edges between
+/// cells in `nodes`. Cells that are part of a cycle never reach an in-degree of zero and are left
+/// out of the result.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+/// * `nodes` - The cells to order, as `(row, col)` pairs.
+///
+/// # Returns
+/// A `Vec<(i32, i32)>` with the cells in evaluation order.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// update_cell(&mut sheet, 0, 1, "A1+1");
+/// let order = topological_order(&sheet, &[(0, 1), (0, 0)]);
+/// assert_eq!(order, vec![(0, 0), (0, 1)]);
+/// ```
+fn topological_order(sheet: &Sheet, nodes: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let members: HashSet<(i32, i32)> = nodes.iter().copied().collect();
     let mut graph = HashMap::new();
     let mut in_degree = HashMap::new();
 
-    for &(row, col) in &dependents {
+    for &(row, col) in nodes {
         let node = (row, col);
         in_degree.entry(node).or_insert(0);
 
@@ -361,7 +633,7 @@ pub fn recalculate_dependents(sheet: &mut Sheet, start_row: i32, start_col: i32)
             for dep in &cell_deps.dependencies {
                 match dep {
                     DependencyType::Single { row: r, col: c } => {
-                        if dependents.contains(&(*r, *c)) {
+                        if members.contains(&(*r, *c)) {
                             graph.entry((*r, *c)).or_insert_with(Vec::new).push(node);
                             *in_degree.entry(node).or_insert(0) += 1;
                         }
@@ -374,7 +646,7 @@ pub fn recalculate_dependents(sheet: &mut Sheet, start_row: i32, start_col: i32)
                     } => {
                         for i in *s_row..=*e_row {
                             for j in *s_col..=*e_col {
-                                if dependents.contains(&(i, j)) {
+                                if members.contains(&(i, j)) {
                                     graph.entry((i, j)).or_insert_with(Vec::new).push(node);
                                     *in_degree.entry(node).or_insert(0) += 1;
                                 }
@@ -389,7 +661,7 @@ pub fn recalculate_dependents(sheet: &mut Sheet, start_row: i32, start_col: i32)
     let mut topo_order = Vec::new();
     let mut queue = VecDeque::new();
 
-    for &node in &dependents {
+    for &node in nodes {
         if in_degree.get(&node).unwrap_or(&0) == &0 {
             queue.push_back(node);
         }
@@ -408,17 +680,500 @@ pub fn recalculate_dependents(sheet: &mut Sheet, start_row: i32, start_col: i32)
         }
     }
 
-    // Recalculate cells
-    for &(row, col) in &topo_order {
-        if row != start_row || col != start_col {
+    topo_order
+}
+
+/// Replaces the dependencies of a cell in the dependency graph.
+///
+/// The cell is removed from the dependents of the cells it read before and added to the
+/// dependents of the cells in `new_dependencies`. The cells that depend on this cell are kept.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `row` - The row index of the cell.
+/// * `col` - The column index of the cell.
+/// * `new_dependencies` - The cells and ranges the cell reads now.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// set_dependencies(&mut sheet, 0, 1, vec![DependencyType::Single { row: 0, col: 0 }]);
+/// assert_eq!(sheet.dependency_graph[&(0, 0)].dependents.len(), 1);
+/// ```
+pub fn set_dependencies(
+    sheet: &mut Sheet,
+    row: i32,
+    col: i32,
+    new_dependencies: Vec<DependencyType>,
+) {
+    // Get and remove old dependencies
+    let old_cell_deps = sheet.dependency_graph.remove(&(row, col));
+
+    // Remove this cell from dependents of old Single dependencies
+    if let Some(old_cell_deps) = &old_cell_deps {
+        for dep in &old_cell_deps.dependencies {
+            if let DependencyType::Single { row: r, col: c } = dep {
+                if let Some(dep_cell_deps) = sheet.dependency_graph.get_mut(&(*r, *c)) {
+                    dep_cell_deps.dependents.retain(|d| !matches!(d, DependencyType::Single { row: r2, col: c2 } if *r2 == row && *c2 == col));
+                }
+            }
+            // Range dependencies are handled in BFS, so no need to update dependents here
+        }
+    }
+
+    // Create new cell dependencies, preserving existing dependents if any
+    let is_empty = new_dependencies.is_empty();
+    let new_cell_deps = CellDependencies {
+        dependencies: new_dependencies,
+        dependents: old_cell_deps.map_or(Vec::new(), |d| d.dependents),
+    };
+
+    // Add this cell to dependents of new Single dependencies
+    for dep in &new_cell_deps.dependencies {
+        if let DependencyType::Single { row: r, col: c } = dep {
+            let dep_cell_deps =
+                sheet
+                    .dependency_graph
+                    .entry((*r, *c))
+                    .or_insert_with(|| CellDependencies {
+                        dependencies: Vec::new(),
+                        dependents: Vec::new(),
+                    });
+            dep_cell_deps
+                .dependents
+                .push(DependencyType::Single { row, col });
+        }
+        // Range dependencies will be detected dynamically in BFS
+    }
+
+    // Insert the updated dependencies into the graph
+    sheet.dependency_graph.insert((row, col), new_cell_deps);
+
+    // Clean up if both dependencies and dependents are empty
+    if is_empty && sheet.dependency_graph[&(row, col)].dependents.is_empty() {
+        sheet.dependency_graph.remove(&(row, col));
+    }
+}
+
+/// Lists the cells and ranges a formula refers to.
+///
+/// The references are read from the parsed formula, so text in quotes and function names are
+/// never taken for cells, and every reference or range inside the sheet becomes a dependency. A
+/// formula that does not parse has none. A row given
+/// as a cell in braces (e.g., `A1:A{B1}`) adds that cell, and the reference is read with the
+/// row the cell currently gives.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `formula` - The formula to read.
+///
+/// # Returns
+/// A `Vec<DependencyType>` with one entry per reference, in the order they appear.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let deps = formula_dependencies(&mut sheet, "A1+SUM(B1:B2)");
+/// assert_eq!(deps.len(), 2);
+/// ```
+pub fn formula_dependencies(sheet: &mut Sheet, formula: &str) -> Vec<DependencyType> {
+    let mut deps = Vec::new();
+    let resolved;
+    let formula = if formula.contains(['{', '}']) {
+        let mut bounds = Vec::new();
+        replace_dynamic_bounds(formula, |bound| {
+            bounds.push(bound.to_string());
+            Some(String::new())
+        });
+        for bound in bounds {
+            if let Ok((row, col)) = parse_cell_reference(sheet, &bound) {
+                deps.push(DependencyType::Single { row, col });
+            }
+        }
+        match resolve_dynamic_bounds(sheet, formula) {
+            Some(formula) => {
+                resolved = formula;
+                &resolved
+            }
+            None => return deps,
+        }
+    } else {
+        formula
+    };
+    let Some(expr) = parse(formula) else {
+        return deps;
+    };
+    let mut range_start = None;
+    expr.map_refs(&mut |cell_ref, position| {
+        match position {
+            RefPosition::Cell => {
+                if let Ok((row, col)) = parse_cell_reference(sheet, cell_ref) {
+                    deps.push(DependencyType::Single { row, col });
+                }
+            }
+            RefPosition::RangeStart => range_start = Some(cell_ref.to_string()),
+            RefPosition::RangeEnd => {
+                let range = format!("{}:{}", range_start.take().unwrap_or_default(), cell_ref);
+                if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, &range) {
+                    deps.push(DependencyType::Range {
+                        start_row,
+                        start_col,
+                        end_row,
+                        end_col,
+                    });
+                }
+            }
+        }
+        cell_ref.to_string()
+    });
+    deps
+}
+
+/// Lists every cell a formula in the cell at `(row, col)` reads.
+///
+/// These are the references named in the formula, see `formula_dependencies`, and the cells its
+/// OFFSET calls point at with the offsets as they are now.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `formula` - The formula to read.
+/// * `row` - The row index of the formula's cell.
+/// * `col` - The column index of the formula's cell.
+///
+/// # Returns
+/// A `Vec<DependencyType>` with one entry per reference or OFFSET target.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let deps = formula_dependencies_at(&mut sheet, "OFFSET(A1,1,2)", 0, 0);
+/// assert_eq!(deps[1], DependencyType::Single { row: 1, col: 2 });
+/// ```
+pub fn formula_dependencies_at(
+    sheet: &mut Sheet,
+    formula: &str,
+    row: i32,
+    col: i32,
+) -> Vec<DependencyType> {
+    let mut dependencies = formula_dependencies(sheet, formula);
+    // OFFSET reads a cell that is not named in the formula, so it is added separately
+    if let Some(expr) = parse(formula.trim_start_matches('=')) {
+        offset_dependencies(sheet, &expr, row, col, &mut dependencies);
+    }
+    dependencies
+}
+
+/// Whether the cells a formula reads can change without the formula changing, because it has a
+/// `{cell}` row bound or an OFFSET call whose offsets are read from cells.
+fn has_moving_references(formula: &str) -> bool {
+    formula.contains('{') || formula.to_uppercase().contains("OFFSET")
+}
+
+/// Finds the circular dependencies in the spreadsheet.
+///
+/// A formula rejected as circular is kept in its cell but never enters the dependency graph, and
+/// the circular flags are cleared by the next successful update, so the references are read from
+/// the formulas stored in the cells rather than from the graph. Tarjan's algorithm then splits
+/// the formula cells into strongly connected components; each component with more than one
+/// cell, or a cell referring to itself, holds at least one cycle. One cycle is reported per
+/// component: the shortest one through its first cell.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+///
+/// # Returns
+/// A `Vec<Vec<(i32, i32)>>` with one cycle per component, sorted by first cell. Each cycle lists
+/// its cells in order, starting from its first cell in row-major order; every cell refers to the
+/// next one, and the last cell refers back to the first.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// update_cell(&mut sheet, 0, 0, "B1+1");
+/// update_cell(&mut sheet, 0, 1, "A1");
+/// assert_eq!(find_cycles(&mut sheet), vec![vec![(0, 0), (0, 1)]]);
+/// ```
+pub fn find_cycles(sheet: &mut Sheet) -> Vec<Vec<(i32, i32)>> {
+    let mut edges = HashMap::new();
+    for row in 0..sheet.rows {
+        for col in 0..sheet.cols {
             if let Some(formula) = sheet.cells[row as usize][col as usize].formula.clone() {
-                let (new_value, is_error) = evaluate_expression(sheet, &formula, row, col);
-                let cell = &mut sheet.cells[row as usize][col as usize];
-                cell.value = new_value;
-                cell.is_error = is_error;
+                let dependencies = formula_dependencies_at(sheet, &formula, row, col);
+                edges.insert((row, col), dependencies);
+            }
+        }
+    }
+
+    // Only a cell that refers to something can be on a cycle
+    let mut nodes: Vec<(i32, i32)> = edges
+        .iter()
+        .filter(|(_, deps)| !deps.is_empty())
+        .map(|(&cell, _)| cell)
+        .collect();
+    nodes.sort();
+    let positions: HashMap<(i32, i32), usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(idx, &cell)| (cell, idx))
+        .collect();
+    let adjacency: Vec<Vec<usize>> = nodes
+        .iter()
+        .map(|cell| {
+            let mut targets = Vec::new();
+            for dep in &edges[cell] {
+                match *dep {
+                    DependencyType::Single { row, col } => {
+                        targets.extend(positions.get(&(row, col)).copied());
+                    }
+                    DependencyType::Range {
+                        start_row,
+                        start_col,
+                        end_row,
+                        end_col,
+                    } => {
+                        targets.extend(nodes.iter().enumerate().filter_map(|(idx, &(r, c))| {
+                            ((start_row..=end_row).contains(&r)
+                                && (start_col..=end_col).contains(&c))
+                            .then_some(idx)
+                        }));
+                    }
+                }
+            }
+            targets
+        })
+        .collect();
+
+    // Tarjan's algorithm, with an explicit stack of (cell, index of the next edge to follow) so
+    // long chains cannot overflow the call stack
+    let mut index: Vec<Option<usize>> = vec![None; nodes.len()];
+    let mut low = vec![0; nodes.len()];
+    let mut on_stack = vec![false; nodes.len()];
+    let mut stack = Vec::new();
+    let mut counter = 0;
+    let mut components = Vec::new();
+    for root in 0..nodes.len() {
+        if index[root].is_some() {
+            continue;
+        }
+        let mut calls = vec![(root, 0)];
+        while let Some((node, next)) = calls.pop() {
+            if next == 0 {
+                index[node] = Some(counter);
+                low[node] = counter;
+                counter += 1;
+                stack.push(node);
+                on_stack[node] = true;
+            }
+            if let Some(&target) = adjacency[node].get(next) {
+                calls.push((node, next + 1));
+                match index[target] {
+                    None => calls.push((target, 0)),
+                    Some(target_index) if on_stack[target] => {
+                        low[node] = low[node].min(target_index)
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+            if Some(low[node]) == index[node] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+            if let Some(&(parent, _)) = calls.last() {
+                low[parent] = low[parent].min(low[node]);
+            }
+        }
+    }
+
+    let mut cycles = Vec::new();
+    for component in components {
+        let first = *component.iter().min().unwrap();
+        if component.len() == 1 && !adjacency[first].contains(&first) {
+            continue;
+        }
+        // Breadth-first from the first cell, until an edge leads back to it
+        let members: HashSet<usize> = component.into_iter().collect();
+        let mut previous = HashMap::new();
+        let mut queue = VecDeque::from([first]);
+        'search: while let Some(node) = queue.pop_front() {
+            for &target in &adjacency[node] {
+                if target == first {
+                    let mut cycle = vec![nodes[node]];
+                    let mut current = node;
+                    while current != first {
+                        current = previous[&current];
+                        cycle.push(nodes[current]);
+                    }
+                    cycle.reverse();
+                    cycles.push(cycle);
+                    break 'search;
+                }
+                if members.contains(&target) && !previous.contains_key(&target) {
+                    previous.insert(target, node);
+                    queue.push_back(target);
+                }
+            }
+        }
+    }
+    cycles.sort();
+    cycles
+}
+
+/// Finds the longest chain of dependencies in the spreadsheet.
+///
+/// The length of this chain is the recalculation depth: the number of formulas that must be
+/// evaluated one after another when the cell it starts from changes. The formula cells in the
+/// dependency graph are put in topological order, and the depth of each one is worked out from
+/// the cells it reads, which come before it in that order. A cell read through a range counts
+/// like a single reference.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+///
+/// # Returns
+/// A `Vec<(i32, i32)>` with the cells of the chain, starting from the cell it reads from and
+/// ending with its last formula, so the chain has one dependency fewer than it has cells. It is
+/// empty if no formula refers to another cell.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// update_cell(&mut sheet, 0, 1, "A1+1");
+/// update_cell(&mut sheet, 0, 2, "B1*2");
+/// assert_eq!(longest_chain(&sheet), vec![(0, 0), (0, 1), (0, 2)]);
+/// ```
+pub fn longest_chain(sheet: &Sheet) -> Vec<(i32, i32)> {
+    let mut nodes: Vec<(i32, i32)> = sheet
+        .dependency_graph
+        .iter()
+        .filter(|(_, deps)| !deps.dependencies.is_empty())
+        .map(|(&cell, _)| cell)
+        .collect();
+    nodes.sort();
+
+    // Each formula's depth and the cell its longest chain comes through
+    let mut depth: HashMap<(i32, i32), (usize, (i32, i32))> = HashMap::new();
+    for node in topological_order(sheet, &nodes) {
+        let mut best: Option<(usize, (i32, i32))> = None;
+        let mut consider = |cell: (i32, i32)| {
+            let length = depth.get(&cell).map_or(1, |&(length, _)| length + 1);
+            if best.is_none_or(|(best_length, _)| length > best_length) {
+                best = Some((length, cell));
+            }
+        };
+        for dep in &sheet.dependency_graph[&node].dependencies {
+            match *dep {
+                DependencyType::Single { row, col } => consider((row, col)),
+                DependencyType::Range {
+                    start_row,
+                    start_col,
+                    end_row,
+                    end_col,
+                } => {
+                    // The first cell stands for the plain cells of the range
+                    consider((start_row, start_col));
+                    for &(row, col) in &nodes {
+                        if (start_row..=end_row).contains(&row)
+                            && (start_col..=end_col).contains(&col)
+                        {
+                            consider((row, col));
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(best) = best {
+            depth.insert(node, best);
+        }
+    }
+
+    let Some((&last, _)) = depth
+        .iter()
+        .max_by_key(|&(&cell, &(length, _))| (length, std::cmp::Reverse(cell)))
+    else {
+        return Vec::new();
+    };
+    let mut chain = vec![last];
+    let mut current = last;
+    while let Some(&(_, previous)) = depth.get(&current) {
+        chain.push(previous);
+        current = previous;
+    }
+    chain.reverse();
+    chain
+}
+
+/// Re-evaluates every formula cell in the spreadsheet.
+///
+/// This function clears all error and circular dependency flags, re-runs cycle detection for each
+/// formula, and then recalculates every non-circular formula cell in dependency order. Formulas
+/// that are still circular get their circular flag back. It is used by the RECOMPUTE and
+/// RESETFLAGS commands to repair stale cached values and flags after bulk edits.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// update_cell(&mut sheet, 0, 0, "5");
+/// update_cell(&mut sheet, 0, 1, "A1+1");
+/// sheet.cells[0][1].value = 0;
+/// recalculate_all(&mut sheet);
+/// assert_eq!(sheet.cells[0][1].value, 6);
+/// ```
+pub fn recalculate_all(sheet: &mut Sheet) {
+    reset_circular_dependency_flag(sheet);
+
+    let mut formula_cells = Vec::new();
+    for row in 0..sheet.rows {
+        for col in 0..sheet.cols {
+            let cell = &mut sheet.cells[row as usize][col as usize];
+            cell.is_error = false;
+            cell.error_kind = None;
+            if let Some(formula) = &cell.formula {
+                formula_cells.push((row, col, formula.clone()));
+            }
+        }
+    }
+
+    // Formulas rejected as circular never entered the graph, so check each one again
+    let mut nodes = Vec::new();
+    for (row, col, formula) in &formula_cells {
+        if has_circular_dependency(sheet, *row, *col, formula) {
+            let cell = &mut sheet.cells[*row as usize][*col as usize];
+            cell.has_circular = true;
+            cell.error_kind = Some(ErrorKind::Circular);
+            sheet.circular_dependency_detected = true;
+        } else {
+            nodes.push((*row, *col));
+        }
+    }
+
+    sheet.recalculating = true;
+    for (row, col) in topological_order(sheet, &nodes) {
+        if let Some(formula) = sheet.cells[row as usize][col as usize].formula.clone() {
+            if has_moving_references(&formula) {
+                let dependencies = formula_dependencies_at(sheet, &formula, row, col);
+                set_dependencies(sheet, row, col, dependencies);
             }
+            let (new_value, error) = evaluate_expression(sheet, &formula, row, col);
+            let stats = range_stats(sheet, &formula);
+            let cell = &mut sheet.cells[row as usize][col as usize];
+            cell.value = new_value;
+            cell.is_error = error.is_some();
+            cell.error_kind = error;
+            cell.stats = stats;
         }
     }
+    sheet.recalculating = false;
 }
 
 /// Resets the circular dependency flags for all cells in the spreadsheet.
@@ -1,32 +1,54 @@
 mod cell;
 mod dependencies;
+mod parser;
 mod sheet;
 #[cfg(test)]
 mod tests;
 mod types;
 mod utils;
 
-use crate::sheet::{create_sheet, display_sheet, process_command};
-use crate::types::{Sheet, SHEET};
-use crate::utils::{encode_column, is_valid_command};
+use crate::sheet::{
+    column_widths, create_sheet, format_grid, process_command, recall_command, visible_span,
+    write_delimited_rows,
+};
+use crate::types::{ErrorKind, Sheet, COMMAND_MESSAGES, COMMAND_QUEUE, MAX_COLS, MAX_ROWS, SHEET};
+use crate::utils::{
+    encode_column, is_valid_command, parse_cell_reference, parse_range, split_csv_line,
+};
 use calamine::{open_workbook, Reader, Xlsx};
+use rocket::http::{ContentType, Status};
 use rocket::{form::Form, get, post, response::Redirect};
 use rocket_dyn_templates::Template;
 use serde_json::json;
 use std::fs::File;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
 use std::time::Instant;
 
-const MAX_ROWS: i32 = 999;
-const MAX_COLS: i32 = 18278;
-const DISPLAY_SIZE: i32 = 10;
+/// Widest a column of the web grid is sized, in characters. Longer values are cut off.
+const MAX_WEB_COLUMN_WIDTH: usize = 40;
+/// Reported by the web interface when the global sheet was never created.
+const NO_SHEET_MESSAGE: &str = "No spreadsheet is loaded";
+/// How many messages from queued commands are kept for their clients to pick up.
+const MAX_KEPT_MESSAGES: usize = 100;
+/// The id given to the next command queued from the web interface.
+static NEXT_COMMAND_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(rocket::form::FromForm)]
 struct CommandForm {
     command: String,
 }
 
+#[derive(rocket::form::FromForm)]
+struct EditForm {
+    row: i32, // 0-based row of the clicked cell
+    col: i32, // 0-based column of the clicked cell
+    value: String,
+}
+
 /// Renders the main web interface for the spreadsheet.
 ///
 /// This function generates the HTML template for the spreadsheet's web interface, displaying
@@ -35,9 +57,12 @@ struct CommandForm {
 ///
 /// # Arguments
 /// * `message` - An optional message to display (e.g., error or success message).
+/// * `pending` - The id of a command this client queued. Its message is shown once the command
+///   has run, or a note that it is still running.
 ///
 /// # Returns
-/// A `Template` containing the rendered HTML.
+/// A `Template` containing the rendered HTML, or `503 Service Unavailable` and a message if no
+/// sheet is loaded.
 ///
 /// # Example
 /// ```text
@@ -45,49 +70,92 @@ struct CommandForm {
 /// // The interface shows a 10x10 grid of cells with values and styling
 /// ```
 #[cfg(not(tarpaulin_include))]
-#[get("/?<message>")]
-fn index(message: Option<String>) -> Template {
+#[get("/?<message>&<pending>")]
+fn index(
+    message: Option<String>,
+    pending: Option<u64>,
+) -> Result<Template, (Status, &'static str)> {
     let sheet = SHEET.lock().unwrap();
-    let sheet = sheet.as_ref().unwrap();
-    let view_row = sheet.view_row;
-    let view_col = sheet.view_col;
-    let rows = (view_row..(view_row + DISPLAY_SIZE).min(sheet.rows)).collect::<Vec<_>>();
-    let columns = (view_col..(view_col + DISPLAY_SIZE).min(sheet.cols))
-        .map(|col| {
-            let mut col_str = String::new();
-            encode_column(col, &mut col_str);
-            col_str
-        })
+    let Some(sheet) = sheet.as_ref() else {
+        return Err((Status::ServiceUnavailable, NO_SHEET_MESSAGE));
+    };
+    let rows = sheet.visible_rows();
+    let visible_cols = sheet.visible_cols();
+    let columns = visible_cols
+        .iter()
+        .map(|&col| sheet.column_label(col))
+        .collect::<Vec<_>>();
+    // Sized like the terminal grid, so one long value does not stretch the whole page
+    let widths = column_widths(sheet, &rows, &visible_cols)
+        .into_iter()
+        .map(|width| width.min(MAX_WEB_COLUMN_WIDTH))
         .collect::<Vec<_>>();
 
     let rows_data = rows
         .iter()
         .map(|&row| {
-            let cells = (view_col..(view_col + DISPLAY_SIZE).min(sheet.cols))
-                .map(|col| {
-                    let cell = &sheet.cells[row as usize][col as usize];
-                    let value = if cell.is_error && !cell.has_circular {
-                        "err".to_string()
+            let cells = visible_cols
+                .iter()
+                .filter_map(|&col| {
+                    // Merged regions render once at their first visible cell and span the rest
+                    let (cell_ref, colspan, rowspan) = match sheet.merged_region_at(row, col) {
+                        Some((start_row, start_col, region_end_row, region_end_col)) => {
+                            let (first_row, last_row) =
+                                visible_span(&rows, start_row, region_end_row)?;
+                            let (first_col, last_col) =
+                                visible_span(&visible_cols, start_col, region_end_col)?;
+                            if (row, col) != (rows[first_row], visible_cols[first_col]) {
+                                return None;
+                            }
+                            (
+                                (start_row, start_col),
+                                last_col - first_col + 1,
+                                last_row - first_row + 1,
+                            )
+                        }
+                        None => ((row, col), 1, 1),
+                    };
+                    let cell = &sheet.cells[cell_ref.0 as usize][cell_ref.1 as usize];
+                    // Circular cells keep their last value, so they are marked to be found. The
+                    // error kind stays until the cycle is broken, unlike `has_circular`, which
+                    // every later assignment clears
+                    let circular = cell.error_kind == Some(ErrorKind::Circular);
+                    let value = if circular {
+                        ErrorKind::Circular.code().to_string()
                     } else {
-                        cell.value.to_string()
+                        sheet.display_value(cell)
                     };
                     let classes = {
                         let mut c = Vec::new();
                         if cell.is_bold {
-                            c.push("bold");
+                            c.push("bold".to_string());
                         }
                         if cell.is_italic {
-                            c.push("italic");
+                            c.push("italic".to_string());
                         }
                         if cell.is_underline {
-                            c.push("underline");
+                            c.push("underline".to_string());
+                        }
+                        if cell.is_strike {
+                            c.push("strike".to_string());
+                        }
+                        if let Some(color) = &cell.color {
+                            c.push(format!("color-{}", color));
+                        }
+                        if circular {
+                            c.push("circular".to_string());
+                        }
+                        if sheet.selected == Some(cell_ref) {
+                            c.push("selected".to_string());
                         }
                         c.join(" ")
                     };
-                    json!({
+                    Some(json!({
                         "value": value,
                         "classes": classes,
-                    })
+                        "colspan": colspan,
+                        "rowspan": rowspan,
+                    }))
                 })
                 .collect::<Vec<_>>();
             json!({
@@ -97,51 +165,257 @@ fn index(message: Option<String>) -> Template {
         })
         .collect::<Vec<_>>();
 
-    Template::render(
+    Ok(Template::render(
         "index",
         json!({
             "columns": columns,
+            "column_widths": widths,
             "rows": rows_data,
             "circular_detected": sheet.circular_dependency_detected,
-            "message": message,
+            "message": message.or_else(|| pending.and_then(queued_message)),
         }),
-    )
+    ))
 }
 
-/// Processes a command submitted via the web interface.
+/// Queues a command submitted via the web interface.
 ///
-/// This function handles commands submitted through the web form, processes them using
-/// `process_command`, and redirects to the main page with an optional message.
+/// This function hands the command to the command worker and redirects to the main page right
+/// away, so a slow command (such as one using SLEEP) does not hold up the request. The worker
+/// applies commands in the order they were posted. The redirect carries the command's id, so
+/// its message is shown to this client only (see `queued_message`).
 ///
 /// # Arguments
 /// * `form` - The form data containing the command string.
 ///
 /// # Returns
-/// A `Redirect` to the main page, possibly with a message.
+/// A `Redirect` to the main page, with a message if the command could not be queued.
 ///
 /// # Example
 /// ```text
 /// // POST request to /command with form data "command=A1=5"
-/// // Redirects to main page after setting A1 to 5
+/// // Redirects to main page; the worker then sets A1 to 5
 /// ```
 #[cfg(not(tarpaulin_include))]
 #[post("/command", data = "<form>")]
 fn command(form: Form<CommandForm>) -> Redirect {
-    let command = form.command.clone();
-    let message = {
-        let mut sheet = SHEET.lock().unwrap();
-        if let Some(ref mut sheet) = *sheet {
-            process_command(sheet, &command)
-        } else {
-            None
+    queued_redirect(form.command.clone())
+}
+
+/// Hands a command to the command worker and redirects to the page that shows its message.
+///
+/// # Arguments
+/// * `command` - The command to queue.
+///
+/// # Returns
+/// A `Redirect` to the main page with the command's id, or with a message if the command worker
+/// is not running.
+fn queued_redirect(command: String) -> Redirect {
+    let id = NEXT_COMMAND_ID.fetch_add(1, Ordering::Relaxed);
+    let queued = COMMAND_QUEUE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|queue| queue.send((id, command)).is_ok());
+    if queued {
+        Redirect::to(format!("/?pending={}", id))
+    } else {
+        let encoded_msg = urlencoding::encode("Command queue is not running");
+        Redirect::to(format!("/?message={}", encoded_msg))
+    }
+}
+
+/// Looks up the message of a queued command for the page of the client that queued it.
+///
+/// The worker runs commands in the order of their ids, so an id past the last finished one is
+/// still waiting or running. Only the latest `MAX_KEPT_MESSAGES` messages are kept.
+///
+/// # Arguments
+/// * `id` - The id the command was queued with.
+///
+/// # Returns
+/// An `Option<String>` with the command's message, a note that it is still running, or `None`
+/// if it finished without a message or its message is no longer kept.
+fn queued_message(id: u64) -> Option<String> {
+    let messages = COMMAND_MESSAGES.lock().unwrap();
+    match messages.get(&id) {
+        Some(message) => message.clone(),
+        None if messages.last_key_value().is_none_or(|(&last, _)| id > last) => {
+            Some("Command is still running; reload to see its result".to_string())
+        }
+        None => None,
+    }
+}
+
+/// Sets a cell clicked in the web interface.
+///
+/// This function takes the row and column of a cell in the grid along with the new value or
+/// formula, and queues the assignment (e.g. `A1=5`) like a command posted to `/command`. It is
+/// applied in order with the other queued commands, and the same rules apply as when it is
+/// typed: locked cells, cells hidden by a merged region and values outside a VALIDATE rule are
+/// refused. The change can be undone like any other assignment.
+///
+/// # Arguments
+/// * `form` - The form data containing the cell's 0-based `row` and `col`, and its `value`.
+///
+/// # Returns
+/// A `Redirect` to the main page with the queued command's id, or with a message if the cell is
+/// outside the sheet.
+///
+/// # Example
+/// ```text
+/// // POST request to /edit with form data "row=1&col=0&value=A1+5"
+/// // Queues A2=A1+5 and redirects to the main page
+/// ```
+#[cfg(not(tarpaulin_include))]
+#[post("/edit", data = "<form>")]
+fn edit(form: Form<EditForm>) -> Redirect {
+    let (row, col) = (form.row, form.col);
+    let inside = SHEET
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|sheet| row >= 0 && row < sheet.rows && col >= 0 && col < sheet.cols);
+    if !inside {
+        let message = format!("Cell ({}, {}) is outside the sheet", row, col);
+        return Redirect::to(format!("/?message={}", urlencoding::encode(&message)));
+    }
+    let mut cell_ref = String::new();
+    encode_column(col, &mut cell_ref);
+    queued_redirect(format!("{}{}={}", cell_ref, row + 1, form.value.trim()))
+}
+
+/// Starts the worker that applies queued web commands to the spreadsheet.
+///
+/// The worker runs on its own thread and takes `(id, command)` pairs from the returned sender
+/// one at a time (see `run_queued_command`). Each command's message is kept in
+/// `COMMAND_MESSAGES` under its id. The worker stops once every sender is dropped.
+///
+/// # Returns
+/// A `Sender<(u64, String)>` to queue commands on.
+///
+/// # Example
+/// ```text
+/// let queue = start_command_worker();
+/// queue.send((1, "A1=5".to_string())).unwrap();
+/// ```
+fn start_command_worker() -> Sender<(u64, String)> {
+    let (sender, receiver) = mpsc::channel::<(u64, String)>();
+    thread::spawn(move || {
+        for (id, command) in receiver {
+            let message = run_queued_command(&command);
+            let mut messages = COMMAND_MESSAGES.lock().unwrap();
+            messages.insert(id, message);
+            while messages.len() > MAX_KEPT_MESSAGES {
+                messages.pop_first();
+            }
         }
+    });
+    sender
+}
+
+/// Runs one queued command without keeping the global sheet locked while it runs.
+///
+/// The sheet is taken out of `SHEET` and a copy of what it shows (see `Sheet::view_copy`) is
+/// left in its place, so pages load, scroll and select while a slow command such as a SLEEP
+/// runs. Afterwards the sheet is put back. A scroll or selection made in the meantime is kept,
+/// unless the command itself moved the view or the selection.
+///
+/// # Arguments
+/// * `command` - The command to run.
+///
+/// # Returns
+/// An `Option<String>` with the command's message.
+fn run_queued_command(command: &str) -> Option<String> {
+    let taken = {
+        let mut shared = SHEET.lock().unwrap();
+        let sheet = shared.take();
+        *shared = sheet.as_ref().map(Sheet::view_copy);
+        sheet
     };
-    if let Some(msg) = message {
-        let encoded_msg = urlencoding::encode(&msg);
-        Redirect::to(format!("/?message={}", encoded_msg))
-    } else {
-        Redirect::to("/")
+    let Some(mut sheet) = taken else {
+        return Some(NO_SHEET_MESSAGE.to_string());
+    };
+    let view = (sheet.view_row, sheet.view_col);
+    let selected = sheet.selected;
+    let start = Instant::now();
+    let message = process_command(&mut sheet, command);
+    sheet.last_elapsed = start.elapsed().as_secs_f64();
+
+    let mut shared = SHEET.lock().unwrap();
+    if let Some(shown) = shared.as_ref() {
+        if (sheet.view_row, sheet.view_col) == view {
+            sheet.view_row = shown.view_row;
+            sheet.view_col = shown.view_col;
+            sheet.view_history.extend_from_slice(&shown.view_history);
+        }
+        if sheet.selected == selected {
+            sheet.selected = shown.selected;
+        }
+    }
+    *shared = Some(sheet);
+    message
+}
+
+/// Startup settings read from `spreadsheet.toml`.
+///
+/// Every setting is optional; command-line arguments take precedence over the file.
+#[derive(Debug, Default, PartialEq)]
+struct Config {
+    rows: Option<i32>,
+    cols: Option<i32>,
+    extension: Option<bool>,
+    view_size: Option<i32>,
+    recalc_budget: Option<usize>,
+}
+
+/// Parses the contents of a `spreadsheet.toml` config file.
+///
+/// Only the small subset of TOML the settings need is understood: one `key = value` per line,
+/// integer or `true`/`false` values, blank lines and `#` comments. The keys are `rows`, `cols`,
+/// `extension`, `view_size` (the number of rows and columns shown at once) and `recalc_budget`
+/// (the most dependents recalculated by one command, as set by `BUDGET`).
+///
+/// # Arguments
+/// * `text` - The contents of the config file.
+///
+/// # Returns
+/// A `Result<Config, String>` with the settings, or a message naming the offending line.
+///
+/// # Example
+/// ```text
+/// let config = parse_config("rows = 100\nextension = true").unwrap();
+/// assert_eq!(config.rows, Some(100));
+/// assert_eq!(config.extension, Some(true));
+/// ```
+fn parse_config(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("Invalid setting on line {}: {}", index + 1, line);
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let value = value.trim();
+        let number = || {
+            value
+                .parse::<i32>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(invalid)
+        };
+        match key.trim() {
+            "rows" => config.rows = Some(number()?),
+            "cols" => config.cols = Some(number()?),
+            "view_size" => config.view_size = Some(number()?),
+            "recalc_budget" => {
+                config.recalc_budget = Some(usize::try_from(number()?).map_err(|_| invalid())?)
+            }
+            "extension" => config.extension = Some(value.parse::<bool>().map_err(|_| invalid())?),
+            other => return Err(format!("Unknown setting on line {}: {}", index + 1, other)),
+        }
     }
+    Ok(config)
 }
 
 /// Scrolls the spreadsheet view in the specified direction.
@@ -174,93 +448,232 @@ fn scroll(direction: String) -> Redirect {
     Redirect::to("/")
 }
 
+/// Selects a cell, so it is highlighted in the web interface.
+///
+/// This function parses the cell reference submitted via a POST request and stores it as the
+/// sheet's selected cell. Only one cell is selected at a time; selecting another cell moves the
+/// highlight.
+///
+/// # Arguments
+/// * `cell_ref` - The cell to select (e.g., "B2").
+///
+/// # Returns
+/// A `Redirect` to the main page, with a message if the reference is invalid.
+///
+/// # Example
+/// ```text
+/// // POST request to /select/B2
+/// // Highlights cell B2 on the next page load
+/// ```
+#[cfg(not(tarpaulin_include))]
+#[post("/select/<cell_ref>")]
+fn select(cell_ref: &str) -> Redirect {
+    let mut sheet = SHEET.lock().unwrap();
+    if let Some(ref mut sheet) = *sheet {
+        match parse_cell_reference(sheet, cell_ref) {
+            Ok(cell) => sheet.selected = Some(cell),
+            Err(_) => {
+                let message = format!("Invalid cell reference: {}", cell_ref);
+                return Redirect::to(format!("/?message={}", urlencoding::encode(&message)));
+            }
+        }
+    }
+    Redirect::to("/")
+}
+
+/// Returns the values of a range as JSON, for scripts and other programs reading the sheet.
+///
+/// The global sheet is locked only while a snapshot of the range is taken, and the JSON is built
+/// after the lock is released, so reading does not hold up the web interface or queued commands.
+/// Each row of the range is an array holding numbers, or error codes such as `"#DIV/0!"`.
+///
+/// # Arguments
+/// * `range` - The range to read (e.g., "A1:C3").
+///
+/// # Returns
+/// A `(Status, (ContentType, String))` with the JSON values, or `400 Bad Request` and a message
+/// if the range is invalid, or `503 Service Unavailable` if no sheet is loaded.
+///
+/// # Example
+/// ```text
+/// // GET request to /values/A1:B2
+/// // Returns [[5,6],[0,"#DIV/0!"]]
+/// ```
+#[cfg(not(tarpaulin_include))]
+#[get("/values/<range>")]
+fn values(range: &str) -> (Status, (ContentType, String)) {
+    let snapshot = {
+        let mut sheet = SHEET.lock().unwrap();
+        let Some(sheet) = sheet.as_mut() else {
+            let message = NO_SHEET_MESSAGE.to_string();
+            return (Status::ServiceUnavailable, (ContentType::Plain, message));
+        };
+        parse_range(sheet, range).and_then(|(start_row, start_col, end_row, end_col)| {
+            sheet.snapshot(start_row, start_col, end_row, end_col)
+        })
+    };
+    let Some(snapshot) = snapshot else {
+        return (
+            Status::BadRequest,
+            (ContentType::Plain, format!("Invalid range: {}", range)),
+        );
+    };
+    let rows = snapshot
+        .values
+        .iter()
+        .map(|line| {
+            line.iter()
+                .map(|value| match value {
+                    Ok(value) => json!(value),
+                    Err(kind) => json!(kind.code()),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    (Status::Ok, (ContentType::JSON, json!(rows).to_string()))
+}
+
 /// Loads a CSV file into the spreadsheet.
 ///
-/// This function reads a CSV file and populates the spreadsheet with its values.
-/// Formulas (starting with '=') are processed after all values are loaded.
+/// This function reads a delimited text file and populates the spreadsheet with its values.
+/// Formulas (starting with '=') are processed after all values are loaded. The delimiter is
+/// usually ',' but semicolon- and tab-separated exports are loaded the same way. Fields may be
+/// quoted, so a quoted field can contain the delimiter. Lines starting with '#' are comments and
+/// are skipped along with blank lines, so the first data line fills row 1.
+///
+/// Problems with individual cells do not abort the load. Values beyond the last column,
+/// non-numeric values and invalid formulas are skipped, and a warning describing each one is
+/// returned so the rest of the file still loads.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
 /// * `filename` - The path to the CSV file.
+/// * `delimiter` - The character separating fields on each line.
 ///
 /// # Returns
-/// A `Result<(), String>` indicating success or an error message.
+/// A `Result<Vec<String>, String>` containing the warnings for skipped cells, or an error
+/// message if the file could not be loaded at all.
 ///
 /// # Example
 /// ```
 /// let mut sheet = create_sheet(10, 10, true).unwrap();
 /// // Assume "data.csv" contains "5,=A1+1"
-/// load_csv_file(&mut sheet, "data.csv").unwrap();
+/// let warnings = load_csv_file(&mut sheet, "data.csv", ',').unwrap();
+/// assert!(warnings.is_empty());
 /// assert_eq!(sheet.cells[0][0].value, 5);
 /// assert_eq!(sheet.cells[0][1].value, 6);
 /// ```
 #[cfg(not(tarpaulin_include))]
-fn load_csv_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
-    let file = File::open(filename).map_err(|e| format!("Failed to open CSV file: {}", e))?;
-    let reader = io::BufReader::new(file);
-
-    let mut formulas = Vec::new();
-    for (row_idx_usize, line) in reader.lines().enumerate() {
-        let row_idx = row_idx_usize as i32;
-        if row_idx >= sheet.rows {
-            return Err(format!(
-                "CSV file has more rows than the spreadsheet (max: {})",
-                sheet.rows
-            ));
-        }
+fn load_csv_file(
+    sheet: &mut Sheet,
+    filename: &str,
+    delimiter: char,
+) -> Result<Vec<String>, String> {
+    load_delimited_file(sheet, filename, delimiter, false)
+}
 
-        let line = line.map_err(|e| format!("Error reading CSV line: {}", e))?;
-        let values: Vec<&str> = line.split(',').collect();
+/// Loads a CSV file whose first line names the columns.
+///
+/// This function works like `load_csv_file`, except that the first line is read as column
+/// names instead of data. The names are stored in `sheet.column_headers` and shown next to the
+/// column letters, and the data from the second line onward is loaded starting at row 1.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `filename` - The path to the CSV file.
+/// * `delimiter` - The character separating fields on each line.
+///
+/// # Returns
+/// A `Result<Vec<String>, String>` containing the warnings for skipped cells, or an error
+/// message if the file could not be loaded at all.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, true).unwrap();
+/// // Assume "data.csv" contains "Price,Total" followed by "5,=A1+1"
+/// load_csv_file_with_header(&mut sheet, "data.csv", ',').unwrap();
+/// assert_eq!(sheet.column_headers[0], Some("Price".to_string()));
+/// assert_eq!(sheet.cells[0][1].value, 6);
+/// ```
+#[cfg(not(tarpaulin_include))]
+fn load_csv_file_with_header(
+    sheet: &mut Sheet,
+    filename: &str,
+    delimiter: char,
+) -> Result<Vec<String>, String> {
+    load_delimited_file(sheet, filename, delimiter, true)
+}
 
-        for (col_idx_usize, value) in values.into_iter().enumerate() {
-            let col_idx = col_idx_usize as i32;
-            if col_idx >= sheet.cols {
-                return Err(format!(
-                    "CSV file has more columns than the spreadsheet (max: {})",
-                    sheet.cols
-                ));
-            }
+#[cfg(not(tarpaulin_include))]
+fn load_delimited_file(
+    sheet: &mut Sheet,
+    filename: &str,
+    delimiter: char,
+    has_header: bool,
+) -> Result<Vec<String>, String> {
+    let file = File::open(filename).map_err(|e| format!("Failed to open CSV file: {}", e))?;
+    // Comment lines starting with '#' and blank lines are not rows of the sheet
+    let mut lines = io::BufReader::new(file).lines().filter(|line| {
+        line.as_ref().map_or(true, |line| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+    });
 
-            let value = value.trim();
-            if let Ok(num_value) = value.parse::<i32>() {
-                sheet.cells[row_idx as usize][col_idx as usize].value = num_value;
-            } else if let Some(stripped) = value.strip_prefix('=') {
-                let formula = stripped.to_string();
-                formulas.push((row_idx, col_idx, formula));
-            } else if !value.is_empty() {
-                sheet.cells[row_idx as usize][col_idx as usize].value = 0;
+    if has_header {
+        if let Some(line) = lines.next() {
+            let line = line.map_err(|e| format!("Error reading CSV line: {}", e))?;
+            sheet.column_headers = vec![None; sheet.cols as usize];
+            for (col_idx, name) in split_csv_line(&line, delimiter)
+                .into_iter()
+                .take(sheet.cols as usize)
+                .enumerate()
+            {
+                let name = name.trim();
+                if !name.is_empty() {
+                    sheet.column_headers[col_idx] = Some(name.to_string());
+                }
             }
         }
     }
 
-    for (row, col, formula) in formulas {
-        crate::cell::update_cell(sheet, row, col, &formula);
-    }
-    Ok(())
+    let rows = lines
+        .map(|line| {
+            line.map(|line| split_csv_line(&line, delimiter))
+                .map_err(|e| format!("Error reading CSV line: {}", e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    write_delimited_rows(sheet, &rows, 0, 0)
 }
 
 /// Loads an Excel file into the spreadsheet.
 ///
 /// This function reads an Excel (.xlsx) file and populates the spreadsheet with its values.
-/// It handles various data types (int, float, string, bool) and processes formulas.
+/// It handles various data types (int, float, string, bool) and processes formulas. Formulas
+/// stored in the worksheet are loaded as well, so numeric cells that only cache a formula's
+/// result keep recalculating when their inputs change. Cells only hold integers, so a float
+/// with a fractional part is truncated towards zero; every such cell is reported in a warning
+/// so the loss of precision is not silent.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
 /// * `filename` - The path to the Excel file.
 ///
 /// # Returns
-/// A `Result<(), String>` indicating success or an error message.
+/// A `Result<Vec<String>, String>` holding any warnings raised while loading, or an error
+/// message.
 ///
 /// # Example
 /// ```
 /// let mut sheet = create_sheet(10, 10, true).unwrap();
 /// // Assume "data.xlsx" contains a sheet with "10" in A1 and "=A1+5" in B1
-/// load_excel_file(&mut sheet, "data.xlsx").unwrap();
+/// let warnings = load_excel_file(&mut sheet, "data.xlsx").unwrap();
+/// assert!(warnings.is_empty());
 /// assert_eq!(sheet.cells[0][0].value, 10);
 /// assert_eq!(sheet.cells[0][1].value, 15);
 /// ```
 #[cfg(not(tarpaulin_include))]
-fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
+fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<Vec<String>, String> {
     let mut workbook: Xlsx<_> =
         open_workbook(filename).map_err(|e| format!("Failed to open Excel file: {}", e))?;
 
@@ -291,6 +704,7 @@ fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
         ));
     }
 
+    let mut truncated = Vec::new();
     for row_idx in 0..height {
         for col_idx in 0..width {
             match worksheet.get_value((
@@ -299,9 +713,14 @@ fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
             )) {
                 Some(calamine::DataType::Int(value)) => {
                     sheet.cells[row_idx as usize][col_idx as usize].value = *value as i32;
+                    sheet.cells[row_idx as usize][col_idx as usize].is_set = true;
                 }
                 Some(calamine::DataType::Float(value)) => {
                     sheet.cells[row_idx as usize][col_idx as usize].value = *value as i32;
+                    sheet.cells[row_idx as usize][col_idx as usize].is_set = true;
+                    if value.fract() != 0.0 {
+                        truncated.push((row_idx, col_idx, *value));
+                    }
                 }
                 Some(calamine::DataType::String(value)) => {
                     if let Some(stripped) = value.strip_prefix('=') {
@@ -314,6 +733,7 @@ fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
                 Some(calamine::DataType::Bool(value)) => {
                     sheet.cells[row_idx as usize][col_idx as usize].value =
                         if *value { 1 } else { 0 };
+                    sheet.cells[row_idx as usize][col_idx as usize].is_set = true;
                 }
                 _ => {
                     sheet.cells[row_idx as usize][col_idx as usize].value = 0;
@@ -321,6 +741,149 @@ fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
             }
         }
     }
+
+    // Numeric cells only carry the cached result of a formula, so read the formulas
+    // separately and apply them once every cached value is in place.
+    let mut formulas = Vec::new();
+    if let Some(Ok(formula_range)) = workbook.worksheet_formula(&sheet_names[0]) {
+        let (start_row, start_col) = formula_range.start().unwrap_or((0, 0));
+        for (row_idx, col_idx, formula) in formula_range.used_cells() {
+            let formula = formula.strip_prefix('=').unwrap_or(formula).trim();
+            if formula.is_empty() {
+                continue;
+            }
+            let row = start_row as i32 + row_idx as i32;
+            let col = start_col as i32 + col_idx as i32;
+            if row < sheet.rows && col < sheet.cols {
+                formulas.push((row, col, formula.to_string()));
+            }
+        }
+    }
+
+    // A fractional cached result is recomputed from the formula, so it loses nothing.
+    truncated.retain(|&(row, col, _)| !formulas.iter().any(|(r, c, _)| *r == row && *c == col));
+    for (row, col, formula) in formulas {
+        crate::cell::update_cell(sheet, row, col, &formula);
+    }
+
+    let mut warnings = truncated
+        .iter()
+        .map(|&(row, col, value)| {
+            let mut name = String::new();
+            encode_column(col, &mut name);
+            format!(
+                "Cell {}{}: truncated {} to {}",
+                name,
+                row + 1,
+                value,
+                value as i32
+            )
+        })
+        .collect::<Vec<_>>();
+    if !warnings.is_empty() {
+        warnings.push(format!(
+            "{} value(s) with a fractional part were truncated to whole numbers",
+            truncated.len()
+        ));
+    }
+    Ok(warnings)
+}
+
+/// Runs the terminal interface, reading commands from `input` until `q` or the end of input.
+///
+/// Interactively, the current view of the sheet and a prompt with the time taken by the last
+/// command are shown before each command. When the commands come from a pipe or file instead,
+/// both are left out and only the messages from the commands are written to `output`. The
+/// sheet's own output setting is not touched, so `toggle_output` reports the same either way.
+///
+/// # Arguments
+/// * `input` - Where to read commands from, one per line.
+/// * `output` - Where to write the grid, the prompt and the messages from commands.
+/// * `interactive` - Whether to show the grid and the prompt, i.e. whether input is a terminal.
+///
+/// # Returns
+/// An `io::Result<()>` with any error from reading input or writing output.
+///
+/// # Example
+/// ```text
+/// // printf 'A1=5\nA2=A1/0\n' | cargo run -- 10 10
+/// // Prints nothing but messages from the commands, then exits at the end of input
+/// ```
+fn run_terminal(
+    mut input: impl BufRead,
+    output: &mut impl Write,
+    interactive: bool,
+) -> io::Result<()> {
+    let mut elapsed_time = 0.0;
+    let mut is_valid = true;
+
+    loop {
+        if interactive {
+            {
+                let sheet_guard = SHEET.lock().unwrap();
+                if let Some(ref sheet) = *sheet_guard {
+                    if sheet.output_enabled {
+                        let grid =
+                            format_grid(sheet, &sheet.visible_rows(), &sheet.visible_cols(), true);
+                        write!(output, "{}", grid)?;
+                    }
+                }
+            }
+
+            write!(
+                output,
+                "[{:.1}] {}> ",
+                elapsed_time,
+                if is_valid {
+                    if SHEET
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .unwrap()
+                        .circular_dependency_detected
+                    {
+                        "(err)"
+                    } else {
+                        "(ok)"
+                    }
+                } else {
+                    "(err)"
+                }
+            )?;
+            output.flush()?;
+        }
+
+        let mut command = String::new();
+        if input.read_line(&mut command)? == 0 {
+            break;
+        }
+        let command = command.trim();
+
+        if command == "q" {
+            break;
+        }
+        let Some(command) = recall_command(SHEET.lock().unwrap().as_mut().unwrap(), command) else {
+            writeln!(output, "No previous command")?;
+            continue;
+        };
+        let command = command.as_str();
+        is_valid = is_valid_command(SHEET.lock().unwrap().as_mut().unwrap(), command);
+        let start = Instant::now();
+        let message = {
+            let mut sheet_guard = SHEET.lock().unwrap();
+            if let Some(ref mut sheet) = *sheet_guard {
+                let message = process_command(sheet, command);
+                sheet.last_elapsed = start.elapsed().as_secs_f64();
+                message
+            } else {
+                None
+            }
+        };
+        if let Some(msg) = message {
+            writeln!(output, "{}", msg)?;
+        }
+        elapsed_time = start.elapsed().as_secs_f64();
+    }
     Ok(())
 }
 
@@ -328,7 +891,9 @@ fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
 ///
 /// This function initializes the spreadsheet, processes command-line arguments, and either
 /// starts a web server (if extensions are enabled) or runs a terminal-based interface.
-/// It also handles loading input files if provided.
+/// It also handles loading input files if provided. Defaults for the sheet size, extension
+/// mode and view size are read from `spreadsheet.toml` in the working directory, if present;
+/// see `parse_config`.
 ///
 /// # Returns
 /// A `Result<(), rocket::Error>` indicating whether the application ran successfully.
@@ -339,45 +904,83 @@ fn load_excel_file(sheet: &mut Sheet, filename: &str) -> Result<(), String> {
 /// // Creates a 10x10 spreadsheet and starts the terminal interface
 /// // Or with: cargo run --extension 10 10 data.csv
 /// // Loads data.csv and starts the web server
+/// // Or with: cargo run --extension --delimiter ";" 10 10 data.csv
+/// // Loads a semicolon-separated data.csv
+/// // Or with: cargo run --extension --header 10 10 data.csv
+/// // Uses the first line of data.csv as column names
+/// // Or, with rows and cols set in spreadsheet.toml: cargo run --no-extension
 /// ```
 #[cfg(not(tarpaulin_include))]
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
     let args: Vec<String> = std::env::args().collect();
-    let mut extension_enabled = false;
+    let config = match std::fs::read_to_string("spreadsheet.toml") {
+        Ok(text) => match parse_config(&text) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Error in spreadsheet.toml: {}", e);
+                return Ok(());
+            }
+        },
+        Err(_) => Config::default(),
+    };
+    let mut extension_enabled = config.extension.unwrap_or(false);
     let mut row_col_args = Vec::new();
     let mut input_file = None;
+    let mut delimiter = None;
+    let mut has_header = false;
 
     let mut i = 1;
     while i < args.len() {
         if args[i] == "--extension" {
             extension_enabled = true;
             i += 1;
+        } else if args[i] == "--no-extension" {
+            extension_enabled = false;
+            i += 1;
+        } else if args[i] == "--header" {
+            has_header = true;
+            i += 1;
+        } else if args[i] == "--delimiter" && i + 1 < args.len() {
+            delimiter = match args[i + 1].as_str() {
+                "tab" | "\\t" => Some('\t'),
+                other if other.chars().count() == 1 => other.chars().next(),
+                other => {
+                    println!(
+                        "Invalid delimiter: {} (use a single character or 'tab')",
+                        other
+                    );
+                    return Ok(());
+                }
+            };
+            i += 2;
         } else {
             row_col_args.push(args[i].clone());
             i += 1;
         }
     }
 
-    if extension_enabled && row_col_args.len() == 3 {
-        let potential_file = &row_col_args[2];
+    if extension_enabled && (row_col_args.len() == 3 || row_col_args.len() == 1) {
+        let potential_file = row_col_args.last().unwrap();
         if Path::new(potential_file).exists() {
-            input_file = Some(potential_file.clone());
-            row_col_args.pop();
+            input_file = row_col_args.pop();
         }
     }
 
-    if row_col_args.len() != 2 {
-        println!(
-            "Usage: {} [--extension] <rows> <columns> [input_file.csv|xlsx]",
-            args[0]
-        );
-        println!("Note: File loading is only available with --extension flag");
-        return Ok(());
-    }
-
-    let rows: i32 = row_col_args[0].parse().unwrap_or(0);
-    let cols: i32 = row_col_args[1].parse().unwrap_or(0);
+    // The size comes from the command line, or else from the config file
+    let (rows, cols) = match (row_col_args.as_slice(), config.rows, config.cols) {
+        ([rows, cols], _, _) => (rows.parse().unwrap_or(0), cols.parse().unwrap_or(0)),
+        ([], Some(rows), Some(cols)) => (rows, cols),
+        _ => {
+            println!(
+                "Usage: {} [--extension|--no-extension] [--delimiter <char>] [--header] <rows> <columns> [input_file.csv|tsv|xlsx]",
+                args[0]
+            );
+            println!("Note: File loading is only available with --extension flag");
+            println!("Note: <rows> and <columns> may be left out if spreadsheet.toml sets them");
+            return Ok(());
+        }
+    };
 
     if !(1..=MAX_ROWS).contains(&rows) || !(1..=MAX_COLS).contains(&cols) {
         println!(
@@ -390,6 +993,10 @@ async fn main() -> Result<(), rocket::Error> {
     {
         let mut sheet_guard = SHEET.lock().unwrap();
         *sheet_guard = create_sheet(rows, cols, extension_enabled);
+        if let Some(ref mut sheet) = *sheet_guard {
+            sheet.view_size = config.view_size;
+            sheet.recalc_budget = config.recalc_budget;
+        }
 
         if extension_enabled {
             if let Some(filename) = input_file {
@@ -399,14 +1006,25 @@ async fn main() -> Result<(), rocket::Error> {
                         .and_then(|ext| ext.to_str())
                         .unwrap_or("");
 
+                    let load_delimited = if has_header {
+                        load_csv_file_with_header
+                    } else {
+                        load_csv_file
+                    };
                     let result = match extension.to_lowercase().as_str() {
-                        "csv" => load_csv_file(sheet, &filename),
+                        "csv" => load_delimited(sheet, &filename, delimiter.unwrap_or(',')),
+                        "tsv" => load_delimited(sheet, &filename, delimiter.unwrap_or('\t')),
                         "xlsx" => load_excel_file(sheet, &filename),
                         _ => Err(format!("Unsupported file format: {}", extension)),
                     };
 
                     match result {
-                        Ok(_) => println!("Successfully loaded file: {}", filename),
+                        Ok(warnings) => {
+                            for warning in &warnings {
+                                println!("Warning: {}", warning);
+                            }
+                            println!("Successfully loaded file: {}", filename);
+                        }
                         Err(e) => println!("Error loading file: {}", e),
                     }
                 }
@@ -415,71 +1033,19 @@ async fn main() -> Result<(), rocket::Error> {
     }
 
     if extension_enabled {
+        *COMMAND_QUEUE.lock().unwrap() = Some(start_command_worker());
         rocket::build()
             .configure(rocket::Config::figment()
                 .merge(("port", 8000)))  // Configure to use port 1835 because port 80 is privileged and can only be accessed by using sudo.
-            .mount("/", rocket::routes![index, command, scroll])
+            .mount("/", rocket::routes![index, command, edit, scroll, select, values])
             .attach(Template::fairing())
             .launch()
             .await?;
     } else {
-        let mut elapsed_time = 0.0;
-        let mut is_valid = true;
         let stdin = io::stdin();
-
-        loop {
-            {
-                let sheet_guard = SHEET.lock().unwrap();
-                if let Some(ref sheet) = *sheet_guard {
-                    display_sheet(sheet);
-                }
-            }
-
-            print!(
-                "[{:.1}] {}> ",
-                elapsed_time,
-                if is_valid {
-                    if SHEET
-                        .lock()
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .circular_dependency_detected
-                    {
-                        "(err)"
-                    } else {
-                        "(ok)"
-                    }
-                } else {
-                    "(err)"
-                }
-            );
-            io::stdout().flush().unwrap();
-
-            let mut command = String::new();
-            if stdin.lock().read_line(&mut command).is_err() {
-                break;
-            }
-            let command = command.trim();
-
-            if command == "q" {
-                break;
-            }
-            is_valid = is_valid_command(SHEET.lock().unwrap().as_mut().unwrap(), command);
-            let start = Instant::now();
-            let message = {
-                let mut sheet_guard = SHEET.lock().unwrap();
-                if let Some(ref mut sheet) = *sheet_guard {
-                    process_command(sheet, command)
-                } else {
-                    None
-                }
-            };
-            if let Some(msg) = message {
-                println!("{}", msg);
-            }
-            elapsed_time = start.elapsed().as_secs_f64();
-        }
+        let interactive = stdin.is_terminal();
+        // Output errors (e.g., a closed pipe) just end the session
+        let _ = run_terminal(stdin.lock(), &mut io::stdout(), interactive);
     }
     Ok(())
 }
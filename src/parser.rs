@@ -0,0 +1,420 @@
+use std::fmt;
+
+/// A lexical token of a formula.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Number(i32),
+    Ident(String),
+    Text(String),
+    Compare(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+    Comma,
+    Colon,
+}
+
+/// A parsed formula expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(i32),
+    Bool(bool),
+    Text(String),
+    CellRef(String),
+    Range(String, String),
+    Neg(Box<Expr>),
+    Binary {
+        op: char,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Criterion {
+        op: String,
+        operand: Box<Expr>,
+    },
+    Comparison {
+        op: String,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+}
+
+/// Where a cell reference appears in an expression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefPosition {
+    Cell,
+    RangeStart,
+    RangeEnd,
+}
+
+impl Expr {
+    /// Rebuilds the expression with every cell reference passed through `f`.
+    ///
+    /// The two corners of a range are passed separately, tagged with their position, so a
+    /// caller can move the start and the end of a range independently (e.g., to grow a range
+    /// when a row is inserted inside it).
+    ///
+    /// # Arguments
+    /// * `f` - Maps a cell reference and its position to the replacement reference.
+    ///
+    /// # Returns
+    /// A new `Expr` with the rewritten references.
+    ///
+    /// # Example
+    /// ```
+    /// let expr = parse("SUM(A1:A3)+B2").unwrap();
+    /// let moved = expr.map_refs(&mut |cell_ref, _| format!("{}0", cell_ref));
+    /// assert_eq!(moved.to_string(), "SUM(A10:A30)+B20");
+    /// ```
+    pub fn map_refs(&self, f: &mut impl FnMut(&str, RefPosition) -> String) -> Expr {
+        match self {
+            Expr::Number(value) => Expr::Number(*value),
+            Expr::Bool(value) => Expr::Bool(*value),
+            Expr::Text(text) => Expr::Text(text.clone()),
+            Expr::CellRef(cell_ref) => Expr::CellRef(f(cell_ref, RefPosition::Cell)),
+            Expr::Range(start, end) => Expr::Range(
+                f(start, RefPosition::RangeStart),
+                f(end, RefPosition::RangeEnd),
+            ),
+            Expr::Neg(inner) => Expr::Neg(Box::new(inner.map_refs(f))),
+            Expr::Binary { op, left, right } => Expr::Binary {
+                op: *op,
+                left: Box::new(left.map_refs(f)),
+                right: Box::new(right.map_refs(f)),
+            },
+            Expr::Call { name, args } => Expr::Call {
+                name: name.clone(),
+                args: args.iter().map(|arg| arg.map_refs(f)).collect(),
+            },
+            Expr::Criterion { op, operand } => Expr::Criterion {
+                op: op.clone(),
+                operand: Box::new(operand.map_refs(f)),
+            },
+            Expr::Comparison { op, left, right } => Expr::Comparison {
+                op: op.clone(),
+                left: Box::new(left.map_refs(f)),
+                right: Box::new(right.map_refs(f)),
+            },
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary { op: '+' | '-', .. } => 1,
+            Expr::Binary { .. } => 2,
+            _ => 3,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Bool(value) => write!(f, "{}", if *value { "TRUE" } else { "FALSE" }),
+            Expr::Text(text) => write!(f, "\"{}\"", text),
+            Expr::CellRef(cell_ref) => write!(f, "{}", cell_ref),
+            Expr::Range(start, end) => write!(f, "{}:{}", start, end),
+            Expr::Neg(inner) => {
+                if inner.precedence() < 3 {
+                    write!(f, "-({})", inner)
+                } else {
+                    write!(f, "-{}", inner)
+                }
+            }
+            Expr::Binary { op, left, right } => {
+                let prec = self.precedence();
+                if left.precedence() < prec {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
+                }
+                write!(f, "{}", op)?;
+                // Right operands of equal precedence need parentheses to keep
+                // left-associative operators like `-` and `/` intact.
+                if right.precedence() <= prec {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            }
+            Expr::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Criterion { op, operand } => write!(f, "{}{}", op, operand),
+            Expr::Comparison { op, left, right } => write!(f, "{}{}{}", left, op, right),
+        }
+    }
+}
+
+/// Splits a formula into tokens.
+///
+/// This function scans the input left to right, producing numbers, identifiers (cell references
+/// and function names), text in double quotes, operators, comparison operators and punctuation.
+/// Whitespace separates tokens and is otherwise ignored. A reference into another sheet such as
+/// `Sheet2!A1` is kept as one identifier.
+///
+/// # Arguments
+/// * `input` - The formula string (e.g., "SUM(A1:A2)*2").
+///
+/// # Returns
+/// An `Option<Vec<Token>>` containing the tokens, or `None` if the input contains an invalid
+/// character, a number that does not fit in an `i32`, or text without a closing quote.
+///
+/// # Example
+/// ```
+/// let tokens = tokenize("A1+2").unwrap();
+/// assert_eq!(tokens, vec![Token::Ident("A1".to_string()), Token::Plus, Token::Number(2)]);
+/// ```
+pub fn tokenize(input: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && chars[pos].is_ascii_digit() {
+                pos += 1;
+            }
+            let number: String = chars[start..pos].iter().collect();
+            tokens.push(Token::Number(number.parse().ok()?));
+        } else if c.is_ascii_alphabetic() {
+            let start = pos;
+            while pos < chars.len() && chars[pos].is_ascii_alphanumeric() {
+                pos += 1;
+            }
+            // A reference into another sheet, e.g. Sheet2!A1, stays a single identifier
+            if pos + 1 < chars.len() && chars[pos] == '!' && chars[pos + 1].is_ascii_alphabetic() {
+                pos += 1;
+                while pos < chars.len() && chars[pos].is_ascii_alphanumeric() {
+                    pos += 1;
+                }
+            }
+            tokens.push(Token::Ident(chars[start..pos].iter().collect()));
+        } else if c == '"' {
+            let start = pos + 1;
+            pos = start + chars[start..].iter().position(|&c| c == '"')?;
+            tokens.push(Token::Text(chars[start..pos].iter().collect()));
+            pos += 1;
+        } else if "<>=".contains(c) {
+            let start = pos;
+            while pos < chars.len() && "<>=".contains(chars[pos]) && pos - start < 2 {
+                pos += 1;
+            }
+            let op: String = chars[start..pos].iter().collect();
+            if !["=", "<>", "<", "<=", ">", ">="].contains(&op.as_str()) {
+                return None;
+            }
+            tokens.push(Token::Compare(op));
+        } else {
+            tokens.push(match c {
+                '+' => Token::Plus,
+                '-' => Token::Minus,
+                '*' => Token::Star,
+                '/' => Token::Slash,
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ',' => Token::Comma,
+                ':' => Token::Colon,
+                _ => return None,
+            });
+            pos += 1;
+        }
+    }
+
+    Some(tokens)
+}
+
+/// Parses a formula into an expression tree.
+///
+/// This function tokenizes the input and runs a recursive-descent parser over the tokens.
+/// Multiplication and division bind tighter than addition and subtraction, unary minus may
+/// appear in front of any operand (there is no unary plus, so `+A1` and `A1++B1` are malformed,
+/// as is a trailing operator like `A1+`), and function calls may be nested inside arithmetic
+/// (e.g., `SUM(A1:A2) + MAX(B1:B2)`). Function arguments may be ranges or, for conditional
+/// functions like SUMIF, comparison criteria such as `>5`. Logical functions like AND take
+/// comparisons such as `A1>5` as arguments, and `TRUE` and `FALSE` are read as boolean literals.
+///
+/// # Arguments
+/// * `input` - The formula string.
+///
+/// # Returns
+/// An `Option<Expr>` containing the expression tree, or `None` if the formula is malformed.
+///
+/// # Example
+/// ```
+/// let expr = parse("1+2*3").unwrap();
+/// assert_eq!(expr.to_string(), "1+2*3");
+/// ```
+pub fn parse(input: &str) -> Option<Expr> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_expr()?;
+    if parser.pos == parser.tokens.len() {
+        Some(expr)
+    } else {
+        None
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Option<()> {
+        if self.next()? == expected {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn parse_expr(&mut self) -> Option<Expr> {
+        let mut left = self.parse_term()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::Plus) => Some('+'),
+            Some(Token::Minus) => Some('-'),
+            _ => None,
+        } {
+            self.pos += 1;
+            let right = self.parse_term()?;
+            left = Expr::Binary {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+        Some(left)
+    }
+
+    fn parse_term(&mut self) -> Option<Expr> {
+        let mut left = self.parse_unary()?;
+        while let Some(op) = match self.peek() {
+            Some(Token::Star) => Some('*'),
+            Some(Token::Slash) => Some('/'),
+            _ => None,
+        } {
+            self.pos += 1;
+            let right = self.parse_unary()?;
+            left = Expr::Binary {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+        Some(left)
+    }
+
+    fn parse_unary(&mut self) -> Option<Expr> {
+        if self.peek() == Some(&Token::Minus) {
+            self.pos += 1;
+            return match self.parse_unary()? {
+                Expr::Number(value) => Some(Expr::Number(-value)),
+                inner => Some(Expr::Neg(Box::new(inner))),
+            };
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Option<Expr> {
+        match self.next()? {
+            Token::Number(value) => Some(Expr::Number(value)),
+            Token::Text(text) => Some(Expr::Text(text)),
+            Token::LParen => {
+                let expr = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                Some(expr)
+            }
+            Token::Ident(name) => match self.peek() {
+                Some(Token::LParen) => {
+                    self.pos += 1;
+                    let args = self.parse_args()?;
+                    Some(Expr::Call {
+                        name: name.to_uppercase(),
+                        args,
+                    })
+                }
+                Some(Token::Colon) => {
+                    self.pos += 1;
+                    match self.next()? {
+                        Token::Ident(end) => Some(Expr::Range(name, end)),
+                        _ => None,
+                    }
+                }
+                _ if name.eq_ignore_ascii_case("TRUE") => Some(Expr::Bool(true)),
+                _ if name.eq_ignore_ascii_case("FALSE") => Some(Expr::Bool(false)),
+                _ => Some(Expr::CellRef(name)),
+            },
+            _ => None,
+        }
+    }
+
+    fn parse_args(&mut self) -> Option<Vec<Expr>> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.pos += 1;
+            return Some(args);
+        }
+        loop {
+            args.push(self.parse_arg()?);
+            match self.next()? {
+                Token::Comma => {}
+                Token::RParen => return Some(args),
+                _ => return None,
+            }
+        }
+    }
+
+    fn parse_arg(&mut self) -> Option<Expr> {
+        if let Some(Token::Compare(op)) = self.peek().cloned() {
+            self.pos += 1;
+            let operand = self.parse_expr()?;
+            return Some(Expr::Criterion {
+                op,
+                operand: Box::new(operand),
+            });
+        }
+        // A comparison such as `A1>5` is an argument of a logical function like AND
+        let left = self.parse_expr()?;
+        if let Some(Token::Compare(op)) = self.peek().cloned() {
+            self.pos += 1;
+            let right = self.parse_expr()?;
+            return Some(Expr::Comparison {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            });
+        }
+        Some(left)
+    }
+}
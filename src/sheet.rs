@@ -1,16 +1,37 @@
-use crate::cell::update_cell;
-use crate::dependencies::remove_dependency;
+use crate::cell::{evaluate_expression, update_cell};
+use crate::dependencies::{
+    clear_cell_dependencies, find_cycles, formula_dependencies, formula_dependencies_at,
+    longest_chain, recalculate_after_change, recalculate_all, recalculate_dependents,
+    remove_dependency, set_dependencies,
+};
+use crate::parser::{parse, Expr, RefPosition};
 use crate::types::{
-    Cell, Clipboard, DependencyType, GraphType, PatternType, Sheet, SheetState, CLIPBOARD,
+    Cell, CellDependencies, Clipboard, DataKind, DependencyType, ErrorKind, GraphType, ParseError,
+    PatternType, Sheet, SheetSnapshot, SheetState, Workbook, CLIPBOARD,
 };
 use crate::utils::{
-    detect_pattern, encode_column, factorial, parse_cell_reference, parse_range, triangular,
+    ansi_color_code, decode_column, detect_pattern, encode_column, factorial, format_cell_value,
+    is_plain_file_name, is_valid_formula, is_valid_macro_name, is_valid_sheet_name,
+    parse_cell_or_range, parse_cell_reference, parse_range, split_import_text, suggest_command,
+    triangular, COMMANDS,
 };
-use std::collections::HashMap;
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const DISPLAY_SIZE: i32 = 10;
-type CellAttributes = (i32, Option<String>, bool, bool, bool, bool, bool);
+const SCIENTIFIC_THRESHOLD: u32 = 1_000_000;
+type CellAttributes = (
+    i32,
+    Option<String>,
+    bool,
+    bool,
+    bool,
+    bool,
+    bool,
+    bool,
+    Option<String>,
+    Option<ErrorKind>,
+);
 
 /// Creates a new spreadsheet with the specified dimensions.
 ///
@@ -47,19 +68,50 @@ pub fn create_sheet(rows: i32, cols: i32, extension_enabled: bool) -> Option<She
         output_enabled: true,
         circular_dependency_detected: false,
         extension_enabled,
-        // command_history: Vec::with_capacity(10),
-        // command_position: 0,
+        command_history: Vec::with_capacity(10),
+        command_position: 0,
         max_history_size: 10,
         dependency_graph: HashMap::new(),
         undo_stack: Vec::new(),
         redo_stack: Vec::new(),
+        merged_regions: Vec::new(),
+        column_headers: vec![None; cols as usize],
+        frozen_rows: 0,
+        frozen_cols: 0,
+        scientific_notation: false,
+        show_formulas: false,
+        cell_history: HashMap::new(),
+        last_elapsed: 0.0,
+        sleep_cache: HashMap::new(),
+        recalculating: false,
+        workbook: Workbook {
+            active: "Sheet1".to_string(),
+            sheets: HashMap::new(),
+        },
+        macros: HashMap::new(),
+        validation_rules: Vec::new(),
+        view_history: Vec::new(),
+        checkpoints: HashMap::new(),
+        view_size: None,
+        sparse_view: false,
+        error_text: None,
+        selected: None,
+        // Unseeded sheets draw different numbers on every run
+        rng_state: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64),
+        random_cache: HashMap::new(),
+        recalc_budget: None,
+        recalc_remaining: None,
+        deferred_cells: HashSet::new(),
     })
 }
 
 /// Scrolls the spreadsheet view in the specified direction.
 ///
 /// This function adjusts the viewable portion of the spreadsheet based on the direction
-/// (w: up, s: down, a: left, d: right), ensuring the view stays within bounds.
+/// (w: up, s: down, a: left, d: right) by `display_size()` rows or columns, ensuring the view
+/// stays within bounds.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
@@ -72,39 +124,34 @@ pub fn create_sheet(rows: i32, cols: i32, extension_enabled: bool) -> Option<She
 /// assert_eq!(sheet.view_row, 10);
 /// ```
 pub fn scroll_sheet(sheet: &mut Sheet, direction: char) {
+    let size = sheet.display_size();
     match direction {
         'w' => {
-            if sheet.view_row > 0 && sheet.view_row - 10 >= 0 {
-                sheet.view_row -= DISPLAY_SIZE;
+            if sheet.view_row > 0 && sheet.view_row - size >= 0 {
+                sheet.view_row -= size;
             } else if sheet.view_row >= 0 {
                 sheet.view_row = 0;
             }
         }
         's' => {
-            if sheet.view_row + DISPLAY_SIZE < sheet.rows && sheet.view_row + 20 <= sheet.rows {
-                sheet.view_row += DISPLAY_SIZE;
-            } else if sheet.view_row + DISPLAY_SIZE < sheet.rows
-                && sheet.view_row + 10 <= sheet.rows
-                && sheet.view_row + 20 > sheet.rows
-            {
-                sheet.view_row += sheet.rows - sheet.view_row - 10;
+            if sheet.view_row + size < sheet.rows && sheet.view_row + 2 * size <= sheet.rows {
+                sheet.view_row += size;
+            } else if sheet.view_row + size < sheet.rows && sheet.view_row + 2 * size > sheet.rows {
+                sheet.view_row += sheet.rows - sheet.view_row - size;
             }
         }
         'a' => {
-            if sheet.view_col - DISPLAY_SIZE >= 0 && sheet.view_col - 10 >= 0 {
-                sheet.view_col -= DISPLAY_SIZE;
+            if sheet.view_col - size >= 0 {
+                sheet.view_col -= size;
             } else if sheet.view_col >= 0 {
                 sheet.view_col = 0;
             }
         }
         'd' => {
-            if sheet.view_col + DISPLAY_SIZE < sheet.cols && sheet.view_col + 20 <= sheet.cols {
-                sheet.view_col += DISPLAY_SIZE;
-            } else if sheet.view_col + DISPLAY_SIZE < sheet.cols
-                && sheet.view_col + 10 <= sheet.cols
-                && sheet.view_col + 20 > sheet.cols
-            {
-                sheet.view_col += sheet.cols - sheet.view_col - 10;
+            if sheet.view_col + size < sheet.cols && sheet.view_col + 2 * size <= sheet.cols {
+                sheet.view_col += size;
+            } else if sheet.view_col + size < sheet.cols && sheet.view_col + 2 * size > sheet.cols {
+                sheet.view_col += sheet.cols - sheet.view_col - size;
             }
         }
         _ => {}
@@ -143,8 +190,9 @@ pub fn scroll_to_cell(sheet: &mut Sheet, row: i32, col: i32) {
 
 /// Saves the current state of the spreadsheet to the undo stack.
 ///
-/// This function captures the current state of the spreadsheet's cells and dependency graph
-/// and adds it to the undo stack. It ensures the stack does not exceed the maximum history size.
+/// This function captures the current state of the spreadsheet's cells, dependency graph,
+/// merged regions and VALIDATE rules and adds it to the undo stack. It ensures the stack does
+/// not exceed the maximum history size.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
@@ -163,11 +211,15 @@ pub fn save_state(sheet: &mut Sheet) {
 
     // Clear redo stack when a new action is performed
     sheet.redo_stack.clear();
+    // Earlier views can no longer be stepped back to once data changes
+    sheet.view_history.clear();
 
     // Create a snapshot of the current state
     let state = SheetState {
         cells: sheet.cells.clone(),
         dependency_graph: sheet.dependency_graph.clone(),
+        merged_regions: sheet.merged_regions.clone(),
+        validation_rules: sheet.validation_rules.clone(),
     };
 
     // Add to undo stack
@@ -179,6 +231,104 @@ pub fn save_state(sheet: &mut Sheet) {
     }
 }
 
+/// Records the view shown before a scroll, so that `undo` can return to it.
+///
+/// # Description
+/// View changes are undone separately from data changes: `undo` first steps back through the
+/// views recorded since the last data change, one scroll at a time, and only then reverts data.
+/// A data change forgets the recorded views (see `save_state`), and view steps are not redone.
+/// Like the undo stack, at most `max_history_size` views are kept.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet, already scrolled to its new view.
+/// * `previous` - The `(view_row, view_col)` shown before the scroll.
+fn remember_view(sheet: &mut Sheet, previous: (i32, i32)) {
+    if !sheet.extension_enabled || previous == (sheet.view_row, sheet.view_col) {
+        return;
+    }
+    sheet.view_history.push(previous);
+    if sheet.view_history.len() > sheet.max_history_size {
+        sheet.view_history.remove(0);
+    }
+}
+
+/// Takes back the most recent change for the `undo` command.
+///
+/// View changes made since the last data change are stepped back first, one scroll at a time
+/// (see `remember_view`); after that, data changes are reverted with `undo`.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+///
+/// # Returns
+/// `true` if a view or data change was taken back, `false` if there was nothing to undo.
+fn undo_step(sheet: &mut Sheet) -> bool {
+    if let Some((view_row, view_col)) = sheet.view_history.pop() {
+        sheet.view_row = view_row;
+        sheet.view_col = view_col;
+        return true;
+    }
+    undo(sheet)
+}
+
+/// Saves the current cells and dependencies under a name, for `CHECKPOINT <name>`.
+///
+/// A checkpoint is kept apart from the undo stack, so it is not lost when old undo steps are
+/// dropped. Saving under an existing name replaces the earlier checkpoint.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `name` - The name to save the checkpoint under.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// sheet.cells[0][0].value = 10;
+/// save_checkpoint(&mut sheet, "start");
+/// assert!(sheet.checkpoints.contains_key("start"));
+/// ```
+pub fn save_checkpoint(sheet: &mut Sheet, name: &str) {
+    let state = SheetState {
+        cells: sheet.cells.clone(),
+        dependency_graph: sheet.dependency_graph.clone(),
+        merged_regions: sheet.merged_regions.clone(),
+        validation_rules: sheet.validation_rules.clone(),
+    };
+    sheet.checkpoints.insert(name.to_string(), state);
+}
+
+/// Returns the spreadsheet to a named checkpoint, for `RESTORE <name>`.
+///
+/// The current state is saved to the undo stack first, so a restore can itself be undone. The
+/// checkpoint is kept and can be restored again later.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `name` - The name the checkpoint was saved under.
+///
+/// # Returns
+/// `true` if the checkpoint was restored, `false` if there is no checkpoint with that name.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// save_checkpoint(&mut sheet, "start");
+/// sheet.cells[0][0].value = 20;
+/// assert!(restore_checkpoint(&mut sheet, "start"));
+/// assert_eq!(sheet.cells[0][0].value, 0);
+/// ```
+pub fn restore_checkpoint(sheet: &mut Sheet, name: &str) -> bool {
+    let Some(state) = sheet.checkpoints.get(name).cloned() else {
+        return false;
+    };
+    save_state(sheet);
+    sheet.cells = state.cells;
+    sheet.dependency_graph = state.dependency_graph;
+    sheet.merged_regions = state.merged_regions;
+    sheet.validation_rules = state.validation_rules;
+    true
+}
+
 /// Reverts the spreadsheet to the previous state in the undo stack.
 ///
 /// This function restores the spreadsheet to the most recent state in the undo stack,
@@ -208,6 +358,8 @@ pub fn undo(sheet: &mut Sheet) -> bool {
     let current_state = SheetState {
         cells: sheet.cells.clone(),
         dependency_graph: sheet.dependency_graph.clone(),
+        merged_regions: sheet.merged_regions.clone(),
+        validation_rules: sheet.validation_rules.clone(),
     };
     sheet.redo_stack.push(current_state);
 
@@ -217,6 +369,8 @@ pub fn undo(sheet: &mut Sheet) -> bool {
     // Restore the sheet to the previous state
     sheet.cells = previous_state.cells;
     sheet.dependency_graph = previous_state.dependency_graph;
+    sheet.merged_regions = previous_state.merged_regions;
+    sheet.validation_rules = previous_state.validation_rules;
 
     true
 }
@@ -251,6 +405,8 @@ pub fn redo(sheet: &mut Sheet) -> bool {
     let current_state = SheetState {
         cells: sheet.cells.clone(),
         dependency_graph: sheet.dependency_graph.clone(),
+        merged_regions: sheet.merged_regions.clone(),
+        validation_rules: sheet.validation_rules.clone(),
     };
     sheet.undo_stack.push(current_state);
 
@@ -260,16 +416,73 @@ pub fn redo(sheet: &mut Sheet) -> bool {
     // Restore the sheet to the next state
     sheet.cells = next_state.cells;
     sheet.dependency_graph = next_state.dependency_graph;
+    sheet.merged_regions = next_state.merged_regions;
+    sheet.validation_rules = next_state.validation_rules;
 
     true
 }
 
+/// Records a command typed at the terminal prompt, expanding the `!!` and `!-N` shortcuts.
+///
+/// # Description
+/// Typing `!!` re-runs the previous command, as in a shell. Typing `!-N` re-runs the command `N`
+/// entries back from `command_position`, and leaves `command_position` on that entry, so that
+/// typing `!-1` again steps one further back, like pressing the up arrow repeatedly. Any other
+/// command, and `!!`, moves `command_position` past the newest entry again. The resolved command
+/// is appended to `command_history`, which keeps at most `max_history_size` entries. Empty input
+/// is not recorded.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `input` - The line typed at the prompt, already trimmed.
+///
+/// # Returns
+/// An `Option<String>` with the command to run, or `None` if the shortcut reaches back past the
+/// oldest recorded command.
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, recall_command};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// assert_eq!(recall_command(&mut sheet, "!!"), None);
+/// assert_eq!(recall_command(&mut sheet, "A1=1"), Some("A1=1".to_string()));
+/// assert_eq!(recall_command(&mut sheet, "A1=2"), Some("A1=2".to_string()));
+/// assert_eq!(recall_command(&mut sheet, "!-1"), Some("A1=2".to_string()));
+/// assert_eq!(recall_command(&mut sheet, "!-1"), Some("A1=1".to_string()));
+/// ```
+pub fn recall_command(sheet: &mut Sheet, input: &str) -> Option<String> {
+    let steps_back = input
+        .strip_prefix("!-")
+        .and_then(|steps| steps.parse::<usize>().ok())
+        .filter(|&steps| steps >= 1);
+    let (command, position) = if input == "!!" {
+        (sheet.command_history.last()?.clone(), None)
+    } else if let Some(steps) = steps_back {
+        let index = sheet.command_position.checked_sub(steps)?;
+        (sheet.command_history.get(index)?.clone(), Some(index))
+    } else {
+        (input.to_string(), None)
+    };
+
+    if !command.is_empty() {
+        sheet.command_history.push(command.clone());
+        sheet.command_position = position.unwrap_or(sheet.command_history.len());
+        if sheet.command_history.len() > sheet.max_history_size {
+            sheet.command_history.remove(0);
+            sheet.command_position = sheet.command_position.saturating_sub(1);
+        }
+    }
+    Some(command)
+}
+
 /// Processes a user command to manipulate the spreadsheet.
 ///
 /// # Description
 /// Interprets and executes a command string, such as scrolling, setting cell values, or advanced operations like sorting or autofilling.
 /// Supports commands like `w`, `s`, `a`, `d` for scrolling, `undo`, `redo`, `FORMULA`, `ROWDEL`, `COLDEL`, `COPY`, `CUT`, `PASTE`, `GRAPH`, `scroll_to`, and cell assignments (e.g., `A1=5`).
 /// Returns an error message if the command is invalid or fails, or `None` on success.
+/// Afterwards, unless the command only moved the view or reported on the sheet, formulas on other
+/// sheets of the workbook that refer to the active sheet are recalculated.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
@@ -289,49 +502,155 @@ pub fn redo(sheet: &mut Sheet) -> bool {
 /// assert_eq!(process_command(&mut sheet, "invalid"), Some("Invalid command format".to_string()));
 /// ```
 pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
+    // The budget covers the whole command, however many cells it changes
+    sheet.deferred_cells.clear();
+    sheet.recalc_remaining = sheet.recalc_budget;
+    let message = run_command(sheet, command, &mut Vec::new());
+    sheet.recalc_remaining = None;
+    if !is_view_only(command) {
+        refresh_linked_sheets(sheet);
+    }
+    if message.is_none() && !sheet.deferred_cells.is_empty() {
+        return Some(format!(
+            "Partial recompute - {} cells deferred, use RECOMPUTE to finish",
+            sheet.deferred_cells.len()
+        ));
+    }
+    message
+}
+
+/// Runs a command, expanding it first if it invokes a macro.
+///
+/// # Description
+/// A command whose first word names a macro (or `RUN <name>`) runs each recorded command of the
+/// macro in turn, stopping at the first one that fails. Any words after the name are passed on
+/// to each recorded command: a bare function name like `BOLD` is called with them (`fmt A1` runs
+/// `A1=BOLD(A1)`), anything else gets them appended. Macros may run other macros, but a macro that
+/// ends up running itself is refused instead of recursing forever.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `command` - The command string to run.
+/// * `expanding` - The names of the macros currently being expanded.
+///
+/// # Returns
+/// An `Option<String>` containing an error message if the command fails, or `None` if successful.
+fn run_command(sheet: &mut Sheet, command: &str, expanding: &mut Vec<String>) -> Option<String> {
+    if !sheet.extension_enabled {
+        return execute_command(sheet, command);
+    }
+    let invocation = command.strip_prefix("RUN ").unwrap_or(command).trim();
+    let (name, args) = invocation
+        .split_once(' ')
+        .map_or((invocation, ""), |(name, args)| (name, args.trim()));
+    let Some(steps) = sheet.macros.get(name).cloned() else {
+        if command.starts_with("RUN ") {
+            return Some(format!("No macro named {}", name));
+        }
+        return execute_command(sheet, command);
+    };
+    if expanding.iter().any(|running| running == name) {
+        return Some(format!("Macro {} runs itself", name));
+    }
+
+    expanding.push(name.to_string());
+    let mut message = None;
+    for step in steps {
+        let step = if args.is_empty() {
+            step
+        } else if step.chars().all(|c| c.is_ascii_alphabetic()) {
+            // Functions like BOLD(A1:B2) run as an assignment to the first cell they name
+            let target = args.split([':', ',']).next().unwrap_or(args).trim();
+            format!("{}={}({})", target, step, args)
+        } else {
+            format!("{} {}", step, args)
+        };
+        message = run_command(sheet, &step, expanding);
+        if message.is_some() {
+            break;
+        }
+    }
+    expanding.pop();
+    message
+}
+
+/// Runs a single command against the active sheet. See `process_command`.
+fn execute_command(sheet: &mut Sheet, command: &str) -> Option<String> {
     if command.is_empty() {
         return None;
     }
 
     if command.len() == 1 {
         match command.chars().next().unwrap() {
-            'w' => {
-                scroll_sheet(sheet, 'w');
-                return None;
-            }
-            'a' => {
-                scroll_sheet(sheet, 'a');
-                return None;
-            }
-            's' => {
-                scroll_sheet(sheet, 's');
-                return None;
-            }
-            'd' => {
-                scroll_sheet(sheet, 'd');
+            direction @ ('w' | 'a' | 's' | 'd') => {
+                let previous = (sheet.view_row, sheet.view_col);
+                scroll_sheet(sheet, direction);
+                remember_view(sheet, previous);
                 return None;
             }
             'q' => std::process::exit(0),
-            _ => return Some("Invalid single-character command".to_string()),
+            _ => return Some(with_suggestion("Invalid single-character command", command)),
         }
     }
 
-    if command == "disable_output" {
-        sheet.output_enabled = false;
-        return None;
+    // Each output command reports the resulting state, so a script can tell where it stands
+    if let Some(enabled) = match command {
+        "disable_output" => Some(false),
+        "enable_output" => Some(true),
+        "toggle_output" => Some(!sheet.output_enabled),
+        _ => None,
+    } {
+        sheet.output_enabled = enabled;
+        return Some(format!(
+            "Output {}",
+            if enabled { "enabled" } else { "disabled" }
+        ));
     }
-    if command == "enable_output" {
-        sheet.output_enabled = true;
+    if matches!(command, "RECOMPUTE" | "RESETFLAGS") {
+        // Recomputing starts by clearing every error and circular flag, then flags only the
+        // formulas that still fail or are still circular, so RESETFLAGS is the same command
+        recalculate_all(sheet);
         return None;
     }
 
     if sheet.extension_enabled {
         if command == "undo" {
-            if !undo(sheet) {
+            if !undo_step(sheet) {
+                return Some("Nothing to undo".to_string());
+            }
+            return None;
+        }
+        if let Some(stripped) = command.strip_prefix("UNDO ") {
+            let Some(steps) = stripped
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|&steps| steps >= 1)
+            else {
+                return Some("Invalid UNDO format: use UNDO <steps>".to_string());
+            };
+            // Asking for more steps than were recorded undoes everything there is
+            let undone = (0..steps).take_while(|_| undo_step(sheet)).count();
+            if undone == 0 {
                 return Some("Nothing to undo".to_string());
             }
             return None;
         }
+        if let Some(stripped) = command.strip_prefix("CHECKPOINT ") {
+            let name = stripped.trim();
+            if !is_valid_sheet_name(name) {
+                return Some(format!("Invalid checkpoint name: {}", name));
+            }
+            save_checkpoint(sheet, name);
+            return None;
+        }
+        if let Some(stripped) = command.strip_prefix("RESTORE ") {
+            let name = stripped.trim();
+            if !restore_checkpoint(sheet, name) {
+                return Some(format!("No checkpoint named {}", name));
+            }
+            return None;
+        }
         if command == "redo" {
             if !redo(sheet) {
                 return Some("Nothing to redo".to_string());
@@ -339,34 +658,205 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
             return None;
         }
 
+        if let Some(stripped) = command.strip_prefix("NEWSHEET ") {
+            let name = stripped.trim();
+            if !is_valid_sheet_name(name) {
+                return Some(format!("Invalid sheet name: {}", name));
+            }
+            if name == sheet.workbook.active || sheet.workbook.sheets.contains_key(name) {
+                return Some(format!("Sheet {} already exists", name));
+            }
+            let Some(mut new_sheet) = create_sheet(sheet.rows, sheet.cols, sheet.extension_enabled)
+            else {
+                return Some(format!("Could not create sheet {}", name));
+            };
+            new_sheet.workbook.active = name.to_string();
+            new_sheet.view_size = sheet.view_size;
+            sheet.workbook.sheets.insert(name.to_string(), new_sheet);
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("SHEET ") {
+            let name = stripped.trim();
+            if name != sheet.workbook.active && !switch_sheet(sheet, name) {
+                return Some(format!("No sheet named {}", name));
+            }
+            return None;
+        }
+
         if let Some(stripped) = command.strip_prefix("FORMULA ") {
             let cell_ref = &stripped.trim();
-            if let Some((row, col)) = parse_cell_reference(sheet, cell_ref) {
-                let cell = &sheet.cells[row as usize][col as usize];
-                if let Some(formula) = &cell.formula {
-                    return Some(format!("Formula in cell {}: {}", cell_ref, formula));
+            match parse_cell_reference(sheet, cell_ref) {
+                Ok((row, col)) => {
+                    let cell = &sheet.cells[row as usize][col as usize];
+                    if let Some(formula) = &cell.formula {
+                        return Some(format!("Formula in cell {}: {}", cell_ref, formula));
+                    } else {
+                        return Some(format!("No formula stored in cell {}", cell_ref));
+                    }
+                }
+                Err(error) => return Some(reference_error(cell_ref, error)),
+            }
+        }
+
+        if let Some(stripped) = command.strip_prefix("GET ") {
+            let cell_ref = &stripped.trim();
+            match parse_cell_reference(sheet, cell_ref) {
+                Ok((row, col)) => {
+                    let cell = &sheet.cells[row as usize][col as usize];
+                    if cell.is_error || cell.has_circular {
+                        let code = cell.error_kind.map_or("err", |kind| kind.code());
+                        return Some(format!("Cell {} has an error: {}", cell_ref, code));
+                    }
+                    return Some(format!("Value in cell {}: {}", cell_ref, cell.value));
+                }
+                Err(error) => return Some(reference_error(cell_ref, error)),
+            }
+        }
+
+        if let Some(stripped) = command.strip_prefix("WHATIF ") {
+            let usage = "Invalid WHATIF format: use WHATIF <cell>=<formula> : GET <cell>";
+            let Some((assignment, query)) = stripped.split_once(" : ") else {
+                return Some(usage.to_string());
+            };
+            let (assignment, query) = (assignment.trim(), query.trim());
+            if !assignment.contains('=') || !query.starts_with("GET ") {
+                return Some(usage.to_string());
+            }
+            return Some(what_if(sheet, assignment, query));
+        }
+
+        if command == "STATS" {
+            let cells = sheet.cells.iter().flatten();
+            let non_empty = cells
+                .clone()
+                .filter(|cell| cell.formula.is_some() || cell.value != 0)
+                .count();
+            // Plain numbers are stored as formulas too, so only count the ones that compute
+            let formulas = cells.clone().filter(|cell| cell.is_computed()).count();
+            let errors = cells.filter(|cell| cell.is_error).count();
+            let links: usize = sheet
+                .dependency_graph
+                .values()
+                .map(|deps| deps.dependencies.len())
+                .sum();
+            return Some(format!(
+                "Non-empty cells: {}, Formula cells: {}, Error cells: {}, Dependency links: {}, Circular dependency: {}",
+                non_empty,
+                formulas,
+                errors,
+                links,
+                if sheet.circular_dependency_detected {
+                    "detected"
                 } else {
-                    return Some(format!("No formula stored in cell {}", cell_ref));
+                    "none"
                 }
-            } else {
-                return Some(format!("Invalid cell reference: {}", cell_ref));
+            ));
+        }
+
+        if command == "FORMULAS" {
+            let mut listing = Vec::new();
+            for (row, cells) in sheet.cells.iter().enumerate() {
+                for (col, cell) in cells.iter().enumerate() {
+                    // Plain numbers are stored as formulas too, so only list the ones that compute
+                    if let (true, Some(formula)) = (cell.is_computed(), &cell.formula) {
+                        let mut name = String::new();
+                        encode_column(col as i32, &mut name);
+                        listing.push(format!("{}{}: {}", name, row + 1, formula));
+                    }
+                }
+            }
+            if listing.is_empty() {
+                return Some("No formulas stored".to_string());
+            }
+            return Some(format!("Formulas:\n{}", listing.join("\n")));
+        }
+
+        if command == "CYCLES" {
+            let cycles = find_cycles(sheet);
+            if cycles.is_empty() {
+                return Some("No circular dependencies".to_string());
+            }
+            let listing = cycles
+                .iter()
+                .map(|cycle| {
+                    // Each path ends where it started, to show the loop
+                    cycle
+                        .iter()
+                        .chain(cycle.first())
+                        .map(|&(row, col)| {
+                            let mut name = String::new();
+                            encode_column(col, &mut name);
+                            format!("{}{}", name, row + 1)
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" -> ")
+                })
+                .collect::<Vec<_>>();
+            return Some(format!("Cycles:\n{}", listing.join("\n")));
+        }
+
+        if command == "LONGESTCHAIN" {
+            let chain = longest_chain(sheet);
+            if chain.is_empty() {
+                return Some("No dependencies".to_string());
             }
+            let path = chain
+                .iter()
+                .map(|&(row, col)| {
+                    let mut name = String::new();
+                    encode_column(col, &mut name);
+                    format!("{}{}", name, row + 1)
+                })
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Some(format!(
+                "Longest dependency chain: {} ({})",
+                chain.len() - 1,
+                path
+            ));
+        }
+
+        if let Some(stripped) = command.strip_prefix("HISTORY ") {
+            let cell_ref = stripped.trim();
+            let (row, col) = match parse_cell_reference(sheet, cell_ref) {
+                Ok(position) => position,
+                Err(error) => return Some(reference_error(cell_ref, error)),
+            };
+            return Some(match sheet.cell_history.get(&(row, col)) {
+                Some(history) if !history.is_empty() => format!(
+                    "History of cell {}: {}",
+                    cell_ref,
+                    history
+                        .iter()
+                        .map(|(value, formula)| format!("{} = {}", formula, value))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                _ => format!("No history recorded for cell {}", cell_ref),
+            });
         }
 
         if let Some(stripped) = command.strip_prefix("ROWDEL") {
-            save_state(sheet);
             let row_str = &stripped.trim();
             if let Ok(row) = row_str.parse::<i32>() {
                 if row >= 1 && row <= sheet.rows {
+                    if sheet.is_range_locked(row - 1, 0, row - 1, sheet.cols - 1) {
+                        return Some("Cannot delete locked cells".to_string());
+                    }
+                    save_state(sheet);
                     for col in 0..sheet.cols {
                         let cell = &mut sheet.cells[(row - 1) as usize][col as usize];
                         cell.value = 0;
+                        cell.is_set = false;
                         cell.formula = None;
                         cell.is_formula = false;
                         cell.is_error = false;
                         cell.is_bold = false;
                         cell.is_italic = false;
                         cell.is_underline = false;
+                        cell.is_strike = false;
+                        cell.color = None;
                         if let Some(cell_deps) = sheet.dependency_graph.remove(&((row - 1), col)) {
                             for dep in cell_deps.dependencies {
                                 match dep {
@@ -397,6 +887,12 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                             }
                         }
                     }
+                    // A VALIDATE rule that only covered the deleted row goes with it
+                    sheet
+                        .validation_rules
+                        .retain(|&(start_row, _, end_row, _, _, _)| {
+                            start_row != row - 1 || end_row != row - 1
+                        });
 
                     return None;
                 } else {
@@ -408,19 +904,25 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
         }
 
         if let Some(stripped) = command.strip_prefix("COLDEL") {
-            save_state(sheet);
             let col_str = &stripped.trim();
             if !col_str.is_empty() && col_str.chars().all(|c| c.is_ascii_alphabetic()) {
-                if let Some((_, col)) = parse_cell_reference(sheet, &format!("{}1", col_str)) {
+                if let Ok((_, col)) = parse_cell_reference(sheet, &format!("{}1", col_str)) {
+                    if sheet.is_range_locked(0, col, sheet.rows - 1, col) {
+                        return Some("Cannot delete locked cells".to_string());
+                    }
+                    save_state(sheet);
                     for row in 0..sheet.rows {
                         let cell = &mut sheet.cells[row as usize][col as usize];
                         cell.value = 0;
+                        cell.is_set = false;
                         cell.formula = None;
                         cell.is_formula = false;
                         cell.is_error = false;
                         cell.is_bold = false;
                         cell.is_italic = false;
                         cell.is_underline = false;
+                        cell.is_strike = false;
+                        cell.color = None;
                         if let Some(cell_deps) = sheet.dependency_graph.remove(&(row, col)) {
                             for dep in cell_deps.dependencies {
                                 match dep {
@@ -451,6 +953,12 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                             }
                         }
                     }
+                    // A VALIDATE rule that only covered the deleted column goes with it
+                    sheet
+                        .validation_rules
+                        .retain(|&(_, start_col, _, end_col, _, _)| {
+                            start_col != col || end_col != col
+                        });
                     return None;
                 } else {
                     return Some(format!("Invalid column reference: {}", col_str));
@@ -460,119 +968,955 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
             }
         }
 
-        if let Some(stripped) = command.strip_prefix("COPY ") {
-            let range = &stripped;
-            if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, range) {
-                if copy_range(sheet, start_row, start_col, end_row, end_col) {
-                    return Some("Copied to clipboard".to_string());
-                } else {
-                    return Some("Invalid range for copy".to_string());
+        if let Some(stripped) = command.strip_prefix("CLEARFORMAT ") {
+            let Some((start_row, start_col, end_row, end_col)) =
+                parse_cell_or_range(sheet, stripped.trim())
+            else {
+                return Some("Invalid CLEARFORMAT format: use CLEARFORMAT <range>".to_string());
+            };
+            if sheet.is_range_locked(start_row, start_col, end_row, end_col) {
+                return Some("Cannot change locked cells".to_string());
+            }
+            save_state(sheet);
+            // Values, formulas and locks are kept; only the styling goes
+            for i in start_row..=end_row {
+                for j in start_col..=end_col {
+                    let cell = &mut sheet.cells[i as usize][j as usize];
+                    cell.is_bold = false;
+                    cell.is_italic = false;
+                    cell.is_underline = false;
+                    cell.is_strike = false;
+                    cell.color = None;
                 }
-            } else {
-                return Some("Invalid COPY format: use COPY <range>".to_string());
             }
+            return None;
         }
 
-        if let Some(stripped) = command.strip_prefix("CUT ") {
-            save_state(sheet);
-            let range = &stripped;
-            if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, range) {
-                if cut_range(sheet, start_row, start_col, end_row, end_col) {
-                    return Some("Cut to clipboard".to_string());
+        for (prefix, locked) in [("LOCK ", true), ("UNLOCK ", false)] {
+            if let Some(stripped) = command.strip_prefix(prefix) {
+                if let Some((start_row, start_col, end_row, end_col)) =
+                    parse_cell_or_range(sheet, stripped.trim())
+                {
+                    save_state(sheet);
+                    for i in start_row..=end_row {
+                        for j in start_col..=end_col {
+                            sheet.cells[i as usize][j as usize].is_locked = locked;
+                        }
+                    }
+                    return None;
                 } else {
-                    return Some("Invalid range for cut".to_string());
+                    return Some(format!(
+                        "Invalid {} format: use {}<range>",
+                        prefix.trim(),
+                        prefix
+                    ));
                 }
-            } else {
-                return Some("Invalid CUT format: use CUT <range>".to_string());
             }
         }
 
-        if let Some(stripped) = command.strip_prefix("PASTE ") {
-            save_state(sheet);
-            let cell_ref = &stripped;
-            if let Some((row, col)) = parse_cell_reference(sheet, cell_ref) {
-                if paste_range(sheet, row, col) {
-                    return Some("Pasted from clipboard".to_string());
-                } else {
-                    return Some("Nothing to paste or invalid target".to_string());
+        if let Some(stripped) = command.strip_prefix("MERGE ") {
+            if let Some((start_row, start_col, end_row, end_col)) =
+                parse_range(sheet, stripped.trim())
+            {
+                if start_row == end_row && start_col == end_col {
+                    return Some("MERGE needs a range of at least two cells".to_string());
                 }
+                if sheet.merged_regions.iter().any(|&(r1, c1, r2, c2)| {
+                    start_row <= r2 && r1 <= end_row && start_col <= c2 && c1 <= end_col
+                }) {
+                    return Some("Range overlaps an existing merged region".to_string());
+                }
+                save_state(sheet);
+                sheet
+                    .merged_regions
+                    .push((start_row, start_col, end_row, end_col));
+                return None;
             } else {
-                return Some("Invalid PASTE format: use PASTE <cell>".to_string());
+                return Some("Invalid MERGE format: use MERGE <range>".to_string());
             }
         }
 
-        if command.starts_with("GRAPH ") {
-            let parts: Vec<&str> = command.split_whitespace().collect();
-            if parts.len() == 3 {
-                let graph_type = match parts[1].to_uppercase().as_str() {
-                    "(BAR)" => GraphType::Bar,
-                    "(SCATTER)" => GraphType::Scatter,
-                    _ => return Some("Invalid graph type. Use (BAR) or (SCATTER)".to_string()),
+        if let Some(stripped) = command.strip_prefix("VALIDATE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let rule = match parts.as_slice() {
+                [range, min, max] => parse_cell_or_range(sheet, range)
+                    .zip(min.parse::<i32>().ok().zip(max.parse::<i32>().ok()))
+                    .filter(|(_, (min, max))| min <= max),
+                _ => None,
+            };
+            let Some(((start_row, start_col, end_row, end_col), (min, max))) = rule else {
+                return Some(
+                    "Invalid VALIDATE format: use VALIDATE <range> <min> <max>".to_string(),
+                );
+            };
+            save_state(sheet);
+            sheet
+                .validation_rules
+                .push((start_row, start_col, end_row, end_col, min, max));
+            // Values already in the range are checked against the new rule
+            recalculate_all(sheet);
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("UNMERGE ") {
+            if let Some((start_row, start_col, end_row, end_col)) =
+                parse_cell_or_range(sheet, stripped.trim())
+            {
+                let overlaps = |&(r1, c1, r2, c2): &(i32, i32, i32, i32)| {
+                    start_row <= r2 && r1 <= end_row && start_col <= c2 && c1 <= end_col
                 };
-                if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, parts[2])
-                {
-                    let graph_output =
-                        display_graph(sheet, graph_type, start_row, start_col, end_row, end_col);
-                    return Some(graph_output);
-                } else {
-                    return Some("Invalid range for graph".to_string());
+                if !sheet.merged_regions.iter().any(overlaps) {
+                    return Some(format!("No merged region at {}", stripped.trim()));
                 }
+                save_state(sheet);
+                sheet.merged_regions.retain(|region| !overlaps(region));
+                return None;
             } else {
-                return Some("Usage: GRAPH <type> <range> (e.g., GRAPH (BAR) A1:A10)".to_string());
+                return Some("Invalid UNMERGE format: use UNMERGE <range>".to_string());
             }
         }
-    }
 
-    if let Some(stripped) = command.strip_prefix("scroll_to ") {
-        let cell_ref = &stripped;
-        if let Some((row, col)) = parse_cell_reference(sheet, cell_ref) {
-            scroll_to_cell(sheet, row, col);
+        if let Some(stripped) = command.strip_prefix("SCINOTATION ") {
+            match stripped.trim().to_lowercase().as_str() {
+                "on" => sheet.scientific_notation = true,
+                "off" => sheet.scientific_notation = false,
+                _ => return Some("Invalid SCINOTATION format: use SCINOTATION on|off".to_string()),
+            }
             return None;
-        } else {
-            return Some("Invalid cell reference for scroll".to_string());
         }
-    }
 
-    if let Some((cell_ref, formula)) = command.split_once('=') {
-        let cell_ref = cell_ref.trim();
-        let formula = formula.trim();
-        if let Some((row, col)) = parse_cell_reference(sheet, cell_ref) {
-            if sheet.extension_enabled {
-                save_state(sheet);
+        if let Some(stripped) = command.strip_prefix("ERRSTR ") {
+            let stripped = stripped.trim();
+            if stripped.eq_ignore_ascii_case("off") {
+                sheet.error_text = None;
+                return None;
             }
-            if let Some((func_name, args)) = formula.split_once('(') {
-                if let Some(range_arg) = args.strip_suffix(')') {
-                    let range_arg = range_arg.trim();
-                    if func_name.trim().to_uppercase() == "SORTA"
-                        || func_name.trim().to_uppercase() == "SORTD"
-                    {
-                        if let Some((start_row, start_col, end_row, end_col)) =
-                            parse_range(sheet, range_arg)
-                        {
-                            if start_col == end_col {
-                                let mut values: Vec<(i32, i32)> = Vec::new();
-                                for i in start_row..=end_row {
-                                    values.push((
-                                        i,
-                                        sheet.cells[i as usize][start_col as usize].value,
-                                    ));
-                                }
-                                if func_name.trim().to_uppercase() == "SORTA" {
-                                    values.sort_by(|a, b| a.1.cmp(&b.1));
-                                } else {
-                                    values.sort_by(|a, b| b.1.cmp(&a.1));
-                                }
-                                let mut original_values: Vec<CellAttributes> = Vec::new();
-                                for i in start_row..=end_row {
-                                    let cell = &sheet.cells[i as usize][start_col as usize];
-                                    original_values.push((
-                                        cell.value,
-                                        cell.formula.clone(),
+            match stripped
+                .strip_prefix('"')
+                .and_then(|text| text.strip_suffix('"'))
+            {
+                Some(text) if !text.is_empty() => sheet.error_text = Some(text.to_string()),
+                _ => {
+                    return Some(
+                        "Invalid ERRSTR format: use ERRSTR \"<text>\" or ERRSTR off".to_string(),
+                    )
+                }
+            }
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("BUDGET ") {
+            let stripped = stripped.trim();
+            if stripped.eq_ignore_ascii_case("off") {
+                sheet.recalc_budget = None;
+                return None;
+            }
+            match stripped.parse::<usize>() {
+                Ok(budget) if budget > 0 => sheet.recalc_budget = Some(budget),
+                _ => {
+                    return Some(
+                        "Invalid BUDGET format: use BUDGET <cells> or BUDGET off".to_string(),
+                    )
+                }
+            }
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("SEED ") {
+            let Ok(seed) = stripped.trim().parse::<i64>() else {
+                return Some("Invalid SEED format: use SEED <number>".to_string());
+            };
+            sheet.rng_state = seed as u64;
+            sheet.random_cache.clear();
+            recalculate_all(sheet);
+            return None;
+        }
+        if command == "REFRESH" {
+            // Every RANDBETWEEN cell draws again, continuing from the current generator state
+            sheet.random_cache.clear();
+            recalculate_all(sheet);
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("SPARSEVIEW ") {
+            match stripped.trim().to_lowercase().as_str() {
+                "on" => sheet.sparse_view = true,
+                "off" => sheet.sparse_view = false,
+                _ => return Some("Invalid SPARSEVIEW format: use SPARSEVIEW on|off".to_string()),
+            }
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("SHOWFORMULAS ") {
+            match stripped.trim().to_lowercase().as_str() {
+                "on" => sheet.show_formulas = true,
+                "off" => sheet.show_formulas = false,
+                _ => {
+                    return Some("Invalid SHOWFORMULAS format: use SHOWFORMULAS on|off".to_string())
+                }
+            }
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("FREEZE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let counts = match parts.as_slice() {
+                [rows, cols] => rows.parse::<i32>().ok().zip(cols.parse::<i32>().ok()),
+                _ => None,
+            };
+            let Some((rows, cols)) = counts else {
+                return Some("Invalid FREEZE format: use FREEZE <rows> <columns>".to_string());
+            };
+            if !(0..=sheet.rows).contains(&rows) || !(0..=sheet.cols).contains(&cols) {
+                return Some("FREEZE counts are outside the sheet".to_string());
+            }
+            sheet.frozen_rows = rows;
+            sheet.frozen_cols = cols;
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("INSERTROW ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let parsed = match parts.as_slice() {
+                [row] => row.parse::<i32>().ok().map(|row| (row, false)),
+                [row, "EXTEND"] => row.parse::<i32>().ok().map(|row| (row, true)),
+                _ => None,
+            };
+            let Some((row, extend_ranges)) = parsed else {
+                return Some("Invalid INSERTROW format: use INSERTROW <row> [EXTEND]".to_string());
+            };
+            return insert_row(sheet, row - 1, extend_ranges).err();
+        }
+
+        if let Some(stripped) = command.strip_prefix("DELETERANGE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let range = match parts.as_slice() {
+                [range, direction] if direction.eq_ignore_ascii_case("up") => {
+                    parse_cell_or_range(sheet, range)
+                }
+                _ => None,
+            };
+            let Some((start_row, start_col, end_row, end_col)) = range else {
+                return Some("Invalid DELETERANGE format: use DELETERANGE <range> up".to_string());
+            };
+            return delete_range(sheet, start_row, start_col, end_row, end_col).err();
+        }
+
+        if let Some(stripped) = command.strip_prefix("SHOW ") {
+            let Some((start_row, start_col, end_row, end_col)) =
+                parse_cell_or_range(sheet, stripped.trim())
+            else {
+                return Some("Invalid SHOW format: use SHOW <range>".to_string());
+            };
+            let rows: Vec<i32> = (start_row..=end_row).collect();
+            let cols: Vec<i32> = (start_col..=end_col).collect();
+            let grid = format_grid(sheet, &rows, &cols, false);
+            return Some(grid.trim_end_matches('\n').to_string());
+        }
+
+        if let Some(stripped) = command.strip_prefix("COMPARE ") {
+            let targets: Vec<&str> = stripped.split_whitespace().collect();
+            let ranges = if targets.len() == 2 {
+                parse_range(sheet, targets[0]).zip(parse_range(sheet, targets[1]))
+            } else {
+                None
+            };
+            let Some((first, second)) = ranges else {
+                return Some("Invalid COMPARE format: use COMPARE <range> <range>".to_string());
+            };
+            if first.2 - first.0 != second.2 - second.0 || first.3 - first.1 != second.3 - second.1
+            {
+                return Some("COMPARE ranges must have the same shape".to_string());
+            }
+
+            // Single columns are reported by row; wider ranges name the cell in the first range
+            let mut differences = Vec::new();
+            for i in 0..=(first.2 - first.0) {
+                for j in 0..=(first.3 - first.1) {
+                    let left = format_cell_value(
+                        &sheet.cells[(first.0 + i) as usize][(first.1 + j) as usize],
+                    );
+                    let right = format_cell_value(
+                        &sheet.cells[(second.0 + i) as usize][(second.1 + j) as usize],
+                    );
+                    if left == right {
+                        continue;
+                    }
+                    let label = if first.1 == first.3 {
+                        format!("Row {}", first.0 + i + 1)
+                    } else {
+                        let mut cell_ref = String::new();
+                        encode_column(first.1 + j, &mut cell_ref);
+                        format!("{}{}", cell_ref, first.0 + i + 1)
+                    };
+                    differences.push(format!("{}: {} vs {}", label, left, right));
+                }
+            }
+            return Some(if differences.is_empty() {
+                "Ranges match".to_string()
+            } else {
+                format!(
+                    "{} difference(s): {}",
+                    differences.len(),
+                    differences.join("; ")
+                )
+            });
+        }
+
+        if let Some(stripped) = command.strip_prefix("SWAP ") {
+            let targets: Vec<&str> = stripped.split_whitespace().collect();
+            if targets.len() != 2 {
+                return Some("Invalid SWAP format: use SWAP <range> <range>".to_string());
+            }
+            let (Some(first), Some(second)) = (
+                parse_cell_or_range(sheet, targets[0]),
+                parse_cell_or_range(sheet, targets[1]),
+            ) else {
+                return Some("Invalid SWAP format: use SWAP <range> <range>".to_string());
+            };
+            if first.2 - first.0 != second.2 - second.0 || first.3 - first.1 != second.3 - second.1
+            {
+                return Some("SWAP ranges must have the same shape".to_string());
+            }
+            if first.0 <= second.2
+                && second.0 <= first.2
+                && first.1 <= second.3
+                && second.1 <= first.3
+            {
+                return Some("SWAP ranges must not overlap".to_string());
+            }
+            if sheet.is_range_locked(first.0, first.1, first.2, first.3)
+                || sheet.is_range_locked(second.0, second.1, second.2, second.3)
+            {
+                return Some("Cannot swap locked cells".to_string());
+            }
+
+            save_state(sheet);
+            let first_cells = sheet.get_cell_range(first.0, first.1, first.2, first.3);
+            let second_cells = sheet.get_cell_range(second.0, second.1, second.2, second.3);
+            // Drop the old formulas' links; set_cell_range re-registers any incoming formulas
+            for (start_row, start_col) in [(first.0, first.1), (second.0, second.1)] {
+                for i in 0..=(first.2 - first.0) {
+                    for j in 0..=(first.3 - first.1) {
+                        clear_cell_dependencies(sheet, start_row + i, start_col + j);
+                    }
+                }
+            }
+            // Writing the cells records a HISTORY entry for each; they keep their own instead
+            let history = std::mem::take(&mut sheet.cell_history);
+            sheet.set_cell_range(first.0, first.1, &second_cells);
+            sheet.set_cell_range(second.0, second.1, &first_cells);
+            sheet.cell_history = history;
+            let (row_offset, col_offset) = (second.0 - first.0, second.1 - first.1);
+            let within = |range: (i32, i32, i32, i32), row: i32, col: i32| {
+                (range.0..=range.2).contains(&row) && (range.1..=range.3).contains(&col)
+            };
+            sheet.move_cell_history(|row, col| {
+                Some(if within(first, row, col) {
+                    (row + row_offset, col + col_offset)
+                } else if within(second, row, col) {
+                    (row - row_offset, col - col_offset)
+                } else {
+                    (row, col)
+                })
+            });
+            for (start_row, start_col) in [(first.0, first.1), (second.0, second.1)] {
+                for i in 0..=(first.2 - first.0) {
+                    for j in 0..=(first.3 - first.1) {
+                        recalculate_dependents(sheet, start_row + i, start_col + j);
+                    }
+                }
+            }
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("SERIES ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let parsed = if parts.len() == 3 {
+                parse_cell_or_range(sheet, parts[0])
+                    .zip(parts[1].parse::<i32>().ok())
+                    .zip(parts[2].parse::<i32>().ok())
+            } else {
+                None
+            };
+            let Some((((start_row, start_col, end_row, end_col), start), step)) = parsed else {
+                return Some(
+                    "Invalid SERIES format: use SERIES <range> <start> <step>".to_string(),
+                );
+            };
+            if sheet.is_range_locked(start_row, start_col, end_row, end_col) {
+                return Some("Cannot fill locked cells".to_string());
+            }
+
+            // Values are written row by row, so a column or a row fills in reading order
+            let mut values = Vec::new();
+            let mut value = Some(start);
+            for _ in 0..(end_row - start_row + 1) * (end_col - start_col + 1) {
+                let Some(current) = value else {
+                    return Some("SERIES values overflow".to_string());
+                };
+                values.push(current);
+                value = current.checked_add(step);
+            }
+
+            save_state(sheet);
+            let mut values = values.into_iter();
+            for i in start_row..=end_row {
+                for j in start_col..=end_col {
+                    if let Some(value) = values.next() {
+                        update_cell(sheet, i, j, &value.to_string());
+                    }
+                }
+            }
+            return None;
+        }
+
+        if let Some((keyword, stripped)) = command
+            .strip_prefix("TOTALCOL ")
+            .map(|rest| ("TOTALCOL", rest))
+            .or_else(|| {
+                command
+                    .strip_prefix("TOTALROW ")
+                    .map(|rest| ("TOTALROW", rest))
+            })
+        {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let parsed = if parts.len() == 2 {
+                parse_cell_or_range(sheet, parts[0]).zip(parse_cell_reference(sheet, parts[1]).ok())
+            } else {
+                None
+            };
+            let Some(((start_row, start_col, end_row, end_col), (row, col))) = parsed else {
+                return Some(format!(
+                    "Invalid {} format: use {} <range> <cell>",
+                    keyword, keyword
+                ));
+            };
+            // The total goes directly below a column or directly right of a row
+            let adjacent = if keyword == "TOTALCOL" {
+                start_col == end_col && col == start_col && row == end_row + 1
+            } else {
+                start_row == end_row && row == start_row && col == end_col + 1
+            };
+            if !adjacent {
+                return Some(if keyword == "TOTALCOL" {
+                    "TOTALCOL target must be the cell just below a single-column range".to_string()
+                } else {
+                    "TOTALROW target must be the cell just right of a single-row range".to_string()
+                });
+            }
+            let target = &sheet.cells[row as usize][col as usize];
+            if target.is_locked {
+                return Some(format!("Cell {} is locked", parts[1]));
+            }
+            if target.is_formula || target.value != 0 {
+                return Some(format!("Cell {} is not empty", parts[1]));
+            }
+
+            let mut start_ref = String::new();
+            let mut end_ref = String::new();
+            encode_column(start_col, &mut start_ref);
+            encode_column(end_col, &mut end_ref);
+            let formula = format!(
+                "SUM({}{}:{}{})",
+                start_ref,
+                start_row + 1,
+                end_ref,
+                end_row + 1
+            );
+            save_state(sheet);
+            update_cell(sheet, row, col, &formula);
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("COPY ") {
+            let range = &stripped;
+            if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, range) {
+                if copy_range(sheet, start_row, start_col, end_row, end_col) {
+                    return Some("Copied to clipboard".to_string());
+                } else {
+                    return Some("Invalid range for copy".to_string());
+                }
+            } else {
+                return Some("Invalid COPY format: use COPY <range>".to_string());
+            }
+        }
+
+        if let Some(stripped) = command.strip_prefix("APPLY ") {
+            let usage = "Invalid APPLY format: use APPLY <range> =<formula>";
+            let Some((range, formula)) = stripped.trim().split_once(' ') else {
+                return Some(usage.to_string());
+            };
+            let formula = formula.trim();
+            let formula = formula.strip_prefix('=').unwrap_or(formula).trim();
+            let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, range) else {
+                return Some(usage.to_string());
+            };
+            if sheet.is_range_locked(start_row, start_col, end_row, end_col) {
+                return Some("Cannot apply a formula to locked cells".to_string());
+            }
+            let Some(expr) = parse(formula).filter(|_| is_valid_formula(sheet, formula)) else {
+                return Some(format!("Invalid formula: {}", formula));
+            };
+            // The formula is written for the first cell; the others get it shifted along
+            let mut formulas = Vec::new();
+            for i in start_row..=end_row {
+                for j in start_col..=end_col {
+                    let Some(shifted) = shifted_refs(sheet, &expr, i - start_row, j - start_col)
+                    else {
+                        let mut cell_ref = String::new();
+                        encode_column(j, &mut cell_ref);
+                        return Some(format!(
+                            "The formula for {}{} would refer outside the sheet",
+                            cell_ref,
+                            i + 1
+                        ));
+                    };
+                    formulas.push((i, j, shifted.to_string()));
+                }
+            }
+            save_state(sheet);
+            for (i, j, formula) in formulas {
+                update_cell(sheet, i, j, &formula);
+            }
+            return None;
+        }
+
+        for (prefix, largest) in [("ARGMAX ", true), ("ARGMIN ", false)] {
+            let Some(stripped) = command.strip_prefix(prefix) else {
+                continue;
+            };
+            let Some((start_row, start_col, end_row, end_col)) =
+                parse_range(sheet, stripped.trim())
+            else {
+                return Some(format!(
+                    "Invalid {} format: use {}<range>",
+                    prefix.trim(),
+                    prefix
+                ));
+            };
+            // Error and text cells are skipped; on a tie the first cell in row-major order wins
+            let mut best: Option<(i32, i32, i32)> = None;
+            for i in start_row..=end_row {
+                for j in start_col..=end_col {
+                    let cell = &sheet.cells[i as usize][j as usize];
+                    if cell.is_error || cell.kind == DataKind::Text {
+                        continue;
+                    }
+                    let better = best.is_none_or(|(value, _, _)| {
+                        if largest {
+                            cell.value > value
+                        } else {
+                            cell.value < value
+                        }
+                    });
+                    if better {
+                        best = Some((cell.value, i, j));
+                    }
+                }
+            }
+            return Some(match best {
+                Some((value, row, col)) => {
+                    let mut cell_ref = String::new();
+                    encode_column(col, &mut cell_ref);
+                    format!(
+                        "{} {} at {}{}",
+                        if largest { "Maximum" } else { "Minimum" },
+                        value,
+                        cell_ref,
+                        row + 1
+                    )
+                }
+                None => format!("No values in {}", stripped.trim()),
+            });
+        }
+
+        // Evaluated at a position outside the sheet, so no cell or validation rule is involved
+        if let Some(stripped) = command.strip_prefix("EVAL ") {
+            let expr = stripped.trim();
+            if !is_valid_formula(sheet, expr) {
+                return Some(format!("Invalid formula: {}", expr));
+            }
+            return Some(match evaluate_expression(sheet, expr, -1, -1) {
+                (_, Some(error)) => error.code().to_string(),
+                (value, None) => value.to_string(),
+            });
+        }
+
+        // A whole row or column, as comma-separated values
+        if let Some(stripped) = command.strip_prefix("GETROW ") {
+            return Some(match stripped.trim().parse::<i32>() {
+                Ok(row) if row >= 1 && row <= sheet.rows => sheet.cells[row as usize - 1]
+                    .iter()
+                    .map(format_cell_value)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                _ => "Invalid GETROW format: use GETROW <row number>".to_string(),
+            });
+        }
+        if let Some(stripped) = command.strip_prefix("GETCOL ") {
+            return Some(match decode_column(stripped.trim()) {
+                Some(col) if col < sheet.cols => sheet
+                    .cells
+                    .iter()
+                    .map(|line| format_cell_value(&line[col as usize]))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                _ => "Invalid GETCOL format: use GETCOL <column>".to_string(),
+            });
+        }
+
+        if let Some(stripped) = command.strip_prefix("EXPORTRANGE ") {
+            return match parse_range(sheet, stripped.trim()) {
+                Some((start_row, start_col, end_row, end_col)) => Some(export_range_tsv(
+                    sheet, start_row, start_col, end_row, end_col,
+                )),
+                None => Some("Invalid EXPORTRANGE format: use EXPORTRANGE <range>".to_string()),
+            };
+        }
+
+        if let Some(stripped) = command.strip_prefix("IMPORTRANGE ") {
+            let usage = "Invalid IMPORTRANGE format: use IMPORTRANGE <cell> \"<text>\"";
+            let Some((cell_ref, text)) = stripped.trim().split_once(' ') else {
+                return Some(usage.to_string());
+            };
+            let (Ok((start_row, start_col)), Some(rows)) = (
+                parse_cell_reference(sheet, cell_ref),
+                split_import_text(text),
+            ) else {
+                return Some(usage.to_string());
+            };
+            let end_row = start_row + rows.len() as i32 - 1;
+            let width = rows.iter().map(Vec::len).max().unwrap_or(1) as i32;
+            let end_col = (start_col + width - 1).min(sheet.cols - 1);
+            if end_row >= sheet.rows {
+                return Some("Text does not fit in the sheet".to_string());
+            }
+            if sheet.is_range_locked(start_row, start_col, end_row, end_col) {
+                return Some("Cannot import into a locked range".to_string());
+            }
+
+            save_state(sheet);
+            let warnings = match write_delimited_rows(sheet, &rows, start_row, start_col) {
+                Ok(warnings) => warnings,
+                Err(error) => return Some(error),
+            };
+            for row in start_row..=end_row {
+                for col in start_col..=end_col {
+                    recalculate_dependents(sheet, row, col);
+                }
+            }
+            if warnings.is_empty() {
+                return None;
+            }
+            return Some(warnings.join("\n"));
+        }
+
+        if let Some(stripped) = command.strip_prefix("CUT ") {
+            save_state(sheet);
+            let range = &stripped;
+            if let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, range) {
+                if cut_range(sheet, start_row, start_col, end_row, end_col) {
+                    return Some("Cut to clipboard".to_string());
+                } else {
+                    return Some("Invalid range for cut".to_string());
+                }
+            } else {
+                return Some("Invalid CUT format: use CUT <range>".to_string());
+            }
+        }
+
+        if let Some(stripped) = command
+            .strip_prefix("ALIAS ")
+            .or_else(|| command.strip_prefix("MACRO "))
+        {
+            let is_alias = command.starts_with("ALIAS ");
+            let Some((name, body)) = stripped.trim().split_once(' ') else {
+                return Some(if is_alias {
+                    "Invalid ALIAS format: use ALIAS <name> <command>".to_string()
+                } else {
+                    "Invalid MACRO format: use MACRO <name> <command>; <command>...".to_string()
+                });
+            };
+            if !is_valid_macro_name(sheet, name) {
+                return Some(format!("Invalid macro name: {}", name));
+            }
+            let steps: Vec<String> = if is_alias {
+                vec![body.trim().to_string()]
+            } else {
+                body.split(';')
+                    .map(str::trim)
+                    .filter(|step| !step.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            };
+            sheet.macros.insert(name.to_string(), steps);
+            return None;
+        }
+
+        if let Some(stripped) = command.strip_prefix("MOVE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            let parsed = match parts.as_slice() {
+                [range, target] => {
+                    parse_cell_or_range(sheet, range).zip(parse_cell_reference(sheet, target).ok())
+                }
+                _ => None,
+            };
+            let Some(((start_row, start_col, end_row, end_col), (dest_row, dest_col))) = parsed
+            else {
+                return Some("Invalid MOVE format: use MOVE <range> <cell>".to_string());
+            };
+            return move_range(
+                sheet, start_row, start_col, end_row, end_col, dest_row, dest_col,
+            )
+            .err();
+        }
+
+        if let Some(stripped) = command.strip_prefix("PASTE ") {
+            save_state(sheet);
+            let cell_ref = &stripped;
+            if let Ok((row, col)) = parse_cell_reference(sheet, cell_ref) {
+                if paste_range(sheet, row, col) {
+                    return Some("Pasted from clipboard".to_string());
+                } else {
+                    return Some("Nothing to paste or invalid target".to_string());
+                }
+            } else {
+                return Some("Invalid PASTE format: use PASTE <cell>".to_string());
+            }
+        }
+
+        if let Some(stripped) = command.strip_prefix("PASTETRANSPOSE ") {
+            let Ok((row, col)) = parse_cell_reference(sheet, stripped.trim()) else {
+                return Some(
+                    "Invalid PASTETRANSPOSE format: use PASTETRANSPOSE <cell>".to_string(),
+                );
+            };
+            return Some(if paste_range_transposed(sheet, row, col) {
+                "Pasted from clipboard, transposed".to_string()
+            } else {
+                "Nothing to paste or the transposed range does not fit".to_string()
+            });
+        }
+
+        if let Some(stripped) = command.strip_prefix("DEPGRAPH ") {
+            let path = stripped.trim();
+            if path.is_empty() {
+                return Some("Invalid DEPGRAPH format: use DEPGRAPH <file>".to_string());
+            }
+            if !is_plain_file_name(path) {
+                return Some(format!(
+                    "Invalid file name {}: use a file name without a directory",
+                    path
+                ));
+            }
+            return Some(match std::fs::write(path, dependency_graph_dot(sheet)) {
+                Ok(()) => format!("Dependency graph written to {}", path),
+                Err(e) => format!("Could not write dependency graph to {}: {}", path, e),
+            });
+        }
+
+        if command == "DEPLIST" {
+            let list = dependency_list(sheet);
+            if list.is_empty() {
+                return Some("No dependencies".to_string());
+            }
+            return Some(format!("Dependencies:\n{}", list.trim_end()));
+        }
+        if let Some(stripped) = command.strip_prefix("DEPLIST ") {
+            let path = stripped.trim();
+            if !is_plain_file_name(path) {
+                return Some(format!(
+                    "Invalid file name {}: use a file name without a directory",
+                    path
+                ));
+            }
+            return Some(match std::fs::write(path, dependency_list(sheet)) {
+                Ok(()) => format!("Dependency list written to {}", path),
+                Err(e) => format!("Could not write dependency list to {}: {}", path, e),
+            });
+        }
+
+        if command.starts_with("GRAPHSVG ") {
+            let parts: Vec<&str> = command.split_whitespace().collect();
+            let [_, graph_type, range, path] = parts.as_slice() else {
+                return Some(
+                    "Usage: GRAPHSVG <type> <range> <file> (e.g., GRAPHSVG (BAR) A1:A10 chart.svg)"
+                        .to_string(),
+                );
+            };
+            let graph_type = match graph_type.to_uppercase().as_str() {
+                "(BAR)" => GraphType::Bar,
+                "(SCATTER)" => GraphType::Scatter,
+                _ => return Some("Invalid graph type. Use (BAR) or (SCATTER)".to_string()),
+            };
+            let Some((start_row, start_col, end_row, end_col)) = parse_range(sheet, range) else {
+                return Some("Invalid range for graph".to_string());
+            };
+            if !is_plain_file_name(path) {
+                return Some(format!(
+                    "Invalid file name {}: use a file name without a directory",
+                    path
+                ));
+            }
+            let svg = display_graph_svg(sheet, graph_type, start_row, start_col, end_row, end_col);
+            return Some(match std::fs::write(path, svg) {
+                Ok(()) => format!("Graph written to {}", path),
+                Err(e) => format!("Could not write graph to {}: {}", path, e),
+            });
+        }
+
+        if command.starts_with("GRAPH ") {
+            let parts: Vec<&str> = command.split_whitespace().collect();
+            if (3..=5).contains(&parts.len()) {
+                let graph_type = match parts[1].to_uppercase().as_str() {
+                    "(BAR)" => GraphType::Bar,
+                    "(SCATTER)" => GraphType::Scatter,
+                    _ => return Some("Invalid graph type. Use (BAR) or (SCATTER)".to_string()),
+                };
+                let Some(first) = parse_range(sheet, parts[2]) else {
+                    return Some("Invalid range for graph".to_string());
+                };
+                // A second range plots two series side by side; the optional file name follows
+                let second = parts.get(3).and_then(|range| parse_range(sheet, range));
+                let path = parts.get(if second.is_some() { 4 } else { 3 });
+                if second.is_none() && parts.len() == 5 {
+                    return Some("Invalid range for graph".to_string());
+                }
+                let (start_row, start_col, end_row, end_col) = first;
+                let graph_output = match second {
+                    Some(second) => display_graph_pair(sheet, graph_type, first, second),
+                    None => {
+                        display_graph(sheet, graph_type, start_row, start_col, end_row, end_col)
+                    }
+                };
+                // An optional file name saves a copy of the graph
+                if let Some(path) = path {
+                    if !is_plain_file_name(path) {
+                        return Some(format!(
+                            "Invalid file name {}: use a file name without a directory",
+                            path
+                        ));
+                    }
+                    return Some(match std::fs::write(path, &graph_output) {
+                        Ok(()) => format!("{}\nGraph written to {}", graph_output, path),
+                        Err(e) => format!("Could not write graph to {}: {}", path, e),
+                    });
+                }
+                return Some(graph_output);
+            } else {
+                return Some(
+                    "Usage: GRAPH <type> <range> [range] [file] (e.g., GRAPH (BAR) A1:A10 chart.txt)"
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    if let Some(stripped) = command.strip_prefix("scroll_to ") {
+        let cell_ref = &stripped;
+        match parse_cell_reference(sheet, cell_ref) {
+            Ok((row, col)) => {
+                let previous = (sheet.view_row, sheet.view_col);
+                scroll_to_cell(sheet, row, col);
+                remember_view(sheet, previous);
+                return None;
+            }
+            Err(error) => return Some(reference_error(cell_ref, error)),
+        }
+    }
+
+    if let Some((cell_ref, formula)) = command.split_once('=') {
+        let cell_ref = cell_ref.trim();
+        let formula = formula.trim();
+        let position = parse_cell_reference(sheet, cell_ref);
+        if let Ok((row, col)) = position {
+            if sheet.cells[row as usize][col as usize].is_locked {
+                return Some(format!("Cell {} is locked", cell_ref));
+            }
+            if let Some((start_row, start_col, _, _)) = sheet.merged_region_at(row, col) {
+                if (row, col) != (start_row, start_col) {
+                    return Some(format!("Cell {} is hidden by a merged region", cell_ref));
+                }
+            }
+            if let (Ok(value), Some((min, max))) =
+                (formula.parse::<i32>(), sheet.validation_at(row, col))
+            {
+                if !(min..=max).contains(&value) {
+                    return Some(format!(
+                        "Value {} for {} is outside the allowed range {} to {}",
+                        value, cell_ref, min, max
+                    ));
+                }
+            }
+            // Functions that rewrite or restyle a range are refused over locked cells, and an
+            // unknown color is refused, before an undo step is saved for them
+            if let Some((func_name, args)) = formula
+                .strip_suffix(')')
+                .and_then(|call| call.split_once('('))
+            {
+                let func_name = func_name.trim().to_uppercase();
+                let target = match func_name.as_str() {
+                    "SORTA" | "SORTD" | "AUTOFILL" => parse_range(sheet, args.trim()),
+                    "BOLD" | "ITALIC" | "UNDERLINE" | "STRIKE" => {
+                        parse_cell_or_range(sheet, args.trim())
+                    }
+                    "COLOR" => args
+                        .split_once(',')
+                        .and_then(|(target, _)| parse_cell_or_range(sheet, target.trim())),
+                    _ => None,
+                };
+                if let Some((start_row, start_col, end_row, end_col)) = target {
+                    if sheet.is_range_locked(start_row, start_col, end_row, end_col) {
+                        return Some("Cannot change locked cells".to_string());
+                    }
+                }
+                if let Some((_, color)) = args.split_once(',').filter(|_| func_name == "COLOR") {
+                    let color = color.trim().to_lowercase();
+                    if ansi_color_code(&color).is_none() {
+                        return Some(format!("Unknown color: {}", color));
+                    }
+                }
+            }
+            if sheet.extension_enabled {
+                save_state(sheet);
+            }
+            if let Some((func_name, args)) = formula.split_once('(') {
+                if let Some(range_arg) = args.strip_suffix(')') {
+                    let range_arg = range_arg.trim();
+                    if func_name.trim().to_uppercase() == "SORTA"
+                        || func_name.trim().to_uppercase() == "SORTD"
+                    {
+                        if let Some((start_row, start_col, end_row, end_col)) =
+                            parse_range(sheet, range_arg)
+                        {
+                            if start_col == end_col {
+                                let mut values: Vec<(i32, i32)> = Vec::new();
+                                for i in start_row..=end_row {
+                                    values.push((
+                                        i,
+                                        sheet.cells[i as usize][start_col as usize].value,
+                                    ));
+                                }
+                                if func_name.trim().to_uppercase() == "SORTA" {
+                                    values.sort_by(|a, b| a.1.cmp(&b.1));
+                                } else {
+                                    values.sort_by(|a, b| b.1.cmp(&a.1));
+                                }
+                                let mut original_values: Vec<CellAttributes> = Vec::new();
+                                for i in start_row..=end_row {
+                                    let cell = &sheet.cells[i as usize][start_col as usize];
+                                    original_values.push((
+                                        cell.value,
+                                        cell.formula.clone(),
                                         cell.is_formula,
                                         cell.is_error,
                                         cell.is_bold,
                                         cell.is_italic,
                                         cell.is_underline,
+                                        cell.is_strike,
+                                        cell.color.clone(),
+                                        cell.error_kind,
                                     ));
                                 }
                                 for (idx, (orig_row, value)) in values.iter().enumerate() {
@@ -580,12 +1924,17 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                     let orig_idx = (orig_row - start_row) as usize;
                                     let cell = &mut sheet.cells[i as usize][start_col as usize];
                                     cell.value = *value;
+                                    cell.is_set = true;
                                     cell.formula = original_values[orig_idx].1.clone();
+                                    cell.stats = None; // They belonged to the formula this cell held before
                                     cell.is_formula = original_values[orig_idx].2;
                                     cell.is_error = original_values[orig_idx].3;
                                     cell.is_bold = original_values[orig_idx].4;
                                     cell.is_italic = original_values[orig_idx].5;
                                     cell.is_underline = original_values[orig_idx].6;
+                                    cell.is_strike = original_values[orig_idx].7;
+                                    cell.color = original_values[orig_idx].8.clone();
+                                    cell.error_kind = original_values[orig_idx].9;
                                 }
                             } else if start_row == end_row {
                                 let mut values: Vec<(i32, i32)> = Vec::new();
@@ -611,6 +1960,9 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                         cell.is_bold,
                                         cell.is_italic,
                                         cell.is_underline,
+                                        cell.is_strike,
+                                        cell.color.clone(),
+                                        cell.error_kind,
                                     ));
                                 }
                                 for (idx, (orig_col, value)) in values.iter().enumerate() {
@@ -618,12 +1970,17 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                     let orig_idx = (orig_col - start_col) as usize;
                                     let cell = &mut sheet.cells[start_row as usize][j as usize];
                                     cell.value = *value;
+                                    cell.is_set = true;
                                     cell.formula = original_values[orig_idx].1.clone();
+                                    cell.stats = None; // They belonged to the formula this cell held before
                                     cell.is_formula = original_values[orig_idx].2;
                                     cell.is_error = original_values[orig_idx].3;
                                     cell.is_bold = original_values[orig_idx].4;
                                     cell.is_italic = original_values[orig_idx].5;
                                     cell.is_underline = original_values[orig_idx].6;
+                                    cell.is_strike = original_values[orig_idx].7;
+                                    cell.color = original_values[orig_idx].8.clone();
+                                    cell.error_kind = original_values[orig_idx].9;
                                 }
                             } else {
                                 let mut all_values: Vec<i32> = Vec::new();
@@ -645,6 +2002,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                         if idx < all_values.len() {
                                             let cell = &mut sheet.cells[i as usize][j as usize];
                                             cell.value = all_values[idx];
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -653,6 +2011,9 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                     }
                                 }
                             }
+                            recalculate_range_dependents(
+                                sheet, start_row, start_col, end_row, end_col,
+                            );
                             return None;
                         }
                     } else if func_name.trim().to_uppercase() == "AUTOFILL" {
@@ -679,6 +2040,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[i as usize][start_col as usize];
                                             cell.value = value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -692,6 +2054,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[i as usize][start_col as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -703,6 +2066,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[i as usize][start_col as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -720,6 +2084,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[i as usize][start_col as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -731,6 +2096,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[i as usize][start_col as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -743,6 +2109,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[i as usize][start_col as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -767,6 +2134,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[start_row as usize][j as usize];
                                             cell.value = value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -780,6 +2148,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[start_row as usize][j as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -791,6 +2160,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[start_row as usize][j as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -808,6 +2178,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[start_row as usize][j as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -819,6 +2190,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[start_row as usize][j as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -831,6 +2203,7 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                             let cell =
                                                 &mut sheet.cells[start_row as usize][j as usize];
                                             cell.value = new_value;
+                                            cell.is_set = true;
                                             cell.formula = None;
                                             cell.is_formula = false;
                                             cell.is_error = false;
@@ -840,486 +2213,2600 @@ pub fn process_command(sheet: &mut Sheet, command: &str) -> Option<String> {
                                     PatternType::Unknown => {}
                                 }
                             }
+                            recalculate_range_dependents(
+                                sheet, start_row, start_col, end_row, end_col,
+                            );
+                            return None;
+                        }
+                    } else if let Some(target_arg) = args.strip_suffix(')') {
+                        let func_name = func_name.trim().to_uppercase();
+                        if func_name == "COLOR" {
+                            if let Some((target_arg, color)) = target_arg.split_once(',') {
+                                let color = color.trim().to_lowercase();
+                                if let Some((start_row, start_col, end_row, end_col)) =
+                                    parse_cell_or_range(sheet, target_arg.trim())
+                                {
+                                    // Applying the current color again clears it
+                                    let mut all_set = true;
+                                    for i in start_row..=end_row {
+                                        for j in start_col..=end_col {
+                                            let cell = &sheet.cells[i as usize][j as usize];
+                                            all_set &= cell.color.as_deref() == Some(&color);
+                                        }
+                                    }
+                                    for i in start_row..=end_row {
+                                        for j in start_col..=end_col {
+                                            sheet.cells[i as usize][j as usize].color =
+                                                (!all_set).then(|| color.clone());
+                                        }
+                                    }
+                                    return None;
+                                }
+                            }
+                            return Some(
+                                "Invalid COLOR format: use COLOR(<cell>, <color>)".to_string(),
+                            );
+                        }
+                        let flag: Option<fn(&mut Cell) -> &mut bool> = match func_name.as_str() {
+                            "BOLD" => Some(|cell| &mut cell.is_bold),
+                            "ITALIC" => Some(|cell| &mut cell.is_italic),
+                            "UNDERLINE" => Some(|cell| &mut cell.is_underline),
+                            "STRIKE" => Some(|cell| &mut cell.is_strike),
+                            _ => None,
+                        };
+                        if let (Some(flag), Some((start_row, start_col, end_row, end_col))) =
+                            (flag, parse_cell_or_range(sheet, target_arg.trim()))
+                        {
+                            // Toggle: clear the flag if every cell already has it, else set it
+                            let mut all_set = true;
+                            for i in start_row..=end_row {
+                                for j in start_col..=end_col {
+                                    all_set &= *flag(&mut sheet.cells[i as usize][j as usize]);
+                                }
+                            }
+                            for i in start_row..=end_row {
+                                for j in start_col..=end_col {
+                                    *flag(&mut sheet.cells[i as usize][j as usize]) = !all_set;
+                                }
+                            }
                             return None;
                         }
-                    } else if let Some(cell_arg) = args.strip_suffix(')') {
-                        save_state(sheet);
-                        let cell_arg = cell_arg.trim();
-                        if let Some((row, col)) = parse_cell_reference(sheet, cell_arg) {
-                            match func_name.trim().to_uppercase().as_str() {
-                                "BOLD" => {
-                                    sheet.cells[row as usize][col as usize].is_bold = true;
-                                    return None;
-                                }
-                                "ITALIC" => {
-                                    sheet.cells[row as usize][col as usize].is_italic = true;
-                                    return None;
-                                }
-                                "UNDERLINE" => {
-                                    sheet.cells[row as usize][col as usize].is_underline = true;
-                                    return None;
-                                }
-                                _ => {}
-                            }
+                    }
+                }
+            }
+            update_cell(sheet, row, col, formula);
+            None
+        } else {
+            position.err().map(|error| reference_error(cell_ref, error))
+        }
+    } else {
+        Some(with_suggestion("Invalid command format", command))
+    }
+}
+
+/// Runs an assignment, reads a cell, and then puts the sheet back as it was, for `WHATIF`.
+///
+/// # Description
+/// The cells, dependencies, undo history, random number state, caches and related state are
+/// copied before the assignment and restored after the query, so neither leaves a trace. The query's message is reported
+/// with the assignment it assumed; if the assignment itself fails, its error is reported.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `assignment` - The cell assignment to try (e.g., "A1=50").
+/// * `query` - The `GET` command to run afterwards (e.g., "GET B5").
+///
+/// # Returns
+/// A `String` with the hypothetical result.
+fn what_if(sheet: &mut Sheet, assignment: &str, query: &str) -> String {
+    let state = SheetState {
+        cells: sheet.cells.clone(),
+        dependency_graph: sheet.dependency_graph.clone(),
+        merged_regions: sheet.merged_regions.clone(),
+        validation_rules: sheet.validation_rules.clone(),
+    };
+    let undo_stack = sheet.undo_stack.clone();
+    let redo_stack = sheet.redo_stack.clone();
+    let view_history = sheet.view_history.clone();
+    let cell_history = sheet.cell_history.clone();
+    let circular_dependency_detected = sheet.circular_dependency_detected;
+    // Evaluation also draws random numbers and fills caches, which must not move on either
+    let rng_state = sheet.rng_state;
+    let random_cache = sheet.random_cache.clone();
+    let sleep_cache = sheet.sleep_cache.clone();
+    let deferred_cells = sheet.deferred_cells.clone();
+    let recalc_remaining = sheet.recalc_remaining;
+
+    let message = match execute_command(sheet, assignment) {
+        Some(error) => error,
+        None => {
+            let result = execute_command(sheet, query).unwrap_or_default();
+            format!("What if {}: {}", assignment, result)
+        }
+    };
+
+    sheet.cells = state.cells;
+    sheet.dependency_graph = state.dependency_graph;
+    sheet.merged_regions = state.merged_regions;
+    sheet.validation_rules = state.validation_rules;
+    sheet.undo_stack = undo_stack;
+    sheet.redo_stack = redo_stack;
+    sheet.view_history = view_history;
+    sheet.cell_history = cell_history;
+    sheet.circular_dependency_detected = circular_dependency_detected;
+    sheet.rng_state = rng_state;
+    sheet.random_cache = random_cache;
+    sheet.sleep_cache = sleep_cache;
+    sheet.deferred_cells = deferred_cells;
+    sheet.recalc_remaining = recalc_remaining;
+    message
+}
+
+/// Adds the closest known command, if there is one, to the error for a command that was not
+/// understood.
+fn with_suggestion(error: &str, command: &str) -> String {
+    match suggest_command(command) {
+        Some(suggestion) => format!("{} (did you mean {}?)", error, suggestion),
+        None => error.to_string(),
+    }
+}
+
+/// Builds the message reported for a cell reference that could not be parsed.
+fn reference_error(cell_ref: &str, error: ParseError) -> String {
+    match error {
+        ParseError::BadSyntax => format!("Invalid cell reference: {}", cell_ref),
+        ParseError::OutOfBounds => format!("Cell reference out of bounds: {}", cell_ref),
+    }
+}
+
+/// Formats the given rows and columns of the spreadsheet as a text grid.
+///
+/// The grid starts with a line of column headers, followed by one line per row with the row
+/// number and the cell values, right-aligned to the column widths. Merged regions are drawn
+/// once, across their displayed span. With `styled`, ANSI escape codes are added for bold,
+/// italic, underline, strikethrough and colored cells; without it the grid is plain text.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+/// * `rows` - The rows to show (0-based), in increasing order.
+/// * `cols` - The columns to show (0-based), in increasing order.
+/// * `styled` - Whether to include ANSI formatting codes.
+///
+/// # Returns
+/// A `String` with the grid, each line ending in a newline.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// sheet.cells[0][1].value = 42;
+/// assert_eq!(format_grid(&sheet, &[0], &[0, 1], false), "     A  B  \n   1 0 42 \n");
+/// ```
+pub fn format_grid(sheet: &Sheet, rows: &[i32], cols: &[i32], styled: bool) -> String {
+    let max_widths = column_widths(sheet, rows, cols);
+    let mut output = String::new();
+
+    output.push_str("     ");
+    for (pos, &j) in cols.iter().enumerate() {
+        output.push_str(&pad_to_width(
+            &sheet.column_label(j),
+            max_widths[pos],
+            false,
+        ));
+        output.push(' ');
+    }
+    output.push('\n');
+
+    for &i in rows {
+        output.push_str(&format!("{:4} ", i + 1));
+        for (pos, &j) in cols.iter().enumerate() {
+            // A merged region is drawn once, at its first visible cell, across its visible span
+            let (cell, width) =
+                if let Some((start_row, start_col, region_end_row, region_end_col)) =
+                    sheet.merged_region_at(i, j)
+                {
+                    let (Some((anchor_row, _)), Some((first_pos, last_pos))) = (
+                        visible_span(rows, start_row, region_end_row),
+                        visible_span(cols, start_col, region_end_col),
+                    ) else {
+                        continue;
+                    };
+                    if pos != first_pos {
+                        continue;
+                    }
+                    let width = span_width(&max_widths, first_pos, last_pos);
+                    if i != rows[anchor_row] {
+                        output.push_str(&format!("{:width$} ", "", width = width));
+                        continue;
+                    }
+                    (&sheet.cells[start_row as usize][start_col as usize], width)
+                } else {
+                    (&sheet.cells[i as usize][j as usize], max_widths[pos])
+                };
+            let value_str = sheet.display_value(cell);
+            if !styled {
+                output.push_str(&pad_to_width(&value_str, width, true));
+                output.push(' ');
+                continue;
+            }
+
+            // The padding is worked out from the visible text and kept outside the escape codes,
+            // which take up no room on the terminal
+            let padding = width.saturating_sub(display_width(&value_str));
+            let mut formatted = " ".repeat(padding);
+            if cell.is_bold {
+                formatted.push_str("\x1b[1m");
+            }
+            if cell.is_italic {
+                formatted.push_str("\x1b[3m");
+            }
+            if cell.is_underline {
+                formatted.push_str("\x1b[4m");
+            }
+            if cell.is_strike {
+                formatted.push_str("\x1b[9m");
+            }
+            let color_code = cell.color.as_deref().and_then(ansi_color_code);
+            if let Some(code) = color_code {
+                formatted.push_str(&format!("\x1b[{}m", code));
+            }
+            formatted.push_str(&value_str);
+            if cell.is_bold
+                || cell.is_italic
+                || cell.is_underline
+                || cell.is_strike
+                || color_code.is_some()
+            {
+                formatted.push_str("\x1b[0m");
+            }
+
+            output.push_str(&formatted);
+            output.push(' ');
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Computes the display width of each shown column.
+///
+/// Each column is as wide as its header and the widest unmerged value in the shown rows. The
+/// value of a merged region is laid out across all of its shown columns, so it only widens the
+/// last of them, and only if it does not already fit in the combined span.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+/// * `rows` - The rows being shown (0-based), in increasing order.
+/// * `cols` - The columns being shown (0-based), in increasing order.
+///
+/// # Returns
+/// A `Vec<usize>` with one width per entry of `cols`, in display order.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// sheet.cells[0][0].value = 12345;
+/// let widths = column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols());
+/// assert_eq!(widths[0], 5);
+/// assert_eq!(widths[1], 1);
+/// ```
+pub fn column_widths(sheet: &Sheet, rows: &[i32], cols: &[i32]) -> Vec<usize> {
+    let mut max_widths: Vec<usize> = cols
+        .iter()
+        .map(|&j| display_width(&sheet.column_label(j)))
+        .collect();
+
+    for &i in rows {
+        for (pos, &j) in cols.iter().enumerate() {
+            if sheet.merged_region_at(i, j).is_some() {
+                continue;
+            }
+            let cell = &sheet.cells[i as usize][j as usize];
+            let width = display_width(&sheet.display_value(cell));
+            max_widths[pos] = max_widths[pos].max(width);
+        }
+    }
+
+    for &(start_row, start_col, region_end_row, region_end_col) in &sheet.merged_regions {
+        let (Some(_), Some((first_pos, last_pos))) = (
+            visible_span(rows, start_row, region_end_row),
+            visible_span(cols, start_col, region_end_col),
+        ) else {
+            continue;
+        };
+        let needed = display_width(
+            &sheet.display_value(&sheet.cells[start_row as usize][start_col as usize]),
+        );
+        let available = span_width(&max_widths, first_pos, last_pos);
+        if needed > available {
+            max_widths[last_pos] += needed - available;
+        }
+    }
+
+    max_widths
+}
+
+/// Returns the number of terminal columns a displayed value takes up.
+///
+/// Column names from a CSV header and the ERRORTEXT placeholder can hold any text, so neither the
+/// byte length nor the character count is the width on screen. Combining marks and zero-width
+/// characters take no room, East Asian wide characters take two columns, and everything else one.
+///
+/// # Arguments
+/// * `text` - The text to measure.
+///
+/// # Returns
+/// The number of terminal columns the text covers.
+fn display_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// Returns the number of terminal columns a single character takes up, as used by `display_width`.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F
+        | 0x1AB0..=0x1AFF
+        | 0x1DC0..=0x1DFF
+        | 0x200B..=0x200F
+        | 0x20D0..=0x20FF
+        | 0xFE00..=0xFE0F
+        | 0xFE20..=0xFE2F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Pads `text` with spaces to `width` terminal columns, on the left or the right.
+///
+/// `format!` pads by counting characters, which misaligns wide and combining characters, so the
+/// grid pads by `display_width` instead.
+fn pad_to_width(text: &str, width: usize, align_right: bool) -> String {
+    let padding = " ".repeat(width.saturating_sub(display_width(text)));
+    if align_right {
+        format!("{}{}", padding, text)
+    } else {
+        format!("{}{}", text, padding)
+    }
+}
+
+/// Returns the total width of the displayed columns at positions `first..=last`, including the
+/// separating spaces.
+fn span_width(max_widths: &[usize], first: usize, last: usize) -> usize {
+    max_widths[first..=last].iter().sum::<usize>() + (last - first)
+}
+
+/// Finds which displayed rows or columns fall inside a region.
+///
+/// The visible rows and columns are in increasing order, so the ones inside `start..=end` are
+/// always next to each other on screen, even when the region crosses a frozen pane.
+///
+/// # Arguments
+/// * `visible` - The displayed rows or columns, as returned by `Sheet::visible_rows` or
+///   `Sheet::visible_cols`.
+/// * `start` - The first row or column of the region (0-based).
+/// * `end` - The last row or column of the region (0-based).
+///
+/// # Returns
+/// An `Option<(usize, usize)>` with the first and last display positions inside the region, or
+/// `None` if none of the region is displayed.
+///
+/// # Example
+/// ```
+/// assert_eq!(visible_span(&[0, 5, 6, 7], 0, 6), Some((0, 2)));
+/// assert_eq!(visible_span(&[0, 5, 6, 7], 1, 4), None);
+/// ```
+pub fn visible_span(visible: &[i32], start: i32, end: i32) -> Option<(usize, usize)> {
+    let inside = |&index: &i32| index >= start && index <= end;
+    Some((
+        visible.iter().position(inside)?,
+        visible.iter().rposition(inside)?,
+    ))
+}
+
+/// Generates an ASCII-based graph for a range of cells.
+///
+/// # Description
+/// Creates a bar or scatter plot for the values in the specified cell range, outputting the result as a string.
+/// The graph uses ASCII characters to represent data points or bars, with labels derived from cell references.
+/// Suitable for terminal-based visualization of data.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `graph_type` - The type of graph (`GraphType::Bar` or `GraphType::Scatter`).
+/// * `start_row` - The starting row of the range (0-based).
+/// * `start_col` - The starting column of the range (0-based).
+/// * `end_row` - The ending row of the range (0-based).
+/// * `end_col` - The ending column of the range (0-based).
+///
+/// # Returns
+/// A `String` containing the ASCII representation of the graph.
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, display_graph, GraphType};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][0].value = 5;
+/// sheet.cells[1][0].value = 3;
+/// let output = display_graph(&mut sheet, GraphType::Bar, 0, 0, 1, 0);
+/// println!("{}", output);
+/// // Outputs an ASCII bar graph for A1:A2 with values 5 and 3
+/// ```
+pub fn display_graph(
+    sheet: &mut Sheet,
+    graph_type: GraphType,
+    start_row: i32,
+    start_col: i32,
+    end_row: i32,
+    end_col: i32,
+) -> String {
+    let mut values = Vec::new();
+    let mut labels = Vec::new();
+
+    for i in start_row..=end_row {
+        for j in start_col..=end_col {
+            let cell = &sheet.cells[i as usize][j as usize];
+            values.push(cell.value);
+            let mut label = String::new();
+            encode_column(j, &mut label);
+            label.push_str(&(i + 1).to_string());
+            labels.push(label);
+        }
+    }
+
+    let max_val = *values.iter().filter(|&&v| v > 0).max().unwrap_or(&10);
+    let max_label_width = labels.iter().map(|l| l.len()).max().unwrap_or(2);
+    let column_width = max_label_width.max(3) + 1;
+
+    let mut output = String::new();
+
+    match graph_type {
+        GraphType::Bar => {
+            output.push_str("\nBar Graph for range:\n".to_string().as_str());
+            for value in (1..=max_val).rev() {
+                output.push_str(&format!("{:2} |", value));
+                for &cell_value in &values {
+                    if cell_value >= value {
+                        output.push_str(&format!("{:^width$}", "█", width = column_width));
+                    } else {
+                        output.push_str(&format!("{:^width$}", " ", width = column_width));
+                    }
+                }
+                output.push('\n');
+            }
+            output.push_str("---+");
+            for _ in &values {
+                output.push_str(&"-".repeat(column_width));
+            }
+            output.push('\n');
+            output.push_str("   |");
+            for label in &labels {
+                output.push_str(&format!("{:^width$}", label, width = column_width));
+            }
+            output.push('\n');
+        }
+        GraphType::Scatter => {
+            output.push_str("\nScatter Plot for range:\n".to_string().as_str());
+            for value in (1..=max_val).rev() {
+                output.push_str(&format!("{:2} |", value));
+                for &cell_value in &values {
+                    let center = column_width / 2;
+                    if cell_value == value {
+                        output.push_str(&format!("{:width$}", " ", width = center));
+                        output.push('*');
+                        output.push_str(&format!(
+                            "{:width$}",
+                            " ",
+                            width = column_width - center - 1
+                        ));
+                    } else {
+                        output.push_str(&format!("{:^width$}", " ", width = column_width));
+                    }
+                }
+                output.push('\n');
+            }
+            output.push_str("---+");
+            for _ in &values {
+                output.push_str(&"-".repeat(column_width));
+            }
+            output.push('\n');
+            output.push_str("   |");
+            for label in &labels {
+                output.push_str(&format!("{:^width$}", label, width = column_width));
+            }
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Generates an ASCII-based graph comparing two ranges of cells.
+///
+/// # Description
+/// Works like `display_graph`, but plots two series side by side: the n-th value of each range
+/// forms the n-th category, and the two values of a category are drawn next to each other with
+/// different markers. A legend names the range behind each marker. Categories are labelled with
+/// the cell references of the first range; if one range is longer, the other simply has no
+/// value drawn for the extra categories, which are labelled from the longer range.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+/// * `graph_type` - The type of graph (`GraphType::Bar` or `GraphType::Scatter`).
+/// * `first` - The first range as `(start_row, start_col, end_row, end_col)` (0-based).
+/// * `second` - The second range, in the same form.
+///
+/// # Returns
+/// A `String` containing the ASCII representation of the graph.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][0].value = 2;
+/// sheet.cells[0][1].value = 1;
+/// let output = display_graph_pair(&sheet, GraphType::Bar, (0, 0, 0, 0), (0, 1, 0, 1));
+/// assert!(output.contains(" 1 | █▒ "));
+/// assert!(output.contains("Legend: █ A1:A1  ▒ B1:B1"));
+/// ```
+pub fn display_graph_pair(
+    sheet: &Sheet,
+    graph_type: GraphType,
+    first: (i32, i32, i32, i32),
+    second: (i32, i32, i32, i32),
+) -> String {
+    let cell_name = |row: i32, col: i32| {
+        let mut name = String::new();
+        encode_column(col, &mut name);
+        format!("{}{}", name, row + 1)
+    };
+    let series = |(start_row, start_col, end_row, end_col): (i32, i32, i32, i32)| {
+        let mut cells = Vec::new();
+        for i in start_row..=end_row {
+            for j in start_col..=end_col {
+                cells.push((sheet.cells[i as usize][j as usize].value, cell_name(i, j)));
+            }
+        }
+        cells
+    };
+    let range_name = |(start_row, start_col, end_row, end_col): (i32, i32, i32, i32)| {
+        format!(
+            "{}:{}",
+            cell_name(start_row, start_col),
+            cell_name(end_row, end_col)
+        )
+    };
+
+    let first_cells = series(first);
+    let second_cells = series(second);
+    let categories = first_cells.len().max(second_cells.len());
+    let labels: Vec<&str> = (0..categories)
+        .filter_map(|k| first_cells.get(k).or(second_cells.get(k)))
+        .map(|(_, label)| label.as_str())
+        .collect();
+
+    let max_val = first_cells
+        .iter()
+        .chain(&second_cells)
+        .map(|&(value, _)| value)
+        .filter(|&v| v > 0)
+        .max()
+        .unwrap_or(10);
+    let max_label_width = labels.iter().map(|l| l.len()).max().unwrap_or(2);
+    let column_width = max_label_width.max(3) + 1;
+    let (title, first_marker, second_marker) = match graph_type {
+        GraphType::Bar => ("Bar Graph", '█', '▒'),
+        GraphType::Scatter => ("Scatter Plot", '*', 'o'),
+    };
+    // Bars fill every level up to the value, points only mark the value itself
+    let drawn = |cell: Option<&(i32, String)>, level: i32| match (graph_type, cell) {
+        (GraphType::Bar, Some(&(value, _))) => value >= level,
+        (GraphType::Scatter, Some(&(value, _))) => value == level,
+        (_, None) => false,
+    };
+
+    let mut output = format!("\n{} for ranges:\n", title);
+    for level in (1..=max_val).rev() {
+        output.push_str(&format!("{:2} |", level));
+        for k in 0..categories {
+            let pair: String = [
+                (first_cells.get(k), first_marker),
+                (second_cells.get(k), second_marker),
+            ]
+            .into_iter()
+            .map(|(cell, marker)| if drawn(cell, level) { marker } else { ' ' })
+            .collect();
+            output.push_str(&format!("{:^width$}", pair, width = column_width));
+        }
+        output.push('\n');
+    }
+    output.push_str("---+");
+    output.push_str(&"-".repeat(column_width * categories));
+    output.push('\n');
+    output.push_str("   |");
+    for label in &labels {
+        output.push_str(&format!("{:^width$}", label, width = column_width));
+    }
+    output.push('\n');
+    output.push_str(&format!(
+        "Legend: {} {}  {} {}\n",
+        first_marker,
+        range_name(first),
+        second_marker,
+        range_name(second)
+    ));
+    output
+}
+
+/// Generates an SVG chart for a range of cells.
+///
+/// # Description
+/// Draws the values of the range as bars (or, for a scatter plot, as points) in a fixed-height
+/// plot area, labelled with their cell references. Heights are proportional to the values. The
+/// horizontal axis is drawn at zero, so negative values extend below it; a range holding only
+/// zeros is drawn flat on the axis, and an empty range gives a chart saying "No data".
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+/// * `graph_type` - The type of graph (`GraphType::Bar` or `GraphType::Scatter`).
+/// * `start_row` - The starting row of the range (0-based).
+/// * `start_col` - The starting column of the range (0-based).
+/// * `end_row` - The ending row of the range (0-based).
+/// * `end_col` - The ending column of the range (0-based).
+///
+/// # Returns
+/// A `String` containing the SVG markup.
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, display_graph_svg, GraphType};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][0].value = 4;
+/// sheet.cells[1][0].value = 2;
+/// let svg = display_graph_svg(&sheet, GraphType::Bar, 0, 0, 1, 0);
+/// assert!(svg.contains(r#"height="200""#) && svg.contains(r#"height="100""#));
+/// ```
+pub fn display_graph_svg(
+    sheet: &Sheet,
+    graph_type: GraphType,
+    start_row: i32,
+    start_col: i32,
+    end_row: i32,
+    end_col: i32,
+) -> String {
+    const PLOT_HEIGHT: f64 = 200.0;
+    const BAR_WIDTH: f64 = 30.0;
+    const SPACING: f64 = 40.0;
+    const MARGIN: f64 = 20.0;
+
+    let mut points = Vec::new();
+    for i in start_row..=end_row {
+        for j in start_col..=end_col {
+            let mut label = String::new();
+            encode_column(j, &mut label);
+            label.push_str(&(i + 1).to_string());
+            points.push((label, sheet.cells[i as usize][j as usize].value));
+        }
+    }
+
+    let width = MARGIN * 2.0 + SPACING * points.len().max(1) as f64;
+    let height = MARGIN * 3.0 + PLOT_HEIGHT;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n",
+        w = width,
+        h = height
+    );
+    if points.is_empty() {
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">No data</text>\n</svg>\n",
+            width / 2.0,
+            height / 2.0
+        ));
+        return svg;
+    }
+
+    // The plot spans from the largest value (or zero) down to the smallest value (or zero)
+    let top = points
+        .iter()
+        .map(|&(_, value)| value)
+        .max()
+        .unwrap_or(0)
+        .max(0) as f64;
+    let bottom = points
+        .iter()
+        .map(|&(_, value)| value)
+        .min()
+        .unwrap_or(0)
+        .min(0) as f64;
+    let scale = PLOT_HEIGHT / (top - bottom).max(1.0);
+    let zero_y = MARGIN + top * scale;
+    let round = |x: f64| (x * 100.0).round() / 100.0;
+
+    svg.push_str(&format!(
+        "<line x1=\"{}\" y1=\"{y}\" x2=\"{}\" y2=\"{y}\" stroke=\"black\"/>\n",
+        MARGIN,
+        width - MARGIN,
+        y = round(zero_y)
+    ));
+    for (index, (label, value)) in points.iter().enumerate() {
+        let center = MARGIN + SPACING * (index as f64 + 0.5);
+        let value_y = zero_y - *value as f64 * scale;
+        match graph_type {
+            GraphType::Bar => svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"steelblue\"><title>{}: {}</title></rect>\n",
+                round(center - BAR_WIDTH / 2.0),
+                round(value_y.min(zero_y)),
+                BAR_WIDTH,
+                round((value_y - zero_y).abs()),
+                label,
+                value
+            )),
+            GraphType::Scatter => svg.push_str(&format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"4\" fill=\"steelblue\"><title>{}: {}</title></circle>\n",
+                round(center),
+                round(value_y),
+                label,
+                value
+            )),
+        }
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" font-size=\"12\" text-anchor=\"middle\">{}</text>\n",
+            round(center),
+            round(MARGIN * 2.0 + PLOT_HEIGHT),
+            label
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Exports the dependency graph in Graphviz DOT format.
+///
+/// # Description
+/// Every dependency recorded in `dependency_graph` becomes an edge from the cell (or range) that
+/// is read to the cell whose formula reads it, so arrows point the way changes flow. Nodes are
+/// labelled with cell references such as `A1`; ranges are drawn as boxes labelled like `A1:A3`.
+/// Edges are listed in row-major order of the dependent cell, so the output is stable.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+///
+/// # Returns
+/// A `String` with the DOT source, e.g. for `dot -Tpng deps.dot -o deps.png`.
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, dependency_graph_dot, process_command};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// process_command(&mut sheet, "B1=A1+1");
+/// assert!(dependency_graph_dot(&sheet).contains("\"A1\" -> \"B1\";"));
+/// ```
+pub fn dependency_graph_dot(sheet: &Sheet) -> String {
+    let cell_name = |row: i32, col: i32| {
+        let mut name = String::new();
+        encode_column(col, &mut name);
+        format!("{}{}", name, row + 1)
+    };
+
+    let mut cells: Vec<&(i32, i32)> = sheet.dependency_graph.keys().collect();
+    cells.sort();
+    let mut dot = String::from("digraph dependencies {\n");
+    for &(row, col) in cells {
+        let dependent = cell_name(row, col);
+        for dependency in &sheet.dependency_graph[&(row, col)].dependencies {
+            match *dependency {
+                DependencyType::Single { row, col } => {
+                    dot.push_str(&format!(
+                        "    \"{}\" -> \"{}\";\n",
+                        cell_name(row, col),
+                        dependent
+                    ));
+                }
+                DependencyType::Range {
+                    start_row,
+                    start_col,
+                    end_row,
+                    end_col,
+                } => {
+                    let range = format!(
+                        "{}:{}",
+                        cell_name(start_row, start_col),
+                        cell_name(end_row, end_col)
+                    );
+                    dot.push_str(&format!("    \"{}\" [shape=box];\n", range));
+                    dot.push_str(&format!("    \"{}\" -> \"{}\";\n", range, dependent));
+                }
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Lists the dependency graph as an adjacency list, one cell per line.
+///
+/// # Description
+/// Every cell in `dependency_graph` gets a line such as `B1: reads A1; read by C1, D1`, with the
+/// cells and ranges its formula reads and the cells whose formulas read it, or `none`. A cell
+/// counts as read by a formula through a range that covers it, even though the graph only
+/// records such readers on the range. Lines are in row-major order, so the output is stable.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+///
+/// # Returns
+/// A `String` with one line per cell, each ending in a newline.
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, dependency_list, process_command};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// process_command(&mut sheet, "B1=A1+1");
+/// assert_eq!(dependency_list(&sheet), "A1: reads none; read by B1\nB1: reads A1; read by none\n");
+/// ```
+pub fn dependency_list(sheet: &Sheet) -> String {
+    let cell_name = |row: i32, col: i32| {
+        let mut name = String::new();
+        encode_column(col, &mut name);
+        format!("{}{}", name, row + 1)
+    };
+    let names = |names: Vec<String>| {
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        }
+    };
+
+    let mut cells: Vec<&(i32, i32)> = sheet.dependency_graph.keys().collect();
+    cells.sort();
+    let mut list = String::new();
+    for &&(row, col) in &cells {
+        let reads = sheet.dependency_graph[&(row, col)]
+            .dependencies
+            .iter()
+            .map(|dependency| match *dependency {
+                DependencyType::Single { row, col } => cell_name(row, col),
+                DependencyType::Range {
+                    start_row,
+                    start_col,
+                    end_row,
+                    end_col,
+                } => format!(
+                    "{}:{}",
+                    cell_name(start_row, start_col),
+                    cell_name(end_row, end_col)
+                ),
+            })
+            .collect();
+        let read_by = cells
+            .iter()
+            .filter(|&&reader| {
+                sheet.dependency_graph[reader]
+                    .dependencies
+                    .iter()
+                    .any(|dependency| match *dependency {
+                        DependencyType::Single { row: r, col: c } => (r, c) == (row, col),
+                        DependencyType::Range {
+                            start_row,
+                            start_col,
+                            end_row,
+                            end_col,
+                        } => {
+                            (start_row..=end_row).contains(&row)
+                                && (start_col..=end_col).contains(&col)
+                        }
+                    })
+            })
+            .map(|&&(r, c)| cell_name(r, c))
+            .collect();
+        list.push_str(&format!(
+            "{}: reads {}; read by {}\n",
+            cell_name(row, col),
+            names(reads),
+            names(read_by)
+        ));
+    }
+    list
+}
+
+impl Sheet {
+    /// Finds the merged region that contains a cell.
+    ///
+    /// # Description
+    /// Regions are created with the MERGE command. The top-left cell of a region holds its value;
+    /// the other cells are hidden in the display and cannot be assigned to.
+    ///
+    /// # Arguments
+    /// * `row` - The row of the cell (0-based).
+    /// * `col` - The column of the cell (0-based).
+    ///
+    /// # Returns
+    /// An `Option<(i32, i32, i32, i32)>` with the region's `(start_row, start_col, end_row, end_col)`,
+    /// or `None` if the cell is not merged.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// sheet.merged_regions.push((0, 0, 0, 2));
+    /// assert_eq!(sheet.merged_region_at(0, 1), Some((0, 0, 0, 2)));
+    /// assert_eq!(sheet.merged_region_at(1, 1), None);
+    /// ```
+    pub fn merged_region_at(&self, row: i32, col: i32) -> Option<(i32, i32, i32, i32)> {
+        self.merged_regions
+            .iter()
+            .copied()
+            .find(|&(start_row, start_col, end_row, end_col)| {
+                row >= start_row && row <= end_row && col >= start_col && col <= end_col
+            })
+    }
+
+    /// Finds the range of values a cell may hold.
+    ///
+    /// # Description
+    /// Rules are added with the VALIDATE command. When several rules cover the cell, the most
+    /// recently added one applies.
+    ///
+    /// # Arguments
+    /// * `row` - The row of the cell (0-based).
+    /// * `col` - The column of the cell (0-based).
+    ///
+    /// # Returns
+    /// An `Option<(i32, i32)>` with the allowed `(min, max)`, or `None` if no rule covers the cell.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 5, true).unwrap();
+    /// sheet.validation_rules.push((0, 0, 4, 0, 0, 100));
+    /// assert_eq!(sheet.validation_at(2, 0), Some((0, 100)));
+    /// assert_eq!(sheet.validation_at(2, 1), None);
+    /// ```
+    pub fn validation_at(&self, row: i32, col: i32) -> Option<(i32, i32)> {
+        self.validation_rules
+            .iter()
+            .rev()
+            .find(|&&(start_row, start_col, end_row, end_col, _, _)| {
+                row >= start_row && row <= end_row && col >= start_col && col <= end_col
+            })
+            .map(|&(_, _, _, _, min, max)| (min, max))
+    }
+
+    /// The number of rows and columns shown at once.
+    ///
+    /// # Description
+    /// This is `view_size` when it is set (e.g., from `spreadsheet.toml`), or 10 otherwise. The
+    /// w/a/s/d commands scroll by the same amount.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// assert_eq!(sheet.display_size(), 10);
+    /// sheet.view_size = Some(4);
+    /// assert_eq!(sheet.display_size(), 4);
+    /// ```
+    pub fn display_size(&self) -> i32 {
+        self.view_size.unwrap_or(DISPLAY_SIZE)
+    }
+
+    /// Lists the rows shown by the display, in order.
+    ///
+    /// # Description
+    /// Rows frozen with the FREEZE command come first and stay visible wherever the view is
+    /// scrolled; they are followed by up to `display_size()` rows starting at `view_row`.
+    ///
+    /// # Returns
+    /// A `Vec<i32>` of row indices (0-based).
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(30, 5, true).unwrap();
+    /// sheet.frozen_rows = 1;
+    /// sheet.view_row = 10;
+    /// assert_eq!(sheet.visible_rows()[..3], [0, 10, 11]);
+    /// ```
+    pub fn visible_rows(&self) -> Vec<i32> {
+        let frozen = self.frozen_rows.min(self.rows);
+        (0..frozen)
+            .chain(self.view_row.max(frozen)..(self.view_row + self.display_size()).min(self.rows))
+            .collect()
+    }
+
+    /// Lists the columns shown by the display, in order.
+    ///
+    /// # Description
+    /// Works like `visible_rows`: frozen columns come first, followed by up to `display_size()`
+    /// columns starting at `view_col`.
+    ///
+    /// # Returns
+    /// A `Vec<i32>` of column indices (0-based).
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 30, true).unwrap();
+    /// sheet.frozen_cols = 1;
+    /// sheet.view_col = 10;
+    /// assert_eq!(sheet.visible_cols()[..3], [0, 10, 11]);
+    /// ```
+    pub fn visible_cols(&self) -> Vec<i32> {
+        let frozen = self.frozen_cols.min(self.cols);
+        (0..frozen)
+            .chain(self.view_col.max(frozen)..(self.view_col + self.display_size()).min(self.cols))
+            .collect()
+    }
+
+    /// Formats a cell's value the way the display shows it.
+    ///
+    /// # Description
+    /// Works like `format_cell_value`, except that with `SCINOTATION on` numbers of a million
+    /// or more (in either direction) are shown in scientific form with one decimal, which keeps
+    /// columns narrow. With `SHOWFORMULAS on`, a cell whose value is computed by a formula shows
+    /// the formula text instead; cells holding a plain number still show the number. With
+    /// `SPARSEVIEW on`, cells that were never given a value are shown blank, while a cell set to 0
+    /// still shows "0". After `ERRSTR "<text>"`, error cells show that text instead of their
+    /// error code. The stored value is not changed.
+    ///
+    /// # Arguments
+    /// * `cell` - The cell to format.
+    ///
+    /// # Returns
+    /// A `String` with the display text of the cell.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 5, true).unwrap();
+    /// sheet.cells[0][0].value = 1234567;
+    /// sheet.scientific_notation = true;
+    /// assert_eq!(sheet.display_value(&sheet.cells[0][0]), "1.2e6");
+    /// ```
+    pub fn display_value(&self, cell: &Cell) -> String {
+        if self.sparse_view && !cell.is_set {
+            return String::new();
+        }
+        if let Some(text) = &self.error_text {
+            if cell.is_error && !cell.has_circular {
+                return text.clone();
+            }
+        }
+        if self.show_formulas && cell.is_computed() {
+            if let Some(formula) = &cell.formula {
+                return formula.clone();
+            }
+        }
+        if self.scientific_notation
+            && (!cell.is_error || cell.has_circular)
+            && cell.kind == DataKind::Number
+            && cell.value.unsigned_abs() >= SCIENTIFIC_THRESHOLD
+        {
+            format!("{:.1e}", cell.value as f64)
+        } else {
+            format_cell_value(cell)
+        }
+    }
+
+    /// Builds the header label shown above a column.
+    ///
+    /// # Description
+    /// Columns are labelled with their letters. When a name was read from a CSV header row, it is
+    /// shown next to the letters so cell references stay easy to find.
+    ///
+    /// # Arguments
+    /// * `col` - The column (0-based).
+    ///
+    /// # Returns
+    /// A `String` such as `"A"` or `"A (Name)"`.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// sheet.column_headers[1] = Some("Price".to_string());
+    /// assert_eq!(sheet.column_label(0), "A");
+    /// assert_eq!(sheet.column_label(1), "B (Price)");
+    /// ```
+    pub fn column_label(&self, col: i32) -> String {
+        let mut label = String::new();
+        encode_column(col, &mut label);
+        if let Some(Some(name)) = self.column_headers.get(col as usize) {
+            label.push_str(&format!(" ({})", name));
+        }
+        label
+    }
+
+    /// Checks whether any cell in a range is locked.
+    ///
+    /// # Description
+    /// Locked cells are marked read-only with the LOCK command. Operations that overwrite cells,
+    /// such as cut and paste, use this to refuse touching a range that contains a locked cell.
+    ///
+    /// # Arguments
+    /// * `start_row` - The starting row of the range (0-based).
+    /// * `start_col` - The starting column of the range (0-based).
+    /// * `end_row` - The ending row of the range (0-based).
+    /// * `end_col` - The ending column of the range (0-based).
+    ///
+    /// # Returns
+    /// A `bool` that is `true` if at least one cell in the range is locked.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::create_sheet;
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// sheet.cells[1][1].is_locked = true;
+    /// assert!(sheet.is_range_locked(0, 0, 2, 2));
+    /// assert!(!sheet.is_range_locked(2, 2, 3, 3));
+    /// ```
+    pub fn is_range_locked(
+        &self,
+        start_row: i32,
+        start_col: i32,
+        end_row: i32,
+        end_col: i32,
+    ) -> bool {
+        (start_row..=end_row)
+            .any(|i| (start_col..=end_col).any(|j| self.cells[i as usize][j as usize].is_locked))
+    }
+
+    /// Retrieves a range of cells from the spreadsheet.
+    ///
+    /// # Description
+    /// Returns a 2D vector of `Cell` clones for the specified range, defined by start and end row/column indices.
+    /// Used for operations like copying or analyzing a range of cells.
+    ///
+    /// # Arguments
+    /// * `start_row` - The starting row of the range (0-based).
+    /// * `start_col` - The starting column of the range (0-based).
+    /// * `end_row` - The ending row of the range (0-based).
+    /// * `end_col` - The ending column of the range (0-based).
+    ///
+    /// # Returns
+    /// A `Vec<Vec<Cell>>` containing the cells in the specified range.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::{create_sheet, Sheet};
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// sheet.cells[0][0].value = 5;
+    /// let range = sheet.get_cell_range(0, 0, 1, 1);
+    /// assert_eq!(range[0][0].value, 5);
+    /// assert_eq!(range[1][1].value, 0);
+    /// ```
+    pub fn get_cell_range(
+        &self,
+        start_row: i32,
+        start_col: i32,
+        end_row: i32,
+        end_col: i32,
+    ) -> Vec<Vec<Cell>> {
+        let mut range = Vec::new();
+        for i in start_row..=end_row {
+            let mut row = Vec::new();
+            for j in start_col..=end_col {
+                row.push(self.cells[i as usize][j as usize].clone());
+            }
+            range.push(row);
+        }
+        range
+    }
+
+    /// Moves each cell's HISTORY entries to where the cell itself was moved.
+    ///
+    /// # Description
+    /// The history is kept by cell position, so commands that shift cells around (inserting a
+    /// row, deleting or moving a range, swapping ranges) call this to keep each cell's past
+    /// values with the cell. Entries for cells that were deleted or written over are dropped.
+    ///
+    /// # Arguments
+    /// * `moved` - Gives the new position of the cell at a row and column (0-based), or `None`
+    ///   if the cell is gone.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::{create_sheet, process_command};
+    /// let mut sheet = create_sheet(5, 5, true).unwrap();
+    /// process_command(&mut sheet, "A1=5");
+    /// sheet.move_cell_history(|row, col| Some((row + 1, col)));
+    /// assert!(sheet.cell_history.contains_key(&(1, 0)));
+    /// ```
+    pub fn move_cell_history(&mut self, moved: impl Fn(i32, i32) -> Option<(i32, i32)>) {
+        self.cell_history = self
+            .cell_history
+            .drain()
+            .filter_map(|((row, col), history)| Some((moved(row, col)?, history)))
+            .collect();
+    }
+
+    /// Copies what is needed to show the sheet and move around in it.
+    ///
+    /// # Description
+    /// The copy has the cells, the view, the merged regions and the display settings, but no
+    /// dependencies, undo history, checkpoints or other worksheets. The web command worker leaves
+    /// it in the global sheet while a command runs, so pages can still be shown, scrolled and
+    /// selected in meanwhile.
+    ///
+    /// # Returns
+    /// A `Sheet` that shows the same as this one.
+    ///
+    /// # Example
+    /// ```
+    /// let mut sheet = create_sheet(5, 5, true).unwrap();
+    /// process_command(&mut sheet, "A1=5");
+    /// let copy = sheet.view_copy();
+    /// assert_eq!(copy.cells[0][0].value, 5);
+    /// assert!(copy.undo_stack.is_empty());
+    /// ```
+    pub fn view_copy(&self) -> Sheet {
+        Sheet {
+            cells: self.cells.clone(),
+            rows: self.rows,
+            cols: self.cols,
+            view_row: self.view_row,
+            view_col: self.view_col,
+            output_enabled: self.output_enabled,
+            circular_dependency_detected: self.circular_dependency_detected,
+            extension_enabled: self.extension_enabled,
+            max_history_size: self.max_history_size,
+            merged_regions: self.merged_regions.clone(),
+            column_headers: self.column_headers.clone(),
+            frozen_rows: self.frozen_rows,
+            frozen_cols: self.frozen_cols,
+            scientific_notation: self.scientific_notation,
+            show_formulas: self.show_formulas,
+            last_elapsed: self.last_elapsed,
+            view_size: self.view_size,
+            sparse_view: self.sparse_view,
+            error_text: self.error_text.clone(),
+            selected: self.selected,
+            ..Sheet::default()
+        }
+    }
+
+    /// Takes a read-only snapshot of the values in a range.
+    ///
+    /// # Description
+    /// Only values and error kinds are copied, not formulas, formatting or dependencies, so a
+    /// snapshot is cheap to take. A reader can take one while briefly holding the lock on the
+    /// global sheet, and then read it without blocking the web server or the terminal.
+    ///
+    /// # Arguments
+    /// * `start_row` - The starting row of the range (0-based).
+    /// * `start_col` - The starting column of the range (0-based).
+    /// * `end_row` - The ending row of the range (0-based).
+    /// * `end_col` - The ending column of the range (0-based).
+    ///
+    /// # Returns
+    /// An `Option<SheetSnapshot>` with the values, or `None` if the range is not inside the sheet.
+    ///
+    /// # Example
+    /// ```
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// sheet.cells[1][1].value = 5;
+    /// let snapshot = sheet.snapshot(0, 0, 1, 1).unwrap();
+    /// assert_eq!(snapshot.values[1][1], Ok(5));
+    /// assert!(sheet.snapshot(0, 0, 5, 5).is_none());
+    /// ```
+    pub fn snapshot(
+        &self,
+        start_row: i32,
+        start_col: i32,
+        end_row: i32,
+        end_col: i32,
+    ) -> Option<SheetSnapshot> {
+        if start_row < 0
+            || start_col < 0
+            || end_row >= self.rows
+            || end_col >= self.cols
+            || start_row > end_row
+            || start_col > end_col
+        {
+            return None;
+        }
+        let values = self.cells[start_row as usize..=end_row as usize]
+            .iter()
+            .map(|line| {
+                line[start_col as usize..=end_col as usize]
+                    .iter()
+                    .map(|cell| {
+                        if cell.is_error {
+                            Err(cell.error_kind.unwrap_or(ErrorKind::Invalid))
+                        } else {
+                            Ok(cell.value)
                         }
+                    })
+                    .collect()
+            })
+            .collect();
+        Some(SheetSnapshot { values })
+    }
+
+    /// Sets a range of cells in the spreadsheet.
+    ///
+    /// # Description
+    /// Updates the spreadsheet with the provided 2D vector of cells, starting at the specified row and column.
+    /// If a cell contains a formula, it is re-evaluated using `update_cell`. Ensures updates stay within spreadsheet bounds.
+    ///
+    /// # Arguments
+    /// * `start_row` - The starting row for pasting the cells (0-based).
+    /// * `start_col` - The starting column for pasting the cells (0-based).
+    /// * `values` - A 2D vector of `Cell` values to set.
+    ///
+    /// # Example
+    /// ```
+    /// use spreadsheet::{create_sheet, Sheet, Cell};
+    /// let mut sheet = create_sheet(5, 5, false).unwrap();
+    /// let mut cell = Cell::new();
+    /// cell.value = 10;
+    /// let values = vec![vec![cell; 2]; 2];
+    /// sheet.set_cell_range(0, 0, &values);
+    /// assert_eq!(sheet.cells[0][0].value, 10);
+    /// assert_eq!(sheet.cells[1][1].value, 10);
+    /// ```
+    pub fn set_cell_range(&mut self, start_row: i32, start_col: i32, values: &[Vec<Cell>]) {
+        for (i, row) in values.iter().enumerate() {
+            for (j, cell) in row.iter().enumerate() {
+                let target_row = start_row + i as i32;
+                let target_col = start_col + j as i32;
+                if target_row < self.rows && target_col < self.cols {
+                    let previous = self.cells[target_row as usize][target_col as usize].clone();
+                    let target = &mut self.cells[target_row as usize][target_col as usize];
+                    *target = Cell {
+                        is_locked: false,
+                        ..cell.clone()
+                    };
+                    if let Some(formula) = &cell.formula {
+                        // update_cell measures the change from the value the cell holds, so the
+                        // value being replaced is put back until the formula is evaluated
+                        target.value = previous.value;
+                        target.is_error = previous.is_error;
+                        target.error_kind = previous.error_kind;
+                        target.kind = previous.kind;
+                        update_cell(self, target_row, target_col, formula);
+                    } else {
+                        recalculate_after_change(self, target_row, target_col, &previous);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Orders formulas loaded together so that each comes after the loaded formulas it reads.
+///
+/// Evaluating a formula once its inputs hold their final values lets a chain such as `C1=B1`,
+/// `B1=A1+1` resolve in a single pass, instead of recalculating the chain each time one of its
+/// cells is loaded. Formulas that read each other in a cycle are all kept, in no particular
+/// order; `update_cell` reports the cycle when they are evaluated.
+fn order_formulas(sheet: &mut Sheet, formulas: Vec<(i32, i32, String)>) -> Vec<(i32, i32, String)> {
+    let positions: HashMap<(i32, i32), usize> = formulas
+        .iter()
+        .enumerate()
+        .map(|(idx, (row, col, _))| ((*row, *col), idx))
+        .collect();
+    // For each formula, the loaded formulas it reads
+    let mut inputs = vec![Vec::new(); formulas.len()];
+    for (idx, (_, _, formula)) in formulas.iter().enumerate() {
+        for dependency in formula_dependencies(sheet, formula) {
+            match dependency {
+                DependencyType::Single { row, col } => {
+                    inputs[idx].extend(positions.get(&(row, col)).copied());
+                }
+                DependencyType::Range {
+                    start_row,
+                    start_col,
+                    end_row,
+                    end_col,
+                } => {
+                    inputs[idx].extend(positions.iter().filter_map(|(&(row, col), &input)| {
+                        ((start_row..=end_row).contains(&row)
+                            && (start_col..=end_col).contains(&col))
+                        .then_some(input)
+                    }));
+                }
+            }
+        }
+    }
+
+    // Depth-first, placing a formula once all of its inputs are placed. The stack holds each
+    // formula being visited with the index of its next input to look at.
+    let mut visited = vec![false; formulas.len()];
+    let mut order = Vec::with_capacity(formulas.len());
+    for start in 0..formulas.len() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![(start, 0)];
+        while let Some((idx, next)) = stack.pop() {
+            match inputs[idx].get(next) {
+                Some(&input) => {
+                    stack.push((idx, next + 1));
+                    if !visited[input] {
+                        visited[input] = true;
+                        stack.push((input, 0));
                     }
                 }
+                None => order.push(idx),
+            }
+        }
+    }
+    order.into_iter().map(|idx| formulas[idx].clone()).collect()
+}
+
+/// Formats a range of computed values as tab-separated text.
+///
+/// # Description
+/// Each row of the range becomes one line, with its values separated by tabs, so the text can be
+/// pasted into other spreadsheet programs. Values are written as `format_cell_value` shows them,
+/// so dates and text keep their form and error cells show their error code.
+///
+/// # Arguments
+/// * `sheet` - A reference to the spreadsheet.
+/// * `start_row` - The starting row of the range (0-based).
+/// * `start_col` - The starting column of the range (0-based).
+/// * `end_row` - The ending row of the range (0-based).
+/// * `end_col` - The ending column of the range (0-based).
+///
+/// # Returns
+/// A `String` with one line per row, without a trailing newline.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][0].value = 1;
+/// sheet.cells[1][1].value = 4;
+/// assert_eq!(export_range_tsv(&sheet, 0, 0, 1, 1), "1\t0\n0\t4");
+/// ```
+pub fn export_range_tsv(
+    sheet: &Sheet,
+    start_row: i32,
+    start_col: i32,
+    end_row: i32,
+    end_col: i32,
+) -> String {
+    (start_row..=end_row)
+        .map(|row| {
+            (start_col..=end_col)
+                .map(|col| format_cell_value(&sheet.cells[row as usize][col as usize]))
+                .collect::<Vec<_>>()
+                .join("\t")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Writes rows of delimited fields into the spreadsheet, starting at a given cell.
+///
+/// # Description
+/// This is the cell-writing part of loading a CSV file, shared with the `IMPORTRANGE` command.
+/// Integer fields are stored as values, and fields starting with '=' are stored as formulas once
+/// every value is in place, so a formula may refer to a value further down. Formulas are
+/// evaluated after the formulas they read, wherever those appear in `rows`. Fields beyond the
+/// last column, non-numeric fields and invalid formulas are skipped with a warning, leaving their
+/// cells as they were. Warnings
+/// count rows from the first row of `rows`, and name cells by their place in the sheet.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `rows` - The fields of each row, as split by `split_csv_line`.
+/// * `start_row` - The row the first line is written to (0-based).
+/// * `start_col` - The column the first field of each line is written to (0-based).
+///
+/// # Returns
+/// A `Result<Vec<String>, String>` containing the warnings for skipped cells, or an error
+/// message if there are more rows than fit below `start_row`.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// let rows = vec![vec!["1".to_string(), "=A1+1".to_string()]];
+/// assert!(write_delimited_rows(&mut sheet, &rows, 0, 0).unwrap().is_empty());
+/// assert_eq!(sheet.cells[0][1].value, 2);
+/// ```
+pub fn write_delimited_rows(
+    sheet: &mut Sheet,
+    rows: &[Vec<String>],
+    start_row: i32,
+    start_col: i32,
+) -> Result<Vec<String>, String> {
+    let cell_name = |row: i32, col: i32| {
+        let mut name = String::new();
+        encode_column(col, &mut name);
+        format!("{}{}", name, row + 1)
+    };
+    let max_rows = sheet.rows - start_row;
+    let max_cols = sheet.cols - start_col;
+
+    let mut warnings = Vec::new();
+    let mut formulas = Vec::new();
+    for (line_idx, values) in rows.iter().enumerate() {
+        if line_idx as i32 >= max_rows {
+            return Err(format!(
+                "CSV file has more rows than the spreadsheet (max: {})",
+                max_rows
+            ));
+        }
+        let row_idx = start_row + line_idx as i32;
+
+        if values.len() > max_cols as usize {
+            warnings.push(format!(
+                "Row {}: skipped {} value(s) beyond the last column (max: {})",
+                line_idx + 1,
+                values.len() - max_cols as usize,
+                max_cols
+            ));
+        }
+
+        for (offset, value) in values.iter().take(max_cols as usize).enumerate() {
+            let col_idx = start_col + offset as i32;
+            let value = value.trim();
+            if let Ok(num_value) = value.parse::<i32>() {
+                // A cell that held a formula is assigned, so its old dependencies are dropped
+                if sheet.cells[row_idx as usize][col_idx as usize]
+                    .formula
+                    .is_some()
+                {
+                    update_cell(sheet, row_idx, col_idx, value);
+                } else {
+                    sheet.cells[row_idx as usize][col_idx as usize].value = num_value;
+                    sheet.cells[row_idx as usize][col_idx as usize].is_set = true;
+                }
+            } else if let Some(stripped) = value.strip_prefix('=') {
+                let formula = stripped.to_string();
+                formulas.push((row_idx, col_idx, formula));
+            } else if !value.is_empty() {
+                warnings.push(format!(
+                    "Cell {}: skipped non-numeric value \"{}\"",
+                    cell_name(row_idx, col_idx),
+                    value
+                ));
+            }
+        }
+    }
+
+    for (row, col, formula) in order_formulas(sheet, formulas) {
+        if !is_valid_formula(sheet, &formula) {
+            warnings.push(format!(
+                "Cell {}: skipped invalid formula \"={}\"",
+                cell_name(row, col),
+                formula
+            ));
+            continue;
+        }
+        update_cell(sheet, row, col, &formula);
+    }
+    Ok(warnings)
+}
+
+/// Copies a range of cells to the clipboard.
+///
+/// # Description
+/// Copies the specified range of cells to the global clipboard, preserving cell data (values, formulas, formatting).
+/// Returns `false` if the range is invalid (e.g., out of bounds or start indices exceed end indices).
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `start_row` - The starting row of the range (0-based).
+/// * `start_col` - The starting column of the range (0-based).
+/// * `end_row` - The ending row of the range (0-based).
+/// * `end_col` - The ending column of the range (0-based).
+///
+/// # Returns
+/// A `bool` indicating success (`true`) or failure (`false`).
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, copy_range};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][0].value = 5;
+/// assert!(copy_range(&mut sheet, 0, 0, 1, 1));
+/// assert!(!copy_range(&mut sheet, 0, 0, 6, 6)); // Out of bounds
+/// ```
+pub fn copy_range(
+    sheet: &mut Sheet,
+    start_row: i32,
+    start_col: i32,
+    end_row: i32,
+    end_col: i32,
+) -> bool {
+    if start_row >= 0
+        && start_col >= 0
+        && end_row < sheet.rows
+        && end_col < sheet.cols
+        && start_row <= end_row
+        && start_col <= end_col
+    {
+        let contents = sheet.get_cell_range(start_row, start_col, end_row, end_col);
+        *CLIPBOARD.lock().unwrap() = Some(Clipboard {
+            contents,
+            is_cut: false,
+            // source_range: None,
+        });
+        true
+    } else {
+        false
+    }
+}
+
+/// Cuts a range of cells to the clipboard, clearing the source.
+///
+/// # Description
+/// Copies the specified range of cells to the global clipboard and clears the source cells in the spreadsheet.
+/// Returns `false` if the range is invalid (e.g., out of bounds or start indices exceed end indices).
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `start_row` - The starting row of the range (0-based).
+/// * `start_col` - The starting column of the range (0-based).
+/// * `end_row` - The ending row of the range (0-based).
+/// * `end_col` - The ending column of the range (0-based).
+///
+/// # Returns
+/// A `bool` indicating success (`true`) or failure (`false`).
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, cut_range};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][0].value = 5;
+/// assert!(cut_range(&mut sheet, 0, 0, 1, 1));
+/// assert_eq!(sheet.cells[0][0].value, 0); // Source cleared
+/// assert!(!cut_range(&mut sheet, 0, 0, 6, 6)); // Out of bounds
+/// ```
+pub fn cut_range(
+    sheet: &mut Sheet,
+    start_row: i32,
+    start_col: i32,
+    end_row: i32,
+    end_col: i32,
+) -> bool {
+    if start_row >= 0
+        && start_col >= 0
+        && end_row < sheet.rows
+        && end_col < sheet.cols
+        && start_row <= end_row
+        && start_col <= end_col
+        && !sheet.is_range_locked(start_row, start_col, end_row, end_col)
+    {
+        let contents = sheet.get_cell_range(start_row, start_col, end_row, end_col);
+        for i in start_row..=end_row {
+            for j in start_col..=end_col {
+                let cell = &mut sheet.cells[i as usize][j as usize];
+                *cell = Cell::new();
             }
-            update_cell(sheet, row, col, formula);
-            None
-        } else {
-            Some("Invalid cell reference".to_string())
         }
+        recalculate_range_dependents(sheet, start_row, start_col, end_row, end_col);
+        *CLIPBOARD.lock().unwrap() = Some(Clipboard {
+            contents,
+            is_cut: true,
+            //source_range: Some((start_row, start_col, end_row, end_col)),
+        });
+        true
     } else {
-        Some("Invalid command format".to_string())
+        false
     }
 }
 
-/// Displays the current view of the spreadsheet in the terminal.
+/// Pastes the clipboard contents to the specified location.
 ///
-/// This function outputs a portion of the spreadsheet (based on the current view) to the terminal,
-/// showing column headers, row numbers, and cell values. It respects the output_enabled flag and
-/// highlights errors or circular dependencies.
+/// # Description
+/// Pastes the contents of the global clipboard to the spreadsheet starting at the specified cell.
+/// If the operation is a cut, the clipboard is cleared after pasting.
+/// Returns `false` if the clipboard is empty or the target location is invalid.
 ///
 /// # Arguments
-/// * `sheet` - A reference to the spreadsheet.
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `start_row` - The starting row for pasting (0-based).
+/// * `start_col` - The starting column for pasting (0-based).
+///
+/// # Returns
+/// A `bool` indicating success (`true`) or failure (`false`).
 ///
 /// # Example
 /// ```
-/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// use spreadsheet::{create_sheet, copy_range, paste_range};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
 /// sheet.cells[0][0].value = 5;
-/// display_sheet(&sheet);
-/// // Outputs a 10x10 grid with "5" in A1 and zeros elsewhere
+/// copy_range(&mut sheet, 0, 0, 0, 0);
+/// assert!(paste_range(&mut sheet, 1, 1));
+/// assert_eq!(sheet.cells[1][1].value, 5);
+/// assert!(!paste_range(&mut sheet, 6, 6)); // Invalid target
 /// ```
-pub fn display_sheet(sheet: &Sheet) {
-    if !sheet.output_enabled {
-        return;
-    }
-
-    let mut max_widths = vec![0; DISPLAY_SIZE as usize];
-    for j in sheet.view_col..(sheet.view_col + DISPLAY_SIZE).min(sheet.cols) {
-        let mut col_header = String::new();
-        encode_column(j, &mut col_header);
-        max_widths[(j - sheet.view_col) as usize] = col_header.len();
-    }
-
-    for i in sheet.view_row..(sheet.view_row + DISPLAY_SIZE).min(sheet.rows) {
-        for j in sheet.view_col..(sheet.view_col + DISPLAY_SIZE).min(sheet.cols) {
-            let cell = &sheet.cells[i as usize][j as usize];
-            let width = if cell.is_error && !cell.has_circular {
-                3
-            } else {
-                let val = cell.value;
-                if val == 0 {
-                    1
-                } else {
-                    val.to_string().len()
-                }
-            };
-            max_widths[(j - sheet.view_col) as usize] =
-                max_widths[(j - sheet.view_col) as usize].max(width);
+pub fn paste_range(sheet: &mut Sheet, start_row: i32, start_col: i32) -> bool {
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    if let Some(clipboard_data) = &*clipboard {
+        let end_row = (start_row + clipboard_data.contents.len() as i32 - 1).min(sheet.rows - 1);
+        let end_col =
+            (start_col + clipboard_data.contents.first().map_or(0, |row| row.len()) as i32 - 1)
+                .min(sheet.cols - 1);
+        if start_row >= 0
+            && start_col >= 0
+            && start_row < sheet.rows
+            && start_col < sheet.cols
+            && !sheet.is_range_locked(start_row, start_col, end_row, end_col)
+        {
+            sheet.set_cell_range(start_row, start_col, &clipboard_data.contents);
+            if clipboard_data.is_cut {
+                *clipboard = None;
+            }
+            true
+        } else {
+            false
         }
+    } else {
+        false
     }
+}
 
-    print!("     ");
-    for j in sheet.view_col..(sheet.view_col + DISPLAY_SIZE).min(sheet.cols) {
-        let mut col_header = String::new();
-        encode_column(j, &mut col_header);
-        print!(
-            "{:width$} ",
-            col_header,
-            width = max_widths[(j - sheet.view_col) as usize]
-        );
-    }
-    println!();
-
-    for i in sheet.view_row..(sheet.view_row + DISPLAY_SIZE).min(sheet.rows) {
-        print!("{:4} ", i + 1);
-        for j in sheet.view_col..(sheet.view_col + DISPLAY_SIZE).min(sheet.cols) {
-            let cell = &sheet.cells[i as usize][j as usize];
-            let width = max_widths[(j - sheet.view_col) as usize];
-            let value_str = if cell.is_error && !cell.has_circular {
-                "err".to_string()
-            } else {
-                cell.value.to_string()
-            };
-
-            let mut formatted = String::new();
-            if cell.is_bold {
-                formatted.push_str("\x1b[1m");
-            }
-            if cell.is_italic {
-                formatted.push_str("\x1b[3m");
-            }
-            if cell.is_underline {
-                formatted.push_str("\x1b[4m");
-            }
-            formatted.push_str(&value_str);
-            if cell.is_bold || cell.is_italic || cell.is_underline {
-                formatted.push_str("\x1b[0m");
+/// Pastes the clipboard contents with rows and columns swapped.
+///
+/// # Description
+/// Works like `paste_range`, except that the first row of the clipboard is written down the
+/// first column from the target cell, the second row down the next column, and so on. Unlike
+/// `paste_range`, which drops whatever falls past the edge of the sheet, the whole transposed
+/// block must fit below and to the right of the target. Formulas are pasted unchanged. An undo
+/// step is saved only once the clipboard is known to fit, so a refused paste leaves none.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `start_row` - The starting row for pasting (0-based).
+/// * `start_col` - The starting column for pasting (0-based).
+///
+/// # Returns
+/// A `bool` indicating success (`true`) or failure (`false`).
+///
+/// # Example
+/// ```
+/// use spreadsheet::{create_sheet, copy_range, paste_range_transposed};
+/// let mut sheet = create_sheet(5, 5, false).unwrap();
+/// sheet.cells[0][1].value = 5;
+/// copy_range(&mut sheet, 0, 0, 0, 1);
+/// assert!(paste_range_transposed(&mut sheet, 2, 0));
+/// assert_eq!(sheet.cells[3][0].value, 5);
+/// assert!(!paste_range_transposed(&mut sheet, 4, 0)); // Two rows do not fit
+/// ```
+pub fn paste_range_transposed(sheet: &mut Sheet, start_row: i32, start_col: i32) -> bool {
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    if let Some(clipboard_data) = &*clipboard {
+        let width = clipboard_data.contents.first().map_or(0, |row| row.len());
+        let transposed: Vec<Vec<Cell>> = (0..width)
+            .map(|j| {
+                clipboard_data
+                    .contents
+                    .iter()
+                    .map(|row| row[j].clone())
+                    .collect()
+            })
+            .collect();
+        let end_row = start_row + width as i32 - 1;
+        let end_col = start_col + clipboard_data.contents.len() as i32 - 1;
+        if width > 0
+            && start_row >= 0
+            && start_col >= 0
+            && end_row < sheet.rows
+            && end_col < sheet.cols
+            && !sheet.is_range_locked(start_row, start_col, end_row, end_col)
+        {
+            save_state(sheet);
+            sheet.set_cell_range(start_row, start_col, &transposed);
+            if clipboard_data.is_cut {
+                *clipboard = None;
             }
-
-            print!("{:>width$} ", formatted, width = width);
+            true
+        } else {
+            false
         }
-        println!();
+    } else {
+        false
     }
-    io::stdout().flush().unwrap();
 }
 
-/// Generates an ASCII-based graph for a range of cells.
+/// Moves a range of cells so that its top-left corner lands on another cell.
 ///
 /// # Description
-/// Creates a bar or scatter plot for the values in the specified cell range, outputting the result as a string.
-/// The graph uses ASCII characters to represent data points or bars, with labels derived from cell references.
-/// Suitable for terminal-based visualization of data.
+/// Works like a cut followed by a paste, but as one step with a single undo snapshot and without
+/// touching the clipboard. The source cells are cleared and the moved cells keep their values,
+/// formulas and formatting. References that point into the moved block are shifted along with
+/// it, both inside the moved formulas and in formulas elsewhere on the sheet, so every formula
+/// keeps reading the same cells; references to cells outside the block are left alone, as is a
+/// range only partly inside it (see `moved_refs`). VALIDATE rules that lie inside the block
+/// move with it. Cells that depended on the source or the destination are recalculated.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
-/// * `graph_type` - The type of graph (`GraphType::Bar` or `GraphType::Scatter`).
-/// * `start_row` - The starting row of the range (0-based).
-/// * `start_col` - The starting column of the range (0-based).
-/// * `end_row` - The ending row of the range (0-based).
-/// * `end_col` - The ending column of the range (0-based).
+/// * `start_row`, `start_col` - The top-left cell of the range to move (0-based).
+/// * `end_row`, `end_col` - The bottom-right cell of the range to move (0-based).
+/// * `dest_row`, `dest_col` - The new top-left cell of the range (0-based).
 ///
 /// # Returns
-/// A `String` containing the ASCII representation of the graph.
+/// A `Result<(), String>` with an error message if the range cannot be moved there.
 ///
 /// # Example
 /// ```
-/// use spreadsheet::{create_sheet, display_graph, GraphType};
-/// let mut sheet = create_sheet(5, 5, false).unwrap();
-/// sheet.cells[0][0].value = 5;
-/// sheet.cells[1][0].value = 3;
-/// let output = display_graph(&mut sheet, GraphType::Bar, 0, 0, 1, 0);
-/// println!("{}", output);
-/// // Outputs an ASCII bar graph for A1:A2 with values 5 and 3
+/// use spreadsheet::{create_sheet, move_range, process_command};
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// process_command(&mut sheet, "A1=2");
+/// process_command(&mut sheet, "A2=A1*3");
+/// move_range(&mut sheet, 0, 0, 1, 0, 0, 2).unwrap();
+/// assert_eq!(sheet.cells[1][2].formula, Some("C1*3".to_string()));
+/// assert_eq!(sheet.cells[0][0].value, 0);
 /// ```
-pub fn display_graph(
+pub fn move_range(
     sheet: &mut Sheet,
-    graph_type: GraphType,
     start_row: i32,
     start_col: i32,
     end_row: i32,
     end_col: i32,
-) -> String {
-    let mut values = Vec::new();
-    let mut labels = Vec::new();
+    dest_row: i32,
+    dest_col: i32,
+) -> Result<(), String> {
+    let row_offset = dest_row - start_row;
+    let col_offset = dest_col - start_col;
+    if dest_row < 0
+        || dest_col < 0
+        || end_row + row_offset >= sheet.rows
+        || end_col + col_offset >= sheet.cols
+    {
+        return Err("Destination does not fit in the sheet".to_string());
+    }
+    if sheet.is_range_locked(start_row, start_col, end_row, end_col)
+        || sheet.is_range_locked(
+            dest_row,
+            dest_col,
+            end_row + row_offset,
+            end_col + col_offset,
+        )
+    {
+        return Err("Cannot move a locked range".to_string());
+    }
 
-    for i in start_row..=end_row {
-        for j in start_col..=end_col {
-            let cell = &sheet.cells[i as usize][j as usize];
-            values.push(cell.value);
-            let mut label = String::new();
-            encode_column(j, &mut label);
-            label.push_str(&(i + 1).to_string());
-            labels.push(label);
+    save_state(sheet);
+    let block = (start_row, start_col, end_row, end_col);
+    let in_destination = |row: i32, col: i32| {
+        (dest_row..=end_row + row_offset).contains(&row)
+            && (dest_col..=end_col + col_offset).contains(&col)
+    };
+    let mut contents = sheet.get_cell_range(start_row, start_col, end_row, end_col);
+    for cell in contents.iter_mut().flatten() {
+        let Some(expr) = cell.formula.as_deref().and_then(parse) else {
+            continue;
+        };
+        let moved = moved_refs(sheet, &expr, block, row_offset, col_offset);
+        if moved != expr {
+            cell.formula = Some(moved.to_string());
         }
     }
 
-    let max_val = *values.iter().filter(|&&v| v > 0).max().unwrap_or(&10);
-    let max_label_width = labels.iter().map(|l| l.len()).max().unwrap_or(2);
-    let column_width = max_label_width.max(3) + 1;
-
-    let mut output = String::new();
-
-    match graph_type {
-        GraphType::Bar => {
-            output.push_str("\nBar Graph for range:\n".to_string().as_str());
-            for value in (1..=max_val).rev() {
-                output.push_str(&format!("{:2} |", value));
-                for &cell_value in &values {
-                    if cell_value >= value {
-                        output.push_str(&format!("{:^width$}", "█", width = column_width));
-                    } else {
-                        output.push_str(&format!("{:^width$}", " ", width = column_width));
-                    }
-                }
-                output.push('\n');
-            }
-            output.push_str("---+");
-            for _ in &values {
-                output.push_str(&"-".repeat(column_width));
-            }
-            output.push('\n');
-            output.push_str("   |");
-            for label in &labels {
-                output.push_str(&format!("{:^width$}", label, width = column_width));
-            }
-            output.push('\n');
-        }
-        GraphType::Scatter => {
-            output.push_str("\nScatter Plot for range:\n".to_string().as_str());
-            for value in (1..=max_val).rev() {
-                output.push_str(&format!("{:2} |", value));
-                for &cell_value in &values {
-                    let center = column_width / 2;
-                    if cell_value == value {
-                        output.push_str(&format!("{:width$}", " ", width = center));
-                        output.push('*');
-                        output.push_str(&format!(
-                            "{:width$}",
-                            " ",
-                            width = column_width - center - 1
-                        ));
-                    } else {
-                        output.push_str(&format!("{:^width$}", " ", width = column_width));
-                    }
-                }
-                output.push('\n');
-            }
-            output.push_str("---+");
-            for _ in &values {
-                output.push_str(&"-".repeat(column_width));
+    // Formulas elsewhere that read the moved cells follow them to their new place
+    for row in 0..sheet.rows {
+        for col in 0..sheet.cols {
+            let in_source =
+                (start_row..=end_row).contains(&row) && (start_col..=end_col).contains(&col);
+            if in_source || in_destination(row, col) {
+                continue;
             }
-            output.push('\n');
-            output.push_str("   |");
-            for label in &labels {
-                output.push_str(&format!("{:^width$}", label, width = column_width));
+            let Some(expr) = sheet.cells[row as usize][col as usize]
+                .formula
+                .as_deref()
+                .and_then(parse)
+            else {
+                continue;
+            };
+            let moved = moved_refs(sheet, &expr, block, row_offset, col_offset);
+            if moved != expr {
+                let formula = moved.to_string();
+                let dependencies = formula_dependencies_at(sheet, &formula, row, col);
+                set_dependencies(sheet, row, col, dependencies);
+                sheet.cells[row as usize][col as usize].formula = Some(formula);
             }
-            output.push('\n');
         }
     }
-    output
-}
 
-impl Sheet {
-    /// Retrieves a range of cells from the spreadsheet.
-    ///
-    /// # Description
-    /// Returns a 2D vector of `Cell` clones for the specified range, defined by start and end row/column indices.
-    /// Used for operations like copying or analyzing a range of cells.
-    ///
-    /// # Arguments
-    /// * `start_row` - The starting row of the range (0-based).
-    /// * `start_col` - The starting column of the range (0-based).
-    /// * `end_row` - The ending row of the range (0-based).
-    /// * `end_col` - The ending column of the range (0-based).
-    ///
-    /// # Returns
-    /// A `Vec<Vec<Cell>>` containing the cells in the specified range.
-    ///
-    /// # Example
-    /// ```
-    /// use spreadsheet::{create_sheet, Sheet};
-    /// let mut sheet = create_sheet(5, 5, false).unwrap();
-    /// sheet.cells[0][0].value = 5;
-    /// let range = sheet.get_cell_range(0, 0, 1, 1);
-    /// assert_eq!(range[0][0].value, 5);
-    /// assert_eq!(range[1][1].value, 0);
-    /// ```
-    pub fn get_cell_range(
-        &self,
-        start_row: i32,
-        start_col: i32,
-        end_row: i32,
-        end_col: i32,
-    ) -> Vec<Vec<Cell>> {
-        let mut range = Vec::new();
-        for i in start_row..=end_row {
-            let mut row = Vec::new();
-            for j in start_col..=end_col {
-                row.push(self.cells[i as usize][j as usize].clone());
+    for row in start_row..=end_row {
+        for col in start_col..=end_col {
+            clear_cell_dependencies(sheet, row, col);
+            sheet.cells[row as usize][col as usize] = Cell::new();
+        }
+    }
+    for row in dest_row..=end_row + row_offset {
+        for col in dest_col..=end_col + col_offset {
+            clear_cell_dependencies(sheet, row, col);
+        }
+    }
+    // VALIDATE rules inside the block move with it, so the moved values are checked as before
+    for rule in &mut sheet.validation_rules {
+        if rule.0 >= start_row && rule.1 >= start_col && rule.2 <= end_row && rule.3 <= end_col {
+            rule.0 += row_offset;
+            rule.1 += col_offset;
+            rule.2 += row_offset;
+            rule.3 += col_offset;
+        }
+    }
+    sheet.set_cell_range(dest_row, dest_col, &contents);
+    sheet.move_cell_history(|row, col| {
+        if (start_row..=end_row).contains(&row) && (start_col..=end_col).contains(&col) {
+            Some((row + row_offset, col + col_offset))
+        } else if in_destination(row, col) {
+            None
+        } else {
+            Some((row, col))
+        }
+    });
+
+    for row in start_row..=end_row {
+        for col in start_col..=end_col {
+            if !in_destination(row, col) {
+                recalculate_dependents(sheet, row, col);
             }
-            range.push(row);
         }
-        range
     }
+    for row in dest_row..=end_row + row_offset {
+        for col in dest_col..=end_col + col_offset {
+            recalculate_dependents(sheet, row, col);
+        }
+    }
+    Ok(())
+}
 
-    /// Sets a range of cells in the spreadsheet.
-    ///
-    /// # Description
-    /// Updates the spreadsheet with the provided 2D vector of cells, starting at the specified row and column.
-    /// If a cell contains a formula, it is re-evaluated using `update_cell`. Ensures updates stay within spreadsheet bounds.
-    ///
-    /// # Arguments
-    /// * `start_row` - The starting row for pasting the cells (0-based).
-    /// * `start_col` - The starting column for pasting the cells (0-based).
-    /// * `values` - A 2D vector of `Cell` values to set.
-    ///
-    /// # Example
-    /// ```
-    /// use spreadsheet::{create_sheet, Sheet, Cell};
-    /// let mut sheet = create_sheet(5, 5, false).unwrap();
-    /// let mut cell = Cell::new();
-    /// cell.value = 10;
-    /// let values = vec![vec![cell; 2]; 2];
-    /// sheet.set_cell_range(0, 0, &values);
-    /// assert_eq!(sheet.cells[0][0].value, 10);
-    /// assert_eq!(sheet.cells[1][1].value, 10);
-    /// ```
-    pub fn set_cell_range(&mut self, start_row: i32, start_col: i32, values: &[Vec<Cell>]) {
-        for (i, row) in values.iter().enumerate() {
-            for (j, cell) in row.iter().enumerate() {
-                let target_row = start_row + i as i32;
-                let target_col = start_col + j as i32;
-                if target_row < self.rows && target_col < self.cols {
-                    self.cells[target_row as usize][target_col as usize] = cell.clone();
-                    if let Some(formula) = &cell.formula {
-                        let mut cell_ref = String::new();
-                        encode_column(target_col, &mut cell_ref);
-                        cell_ref.push_str(&(target_row + 1).to_string());
-                        update_cell(self, target_row, target_col, formula);
-                    }
-                }
-            }
+/// Shifts every reference of a formula by the same number of rows and columns, as when a
+/// formula is filled from one cell into another (see `APPLY`).
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `expr` - The parsed formula.
+/// * `row_offset`, `col_offset` - How far to shift the references.
+///
+/// # Returns
+/// An `Option<Expr>` with the shifted formula, or `None` if a reference would leave the sheet.
+/// References to other worksheets are kept as they are.
+fn shifted_refs(sheet: &mut Sheet, expr: &Expr, row_offset: i32, col_offset: i32) -> Option<Expr> {
+    let mut outside = false;
+    let shifted = expr.map_refs(&mut |cell_ref, _| {
+        let Ok((row, col)) = parse_cell_reference(sheet, cell_ref) else {
+            return cell_ref.to_string();
+        };
+        let (row, col) = (row + row_offset, col + col_offset);
+        if row < 0 || row >= sheet.rows || col < 0 || col >= sheet.cols {
+            outside = true;
+            return cell_ref.to_string();
+        }
+        let mut shifted_ref = String::new();
+        encode_column(col, &mut shifted_ref);
+        format!("{}{}", shifted_ref, row + 1)
+    });
+    (!outside).then_some(shifted)
+}
+
+/// Recalculates the cells that depend on a range whose cells were written directly rather than
+/// through `update_cell`, as a sort or `AUTOFILL` does.
+///
+/// No previous state is known for the written cells, so SUM, AVG and STDEV cells over them read
+/// their whole range again instead of updating their running statistics.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `start_row`, `start_col`, `end_row`, `end_col` - The written range (0-based, inclusive).
+fn recalculate_range_dependents(
+    sheet: &mut Sheet,
+    start_row: i32,
+    start_col: i32,
+    end_row: i32,
+    end_col: i32,
+) {
+    for i in start_row..=end_row {
+        for j in start_col..=end_col {
+            recalculate_dependents(sheet, i, j);
         }
     }
 }
 
-/// Copies a range of cells to the clipboard.
+/// Rewrites the references of a formula for a block of cells moved by `MOVE`.
+///
+/// References to cells of the block are shifted by the offsets. A range is shifted only when
+/// both of its corners are in the block; a range reaching outside the block keeps its place,
+/// since moving one corner alone would change which cells it covers.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `expr` - The parsed formula.
+/// * `block` - The moved block, as `(start_row, start_col, end_row, end_col)` (0-based).
+/// * `row_offset`, `col_offset` - How far the block moved.
+///
+/// # Returns
+/// An `Expr` with the rewritten references.
+fn moved_refs(
+    sheet: &mut Sheet,
+    expr: &Expr,
+    (start_row, start_col, end_row, end_col): (i32, i32, i32, i32),
+    row_offset: i32,
+    col_offset: i32,
+) -> Expr {
+    let mut in_block = |cell_ref: &str| match parse_cell_reference(sheet, cell_ref) {
+        Ok((row, col))
+            if (start_row..=end_row).contains(&row) && (start_col..=end_col).contains(&col) =>
+        {
+            Some((row, col))
+        }
+        _ => None,
+    };
+
+    // The two corners of a range are visited one after the other, so whether each range moves
+    // is settled in a first pass
+    let mut ranges_moving = Vec::new();
+    expr.map_refs(&mut |cell_ref, position| {
+        match position {
+            RefPosition::RangeStart => ranges_moving.push(in_block(cell_ref).is_some()),
+            RefPosition::RangeEnd => {
+                let moving = ranges_moving.last_mut().unwrap();
+                *moving = *moving && in_block(cell_ref).is_some();
+            }
+            RefPosition::Cell => {}
+        }
+        cell_ref.to_string()
+    });
+
+    let mut ranges_moving = ranges_moving.into_iter();
+    let mut range_moving = false;
+    expr.map_refs(&mut |cell_ref, position| {
+        let moving = match position {
+            RefPosition::Cell => true,
+            RefPosition::RangeStart => {
+                range_moving = ranges_moving.next().unwrap_or(false);
+                range_moving
+            }
+            RefPosition::RangeEnd => range_moving,
+        };
+        match in_block(cell_ref) {
+            Some((row, col)) if moving => {
+                let mut moved_ref = String::new();
+                encode_column(col + col_offset, &mut moved_ref);
+                format!("{}{}", moved_ref, row + row_offset + 1)
+            }
+            _ => cell_ref.to_string(),
+        }
+    })
+}
+
+/// Inserts a blank row, shifting the rows below it down.
 ///
 /// # Description
-/// Copies the specified range of cells to the global clipboard, preserving cell data (values, formulas, formatting).
-/// Returns `false` if the range is invalid (e.g., out of bounds or start indices exceed end indices).
+/// Every row from `at` onward moves down by one and the last row is dropped, so the last row
+/// must be empty and unreferenced, and none of the moving rows may hold a locked cell. Formulas and the dependency graph are rewritten to follow
+/// the moved cells: references at or below `at` move down, and a range that spans the inserted
+/// row grows to include it. With `extend_ranges`, a range that ends on the row just above the
+/// inserted row grows as well, so a `SUM` over a column picks up a row added at its bottom.
+/// Merged regions and VALIDATE rules move down with their cells.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
-/// * `start_row` - The starting row of the range (0-based).
-/// * `start_col` - The starting column of the range (0-based).
-/// * `end_row` - The ending row of the range (0-based).
-/// * `end_col` - The ending column of the range (0-based).
+/// * `at` - The row the new blank row takes (0-based).
+/// * `extend_ranges` - Whether ranges ending just above `at` grow to include the new row.
 ///
 /// # Returns
-/// A `bool` indicating success (`true`) or failure (`false`).
+/// A `Result<(), String>` with an error message if the row cannot be inserted.
 ///
 /// # Example
 /// ```
-/// use spreadsheet::{create_sheet, copy_range};
-/// let mut sheet = create_sheet(5, 5, false).unwrap();
-/// sheet.cells[0][0].value = 5;
-/// assert!(copy_range(&mut sheet, 0, 0, 1, 1));
-/// assert!(!copy_range(&mut sheet, 0, 0, 6, 6)); // Out of bounds
+/// use spreadsheet::{create_sheet, insert_row, process_command};
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// process_command(&mut sheet, "B1=SUM(A1:A2)");
+/// insert_row(&mut sheet, 1, false).unwrap();
+/// assert_eq!(sheet.cells[0][1].formula, Some("SUM(A1:A3)".to_string()));
 /// ```
-pub fn copy_range(
-    sheet: &mut Sheet,
-    start_row: i32,
-    start_col: i32,
-    end_row: i32,
-    end_col: i32,
-) -> bool {
-    if start_row >= 0
-        && start_col >= 0
-        && end_row < sheet.rows
-        && end_col < sheet.cols
-        && start_row <= end_row
-        && start_col <= end_col
-    {
-        let contents = sheet.get_cell_range(start_row, start_col, end_row, end_col);
-        *CLIPBOARD.lock().unwrap() = Some(Clipboard {
-            contents,
-            is_cut: false,
-            // source_range: None,
-        });
-        true
-    } else {
-        false
+pub fn insert_row(sheet: &mut Sheet, at: i32, extend_ranges: bool) -> Result<(), String> {
+    if at < 0 || at >= sheet.rows {
+        return Err("Invalid row".to_string());
+    }
+    let shift = |row: i32| if row >= at { row + 1 } else { row };
+    let shift_end = |row: i32| {
+        if row >= at || (extend_ranges && row == at - 1) {
+            row + 1
+        } else {
+            row
+        }
+    };
+
+    let last_row = sheet.rows - 1;
+    let last_row_used = sheet.cells[last_row as usize]
+        .iter()
+        .any(|cell| cell.value != 0 || cell.is_formula || cell.is_locked);
+    let reference_dropped = sheet.dependency_graph.values().any(|deps| {
+        deps.dependencies.iter().any(|dep| match *dep {
+            DependencyType::Single { row, .. } => shift(row) > last_row,
+            DependencyType::Range { end_row, .. } => shift_end(end_row) > last_row,
+        })
+    });
+    if last_row_used || reference_dropped {
+        return Err("Cannot insert a row: the last row is in use".to_string());
+    }
+    if sheet.is_range_locked(at, 0, last_row, sheet.cols - 1) {
+        return Err("Cannot insert a row: it would move locked cells".to_string());
+    }
+
+    save_state(sheet);
+    sheet.cells.pop();
+    sheet
+        .cells
+        .insert(at as usize, vec![Cell::new(); sheet.cols as usize]);
+    sheet.move_cell_history(|row, col| Some((shift(row), col)).filter(|&(row, _)| row <= last_row));
+
+    let shift_dep = |dep: &DependencyType| match *dep {
+        DependencyType::Single { row, col } => DependencyType::Single {
+            row: shift(row),
+            col,
+        },
+        DependencyType::Range {
+            start_row,
+            start_col,
+            end_row,
+            end_col,
+        } => DependencyType::Range {
+            start_row: shift(start_row),
+            start_col,
+            end_row: shift_end(end_row),
+            end_col,
+        },
+    };
+    sheet.dependency_graph = sheet
+        .dependency_graph
+        .drain()
+        .map(|((row, col), deps)| {
+            let deps = CellDependencies {
+                dependencies: deps.dependencies.iter().map(shift_dep).collect(),
+                dependents: deps.dependents.iter().map(shift_dep).collect(),
+            };
+            ((shift(row), col), deps)
+        })
+        .collect();
+
+    for row in 0..sheet.rows {
+        for col in 0..sheet.cols {
+            let Some(formula) = sheet.cells[row as usize][col as usize].formula.clone() else {
+                continue;
+            };
+            let Some(expr) = parse(&formula) else {
+                continue;
+            };
+            let moved = expr.map_refs(&mut |cell_ref, position| {
+                let Ok((ref_row, ref_col)) = parse_cell_reference(sheet, cell_ref) else {
+                    return cell_ref.to_string();
+                };
+                let new_row = if position == RefPosition::RangeEnd {
+                    shift_end(ref_row)
+                } else {
+                    shift(ref_row)
+                };
+                let mut moved_ref = String::new();
+                encode_column(ref_col, &mut moved_ref);
+                format!("{}{}", moved_ref, new_row + 1)
+            });
+            if moved != expr {
+                sheet.cells[row as usize][col as usize].formula = Some(moved.to_string());
+            }
+        }
     }
+
+    sheet.merged_regions = sheet
+        .merged_regions
+        .iter()
+        .map(|&(start_row, start_col, end_row, end_col)| {
+            (
+                shift(start_row),
+                start_col,
+                shift(end_row).min(last_row),
+                end_col,
+            )
+        })
+        .filter(|&(start_row, _, _, _)| start_row <= last_row)
+        .collect();
+    sheet.validation_rules = sheet
+        .validation_rules
+        .iter()
+        .map(|&(start_row, start_col, end_row, end_col, min, max)| {
+            (
+                shift(start_row),
+                start_col,
+                shift(end_row).min(last_row),
+                end_col,
+                min,
+                max,
+            )
+        })
+        .filter(|&(start_row, _, _, _, _, _)| start_row <= last_row)
+        .collect();
+
+    recalculate_all(sheet);
+    Ok(())
 }
 
-/// Cuts a range of cells to the clipboard, clearing the source.
+/// Deletes a range of cells, shifting the cells below it up to fill the gap.
 ///
 /// # Description
-/// Copies the specified range of cells to the global clipboard and clears the source cells in the spreadsheet.
-/// Returns `false` if the range is invalid (e.g., out of bounds or start indices exceed end indices).
+/// Within the columns of the range, every cell below the range moves up by the range's height
+/// and blank cells fill the bottom of the sheet. Formulas and the dependency graph are rewritten
+/// to follow the moved cells, and a range that covers some of the deleted rows shrinks. The
+/// deletion is refused if a cell outside the range refers to a deleted cell, or if a range or
+/// merged region only partly covers the shifted columns, since neither could be kept intact.
+/// It is also refused if a deleted or shifted cell is locked. VALIDATE rules follow the same shifts, and a rule that would not survive is dropped.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
-/// * `start_row` - The starting row of the range (0-based).
-/// * `start_col` - The starting column of the range (0-based).
-/// * `end_row` - The ending row of the range (0-based).
-/// * `end_col` - The ending column of the range (0-based).
+/// * `start_row`, `start_col` - The top-left cell of the range to delete (0-based).
+/// * `end_row`, `end_col` - The bottom-right cell of the range to delete (0-based).
 ///
 /// # Returns
-/// A `bool` indicating success (`true`) or failure (`false`).
+/// A `Result<(), String>` with an error message if the range cannot be deleted.
 ///
 /// # Example
 /// ```
-/// use spreadsheet::{create_sheet, cut_range};
-/// let mut sheet = create_sheet(5, 5, false).unwrap();
-/// sheet.cells[0][0].value = 5;
-/// assert!(cut_range(&mut sheet, 0, 0, 1, 1));
-/// assert_eq!(sheet.cells[0][0].value, 0); // Source cleared
-/// assert!(!cut_range(&mut sheet, 0, 0, 6, 6)); // Out of bounds
+/// use spreadsheet::{create_sheet, delete_range, process_command};
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// process_command(&mut sheet, "A4=7");
+/// process_command(&mut sheet, "B1=A4");
+/// delete_range(&mut sheet, 1, 0, 2, 0).unwrap();
+/// assert_eq!(sheet.cells[1][0].value, 7);
+/// assert_eq!(sheet.cells[0][1].formula, Some("A2".to_string()));
 /// ```
-pub fn cut_range(
+pub fn delete_range(
     sheet: &mut Sheet,
     start_row: i32,
     start_col: i32,
     end_row: i32,
     end_col: i32,
-) -> bool {
-    if start_row >= 0
-        && start_col >= 0
-        && end_row < sheet.rows
-        && end_col < sheet.cols
-        && start_row <= end_row
-        && start_col <= end_col
+) -> Result<(), String> {
+    let height = end_row - start_row + 1;
+    let in_columns = |col: i32| col >= start_col && col <= end_col;
+    let deleted = |row: i32, col: i32| in_columns(col) && row >= start_row && row <= end_row;
+    let shift_start = |row: i32, col: i32| {
+        if !in_columns(col) || row < start_row {
+            row
+        } else if row > end_row {
+            row - height
+        } else {
+            start_row
+        }
+    };
+    let shift_end = |row: i32, col: i32| {
+        if !in_columns(col) || row < start_row {
+            row
+        } else if row > end_row {
+            row - height
+        } else {
+            start_row - 1
+        }
+    };
+    // A range survives if it lies clear of the shifted cells, or spans only shifted columns
+    // and still has rows left once the deleted ones are gone
+    let keeps_range = |first_row: i32, first_col: i32, last_row: i32, last_col: i32| {
+        if last_col < start_col || first_col > end_col || last_row < start_row {
+            true
+        } else {
+            in_columns(first_col)
+                && in_columns(last_col)
+                && shift_start(first_row, first_col) <= shift_end(last_row, last_col)
+        }
+    };
+
+    let cell_name = |row: i32, col: i32| {
+        let mut name = String::new();
+        encode_column(col, &mut name);
+        format!("{}{}", name, row + 1)
+    };
+    let range_name = format!(
+        "{}:{}",
+        cell_name(start_row, start_col),
+        cell_name(end_row, end_col)
+    );
+
+    // Report the first blocking cell so the message does not depend on hash order
+    let blocking_cell = sheet
+        .dependency_graph
+        .iter()
+        .filter(|&(&(row, col), deps)| {
+            !deleted(row, col)
+                && !deps.dependencies.iter().all(|dep| match *dep {
+                    DependencyType::Single {
+                        row: dep_row,
+                        col: dep_col,
+                    } => !deleted(dep_row, dep_col),
+                    DependencyType::Range {
+                        start_row,
+                        start_col,
+                        end_row,
+                        end_col,
+                    } => keeps_range(start_row, start_col, end_row, end_col),
+                })
+        })
+        .map(|(&cell, _)| cell)
+        .min();
+    if let Some((row, col)) = blocking_cell {
+        return Err(format!(
+            "Cannot delete {}: it is referenced by {}",
+            range_name,
+            cell_name(row, col)
+        ));
+    }
+    if sheet
+        .merged_regions
+        .iter()
+        .any(|&(first_row, first_col, last_row, last_col)| {
+            !keeps_range(first_row, first_col, last_row, last_col)
+        })
     {
-        let contents = sheet.get_cell_range(start_row, start_col, end_row, end_col);
-        for i in start_row..=end_row {
-            for j in start_col..=end_col {
-                let cell = &mut sheet.cells[i as usize][j as usize];
-                *cell = Cell::new();
+        return Err(format!(
+            "Cannot delete {}: it overlaps a merged region",
+            range_name
+        ));
+    }
+    // The deleted cells and every cell that moves up into their place must be unlocked
+    if sheet.is_range_locked(start_row, start_col, sheet.rows - 1, end_col) {
+        return Err(format!(
+            "Cannot delete {}: it would change locked cells",
+            range_name
+        ));
+    }
+
+    save_state(sheet);
+    for row in start_row..=end_row {
+        for col in start_col..=end_col {
+            clear_cell_dependencies(sheet, row, col);
+        }
+    }
+    sheet.move_cell_history(|row, col| (!deleted(row, col)).then(|| (shift_start(row, col), col)));
+    for col in start_col..=end_col {
+        for row in start_row..sheet.rows {
+            sheet.cells[row as usize][col as usize] = if row + height < sheet.rows {
+                sheet.cells[(row + height) as usize][col as usize].clone()
+            } else {
+                Cell::new()
+            };
+        }
+    }
+
+    let shift_dep = |dep: &DependencyType| match *dep {
+        DependencyType::Single { row, col } => DependencyType::Single {
+            row: shift_start(row, col),
+            col,
+        },
+        DependencyType::Range {
+            start_row,
+            start_col,
+            end_row,
+            end_col,
+        } => DependencyType::Range {
+            start_row: shift_start(start_row, start_col),
+            start_col,
+            end_row: shift_end(end_row, end_col),
+            end_col,
+        },
+    };
+    sheet.dependency_graph = sheet
+        .dependency_graph
+        .drain()
+        .filter(|&((row, col), _)| !deleted(row, col))
+        .map(|((row, col), deps)| {
+            let deps = CellDependencies {
+                dependencies: deps.dependencies.iter().map(shift_dep).collect(),
+                dependents: deps.dependents.iter().map(shift_dep).collect(),
+            };
+            ((shift_start(row, col), col), deps)
+        })
+        .collect();
+
+    for row in 0..sheet.rows {
+        for col in 0..sheet.cols {
+            let Some(formula) = sheet.cells[row as usize][col as usize].formula.clone() else {
+                continue;
+            };
+            let Some(expr) = parse(&formula) else {
+                continue;
+            };
+            let moved = expr.map_refs(&mut |cell_ref, position| {
+                let Ok((ref_row, ref_col)) = parse_cell_reference(sheet, cell_ref) else {
+                    return cell_ref.to_string();
+                };
+                let new_row = if position == RefPosition::RangeEnd {
+                    shift_end(ref_row, ref_col)
+                } else {
+                    shift_start(ref_row, ref_col)
+                };
+                let mut moved_ref = String::new();
+                encode_column(ref_col, &mut moved_ref);
+                format!("{}{}", moved_ref, new_row + 1)
+            });
+            if moved != expr {
+                sheet.cells[row as usize][col as usize].formula = Some(moved.to_string());
             }
         }
-        *CLIPBOARD.lock().unwrap() = Some(Clipboard {
-            contents,
-            is_cut: true,
-            //source_range: Some((start_row, start_col, end_row, end_col)),
-        });
-        true
-    } else {
-        false
     }
+
+    sheet.merged_regions = sheet
+        .merged_regions
+        .iter()
+        .map(|&(first_row, first_col, last_row, last_col)| {
+            (
+                shift_start(first_row, first_col),
+                first_col,
+                shift_end(last_row, last_col),
+                last_col,
+            )
+        })
+        .collect();
+    // A VALIDATE rule is dropped with its cells, or if it only partly covers the shifted columns
+    sheet.validation_rules = sheet
+        .validation_rules
+        .iter()
+        .filter(|&&(first_row, first_col, last_row, last_col, _, _)| {
+            keeps_range(first_row, first_col, last_row, last_col)
+        })
+        .map(|&(first_row, first_col, last_row, last_col, min, max)| {
+            (
+                shift_start(first_row, first_col),
+                first_col,
+                shift_end(last_row, last_col),
+                last_col,
+                min,
+                max,
+            )
+        })
+        .collect();
+
+    recalculate_all(sheet);
+    Ok(())
 }
 
-/// Pastes the clipboard contents to the specified location.
+/// Makes another sheet of the workbook the active one.
 ///
 /// # Description
-/// Pastes the contents of the global clipboard to the spreadsheet starting at the specified cell.
-/// If the operation is a cut, the clipboard is cleared after pasting. Displays the updated sheet if successful.
-/// Returns `false` if the clipboard is empty or the target location is invalid.
+/// The named sheet takes the place of the active sheet, and the previously active sheet moves
+/// into the workbook under its own name. The workbook itself moves along with the active sheet.
 ///
 /// # Arguments
-/// * `sheet` - A mutable reference to the spreadsheet.
-/// * `start_row` - The starting row for pasting (0-based).
-/// * `start_col` - The starting column for pasting (0-based).
+/// * `sheet` - A mutable reference to the active spreadsheet.
+/// * `name` - The name of the sheet to switch to.
 ///
 /// # Returns
-/// A `bool` indicating success (`true`) or failure (`false`).
+/// A boolean indicating whether the workbook has a sheet with that name.
 ///
 /// # Example
 /// ```
-/// use spreadsheet::{create_sheet, copy_range, paste_range};
-/// let mut sheet = create_sheet(5, 5, false).unwrap();
-/// sheet.cells[0][0].value = 5;
-/// copy_range(&mut sheet, 0, 0, 0, 0);
-/// assert!(paste_range(&mut sheet, 1, 1));
-/// assert_eq!(sheet.cells[1][1].value, 5);
-/// assert!(!paste_range(&mut sheet, 6, 6)); // Invalid target
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// process_command(&mut sheet, "NEWSHEET Sheet2");
+/// assert!(switch_sheet(&mut sheet, "Sheet2"));
+/// assert_eq!(sheet.workbook.active, "Sheet2");
+/// assert!(sheet.workbook.sheets.contains_key("Sheet1"));
 /// ```
-pub fn paste_range(sheet: &mut Sheet, start_row: i32, start_col: i32) -> bool {
-    let success = {
-        let mut clipboard = CLIPBOARD.lock().unwrap();
-        if let Some(clipboard_data) = &*clipboard {
-            if start_row >= 0 && start_col >= 0 && start_row < sheet.rows && start_col < sheet.cols
-            {
-                sheet.set_cell_range(start_row, start_col, &clipboard_data.contents);
-                if clipboard_data.is_cut {
-                    *clipboard = None;
-                }
-                true
-            } else {
-                false
+fn switch_sheet(sheet: &mut Sheet, name: &str) -> bool {
+    let Some(mut next) = sheet.workbook.sheets.remove(name) else {
+        return false;
+    };
+    let mut previous = std::mem::take(sheet);
+    next.workbook.sheets = std::mem::take(&mut previous.workbook.sheets);
+    next.workbook
+        .sheets
+        .insert(previous.workbook.active.clone(), previous);
+    *sheet = next;
+    true
+}
+
+/// Checks whether a formula refers to cells on the named sheet (e.g., `Sheet2!A1`).
+fn refers_to_sheet(formula: &str, name: &str) -> bool {
+    if !formula.contains('!') {
+        return false;
+    }
+    let Some(expr) = parse(formula) else {
+        return false;
+    };
+    let mut found = false;
+    expr.map_refs(&mut |cell_ref, _| {
+        found |= cell_ref
+            .split_once('!')
+            .is_some_and(|(sheet_name, _)| sheet_name == name);
+        cell_ref.to_string()
+    });
+    found
+}
+
+/// Recalculates formulas on other sheets that refer to the active sheet.
+///
+/// # Description
+/// Each sheet holding a formula like `Sheet1!A1` or `SUM(Sheet1!A1:A5)` is made active in turn,
+/// so that the formula can read the sheet it refers to, and the formula and its dependents on
+/// that sheet are recalculated. Links are followed from sheet to sheet, so when Sheet3 reads
+/// Sheet2 and Sheet2 reads the active Sheet1, Sheet3 is updated after Sheet2.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the active spreadsheet.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// process_command(&mut sheet, "NEWSHEET Sheet2");
+/// process_command(&mut sheet, "SHEET Sheet2");
+/// process_command(&mut sheet, "A1=Sheet1!A1*2");
+/// process_command(&mut sheet, "SHEET Sheet1");
+/// sheet.cells[0][0].value = 4;
+/// refresh_linked_sheets(&mut sheet);
+/// assert_eq!(sheet.workbook.sheets["Sheet2"].cells[0][0].value, 8);
+/// ```
+fn refresh_linked_sheets(sheet: &mut Sheet) {
+    let active = sheet.workbook.active.clone();
+    // Sheets whose values may have changed, in the order their readers are refreshed
+    let mut changed = vec![active.clone()];
+    let mut next = 0;
+    while let Some(source) = changed.get(next).cloned() {
+        next += 1;
+        let readers: Vec<String> = sheet
+            .workbook
+            .sheets
+            .iter()
+            .map(|(name, other)| (name, &other.cells))
+            .chain([(&active, &sheet.cells)])
+            .filter(|(name, cells)| {
+                **name != source
+                    && cells.iter().flatten().any(|cell| {
+                        cell.formula
+                            .as_deref()
+                            .is_some_and(|formula| refers_to_sheet(formula, &source))
+                    })
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in readers {
+            if name != active {
+                switch_sheet(sheet, &name);
+            }
+            refresh_formulas_reading(sheet, &source);
+            if name != active {
+                switch_sheet(sheet, &active);
+            }
+            if !changed.contains(&name) {
+                changed.push(name);
             }
-        } else {
-            false
         }
-    };
+    }
+}
 
-    if success {
-        display_sheet(sheet);
+/// Recalculates the formulas of the active sheet that refer to the named sheet, along with
+/// their dependents. See `refresh_linked_sheets`.
+fn refresh_formulas_reading(sheet: &mut Sheet, source: &str) {
+    for row in 0..sheet.rows {
+        for col in 0..sheet.cols {
+            let Some(formula) = sheet.cells[row as usize][col as usize].formula.clone() else {
+                continue;
+            };
+            if !refers_to_sheet(&formula, source) {
+                continue;
+            }
+            let (value, error) = evaluate_expression(sheet, &formula, row, col);
+            let cell = &mut sheet.cells[row as usize][col as usize];
+            cell.value = value;
+            cell.is_error = error.is_some();
+            cell.error_kind = error;
+            recalculate_dependents(sheet, row, col);
+        }
     }
+}
 
-    success
+/// Whether a command only moves the view or reports on the sheet, so that no value another
+/// sheet reads can have changed.
+fn is_view_only(command: &str) -> bool {
+    let keyword = command.split_whitespace().next().unwrap_or("");
+    COMMANDS
+        .iter()
+        .any(|&(name, view_only)| view_only && name == keyword)
 }
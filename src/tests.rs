@@ -1,17 +1,20 @@
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{Sheet, Cell, DependencyType, CellDependencies, PatternType, GraphType};
+    use crate::types::{Sheet, Cell, DataKind, DependencyType, CellDependencies, PatternType, GraphType, ParseError, ErrorKind};
     use crate::sheet::{create_sheet, process_command, scroll_sheet, scroll_to_cell, undo, redo, cut_range, 
-        copy_range, paste_range, display_sheet, display_graph,
+        copy_range, paste_range, display_graph, column_widths, recall_command, format_grid,
     };
     use crate::cell::{update_cell, evaluate_expression};
+    use crate::parser::{parse, tokenize, Expr};
     use crate::utils::{
-        parse_cell_reference, parse_range, calculate_range_function, evaluate_arithmetic,
-        detect_pattern, is_valid_formula, is_valid_command, triangular, is_factorial_sequence, is_triangular_sequence,factorial
+        parse_cell_reference, parse_range, calculate_range_function, calculate_sumif,
+        days_from_civil, civil_from_days, format_cell_value, split_csv_line, levenshtein,
+        decode_column, detect_pattern, is_valid_formula, is_valid_command, triangular, is_factorial_sequence, is_triangular_sequence,factorial
     };
-    use crate::dependencies::{has_circular_dependency, recalculate_dependents, remove_dependency};
+    use crate::dependencies::{formula_dependencies, has_circular_dependency, recalculate_dependents, remove_dependency};
     use std::collections::HashMap;
+    use std::sync::{Mutex, MutexGuard};
     use rocket::http::{ContentType, Status};
     use rocket::local::blocking::Client;
     use std::fs::File;
@@ -23,6 +26,7 @@ mod tests {
     use crate::command;
     use crate::index;
     use crate::load_csv_file;
+    use crate::load_csv_file_with_header;
     use crate::load_excel_file;
     
 
@@ -31,53 +35,82 @@ mod tests {
         create_sheet(rows, cols, extension_enabled).unwrap()
     }
 
+    // Range functions take parsed arguments; these read them from text the way a formula does
+    fn range_function(sheet: &mut Sheet, function: &str, args: &str) -> Result<f64, ()> {
+        match parse(&format!("F({})", args)) {
+            Some(Expr::Call { args, .. }) => calculate_range_function(sheet, function, &args),
+            _ => Err(()),
+        }
+    }
+
+    fn sumif(sheet: &mut Sheet, args: &str) -> Result<i64, ()> {
+        match parse(&format!("SUMIF({})", args)) {
+            Some(Expr::Call { args, .. }) => calculate_sumif(sheet, &args),
+            _ => Err(()),
+        }
+    }
+
+    // Tests that copy, cut or paste share the global clipboard, so they take this lock
+    static CLIPBOARD_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_clipboard() -> MutexGuard<'static, ()> {
+        CLIPBOARD_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Tests that set the global sheet take this lock, so they do not replace each other's sheet
+    static SHEET_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_global_sheet() -> MutexGuard<'static, ()> {
+        SHEET_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
     #[test]
     fn test_parse_cell_reference_comprehensive() {
         let mut sheet = create_test_sheet(10, 26,false); // 10 rows, 26 cols (A-Z)
 
         // Valid single-column references
-        assert_eq!(parse_cell_reference(&mut sheet, "A1"), Some((0, 0)));
-        assert_eq!(parse_cell_reference(&mut sheet, "Z10"), Some((9, 25)));
-        assert_eq!(parse_cell_reference(&mut sheet, "B5"), Some((4, 1)));
+        assert_eq!(parse_cell_reference(&mut sheet, "A1"), Ok((0, 0)));
+        assert_eq!(parse_cell_reference(&mut sheet, "Z10"), Ok((9, 25)));
+        assert_eq!(parse_cell_reference(&mut sheet, "B5"), Ok((4, 1)));
 
         // Invalid multi-column references (out of bounds)
-        assert_eq!(parse_cell_reference(&mut sheet, "AA1"), None); // Col 26 >= 26
-        assert_eq!(parse_cell_reference(&mut sheet, "AB2"), None); // Col 27 > 26
+        assert_eq!(parse_cell_reference(&mut sheet, "AA1"), Err(ParseError::OutOfBounds)); // Col 26 >= 26
+        assert_eq!(parse_cell_reference(&mut sheet, "AB2"), Err(ParseError::OutOfBounds)); // Col 27 > 26
 
         // Edge cases: First and last valid cells
-        assert_eq!(parse_cell_reference(&mut sheet, "A1"), Some((0, 0))); // Top-left
-        assert_eq!(parse_cell_reference(&mut sheet, "Z10"), Some((9, 25))); // Bottom-right
+        assert_eq!(parse_cell_reference(&mut sheet, "A1"), Ok((0, 0))); // Top-left
+        assert_eq!(parse_cell_reference(&mut sheet, "Z10"), Ok((9, 25))); // Bottom-right
 
         // Invalid: Out of bounds
-        assert_eq!(parse_cell_reference(&mut sheet, "A11"), None); // Row too high
-        assert_eq!(parse_cell_reference(&mut sheet, "Z11"), None); // Row too high
-        assert_eq!(parse_cell_reference(&mut sheet, "A0"), None); // Row too low
+        assert_eq!(parse_cell_reference(&mut sheet, "A11"), Err(ParseError::OutOfBounds)); // Row too high
+        assert_eq!(parse_cell_reference(&mut sheet, "Z11"), Err(ParseError::OutOfBounds)); // Row too high
+        assert_eq!(parse_cell_reference(&mut sheet, "A0"), Err(ParseError::OutOfBounds)); // Row too low
 
         // Invalid: Malformed inputs
-        assert_eq!(parse_cell_reference(&mut sheet, ""), None); // Empty string
-        assert_eq!(parse_cell_reference(&mut sheet, "1A"), None); // No letter prefix
-        assert_eq!(parse_cell_reference(&mut sheet, "A"), None); // Missing row
-        assert_eq!(parse_cell_reference(&mut sheet, "123"), None); // Only digits
-        assert_eq!(parse_cell_reference(&mut sheet, "A1B"), None); // Invalid format
-       // assert_eq!(parse_cell_reference(&mut sheet, "A-1"), None); // Negative row
+        assert_eq!(parse_cell_reference(&mut sheet, ""), Err(ParseError::BadSyntax)); // Empty string
+        assert_eq!(parse_cell_reference(&mut sheet, "1A"), Err(ParseError::BadSyntax)); // No letter prefix
+        assert_eq!(parse_cell_reference(&mut sheet, "A"), Err(ParseError::BadSyntax)); // Missing row
+        assert_eq!(parse_cell_reference(&mut sheet, "123"), Err(ParseError::BadSyntax)); // Only digits
+        assert_eq!(parse_cell_reference(&mut sheet, "A1B"), Err(ParseError::BadSyntax)); // Invalid format
+        assert_eq!(parse_cell_reference(&mut sheet, "A-1"), Err(ParseError::BadSyntax)); // Negative row
 
         // Invalid: Non-alphabetic column
-        assert_eq!(parse_cell_reference(&mut sheet, "1A1"), None); // Numeric column
-        assert_eq!(parse_cell_reference(&mut sheet, "!A1"), None); // Special character
+        assert_eq!(parse_cell_reference(&mut sheet, "1A1"), Err(ParseError::BadSyntax)); // Numeric column
+        assert_eq!(parse_cell_reference(&mut sheet, "!A1"), Err(ParseError::BadSyntax)); // Special character
 
         // Whitespace handling
-        assert_eq!(parse_cell_reference(&mut sheet, " A1 "), Some((0, 0))); // Trimmed
-        assert_eq!(parse_cell_reference(&mut sheet, "\tB2\t"), Some((1, 1))); // Trimmed tabs
+        assert_eq!(parse_cell_reference(&mut sheet, " A1 "), Ok((0, 0))); // Trimmed
+        assert_eq!(parse_cell_reference(&mut sheet, "\tB2\t"), Ok((1, 1))); // Trimmed tabs
 
         // Case sensitivity (expect uppercase only)
        // assert_eq!(parse_cell_reference(&mut sheet, "a1"), None); // Lowercase invalid
-        assert_eq!(parse_cell_reference(&mut sheet, "Aa1"), None); // Mixed case invalid
+        assert_eq!(parse_cell_reference(&mut sheet, "Aa1"), Err(ParseError::OutOfBounds)); // Mixed case reads as AA
 
         // // Small sheet bounds
         let mut small_sheet = create_test_sheet(1, 1,false); // 1x1 sheet
-        assert_eq!(parse_cell_reference(&mut small_sheet, "A1"), Some((0, 0)));
-        assert_eq!(parse_cell_reference(&mut small_sheet, "A2"), None); // Row out of bounds
-        assert_eq!(parse_cell_reference(&mut small_sheet, "B1"), None); // Col out of bounds
+        assert_eq!(parse_cell_reference(&mut small_sheet, "A1"), Ok((0, 0)));
+        assert_eq!(parse_cell_reference(&mut small_sheet, "A2"), Err(ParseError::OutOfBounds)); // Row out of bounds
+        assert_eq!(parse_cell_reference(&mut small_sheet, "B1"), Err(ParseError::OutOfBounds)); // Col out of bounds
     }
 
     #[test]
@@ -103,47 +136,47 @@ mod tests {
         sheet.cells[1][1].value = 4; // B2
 
         // Valid range A1:B2
-        assert_eq!(calculate_range_function(&mut sheet, "SUM", "A1:B2"), Ok(10.0)); // 1+2+3+4
-        assert_eq!(calculate_range_function(&mut sheet, "AVG", "A1:B2"), Ok(2.5)); // (1+2+3+4)/4
-        assert_eq!(calculate_range_function(&mut sheet, "MIN", "A1:B2"), Ok(1.0)); // min(1,2,3,4)
-        assert_eq!(calculate_range_function(&mut sheet, "MAX", "A1:B2"), Ok(4.0)); // max(1,2,3,4)
-        let stdev = calculate_range_function(&mut sheet, "STDEV", "A1:B2").unwrap();
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:B2"), Ok(10.0)); // 1+2+3+4
+        assert_eq!(range_function(&mut sheet, "AVG", "A1:B2"), Ok(2.5)); // (1+2+3+4)/4
+        assert_eq!(range_function(&mut sheet, "MIN", "A1:B2"), Ok(1.0)); // min(1,2,3,4)
+        assert_eq!(range_function(&mut sheet, "MAX", "A1:B2"), Ok(4.0)); // max(1,2,3,4)
+        let stdev = range_function(&mut sheet, "STDEV", "A1:B2").unwrap();
         assert!((stdev - 1.0).abs() < 0.01); // STDEV(1,2,3,4) ≈ 1.414, rounded to 1.0
 
         // // Case insensitivity
-        assert_eq!(calculate_range_function(&mut sheet, "sum", "A1:B2"), Ok(10.0));
-        assert_eq!(calculate_range_function(&mut sheet, "StDeV", "A1:B2"), Ok(1.0));
+        assert_eq!(range_function(&mut sheet, "sum", "A1:B2"), Ok(10.0));
+        assert_eq!(range_function(&mut sheet, "StDeV", "A1:B2"), Ok(1.0));
 
         // // Single-cell range (A1 = 1)
-        assert_eq!(calculate_range_function(&mut sheet, "SUM", "A1:A1"), Ok(1.0));
-        assert_eq!(calculate_range_function(&mut sheet, "AVG", "A1:A1"), Ok(1.0));
-        assert_eq!(calculate_range_function(&mut sheet, "MIN", "A1:A1"), Ok(1.0));
-        assert_eq!(calculate_range_function(&mut sheet, "MAX", "A1:A1"), Ok(1.0));
-       // assert_eq!(calculate_range_function(&mut sheet, "STDEV", "A1:A1"), Ok(1.0)); // Single value
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:A1"), Ok(1.0));
+        assert_eq!(range_function(&mut sheet, "AVG", "A1:A1"), Ok(1.0));
+        assert_eq!(range_function(&mut sheet, "MIN", "A1:A1"), Ok(1.0));
+        assert_eq!(range_function(&mut sheet, "MAX", "A1:A1"), Ok(1.0));
+       // assert_eq!(range_function(&mut sheet, "STDEV", "A1:A1"), Ok(1.0)); // Single value
 
         // Range with zero values (A3:B3 = [0, 0])
         sheet.cells[2][0].value = 0; // A3
         sheet.cells[2][1].value = 0; // B3
-        assert_eq!(calculate_range_function(&mut sheet, "SUM", "A3:B3"), Ok(0.0));
-        assert_eq!(calculate_range_function(&mut sheet, "AVG", "A3:B3"), Ok(0.0));
-        assert_eq!(calculate_range_function(&mut sheet, "MIN", "A3:B3"), Ok(0.0));
-        assert_eq!(calculate_range_function(&mut sheet, "MAX", "A3:B3"), Ok(0.0));
-        // assert_eq!(calculate_range_function(&mut sheet, "STDEV", "A3:B3"), Ok(0.0)); // Same values
+        assert_eq!(range_function(&mut sheet, "SUM", "A3:B3"), Ok(0.0));
+        assert_eq!(range_function(&mut sheet, "AVG", "A3:B3"), Ok(0.0));
+        assert_eq!(range_function(&mut sheet, "MIN", "A3:B3"), Ok(0.0));
+        assert_eq!(range_function(&mut sheet, "MAX", "A3:B3"), Ok(0.0));
+        // assert_eq!(range_function(&mut sheet, "STDEV", "A3:B3"), Ok(0.0)); // Same values
 
         // // Error: Invalid range
-        assert_eq!(calculate_range_function(&mut sheet, "SUM", "A1:Z10"), Err(())); // Out of bounds
-        assert_eq!(calculate_range_function(&mut sheet, "AVG", "INVALID"), Err(())); // Malformed
-        assert_eq!(calculate_range_function(&mut sheet, "MIN", "B1:A1"), Err(())); // Reverse range
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:Z10"), Err(())); // Out of bounds
+        assert_eq!(range_function(&mut sheet, "AVG", "INVALID"), Err(())); // Malformed
+        assert_eq!(range_function(&mut sheet, "MIN", "B1:A1"), Err(())); // Reverse range
 
         // // Error: Cell with error
         sheet.cells[0][0].is_error = true; // A1 has error
-        assert_eq!(calculate_range_function(&mut sheet, "SUM", "A1:B2"), Err(()));
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:B2"), Err(()));
         sheet.cells[0][0].is_error = false;
         //  // Reset
 
         // // Error: Invalid function
-        assert_eq!(calculate_range_function(&mut sheet, "INVALID", "A1:B2"), Err(()));
-        assert_eq!(calculate_range_function(&mut sheet, "", "A1:B2"), Err(()));
+        assert_eq!(range_function(&mut sheet, "INVALID", "A1:B2"), Err(()));
+        assert_eq!(range_function(&mut sheet, "", "A1:B2"), Err(()));
 
         // // Large range (A1:E5), extensions enabled
         let mut large_sheet = create_test_sheet(10, 10, true);
@@ -152,37 +185,37 @@ mod tests {
                 large_sheet.cells[i][j].value = (i + j + 1) as i32; // A1:E5 = 1,2,...,9
             }
         }
-        assert_eq!(calculate_range_function(&mut large_sheet, "SUM", "A1:E5"), Ok(125.0)); // Sum of 1 to 9
-        assert_eq!(calculate_range_function(&mut large_sheet, "AVG", "A1:E5"), Ok(5.0)); // (1+2+...+9)/25
-        assert_eq!(calculate_range_function(&mut large_sheet, "MIN", "A1:E5"), Ok(1.0));
-        assert_eq!(calculate_range_function(&mut large_sheet, "MAX", "A1:E5"), Ok(9.0));
+        assert_eq!(range_function(&mut large_sheet, "SUM", "A1:E5"), Ok(125.0)); // Sum of 1 to 9
+        assert_eq!(range_function(&mut large_sheet, "AVG", "A1:E5"), Ok(5.0)); // (1+2+...+9)/25
+        assert_eq!(range_function(&mut large_sheet, "MIN", "A1:E5"), Ok(1.0));
+        assert_eq!(range_function(&mut large_sheet, "MAX", "A1:E5"), Ok(9.0));
 
         // // Small 1x1 sheet
         let mut small_sheet = create_test_sheet(1, 1, false);
         small_sheet.cells[0][0].value = 42;
-        assert_eq!(calculate_range_function(&mut small_sheet, "SUM", "A1:A1"), Ok(42.0));
+        assert_eq!(range_function(&mut small_sheet, "SUM", "A1:A1"), Ok(42.0));
     }
 
     #[test]
     fn test_evaluate_expression_numeric() {
         let mut sheet = create_test_sheet(10, 10, false);
-        let (value, is_error) = evaluate_expression(&mut sheet, "42", 0, 0);
+        let (value, error) = evaluate_expression(&mut sheet, "42", 0, 0);
         assert_eq!(value, 42);
-        assert!(!is_error);
+        assert!(error.is_none());
     }
 
     #[test]
     fn test_evaluate_expression_cell_reference() {
         let mut sheet = create_test_sheet(10, 10, false);
         sheet.cells[0][0].value = 10;
-        let (value, is_error) = evaluate_expression(&mut sheet, "A1", 1, 1);
+        let (value, error) = evaluate_expression(&mut sheet, "A1", 1, 1);
         assert_eq!(value, 10);
-        assert!(!is_error);
+        assert!(error.is_none());
 
         sheet.cells[0][0].is_error = true;
-        let (value, is_error) = evaluate_expression(&mut sheet, "A1", 1, 1);
+        let (value, error) = evaluate_expression(&mut sheet, "A1", 1, 1);
         assert_eq!(value, 10);
-        assert!(is_error);
+        assert!(error.is_some());
     }
 
     #[test]
@@ -190,13 +223,13 @@ mod tests {
         let mut sheet = create_test_sheet(10, 10, false);
         sheet.cells[0][0].value = 5;
         sheet.cells[0][1].value = 3;
-        let (value, is_error) = evaluate_expression(&mut sheet, "A1+B1", 1, 1);
+        let (value, error) = evaluate_expression(&mut sheet, "A1+B1", 1, 1);
         assert_eq!(value, 8);
-        assert!(!is_error);
+        assert!(error.is_none());
 
-        let (value, is_error) = evaluate_expression(&mut sheet, "A1/0", 1, 1);
+        let (value, error) = evaluate_expression(&mut sheet, "A1/0", 1, 1);
         assert_eq!(value, 0);
-        assert!(is_error);
+        assert!(error.is_some());
     }
 
     #[test]
@@ -283,6 +316,7 @@ mod tests {
 
     #[test]
     fn test_copy_paste() {
+        let _clipboard = lock_clipboard();
         let mut sheet = create_test_sheet(10, 10, true);
         sheet.cells[0][0].value = 10;
         sheet.cells[0][1].value = 20;
@@ -638,6 +672,7 @@ mod tests {
 
     #[test]
     fn test_process_command() {
+        let _clipboard = lock_clipboard();
         let mut sheet = Sheet {
             rows: 10,
             cols: 10,
@@ -866,7 +901,7 @@ mod tests {
             writeln!(temp_file, "10,=A1+1,20\n30,,40").unwrap();
             let path = temp_file.path().to_str().unwrap();
     
-            let result = load_csv_file(&mut sheet, path);
+            let result = load_csv_file(&mut sheet, path, ',');
             assert!(result.is_ok());
             assert_eq!(sheet.cells[0][0].value, 10); // A1
             assert_eq!(sheet.cells[0][2].value, 20); // C1
@@ -883,7 +918,7 @@ mod tests {
             writeln!(temp_file, "1,2,3\n4,5,6\n7,8,9").unwrap();
             let path = temp_file.path().to_str().unwrap();
     
-            let result = load_csv_file(&mut sheet, path);
+            let result = load_csv_file(&mut sheet, path, ',');
             assert!(result.is_err());
             assert_eq!(
                 result.unwrap_err(),
@@ -898,18 +933,42 @@ mod tests {
             writeln!(temp_file, "1,2,3").unwrap();
             let path = temp_file.path().to_str().unwrap();
     
-            let result = load_csv_file(&mut sheet, path);
-            assert!(result.is_err());
+            let warnings = load_csv_file(&mut sheet, path, ',').unwrap();
+            assert_eq!(sheet.cells[0][0].value, 1);
+            assert_eq!(sheet.cells[0][1].value, 2);
             assert_eq!(
-                result.unwrap_err(),
-                "CSV file has more columns than the spreadsheet (max: 2)"
+                warnings,
+                vec!["Row 1: skipped 1 value(s) beyond the last column (max: 2)".to_string()]
+            );
+        }
+
+        #[test]
+        fn test_load_csv_file_reports_bad_cells() {
+            let mut sheet = create_test_sheet(5, 3, true);
+            let mut temp_file = NamedTempFile::new().unwrap();
+            writeln!(temp_file, "1,2,3\n4,5,6,7\n8,abc,=A1+\n9,=A1+A2,10").unwrap();
+            let path = temp_file.path().to_str().unwrap();
+
+            let warnings = load_csv_file(&mut sheet, path, ',').unwrap();
+            assert_eq!(
+                warnings,
+                vec![
+                    "Row 2: skipped 1 value(s) beyond the last column (max: 3)".to_string(),
+                    "Cell B3: skipped non-numeric value \"abc\"".to_string(),
+                    "Cell C3: skipped invalid formula \"=A1+\"".to_string(),
+                ]
             );
+            // Everything valid around the bad cells is still loaded
+            assert_eq!(sheet.cells[1][2].value, 6);
+            assert_eq!(sheet.cells[2][0].value, 8);
+            assert_eq!(sheet.cells[3][1].value, 5); // B4 = A1+A2
+            assert_eq!(sheet.cells[3][2].value, 10);
         }
     
         #[test]
         fn test_load_csv_file_invalid_path() {
             let mut sheet = create_test_sheet(5, 5, true);
-            let result = load_csv_file(&mut sheet, "nonexistent.csv");
+            let result = load_csv_file(&mut sheet, "nonexistent.csv", ',');
             assert!(result.is_err());
             assert!(result.unwrap_err().starts_with("Failed to open CSV file"));
         }
@@ -924,6 +983,40 @@ mod tests {
             assert!(result.unwrap_err().starts_with("Failed to open Excel file"));
         }
 
+        #[test]
+        fn test_load_excel_file_with_formulas() {
+            let mut sheet = create_test_sheet(5, 5, true);
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/formulas.xlsx");
+            load_excel_file(&mut sheet, path).unwrap();
+            assert_eq!(sheet.cells[0][1].value, 12); // B1 = A1+A2
+            assert_eq!(sheet.cells[0][1].formula, Some("A1+A2".to_string()));
+            assert_eq!(sheet.cells[1][1].value, 24); // B2 = SUM(A1:A2)*2
+
+            // The loaded formulas keep tracking their inputs
+            process_command(&mut sheet, "A1=10");
+            assert_eq!(sheet.cells[0][1].value, 17);
+            assert_eq!(sheet.cells[1][1].value, 34);
+        }
+
+        #[test]
+        fn test_load_excel_file_reports_truncated_floats() {
+            let mut sheet = create_test_sheet(5, 5, true);
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fractions.xlsx");
+            let warnings = load_excel_file(&mut sheet, path).unwrap();
+            assert_eq!(sheet.cells[0][0].value, 3);
+            assert_eq!(sheet.cells[1][0].value, -1);
+            // B2 only caches 7.7 for its formula, so it is recomputed rather than reported
+            assert_eq!(sheet.cells[1][1].value, 7);
+            assert_eq!(
+                warnings,
+                vec![
+                    "Cell A1: truncated 3.7 to 3".to_string(),
+                    "Cell A2: truncated -1.5 to -1".to_string(),
+                    "2 value(s) with a fractional part were truncated to whole numbers".to_string(),
+                ]
+            );
+        }
+
     #[test]
     fn test_process_command_scroll() {
         let mut sheet = create_test_sheet(20, 20, true);
@@ -1334,10 +1427,10 @@ mod tests {
         let row = 0;
         let col = 0;
 
-        let (result, is_error) = evaluate_expression(&mut sheet, expr, row, col);
+        let (result, error) = evaluate_expression(&mut sheet, expr, row, col);
 
         assert_eq!(result, 5, "SLEEP should return the duration as the result");
-        assert!(!is_error, "SLEEP with valid duration should not result in an error");
+        assert!(error.is_none(), "SLEEP with valid duration should not result in an error");
     }
 
 
@@ -1606,6 +1699,3188 @@ mod tests {
         // Value 5 (originally row 0)
         assert!(sheet.cells[4][0].is_bold);
     }
-    
-}
 
+    #[test]
+    fn test_sum_avg_over_disjoint_ranges() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        for i in 0..3 {
+            sheet.cells[i][0].value = (i + 1) as i32; // A1:A3 = 1, 2, 3
+            sheet.cells[i][2].value = 10 * (i + 1) as i32; // C1:C3 = 10, 20, 30
+        }
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:A3, C1:C3"), Ok(66.0));
+        assert_eq!(range_function(&mut sheet, "AVG", "A1:A3,C1:C3"), Ok(11.0));
+        assert_eq!(range_function(&mut sheet, "MAX", "A1:A3, C1:C3"), Ok(30.0));
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:A3, Z1:Z3"), Err(()));
+
+        assert!(is_valid_formula(&mut sheet, "SUM(A1:A3, C1:C3)"));
+        assert!(!is_valid_formula(&mut sheet, "SUM(A1:A3, INVALID)"));
+
+        update_cell(&mut sheet, 4, 4, "SUM(A1:A3, C1:C3)"); // E5
+        assert_eq!(sheet.cells[4][4].value, 66);
+        let deps = sheet.dependency_graph.get(&(4, 4)).unwrap();
+        assert!(deps.dependencies.contains(&DependencyType::Range { start_row: 0, start_col: 0, end_row: 2, end_col: 0 }));
+        assert!(deps.dependencies.contains(&DependencyType::Range { start_row: 0, start_col: 2, end_row: 2, end_col: 2 }));
+
+        // Editing a cell in the second range updates the sum
+        update_cell(&mut sheet, 0, 2, "100");
+        assert_eq!(sheet.cells[4][4].value, 156);
+
+        // A range list that includes the cell itself is circular
+        assert!(has_circular_dependency(&mut sheet, 4, 4, "SUM(A1:A3, E1:E5)"));
+    }
+
+    #[test]
+    fn test_sum_over_overlapping_ranges() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        for i in 0..4 {
+            sheet.cells[i][0].value = (i + 1) as i32; // A1:A4 = 1, 2, 3, 4
+        }
+        // A2:A3 is covered by both ranges and is counted twice
+        assert_eq!(range_function(&mut sheet, "SUM", "A1:A3, A2:A4"), Ok(15.0));
+        update_cell(&mut sheet, 0, 1, "SUM(A1:A3, A2:A4)");
+        assert_eq!(sheet.cells[0][1].value, 15);
+    }
+
+    #[test]
+    fn test_sumif_two_argument_form() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        for (i, v) in [3, 8, 5, 10, 1].iter().enumerate() {
+            update_cell(&mut sheet, i as i32, 0, &v.to_string()); // A1:A5
+        }
+        assert_eq!(sumif(&mut sheet, "A1:A5, >5"), Ok(18));
+        assert_eq!(sumif(&mut sheet, "A1:A5, >=5"), Ok(23));
+        assert_eq!(sumif(&mut sheet, "A1:A5, <>5"), Ok(22));
+        assert_eq!(sumif(&mut sheet, "A1:A5, 5"), Ok(5));
+        assert_eq!(sumif(&mut sheet, "A1:A5, =>5"), Err(()));
+        assert_eq!(sumif(&mut sheet, "A1:A5"), Err(()));
+
+        assert!(is_valid_formula(&mut sheet, "SUMIF(A1:A5, >5)"));
+        update_cell(&mut sheet, 0, 1, "SUMIF(A1:A5, >5)"); // B1
+        assert_eq!(sheet.cells[0][1].value, 18);
+        assert!(!sheet.cells[0][1].is_error);
+
+        // Changing a cell in the range re-evaluates the condition
+        update_cell(&mut sheet, 0, 0, "7");
+        assert_eq!(sheet.cells[0][1].value, 25);
+    }
+
+    #[test]
+    fn test_sumif_three_argument_form() {
+        let mut sheet = create_test_sheet(10, 10, true);
+        for (i, v) in [3, 8, 5, 10].iter().enumerate() {
+            update_cell(&mut sheet, i as i32, 0, &v.to_string()); // A1:A4
+            update_cell(&mut sheet, i as i32, 1, &(100 * (i + 1)).to_string()); // B1:B4
+        }
+        assert_eq!(sumif(&mut sheet, "A1:A4, >5, B1:B4"), Ok(600));
+        assert_eq!(sumif(&mut sheet, "A1:A4, >5, B1:B3"), Err(())); // Shape mismatch
+
+        process_command(&mut sheet, "C1=SUMIF(A1:A4, >5, B1:B4)");
+        assert_eq!(sheet.cells[0][2].value, 600);
+        let deps = sheet.dependency_graph.get(&(0, 2)).unwrap();
+        assert!(deps.dependencies.contains(&DependencyType::Range { start_row: 0, start_col: 0, end_row: 3, end_col: 0 }));
+        assert!(deps.dependencies.contains(&DependencyType::Range { start_row: 0, start_col: 1, end_row: 3, end_col: 1 }));
+
+        // Both the criteria range and the sum range trigger recalculation
+        process_command(&mut sheet, "B2=1");
+        assert_eq!(sheet.cells[0][2].value, 401);
+        process_command(&mut sheet, "A1=9");
+        assert_eq!(sheet.cells[0][2].value, 501);
+    }
+
+    #[test]
+    fn test_parser_precedence_and_functions() {
+        let mut sheet = create_test_sheet(10, 10, true);
+        sheet.cells[0][0].value = 3; // A1
+        sheet.cells[1][0].value = 4; // A2
+        sheet.cells[0][1].value = 10; // B1
+        sheet.cells[1][1].value = 2; // B2
+
+        assert_eq!(evaluate_expression(&mut sheet, "SUM(A1:A2)*2 + 1", 0, 2), (15, None));
+        assert_eq!(evaluate_expression(&mut sheet, "SUM(A1:A2) + MAX(B1:B2)", 0, 2), (17, None));
+        assert_eq!(evaluate_expression(&mut sheet, "2+3*4", 0, 2), (14, None));
+        assert_eq!(evaluate_expression(&mut sheet, "(2+3)*4", 0, 2), (20, None));
+        assert_eq!(evaluate_expression(&mut sheet, "10-4-3", 0, 2), (3, None));
+        assert_eq!(evaluate_expression(&mut sheet, "-A1*-2", 0, 2), (6, None));
+        assert_eq!(evaluate_expression(&mut sheet, "B1/-(A1-1)", 0, 2), (-5, None));
+        assert_eq!(evaluate_expression(&mut sheet, "SUM(A1:A2)/(B2-2)", 0, 2), (0, Some(ErrorKind::DivByZero)));
+        assert_eq!(evaluate_expression(&mut sheet, "SUM(A1:A2", 0, 2), (0, Some(ErrorKind::Invalid)));
+        assert_eq!(evaluate_expression(&mut sheet, "FOO(A1:A2)", 0, 2), (0, Some(ErrorKind::Invalid)));
+    }
+
+    #[test]
+    fn test_parser_deeply_nested_expressions() {
+        let mut sheet = create_test_sheet(10, 10, true);
+        sheet.cells[0][0].value = 2; // A1
+        sheet.cells[0][1].value = 5; // B1
+
+        assert_eq!(evaluate_expression(&mut sheet, "((((A1+1)*2)-(B1/5))*(3+(4-(1+1))))", 0, 2), (25, None));
+        assert_eq!(evaluate_expression(&mut sheet, "-(-(-(A1)))", 0, 2), (-2, None));
+        let nested = format!("{}A1{}", "(".repeat(50), ")".repeat(50));
+        assert_eq!(evaluate_expression(&mut sheet, &nested, 0, 2), (2, None));
+
+        let expr = parse("1-(2-3)*SUM(A1:B1, C1:C2)").unwrap();
+        assert_eq!(expr.to_string(), "1-(2-3)*SUM(A1:B1, C1:C2)");
+        assert_eq!(parse(&expr.to_string()), Some(expr));
+        assert_eq!(parse("A1+"), None);
+        assert_eq!(parse("A1 B1"), None);
+        assert_eq!(tokenize("A1 $ 2"), None);
+    }
+
+    #[test]
+    fn test_mixed_range_function_and_arithmetic() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "A2=6");
+        process_command(&mut sheet, "B1=3");
+        assert_eq!(process_command(&mut sheet, "C1=SUM(A1:A2)+1"), None);
+        assert_eq!(sheet.cells[0][2].value, 11);
+        assert!(!sheet.cells[0][2].is_error);
+        process_command(&mut sheet, "C2=SUM(A1:A2)+B1*2");
+        assert_eq!(sheet.cells[1][2].value, 16);
+        process_command(&mut sheet, "A1=10");
+        assert_eq!(sheet.cells[0][2].value, 17);
+        assert_eq!(sheet.cells[1][2].value, 22);
+        assert!(!is_valid_formula(&mut sheet, "SUM(A1:A2)+Z9"));
+        assert!(!is_valid_formula(&mut sheet, "A1:A2+1"));
+    }
+
+    #[test]
+    fn test_date_construction() {
+        assert_eq!(days_from_civil(1970, 1, 1), Some(0));
+        assert_eq!(days_from_civil(2024, 1, 1), Some(19723));
+        assert_eq!(days_from_civil(1969, 12, 31), Some(-1));
+        assert_eq!(days_from_civil(2024, 2, 29), Some(19782));
+        assert_eq!(days_from_civil(2023, 2, 29), None);
+        assert_eq!(days_from_civil(2024, 13, 1), None);
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=DATE(2024, 3, 15)");
+        assert_eq!(sheet.cells[0][0].kind, DataKind::Date);
+        assert_eq!(format_cell_value(&sheet.cells[0][0]), "2024-03-15");
+        process_command(&mut sheet, "A2=DATE(2024, 2, 30)");
+        assert!(sheet.cells[1][0].is_error);
+
+        // Plain numbers stay numeric, even after overwriting a date cell
+        process_command(&mut sheet, "A1=42");
+        assert_eq!(sheet.cells[0][0].kind, DataKind::Number);
+        assert_eq!(format_cell_value(&sheet.cells[0][0]), "42");
+    }
+
+    #[test]
+    fn test_datediff() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=DATE(2024, 1, 1)");
+        process_command(&mut sheet, "B1=DATE(2024, 3, 1)");
+        process_command(&mut sheet, "C1=DATEDIFF(A1, B1)");
+        assert_eq!(sheet.cells[0][2].value, 60);
+        assert_eq!(sheet.cells[0][2].kind, DataKind::Number);
+
+        process_command(&mut sheet, "B1=DATE(2023, 12, 25)");
+        assert_eq!(sheet.cells[0][2].value, -7);
+        assert!(!is_valid_formula(&mut sheet, "DATEDIFF(A1)"));
+    }
+
+    #[test]
+    fn test_vlookup_exact_match() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        // Table in B1:D3 keyed by column B
+        for (r, (key, second, third)) in [(10, 100, 1000), (20, 200, 2000), (30, 300, 3000)]
+            .iter()
+            .enumerate()
+        {
+            process_command(&mut sheet, &format!("B{}={}", r + 1, key));
+            process_command(&mut sheet, &format!("C{}={}", r + 1, second));
+            process_command(&mut sheet, &format!("D{}={}", r + 1, third));
+        }
+        process_command(&mut sheet, "A1=20");
+        process_command(&mut sheet, "E1=VLOOKUP(A1, B1:D3, 3)");
+        assert_eq!(sheet.cells[0][4].value, 2000);
+        assert!(!sheet.cells[0][4].is_error);
+
+        // Changing the key or the table recomputes the lookup
+        process_command(&mut sheet, "A1=30");
+        assert_eq!(sheet.cells[0][4].value, 3000);
+        process_command(&mut sheet, "D3=7");
+        assert_eq!(sheet.cells[0][4].value, 7);
+
+        process_command(&mut sheet, "E2=VLOOKUP(10, B1:D3, 2)+1");
+        assert_eq!(sheet.cells[1][4].value, 101);
+    }
+
+    #[test]
+    fn test_vlookup_missing_key() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        process_command(&mut sheet, "B1=1");
+        process_command(&mut sheet, "C1=5");
+        process_command(&mut sheet, "A1=99");
+        process_command(&mut sheet, "E1=VLOOKUP(A1, B1:C1, 2)");
+        assert!(sheet.cells[0][4].is_error);
+
+        // Column offsets outside the range are errors too
+        process_command(&mut sheet, "E2=VLOOKUP(1, B1:C1, 3)");
+        assert!(sheet.cells[1][4].is_error);
+        assert!(!is_valid_formula(&mut sheet, "VLOOKUP(A1, B1, 2)"));
+
+        // Neither an empty key cell nor a text key cell matches 0, which both store
+        process_command(&mut sheet, "C2=7");
+        process_command(&mut sheet, "E3=VLOOKUP(0, B1:C2, 2)");
+        assert!(sheet.cells[2][4].is_error);
+        process_command(&mut sheet, "B2=\"abc\"");
+        process_command(&mut sheet, "E3=VLOOKUP(0, B1:C2, 2)");
+        assert!(sheet.cells[2][4].is_error);
+        process_command(&mut sheet, "B2=0");
+        assert_eq!(sheet.cells[2][4].value, 7);
+        assert!(!sheet.cells[2][4].is_error);
+    }
+
+    #[test]
+    fn test_index_valid_offsets() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        for r in 0..3 {
+            for c in 0..3 {
+                sheet.cells[r][c].value = (r * 10 + c) as i32;
+            }
+        }
+        process_command(&mut sheet, "E1=INDEX(A1:C3, 2, 1)");
+        assert_eq!(sheet.cells[0][4].value, 10);
+        process_command(&mut sheet, "E2=INDEX(A1:C3, 3, 3)");
+        assert_eq!(sheet.cells[1][4].value, 22);
+        process_command(&mut sheet, "E3=INDEX(B2:C3, 1, 2)*2");
+        assert_eq!(sheet.cells[2][4].value, 24);
+
+        // The result follows changes to the indexed cell
+        process_command(&mut sheet, "A2=7");
+        assert_eq!(sheet.cells[0][4].value, 7);
+    }
+
+    #[test]
+    fn test_index_out_of_bounds_offsets() {
+        let mut sheet = create_test_sheet(10, 10, false);
+        process_command(&mut sheet, "E1=INDEX(A1:C3, 4, 1)");
+        assert!(sheet.cells[0][4].is_error);
+        process_command(&mut sheet, "E2=INDEX(A1:C3, 1, 0)");
+        assert!(sheet.cells[1][4].is_error);
+        process_command(&mut sheet, "E3=INDEX(A1:C3, -1, 1)");
+        assert!(sheet.cells[2][4].is_error);
+        assert!(!is_valid_formula(&mut sheet, "INDEX(A1, 1, 1)"));
+    }
+
+    #[test]
+    fn test_recompute_restores_cached_values() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=5");
+        process_command(&mut sheet, "A2=A1*2");
+        process_command(&mut sheet, "A3=SUM(A1:A2)+A2");
+        assert_eq!(sheet.cells[2][0].value, 25);
+
+        // Corrupt cached values directly, bypassing dependency tracking
+        sheet.cells[1][0].value = 999;
+        sheet.cells[2][0].value = -1;
+        sheet.cells[2][0].is_error = true;
+
+        assert_eq!(process_command(&mut sheet, "RECOMPUTE"), None);
+        assert_eq!(sheet.cells[1][0].value, 10);
+        assert_eq!(sheet.cells[2][0].value, 25);
+        assert!(!sheet.cells[2][0].is_error);
+
+        // Circular formulas are still flagged after a recompute
+        process_command(&mut sheet, "A1=A3");
+        assert!(sheet.cells[0][0].has_circular);
+        process_command(&mut sheet, "RECOMPUTE");
+        assert!(sheet.cells[0][0].has_circular);
+        assert!(sheet.circular_dependency_detected);
+        assert!(is_valid_command(&mut sheet, "RECOMPUTE"));
+    }
+
+    #[test]
+    fn test_lock_rejects_assignment_until_unlocked() {
+        let _clipboard = lock_clipboard();
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=5");
+        assert_eq!(process_command(&mut sheet, "LOCK A1:B2"), None);
+        assert!(sheet.cells[1][1].is_locked);
+        assert!(!sheet.cells[2][2].is_locked);
+
+        assert_eq!(
+            process_command(&mut sheet, "A1=10"),
+            Some("Cell A1 is locked".to_string())
+        );
+        assert_eq!(sheet.cells[0][0].value, 5);
+        update_cell(&mut sheet, 0, 0, "20");
+        assert_eq!(sheet.cells[0][0].value, 5);
+
+        // Locked cells still recalculate when their inputs change
+        process_command(&mut sheet, "C1=3");
+        sheet.cells[0][1].is_locked = false;
+        process_command(&mut sheet, "B1=C1*2");
+        process_command(&mut sheet, "LOCK B1");
+        process_command(&mut sheet, "C1=4");
+        assert_eq!(sheet.cells[0][1].value, 8);
+
+        // Pasting over or cutting a locked cell is refused
+        process_command(&mut sheet, "C2=7");
+        process_command(&mut sheet, "COPY C2:C2");
+        assert!(!paste_range(&mut sheet, 0, 0));
+        assert_eq!(sheet.cells[0][0].value, 5);
+        assert!(!cut_range(&mut sheet, 0, 0, 0, 0));
+
+        assert_eq!(process_command(&mut sheet, "UNLOCK A1"), None);
+        assert_eq!(process_command(&mut sheet, "A1=10"), None);
+        assert_eq!(sheet.cells[0][0].value, 10);
+        assert!(is_valid_command(&mut sheet, "LOCK A1:B2"));
+        assert!(!is_valid_command(&mut sheet, "LOCK Z99"));
+    }
+
+    #[test]
+    fn test_strike_and_color_formatting() {
+        let _clipboard = lock_clipboard();
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=5");
+        assert_eq!(process_command(&mut sheet, "A1=STRIKE(A1)"), None);
+        assert!(sheet.cells[0][0].is_strike);
+        assert_eq!(sheet.cells[0][0].value, 5);
+
+        assert_eq!(process_command(&mut sheet, "A1=COLOR(A1, Red)"), None);
+        assert_eq!(sheet.cells[0][0].color, Some("red".to_string()));
+        assert_eq!(
+            process_command(&mut sheet, "A1=COLOR(A1, orange)"),
+            Some("Unknown color: orange".to_string())
+        );
+        assert_eq!(sheet.cells[0][0].color, Some("red".to_string()));
+        // The refused color adds no undo step, and the accepted one takes a single step
+        let undo_steps = sheet.undo_stack.len();
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.cells[0][0].color, None);
+        assert!(sheet.cells[0][0].is_strike);
+        assert_eq!(sheet.undo_stack.len(), undo_steps - 1);
+        process_command(&mut sheet, "redo");
+        assert!(is_valid_formula(&mut sheet, "COLOR(A1, blue)"));
+        assert!(!is_valid_formula(&mut sheet, "COLOR(A1)"));
+
+        // Copy/paste carries the formatting along with the value
+        process_command(&mut sheet, "COPY A1:A1");
+        process_command(&mut sheet, "PASTE B2");
+        assert_eq!(sheet.cells[1][1].value, 5);
+        assert!(sheet.cells[1][1].is_strike);
+        assert_eq!(sheet.cells[1][1].color, Some("red".to_string()));
+    }
+
+    #[test]
+    fn test_formatting_commands_toggle() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=BOLD(A1)");
+        assert!(sheet.cells[0][0].is_bold);
+        process_command(&mut sheet, "A1=BOLD(A1)");
+        assert!(!sheet.cells[0][0].is_bold);
+
+        process_command(&mut sheet, "A1=ITALIC(A1)");
+        process_command(&mut sheet, "A1=STRIKE(A1)");
+        process_command(&mut sheet, "A1=ITALIC(A1)");
+        assert!(!sheet.cells[0][0].is_italic);
+        assert!(sheet.cells[0][0].is_strike);
+
+        process_command(&mut sheet, "A1=COLOR(A1, red)");
+        process_command(&mut sheet, "A1=COLOR(A1, blue)");
+        assert_eq!(sheet.cells[0][0].color, Some("blue".to_string()));
+        process_command(&mut sheet, "A1=COLOR(A1, BLUE)");
+        assert_eq!(sheet.cells[0][0].color, None);
+    }
+
+    #[test]
+    fn test_formatting_applies_to_range() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert_eq!(process_command(&mut sheet, "A1=BOLD(A1:C3)"), None);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!(sheet.cells[i][j].is_bold);
+            }
+        }
+        assert!(!sheet.cells[3][0].is_bold);
+        assert!(!sheet.cells[0][3].is_bold);
+
+        // A partially bold range is made fully bold first, then cleared
+        process_command(&mut sheet, "A1=BOLD(A1)");
+        process_command(&mut sheet, "A1=BOLD(A1:B2)");
+        assert!(sheet.cells[0][0].is_bold && sheet.cells[1][1].is_bold);
+        process_command(&mut sheet, "A1=BOLD(A1:B2)");
+        assert!(!sheet.cells[0][0].is_bold && !sheet.cells[1][1].is_bold);
+        assert!(sheet.cells[2][2].is_bold);
+
+        process_command(&mut sheet, "A1=COLOR(B1:B3, green)");
+        assert_eq!(sheet.cells[2][1].color, Some("green".to_string()));
+
+        // Range formatting can be undone in one step
+        assert!(undo(&mut sheet));
+        assert_eq!(sheet.cells[2][1].color, None);
+    }
+
+    #[test]
+    fn test_merge_region_bookkeeping() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=7");
+        assert_eq!(process_command(&mut sheet, "MERGE A1:C1"), None);
+        assert_eq!(sheet.merged_regions, vec![(0, 0, 0, 2)]);
+        assert_eq!(sheet.merged_region_at(0, 2), Some((0, 0, 0, 2)));
+        assert_eq!(sheet.merged_region_at(1, 0), None);
+
+        assert_eq!(
+            process_command(&mut sheet, "MERGE B1:B3"),
+            Some("Range overlaps an existing merged region".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "B1=5"),
+            Some("Cell B1 is hidden by a merged region".to_string())
+        );
+        assert_eq!(sheet.cells[0][1].value, 0);
+        // The top-left cell stays editable
+        assert_eq!(process_command(&mut sheet, "A1=8"), None);
+        assert_eq!(sheet.cells[0][0].value, 8);
+
+        assert_eq!(process_command(&mut sheet, "UNMERGE B1"), None);
+        assert!(sheet.merged_regions.is_empty());
+        assert_eq!(process_command(&mut sheet, "B1=5"), None);
+        assert_eq!(
+            process_command(&mut sheet, "UNMERGE B1"),
+            Some("No merged region at B1".to_string())
+        );
+        assert!(is_valid_command(&mut sheet, "MERGE A1:B2"));
+    }
+
+    #[test]
+    fn test_merge_display_width_spans_columns() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        sheet.output_enabled = false;
+        process_command(&mut sheet, "A1=123456");
+        assert_eq!(column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[0], 6);
+
+        // Merged across A1:C1 the value shares three columns (1 + 1 + 1 plus two separators)
+        process_command(&mut sheet, "MERGE A1:C1");
+        let widths = column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols());
+        assert_eq!(widths[0], 1);
+        assert_eq!(widths[1], 1);
+        assert_eq!(widths[0] + widths[1] + widths[2] + 2, 6);
+
+        // A value that fits in the span does not widen any column
+        process_command(&mut sheet, "A1=12");
+        assert_eq!(&column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[..3], &[1, 1, 1]);
+
+        // Rendering still works when the region's top-left cell is scrolled out of view
+        sheet.output_enabled = true;
+        sheet.view_col = 1;
+        assert_eq!(column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[0], 1);
+        format_grid(&sheet, &sheet.visible_rows(), &sheet.visible_cols(), true);
+    }
+
+    #[test]
+    fn test_is_valid_command_clipboard_prefixes() {
+        let mut sheet = create_test_sheet(10, 10, true);
+        assert!(is_valid_command(&mut sheet, "A1=5"));
+        assert!(is_valid_command(&mut sheet, "COPY A1:B2"));
+        assert!(is_valid_command(&mut sheet, "CUT A1:B2"));
+        assert!(is_valid_command(&mut sheet, "PASTE C3"));
+        assert!(!is_valid_command(&mut sheet, "COPY A1"));
+        assert!(!is_valid_command(&mut sheet, "PASTE A1:B2"));
+
+        // Short commands used to be sliced past their end and panic
+        assert!(!is_valid_command(&mut sheet, "CO"));
+        assert!(!is_valid_command(&mut sheet, "PAS"));
+    }
+
+    #[test]
+    fn test_is_valid_command_assignment_with_extensions() {
+        let mut sheet_ext = create_test_sheet(10, 10, true);
+        assert!(is_valid_command(&mut sheet_ext, "B2=A1+5"));
+        assert!(is_valid_command(&mut sheet_ext, "C3=SUM(A1:B2)"));
+        assert!(is_valid_command(&mut sheet_ext, "A1=BOLD(A1)"));
+        assert!(!is_valid_command(&mut sheet_ext, "B2=A1+INVALID"));
+        assert!(!is_valid_command(&mut sheet_ext, "Z99=5"));
+        // Keywords are still recognized ahead of assignments
+        assert!(is_valid_command(&mut sheet_ext, "GRAPH (BAR) A1:A5"));
+        assert!(!is_valid_command(&mut sheet_ext, "ROWDEL 11"));
+    }
+
+    #[test]
+    fn test_swap_formatted_cells() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=5");
+        process_command(&mut sheet, "A1=BOLD(A1)");
+        process_command(&mut sheet, "B1=C1*2");
+        process_command(&mut sheet, "B1=COLOR(B1, red)");
+        process_command(&mut sheet, "C1=4");
+        process_command(&mut sheet, "D1=A1+1");
+
+        assert_eq!(process_command(&mut sheet, "SWAP A1 B1"), None);
+        assert_eq!(sheet.cells[0][0].value, 8);
+        assert_eq!(sheet.cells[0][0].formula, Some("C1*2".to_string()));
+        assert!(!sheet.cells[0][0].is_bold);
+        assert_eq!(sheet.cells[0][0].color, Some("red".to_string()));
+        assert_eq!(sheet.cells[0][1].value, 5);
+        assert!(sheet.cells[0][1].is_bold);
+
+        // Dependents of the swapped cells see the new values, and moved formulas keep tracking
+        assert_eq!(sheet.cells[0][3].value, 9);
+        process_command(&mut sheet, "C1=10");
+        assert_eq!(sheet.cells[0][0].value, 20);
+        assert_eq!(sheet.cells[0][3].value, 21);
+
+        assert!(undo(&mut sheet));
+        assert_eq!(sheet.cells[0][0].value, 8);
+    }
+
+    #[test]
+    fn test_swap_columns() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        for i in 1..=3 {
+            process_command(&mut sheet, &format!("A{}={}", i, i));
+            process_command(&mut sheet, &format!("B{}={}", i, i * 10));
+        }
+        assert_eq!(process_command(&mut sheet, "SWAP A1:A3 B1:B3"), None);
+        for i in 0..3 {
+            assert_eq!(sheet.cells[i][0].value, (i as i32 + 1) * 10);
+            assert_eq!(sheet.cells[i][1].value, i as i32 + 1);
+        }
+
+        assert_eq!(
+            process_command(&mut sheet, "SWAP A1:A3 B1:B2"),
+            Some("SWAP ranges must have the same shape".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "SWAP A1:A3 A2:A4"),
+            Some("SWAP ranges must not overlap".to_string())
+        );
+        assert!(is_valid_command(&mut sheet, "SWAP A1:A3 B1:B3"));
+    }
+
+    #[test]
+    fn test_series_positive_step() {
+        let mut sheet = create_test_sheet(10, 5, true);
+        process_command(&mut sheet, "B1=A3*2");
+        assert_eq!(process_command(&mut sheet, "SERIES A1:A10 2 3"), None);
+        for i in 0..10 {
+            assert_eq!(sheet.cells[i][0].value, 2 + 3 * i as i32);
+        }
+        // Dependents pick up the filled values
+        assert_eq!(sheet.cells[0][1].value, 16);
+
+        // Row ranges fill left to right
+        process_command(&mut sheet, "SERIES C1:E1 1 1");
+        assert_eq!(sheet.cells[0][2].value, 1);
+        assert_eq!(sheet.cells[0][4].value, 3);
+
+        assert!(undo(&mut sheet));
+        assert_eq!(sheet.cells[0][2].value, 0);
+    }
+
+    #[test]
+    fn test_series_negative_step_and_single_cell() {
+        let mut sheet = create_test_sheet(10, 5, true);
+        process_command(&mut sheet, "SERIES A1:A4 10 -4");
+        assert_eq!(sheet.cells[0][0].value, 10);
+        assert_eq!(sheet.cells[1][0].value, 6);
+        assert_eq!(sheet.cells[3][0].value, -2);
+
+        process_command(&mut sheet, "SERIES B2 7 100");
+        assert_eq!(sheet.cells[1][1].value, 7);
+        assert_eq!(sheet.cells[2][1].value, 0);
+
+        assert_eq!(
+            process_command(&mut sheet, "SERIES A1:A4 2147483647 1"),
+            Some("SERIES values overflow".to_string())
+        );
+        assert_eq!(sheet.cells[0][0].value, 10);
+        assert!(!is_valid_command(&mut sheet, "SERIES A1:A4 x 1"));
+    }
+
+    #[test]
+    fn test_long_dependency_chain_does_not_overflow() {
+        // A chain built through commands evaluates end to end
+        let mut sheet = create_test_sheet(500, 1, false);
+        process_command(&mut sheet, "A1=1");
+        for i in 2..=500 {
+            process_command(&mut sheet, &format!("A{}=A{}+1", i, i - 1));
+        }
+        assert_eq!(sheet.cells[499][0].value, 500);
+        process_command(&mut sheet, "A1=11");
+        assert_eq!(sheet.cells[499][0].value, 510);
+
+        // A much deeper chain, wired directly into the graph, is walked without recursion
+        let n = 200_000;
+        let mut sheet = create_test_sheet(n, 1, false);
+        for i in 1..n {
+            sheet.dependency_graph.insert(
+                (i, 0),
+                CellDependencies {
+                    dependencies: vec![DependencyType::Single { row: i - 1, col: 0 }],
+                    dependents: vec![],
+                },
+            );
+        }
+        assert!(has_circular_dependency(&mut sheet, 0, 0, &format!("A{}", n)));
+        assert!(!has_circular_dependency(&mut sheet, n - 1, 0, "5"));
+    }
+
+    #[test]
+    fn test_load_csv_file_semicolon_delimited() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "1;2;=A1+B1\n4;5;6").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        assert!(load_csv_file(&mut sheet, path, ';').is_ok());
+        assert_eq!(sheet.cells[0][1].value, 2);
+        assert_eq!(sheet.cells[0][2].value, 3);
+        assert_eq!(sheet.cells[1][2].value, 6);
+    }
+
+    #[test]
+    fn test_load_csv_file_tab_delimited() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "7\t8\n9\t=A1*A2").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        assert!(load_csv_file(&mut sheet, path, '\t').is_ok());
+        assert_eq!(sheet.cells[0][1].value, 8);
+        assert_eq!(sheet.cells[1][0].value, 9);
+        assert_eq!(sheet.cells[1][1].value, 63);
+
+        // With the wrong delimiter each line lands in a single cell
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert!(load_csv_file(&mut sheet, path, ',').is_ok());
+        assert_eq!(sheet.cells[0][1].value, 0);
+    }
+
+    #[test]
+    fn test_load_csv_file_quoted_field_with_comma() {
+        let mut sheet = create_test_sheet(5, 3, true);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "\"1,234\",\"42\",5\n\"=C1+3\",7").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        // Three fields, not four, so the file still fits in three columns
+        assert!(load_csv_file(&mut sheet, path, ',').is_ok());
+        assert_eq!(sheet.cells[0][0].value, 0);
+        assert_eq!(sheet.cells[0][1].value, 42);
+        assert_eq!(sheet.cells[0][2].value, 5);
+        assert_eq!(sheet.cells[1][0].value, 8);
+    }
+
+    #[test]
+    fn test_split_csv_line_escaped_quotes() {
+        assert_eq!(
+            split_csv_line(r#""say ""hi""",7"#, ','),
+            vec!["say \"hi\"".to_string(), "7".to_string()]
+        );
+        assert_eq!(
+            split_csv_line(r#""""";"a;b";"#, ';'),
+            vec!["\"".to_string(), "a;b".to_string(), String::new()]
+        );
+        assert_eq!(split_csv_line("1,2", ','), vec!["1".to_string(), "2".to_string()]);
+    }
+
+    #[test]
+    fn test_load_csv_file_with_header() {
+        let mut sheet = create_test_sheet(5, 3, true);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "Price,,Total\n10,x,=A1*2\n20,5,=A2*2").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        let warnings = load_csv_file_with_header(&mut sheet, path, ',').unwrap();
+        assert_eq!(warnings, vec!["Cell B1: skipped non-numeric value \"x\"".to_string()]);
+        // The header line is not loaded as data
+        assert_eq!(sheet.cells[0][0].value, 10);
+        assert_eq!(sheet.cells[0][2].value, 20); // C1 = A1*2
+        assert_eq!(sheet.cells[1][2].value, 40); // C2 = A2*2
+        assert_eq!(
+            sheet.column_headers,
+            vec![Some("Price".to_string()), None, Some("Total".to_string())]
+        );
+        assert_eq!(sheet.column_label(0), "A (Price)");
+        assert_eq!(sheet.column_label(1), "B");
+        assert_eq!(column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[2], "C (Total)".len());
+    }
+
+    #[test]
+    fn test_totalcol_and_totalrow() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "A2=2");
+        process_command(&mut sheet, "A3=3");
+        assert!(is_valid_command(&mut sheet, "TOTALCOL A1:A3 A4"));
+        assert_eq!(process_command(&mut sheet, "TOTALCOL A1:A3 A4"), None);
+        assert_eq!(sheet.cells[3][0].formula, Some("SUM(A1:A3)".to_string()));
+        assert_eq!(sheet.cells[3][0].value, 6);
+
+        // The total follows changes to its sources
+        process_command(&mut sheet, "A2=10");
+        assert_eq!(sheet.cells[3][0].value, 14);
+
+        assert_eq!(process_command(&mut sheet, "TOTALROW A1:C1 D1"), None);
+        assert_eq!(sheet.cells[0][3].value, 1);
+
+        // Targets must be adjacent and empty
+        assert_eq!(
+            process_command(&mut sheet, "TOTALCOL A1:A3 A5"),
+            Some("TOTALCOL target must be the cell just below a single-column range".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "TOTALROW A1:A2 B1"),
+            Some("TOTALROW target must be the cell just right of a single-row range".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "TOTALCOL A1:A2 A3"),
+            Some("Cell A3 is not empty".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "TOTALCOL A1:A3"),
+            Some("Invalid TOTALCOL format: use TOTALCOL <range> <cell>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_insert_row_grows_summed_range() {
+        let mut sheet = create_test_sheet(6, 3, true);
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "A2=2");
+        process_command(&mut sheet, "A3=3");
+        process_command(&mut sheet, "B1=A3*2");
+        process_command(&mut sheet, "A4=SUM(A1:A3)");
+
+        // Inserting inside the summed range moves the rows below and grows the range
+        assert_eq!(process_command(&mut sheet, "INSERTROW 2"), None);
+        assert_eq!(sheet.cells[4][0].formula, Some("SUM(A1:A4)".to_string()));
+        assert_eq!(sheet.cells[0][1].formula, Some("A4*2".to_string()));
+        assert_eq!(sheet.cells[3][0].value, 3);
+        process_command(&mut sheet, "A2=10");
+        assert_eq!(sheet.cells[4][0].value, 16);
+
+        // A row added at the bottom only joins the sum when EXTEND is given
+        assert_eq!(process_command(&mut sheet, "INSERTROW 5"), None);
+        assert_eq!(sheet.cells[5][0].formula, Some("SUM(A1:A4)".to_string()));
+        process_command(&mut sheet, "undo");
+        assert_eq!(process_command(&mut sheet, "INSERTROW 5 EXTEND"), None);
+        assert_eq!(sheet.cells[5][0].formula, Some("SUM(A1:A5)".to_string()));
+        process_command(&mut sheet, "A5=4");
+        assert_eq!(sheet.cells[5][0].value, 20);
+
+        // The sum now sits in the last row, so there is no room for another row
+        assert_eq!(
+            process_command(&mut sheet, "INSERTROW 1"),
+            Some("Cannot insert a row: the last row is in use".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "INSERTROW 7"));
+    }
+
+    #[test]
+    fn test_compare_ranges() {
+        let mut sheet = create_test_sheet(5, 4, true);
+        for (i, (a, b)) in [(1, 1), (2, 2), (5, 7), (4, 4)].iter().enumerate() {
+            process_command(&mut sheet, &format!("A{}={}", i + 1, a));
+            process_command(&mut sheet, &format!("B{}={}", i + 1, b));
+        }
+        process_command(&mut sheet, "B4=1/0");
+
+        assert!(is_valid_command(&mut sheet, "COMPARE A1:A4 B1:B4"));
+        assert_eq!(
+            process_command(&mut sheet, "COMPARE A1:A4 B1:B4"),
+            Some("2 difference(s): Row 3: 5 vs 7; Row 4: 4 vs #DIV/0!".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "COMPARE A1:A2 B1:B2"),
+            Some("Ranges match".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "COMPARE A3:B3 B3:C3"),
+            Some("2 difference(s): A3: 5 vs 7; B3: 7 vs 0".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "COMPARE A1:A4 B1:B3"),
+            Some("COMPARE ranges must have the same shape".to_string())
+        );
+        // Comparing does not change the sheet
+        assert_eq!(sheet.cells[2][0].value, 5);
+        assert_eq!(sheet.cells[2][1].value, 7);
+    }
+
+    #[test]
+    fn test_freeze_keeps_header_visible() {
+        let mut sheet = create_test_sheet(30, 30, true);
+        process_command(&mut sheet, "A1=7");
+        process_command(&mut sheet, "B12=123456");
+        assert!(is_valid_command(&mut sheet, "FREEZE 1 1"));
+        assert_eq!(process_command(&mut sheet, "FREEZE 1 1"), None);
+
+        // After scrolling down and right, row 1 and column A are still rendered first
+        process_command(&mut sheet, "s");
+        process_command(&mut sheet, "d");
+        assert_eq!(sheet.visible_rows()[..3], [0, 10, 11]);
+        assert_eq!(sheet.visible_cols()[..3], [0, 10, 11]);
+        assert_eq!(sheet.visible_rows().len(), 11);
+        // Widths follow the displayed columns, frozen column first
+        let widths = column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols());
+        assert_eq!(widths.len(), 11);
+        assert_eq!(widths[0], 1);
+
+        process_command(&mut sheet, "a");
+        assert_eq!(column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[1], 6); // B holds 123456 in row 12
+
+        assert_eq!(process_command(&mut sheet, "FREEZE 0 0"), None);
+        assert_eq!(sheet.visible_rows()[0], 10);
+        assert_eq!(
+            process_command(&mut sheet, "FREEZE 31 0"),
+            Some("FREEZE counts are outside the sheet".to_string())
+        );
+    }
+
+    #[test]
+    fn test_scientific_notation_display() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=1234567");
+        process_command(&mut sheet, "A2=0-2500000");
+        process_command(&mut sheet, "A3=999999");
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "1234567");
+        assert_eq!(column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[0], 8);
+
+        assert!(is_valid_command(&mut sheet, "SCINOTATION on"));
+        assert_eq!(process_command(&mut sheet, "SCINOTATION on"), None);
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "1.2e6");
+        assert_eq!(sheet.display_value(&sheet.cells[1][0]), "-2.5e6");
+        assert_eq!(sheet.display_value(&sheet.cells[2][0]), "999999");
+        assert_eq!(column_widths(&sheet, &sheet.visible_rows(), &sheet.visible_cols())[0], 6);
+        // Only the display changes
+        assert_eq!(sheet.cells[0][0].value, 1234567);
+
+        assert_eq!(process_command(&mut sheet, "SCINOTATION off"), None);
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "1234567");
+        assert!(!is_valid_command(&mut sheet, "SCINOTATION maybe"));
+    }
+
+    #[test]
+    fn test_cell_history() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert_eq!(
+            process_command(&mut sheet, "HISTORY A1"),
+            Some("No history recorded for cell A1".to_string())
+        );
+        process_command(&mut sheet, "B1=4");
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "A1=2");
+        process_command(&mut sheet, "A1=B1*3");
+        assert!(is_valid_command(&mut sheet, "HISTORY A1"));
+        assert_eq!(
+            process_command(&mut sheet, "HISTORY A1"),
+            Some("History of cell A1: 1 = 1, 2 = 2, B1*3 = 12".to_string())
+        );
+
+        // Only the most recent entries are kept
+        for i in 0..sheet.max_history_size + 5 {
+            process_command(&mut sheet, &format!("C1={}", i));
+        }
+        assert_eq!(sheet.cell_history[&(0, 2)].len(), sheet.max_history_size);
+        assert_eq!(sheet.cell_history[&(0, 2)][0], (5, "5".to_string()));
+    }
+
+    #[test]
+    fn test_elapsed_function() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        sheet.last_elapsed = 2.7;
+        assert!(is_valid_command(&mut sheet, "A1=ELAPSED()"));
+        assert_eq!(process_command(&mut sheet, "A1=ELAPSED()"), None);
+        assert_eq!(sheet.cells[0][0].value, 2);
+        assert_eq!(process_command(&mut sheet, "A2=ELAPSED()*1000+1"), None);
+        assert_eq!(sheet.cells[1][0].value, 2001);
+        assert!(!is_valid_command(&mut sheet, "A3=ELAPSED(1)"));
+
+        // ELAPSED is only available in extension mode
+        let mut basic = create_test_sheet(5, 5, false);
+        assert!(!is_valid_command(&mut basic, "A1=ELAPSED()"));
+    }
+
+    #[test]
+    fn test_recall_command_history() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        assert_eq!(recall_command(&mut sheet, "!!"), None);
+        assert!(sheet.command_history.is_empty());
+
+        assert_eq!(recall_command(&mut sheet, "A1=A1+1"), Some("A1=A1+1".to_string()));
+        let command = recall_command(&mut sheet, "!!").unwrap();
+        assert_eq!(command, "A1=A1+1");
+        process_command(&mut sheet, &command);
+        assert_eq!(recall_command(&mut sheet, ""), Some(String::new()));
+        assert_eq!(sheet.command_history, vec!["A1=A1+1".to_string(), "A1=A1+1".to_string()]);
+        assert_eq!(sheet.command_position, 2);
+
+        // The history is bounded like the undo stack
+        for i in 0..sheet.max_history_size + 3 {
+            recall_command(&mut sheet, &format!("B1={}", i));
+        }
+        assert_eq!(sheet.command_history.len(), sheet.max_history_size);
+        assert_eq!(sheet.command_history[0], "B1=3");
+        assert_eq!(sheet.command_position, sheet.max_history_size);
+
+        // Repeating !-1 walks back through the history, like pressing the up arrow
+        assert_eq!(recall_command(&mut sheet, "!-1"), Some("B1=12".to_string()));
+        assert_eq!(sheet.command_position, sheet.max_history_size - 2);
+        assert_eq!(recall_command(&mut sheet, "!-1"), Some("B1=11".to_string()));
+        assert_eq!(recall_command(&mut sheet, "!-2"), Some("B1=9".to_string()));
+        assert_eq!(recall_command(&mut sheet, "!-9"), None);
+        // A new command starts again from the newest entry
+        assert_eq!(recall_command(&mut sheet, "C1=1"), Some("C1=1".to_string()));
+        assert_eq!(sheet.command_position, sheet.max_history_size);
+        assert_eq!(recall_command(&mut sheet, "!-2"), Some("B1=9".to_string()));
+        assert_eq!(recall_command(&mut sheet, "!-0"), Some("!-0".to_string()));
+    }
+
+    #[test]
+    fn test_show_range() {
+        let mut sheet = create_test_sheet(20, 20, true);
+        process_command(&mut sheet, "B2=7");
+        process_command(&mut sheet, "C3=1234");
+        process_command(&mut sheet, "s");
+        assert_eq!(format_grid(&sheet, &[1], &[1, 2], false), "     B C \n   2 7 0 \n");
+
+        // SHOW ignores the current view
+        assert!(is_valid_command(&mut sheet, "SHOW B2:C3"));
+        let shown = process_command(&mut sheet, "SHOW B2:C3").unwrap();
+        assert_eq!(shown, "     B C    \n   2 7    0 \n   3 0 1234 ");
+        assert!(shown.contains("1234"));
+        assert_eq!(
+            process_command(&mut sheet, "SHOW B2:Z99"),
+            Some("Invalid SHOW format: use SHOW <range>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cell_reference_error_messages() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert_eq!(
+            process_command(&mut sheet, "A0=1"),
+            Some("Cell reference out of bounds: A0".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "F1=1"),
+            Some("Cell reference out of bounds: F1".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "1A=1"),
+            Some("Invalid cell reference: 1A".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "FORMULA A-1"),
+            Some("Invalid cell reference: A-1".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "scroll_to A99"),
+            Some("Cell reference out of bounds: A99".to_string())
+        );
+        assert_eq!(
+            parse_cell_reference(&mut sheet, "ABCDEFGH1"),
+            Err(ParseError::OutOfBounds)
+        );
+        assert_eq!(
+            parse_cell_reference(&mut sheet, "A99999999999"),
+            Err(ParseError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_mode_range_function() {
+        let mut sheet = create_test_sheet(10, 10, false);
+
+        // Clear mode: A1:A5 = [4, 7, 7, 2, 7]
+        for (i, value) in [4, 7, 7, 2, 7].iter().enumerate() {
+            sheet.cells[i][0].value = *value;
+        }
+        assert_eq!(range_function(&mut sheet, "MODE", "A1:A5"), Ok(7.0));
+
+        // Tie between 3 and 9 is broken by the smallest value
+        for (i, value) in [9, 3, 9, 3, 5].iter().enumerate() {
+            sheet.cells[i][1].value = *value;
+        }
+        assert_eq!(range_function(&mut sheet, "MODE", "B1:B5"), Ok(3.0));
+
+        // All values distinct: every value ties, so the smallest is returned
+        for (i, value) in [8, -2, 6].iter().enumerate() {
+            sheet.cells[i][2].value = *value;
+        }
+        assert_eq!(range_function(&mut sheet, "MODE", "C1:C3"), Ok(-2.0));
+
+        // Any error cell makes the whole range an error
+        sheet.cells[1][0].is_error = true;
+        assert_eq!(range_function(&mut sheet, "MODE", "A1:A5"), Err(()));
+
+        process_command(&mut sheet, "D1=MODE(B1:B5)");
+        assert_eq!(sheet.cells[0][3].value, 3);
+    }
+
+    #[test]
+    fn test_decode_column_rejects_columns_beyond_max() {
+        assert_eq!(decode_column("A"), Some(0));
+        assert_eq!(decode_column("aa"), Some(26));
+        assert_eq!(decode_column("ZZZ"), Some(crate::types::MAX_COLS - 1));
+        assert_eq!(decode_column("AAAA"), None);
+        assert_eq!(decode_column(&"Z".repeat(1000)), None); // Would overflow without the guard
+        assert_eq!(decode_column(""), None);
+        assert_eq!(decode_column("A1"), None);
+
+        let mut sheet = create_test_sheet(10, 10, false);
+        assert_eq!(
+            parse_cell_reference(&mut sheet, &format!("{}1", "Z".repeat(1000))),
+            Err(ParseError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_delete_range_shifts_cells_up() {
+        let mut sheet = create_test_sheet(8, 3, true);
+        for row in 1..=6 {
+            process_command(&mut sheet, &format!("A{}={}", row, row * 10));
+        }
+        process_command(&mut sheet, "B1=A5+1");
+        process_command(&mut sheet, "B2=SUM(A1:A6)");
+        process_command(&mut sheet, "C6=5");
+
+        // A2:A4 go away and A5:A6 move up into A2:A3; column C is untouched
+        assert_eq!(process_command(&mut sheet, "DELETERANGE A2:A4 up"), None);
+        assert_eq!(sheet.cells[1][0].value, 50);
+        assert_eq!(sheet.cells[2][0].value, 60);
+        assert_eq!(sheet.cells[3][0].value, 0);
+        assert_eq!(sheet.cells[5][2].value, 5);
+
+        // The downstream formulas follow the moved cells and the sum loses the deleted rows
+        assert_eq!(sheet.cells[0][1].formula, Some("A2+1".to_string()));
+        assert_eq!(sheet.cells[0][1].value, 51);
+        assert_eq!(sheet.cells[1][1].formula, Some("SUM(A1:A3)".to_string()));
+        assert_eq!(sheet.cells[1][1].value, 120);
+        process_command(&mut sheet, "A2=100");
+        assert_eq!(sheet.cells[0][1].value, 101);
+        assert_eq!(sheet.cells[1][1].value, 170);
+
+        // Cells that are still referenced, or ranges that would be split, cannot be deleted
+        assert_eq!(
+            process_command(&mut sheet, "DELETERANGE A2 up"),
+            Some("Cannot delete A2:A2: it is referenced by B1".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "DELETERANGE A1:A3 up"),
+            Some("Cannot delete A1:A3: it is referenced by B1".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "DELETERANGE A2:A4 down"));
+
+        // Undoing the assignment and then the deletion restores the original column
+        process_command(&mut sheet, "undo");
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.cells[4][0].value, 50);
+        assert_eq!(sheet.cells[0][1].formula, Some("A5+1".to_string()));
+    }
+
+    #[test]
+    fn test_stats_command_counts_cells() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "A2=0");
+        process_command(&mut sheet, "B1=A1*2");
+        process_command(&mut sheet, "C1=A1/A2");
+        assert!(sheet.cells[0][2].is_error);
+
+        assert!(is_valid_command(&mut sheet, "STATS"));
+        assert_eq!(
+            process_command(&mut sheet, "STATS"),
+            Some(
+                "Non-empty cells: 4, Formula cells: 2, Error cells: 1, Dependency links: 3, Circular dependency: none"
+                    .to_string()
+            )
+        );
+
+        process_command(&mut sheet, "A1=B1");
+        assert!(process_command(&mut sheet, "STATS")
+            .unwrap()
+            .ends_with("Circular dependency: detected"));
+    }
+
+    #[test]
+    fn test_sleep_not_repeated_on_unrelated_recalculation() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "B1=2");
+        process_command(&mut sheet, "C1=SLEEP(A1)+B1");
+        assert_eq!(sheet.cells[0][2].value, 3);
+        assert_eq!(sheet.sleep_cache.get(&(0, 2)), Some(&1));
+
+        // Changing B1 recalculates C1, but SLEEP(A1) still has the same duration
+        let start = std::time::Instant::now();
+        process_command(&mut sheet, "B1=5");
+        assert!(start.elapsed().as_secs_f64() < 0.5);
+        assert_eq!(sheet.cells[0][2].value, 6);
+
+        // A new duration is slept for and cached
+        process_command(&mut sheet, "A1=0");
+        assert_eq!(sheet.sleep_cache.get(&(0, 2)), Some(&0));
+        assert_eq!(sheet.cells[0][2].value, 5);
+    }
+
+    #[test]
+    fn test_cross_sheet_references() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=3");
+        process_command(&mut sheet, "A2=4");
+        assert_eq!(process_command(&mut sheet, "NEWSHEET Sheet2"), None);
+        assert_eq!(
+            process_command(&mut sheet, "NEWSHEET Sheet2"),
+            Some("Sheet Sheet2 already exists".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "NEWSHEET 2nd"));
+        assert!(!is_valid_command(&mut sheet, "SHEET Sheet3"));
+
+        // Formulas on Sheet2 read cells and ranges of Sheet1
+        assert_eq!(process_command(&mut sheet, "SHEET Sheet2"), None);
+        assert_eq!(sheet.workbook.active, "Sheet2");
+        assert!(is_valid_formula(&mut sheet, "Sheet1!A1+1"));
+        assert!(!is_valid_formula(&mut sheet, "Sheet3!A1+1"));
+        assert!(!is_valid_formula(&mut sheet, "Sheet1!F1"));
+        process_command(&mut sheet, "B1=Sheet1!A1*10");
+        process_command(&mut sheet, "B2=SUM(Sheet1!A1:A2)");
+        process_command(&mut sheet, "B3=B2+1");
+        assert_eq!(sheet.cells[0][1].value, 30);
+        assert_eq!(sheet.cells[1][1].value, 7);
+        assert_eq!(sheet.cells[2][1].value, 8);
+
+        // Editing Sheet1 recalculates the formulas on Sheet2 and their dependents
+        process_command(&mut sheet, "SHEET Sheet1");
+        assert_eq!(sheet.cells[0][1].value, 0);
+        process_command(&mut sheet, "A2=10");
+        let other = &sheet.workbook.sheets["Sheet2"];
+        assert_eq!(other.cells[0][1].value, 30);
+        assert_eq!(other.cells[1][1].value, 13);
+        assert_eq!(other.cells[2][1].value, 14);
+
+        process_command(&mut sheet, "A1=1/0");
+        assert!(sheet.workbook.sheets["Sheet2"].cells[0][1].is_error);
+    }
+
+    #[test]
+    fn test_show_formulas_mode() {
+        let mut sheet = create_test_sheet(3, 3, true);
+        process_command(&mut sheet, "A1=2");
+        process_command(&mut sheet, "B1=A1*21");
+        let rows = sheet.visible_rows();
+        let cols = sheet.visible_cols();
+        assert!(format_grid(&sheet, &rows, &cols, false).contains("42"));
+
+        assert_eq!(process_command(&mut sheet, "SHOWFORMULAS on"), None);
+        let grid = format_grid(&sheet, &rows, &cols, false);
+        assert!(grid.contains("A1*21"));
+        assert!(!grid.contains("42"));
+        // Plain numbers still show their value
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "2");
+
+        assert_eq!(process_command(&mut sheet, "SHOWFORMULAS off"), None);
+        assert_eq!(sheet.display_value(&sheet.cells[0][1]), "42");
+        assert!(!is_valid_command(&mut sheet, "SHOWFORMULAS maybe"));
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=10");
+        process_command(&mut sheet, "B1=A1/0");
+        assert!(sheet.cells[0][1].is_error);
+        assert_eq!(sheet.cells[0][1].error_kind, Some(ErrorKind::DivByZero));
+        assert_eq!(format_cell_value(&sheet.cells[0][1]), "#DIV/0!");
+
+        // Errors carry over to the cells that refer to them, including through ranges
+        process_command(&mut sheet, "C1=B1+1");
+        process_command(&mut sheet, "D1=SUM(A1:B1)");
+        assert_eq!(sheet.cells[0][2].error_kind, Some(ErrorKind::DivByZero));
+        assert_eq!(sheet.cells[0][3].error_kind, Some(ErrorKind::DivByZero));
+
+        // A reference outside the sheet, or outside a lookup range, is a bad reference
+        assert_eq!(
+            evaluate_expression(&mut sheet, "Z99+1", 1, 0),
+            (0, Some(ErrorKind::BadReference))
+        );
+        process_command(&mut sheet, "A2=INDEX(A1:B1, 1, 3)");
+        assert_eq!(sheet.cells[1][0].error_kind, Some(ErrorKind::BadReference));
+        assert_eq!(format_cell_value(&sheet.cells[1][0]), "#REF!");
+
+        process_command(&mut sheet, "A3=2147483647+1");
+        assert_eq!(sheet.cells[2][0].error_kind, Some(ErrorKind::Overflow));
+
+        // Fixing the cause clears the error
+        process_command(&mut sheet, "B1=A1/2");
+        assert!(!sheet.cells[0][2].is_error);
+        assert_eq!(sheet.cells[0][2].error_kind, None);
+        assert_eq!(sheet.cells[0][2].value, 6);
+    }
+
+    #[test]
+    fn test_command_queue_handles_concurrent_commands() {
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = create_sheet(30, 30, true);
+        *crate::types::COMMAND_QUEUE.lock().unwrap() = Some(crate::start_command_worker());
+        let client = Client::tracked(
+            rocket::build()
+                .mount("/", rocket::routes![index, command, scroll])
+                .attach(Template::fairing()),
+        )
+        .unwrap();
+        let post = |command: &str| {
+            client
+                .post("/command")
+                .header(ContentType::Form)
+                .body(format!("command={}", urlencoding::encode(command)))
+                .dispatch()
+                .headers()
+                .get_one("Location")
+                .unwrap()
+                .to_string()
+        };
+
+        // Commands are queued at once; posting does not wait for the SLEEP to finish
+        let start = std::time::Instant::now();
+        let sleeping = post("A1=SLEEP(1)");
+        let failing = post("XYZ");
+        post("B1=7");
+        assert!(start.elapsed().as_secs_f64() < 0.5);
+
+        // While the SLEEP runs, pages still load and scroll
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        let body = client.get(sleeping.as_str()).dispatch().into_string().unwrap();
+        assert!(body.contains("still running"));
+        client.post("/scroll/s").dispatch();
+        assert!(start.elapsed().as_secs_f64() < 0.5);
+
+        // The worker applies every command in turn, and the scroll is kept
+        let applied = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let sheet = SHEET.lock().unwrap();
+            let sheet = sheet.as_ref().unwrap();
+            sheet.cells[0][0].value == 1 && sheet.cells[0][1].value == 7
+        });
+        assert!(applied);
+        {
+            let sheet = SHEET.lock().unwrap();
+            let sheet = sheet.as_ref().unwrap();
+            assert_eq!(sheet.view_row, 10);
+            assert_eq!(sheet.undo_stack.len(), 2);
+        }
+
+        // Each message goes to the client that queued the command, and stays there
+        let body = client.get(failing.as_str()).dispatch().into_string().unwrap();
+        assert!(body.contains("Invalid command format"));
+        let body = client.get(sleeping.as_str()).dispatch().into_string().unwrap();
+        assert!(!body.contains("class=\"message\""));
+        let body = client.get("/").dispatch().into_string().unwrap();
+        assert!(!body.contains("class=\"message\""));
+        let body = client.get(failing.as_str()).dispatch().into_string().unwrap();
+        assert!(body.contains("Invalid command format"));
+
+        *crate::types::COMMAND_QUEUE.lock().unwrap() = None;
+        *SHEET.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_coldel_validation_checks_bounds() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert!(is_valid_command(&mut sheet, "COLDEL A"));
+        assert!(is_valid_command(&mut sheet, "COLDEL e"));
+        assert!(!is_valid_command(&mut sheet, "COLDEL F"));
+        assert!(!is_valid_command(&mut sheet, "COLDEL ZZ"));
+        assert!(!is_valid_command(&mut sheet, "COLDEL ZZZZZZZZ"));
+        assert!(!is_valid_command(&mut sheet, "COLDEL A1"));
+        assert!(!is_valid_command(&mut sheet, "COLDEL "));
+    }
+
+    #[test]
+    fn test_move_range_adjusts_internal_formulas() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "B1=9");
+        process_command(&mut sheet, "A2=A1*2");
+        process_command(&mut sheet, "B2=A1+E5");
+        process_command(&mut sheet, "E1=A2+1");
+
+        assert_eq!(process_command(&mut sheet, "MOVE A1:B2 C3"), None);
+
+        // The source is cleared and the block lands at C3:D4
+        assert_eq!(sheet.cells[0][0].value, 0);
+        assert_eq!(sheet.cells[1][0].formula, None);
+        assert_eq!(sheet.cells[2][2].value, 4);
+        assert_eq!(sheet.cells[2][3].value, 9);
+
+        // References into the block follow it, references outside it stay put
+        assert_eq!(sheet.cells[3][2].formula, Some("C3*2".to_string()));
+        assert_eq!(sheet.cells[3][2].value, 8);
+        assert_eq!(sheet.cells[3][3].formula, Some("C3+E5".to_string()));
+        process_command(&mut sheet, "C3=5");
+        assert_eq!(sheet.cells[3][2].value, 10);
+        process_command(&mut sheet, "E5=1");
+        assert_eq!(sheet.cells[3][3].value, 6);
+
+        // Formulas outside the block follow the moved cells
+        assert_eq!(sheet.cells[0][4].formula, Some("C4+1".to_string()));
+        assert_eq!(sheet.cells[0][4].value, 11);
+
+        // Undoing the two edits leaves the block where it was moved to
+        process_command(&mut sheet, "UNDO 2");
+        assert_eq!(sheet.cells[2][2].value, 4);
+        assert_eq!(sheet.cells[0][0].value, 0);
+
+        // Then one undo restores the whole move
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.cells[0][0].value, 4);
+        assert_eq!(sheet.cells[1][0].value, 8);
+        assert_eq!(sheet.cells[2][2].value, 0);
+
+        assert!(!is_valid_command(&mut sheet, "MOVE A1:B2 E5"));
+        assert_eq!(
+            process_command(&mut sheet, "MOVE A1:B2 E5"),
+            Some("Destination does not fit in the sheet".to_string())
+        );
+    }
+
+    #[test]
+    fn test_macros_expand_and_run() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert_eq!(process_command(&mut sheet, "ALIAS fmt BOLD"), None);
+        assert_eq!(process_command(&mut sheet, "fmt A1"), None);
+        assert!(sheet.cells[0][0].is_bold);
+
+        // A macro replays its commands in order
+        assert_eq!(process_command(&mut sheet, "MACRO setup A1=3; B1=A1*2"), None);
+        assert!(is_valid_command(&mut sheet, "RUN setup"));
+        assert_eq!(process_command(&mut sheet, "RUN setup"), None);
+        assert_eq!(sheet.cells[0][0].value, 3);
+        assert_eq!(sheet.cells[0][1].value, 6);
+
+        // Macros can run other macros, but not themselves
+        process_command(&mut sheet, "MACRO both setup; fmt B1");
+        assert_eq!(process_command(&mut sheet, "both"), None);
+        assert!(sheet.cells[0][1].is_bold);
+        process_command(&mut sheet, "MACRO ping pong");
+        process_command(&mut sheet, "MACRO pong ping");
+        assert_eq!(
+            process_command(&mut sheet, "ping"),
+            Some("Macro ping runs itself".to_string())
+        );
+
+        assert_eq!(
+            process_command(&mut sheet, "RUN missing"),
+            Some("No macro named missing".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "ALIAS A1 BOLD"),
+            Some("Invalid macro name: A1".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "ALIAS undo redo"));
+    }
+
+    #[test]
+    fn test_range_functions_accept_literals_and_cells() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "B1=9");
+        process_command(&mut sheet, "C1=2");
+        process_command(&mut sheet, "C2=6");
+
+        assert!(is_valid_formula(&mut sheet, "SUM(1, 2, 3)"));
+        process_command(&mut sheet, "D1=SUM(1, 2, 3)");
+        assert_eq!(sheet.cells[0][3].value, 6);
+        process_command(&mut sheet, "D2=AVG(A1, 5, B1)");
+        assert_eq!(sheet.cells[1][3].value, 6);
+        process_command(&mut sheet, "D3=MIN(A1, -3, C1:C2)");
+        assert_eq!(sheet.cells[2][3].value, -3);
+
+        // Cell arguments are dependencies like ranges are
+        process_command(&mut sheet, "B1=12");
+        assert_eq!(sheet.cells[1][3].value, 7);
+        process_command(&mut sheet, "A1=1/0");
+        assert!(sheet.cells[1][3].is_error);
+        assert_eq!(sheet.cells[1][3].error_kind, Some(ErrorKind::DivByZero));
+
+        assert!(!is_valid_formula(&mut sheet, "SUM(1, A1+2)"));
+        assert!(!is_valid_formula(&mut sheet, "SUM(1, Z9)"));
+    }
+
+    #[test]
+    fn test_validate_rejects_out_of_range_values() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A3=150");
+        assert_eq!(process_command(&mut sheet, "VALIDATE A1:A3 0 100"), None);
+        assert!(is_valid_command(&mut sheet, "VALIDATE A1:A3 0 100"));
+        assert!(!is_valid_command(&mut sheet, "VALIDATE A1:A3 100 0"));
+
+        // Values already in the range are checked too
+        assert!(sheet.cells[2][0].is_error);
+
+        assert_eq!(process_command(&mut sheet, "A1=40"), None);
+        assert_eq!(sheet.cells[0][0].value, 40);
+        assert_eq!(
+            process_command(&mut sheet, "A1=101"),
+            Some("Value 101 for A1 is outside the allowed range 0 to 100".to_string())
+        );
+        assert_eq!(sheet.cells[0][0].value, 40);
+        assert_eq!(sheet.cells[0][0].formula, Some("40".to_string()));
+
+        // Formulas and their recalculations flag results outside the range
+        process_command(&mut sheet, "A2=A1*2");
+        assert_eq!(sheet.cells[1][0].value, 80);
+        process_command(&mut sheet, "A1=60");
+        assert!(sheet.cells[1][0].is_error);
+        process_command(&mut sheet, "A1=10");
+        assert!(!sheet.cells[1][0].is_error);
+        assert_eq!(sheet.cells[1][0].value, 20);
+        process_command(&mut sheet, "B1=500");
+        assert_eq!(sheet.cells[0][1].value, 500);
+    }
+
+    #[test]
+    fn test_undo_steps_back_view_changes() {
+        let mut sheet = create_test_sheet(50, 50, true);
+        process_command(&mut sheet, "A1=5");
+        process_command(&mut sheet, "scroll_to C20");
+        process_command(&mut sheet, "d");
+        assert_eq!((sheet.view_row, sheet.view_col), (19, 12));
+
+        // Views scrolled since the last data change are undone first, one scroll at a time
+        assert_eq!(process_command(&mut sheet, "undo"), None);
+        assert_eq!((sheet.view_row, sheet.view_col), (19, 2));
+        assert_eq!(process_command(&mut sheet, "undo"), None);
+        assert_eq!((sheet.view_row, sheet.view_col), (0, 0));
+        assert_eq!(sheet.cells[0][0].value, 5);
+
+        // Then data is undone as before
+        assert_eq!(process_command(&mut sheet, "undo"), None);
+        assert_eq!(sheet.cells[0][0].value, 0);
+
+        // A data change forgets the views scrolled before it
+        process_command(&mut sheet, "s");
+        process_command(&mut sheet, "B1=1");
+        assert_eq!(process_command(&mut sheet, "undo"), None);
+        assert_eq!(sheet.cells[0][1].value, 0);
+        assert_eq!(sheet.view_row, 10);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow_sets_error() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=2000000000+2000000000");
+        assert!(sheet.cells[0][0].is_error);
+        assert_eq!(sheet.cells[0][0].value, 0);
+        process_command(&mut sheet, "A2=100000*100000");
+        assert!(sheet.cells[1][0].is_error);
+        assert_eq!(sheet.cells[1][0].value, 0);
+        process_command(&mut sheet, "A3=-2000000000-2000000000");
+        assert!(sheet.cells[2][0].is_error);
+
+        // Overflow is also caught when a dependency changes
+        process_command(&mut sheet, "B1=1000");
+        process_command(&mut sheet, "B2=B1*B1*B1");
+        assert_eq!(sheet.cells[1][1].value, 1000000000);
+        process_command(&mut sheet, "B1=2000");
+        assert!(sheet.cells[1][1].is_error);
+        assert_eq!(sheet.cells[1][1].error_kind, Some(ErrorKind::Overflow));
+    }
+
+    #[test]
+    fn test_value_and_text_functions() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=\"42\"");
+        process_command(&mut sheet, "A2=\"abc\"");
+        assert_eq!(sheet.cells[0][0].kind, DataKind::Text);
+        assert_eq!(format_cell_value(&sheet.cells[1][0]), "abc");
+
+        // VALUE parses text into a number; text cannot be used as a number directly
+        process_command(&mut sheet, "B1=VALUE(A1)+1");
+        assert_eq!(sheet.cells[0][1].value, 43);
+        process_command(&mut sheet, "B2=VALUE(A2)");
+        assert!(sheet.cells[1][1].is_error);
+        assert_eq!(sheet.cells[1][1].error_kind, Some(ErrorKind::Invalid));
+        process_command(&mut sheet, "B3=A1+1");
+        assert!(sheet.cells[2][1].is_error);
+
+        // TEXT pads a number with leading zeros
+        process_command(&mut sheet, "C1=7");
+        process_command(&mut sheet, "C2=TEXT(C1, \"000\")");
+        assert_eq!(format_cell_value(&sheet.cells[1][2]), "007");
+        process_command(&mut sheet, "C1=1234");
+        assert_eq!(format_cell_value(&sheet.cells[1][2]), "1234");
+        process_command(&mut sheet, "C3=VALUE(C2)");
+        assert_eq!(sheet.cells[2][2].value, 1234);
+        assert!(!is_valid_formula(&mut sheet, "TEXT(C1, \"0.00\")"));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = crate::parse_config(
+            "# Defaults for new sheets\nrows = 200\ncols = 30\n\nextension = true  # web UI\nview_size = 15\n",
+        )
+        .unwrap();
+        assert_eq!(config.rows, Some(200));
+        assert_eq!(config.cols, Some(30));
+        assert_eq!(config.extension, Some(true));
+        assert_eq!(config.view_size, Some(15));
+        assert_eq!(crate::parse_config("").unwrap(), crate::Config::default());
+
+        assert_eq!(
+            crate::parse_config("rows = many"),
+            Err("Invalid setting on line 1: rows = many".to_string())
+        );
+        assert_eq!(
+            crate::parse_config("rows = 5\ncolour = red"),
+            Err("Unknown setting on line 2: colour".to_string())
+        );
+        assert!(crate::parse_config("view_size = 0").is_err());
+
+        // The view size decides how far w/a/s/d scroll
+        let mut sheet = create_test_sheet(50, 50, false);
+        sheet.view_size = config.view_size;
+        process_command(&mut sheet, "s");
+        assert_eq!(sheet.view_row, 15);
+    }
+
+    #[test]
+    fn test_graph_written_to_file() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=3");
+        process_command(&mut sheet, "A2=5");
+        // Files are written to the current directory, so the name is unique to this test
+        let path = "test_graph_written_to_file.txt";
+
+        assert!(is_valid_command(&mut sheet, &format!("GRAPH (BAR) A1:A2 {}", path)));
+        let message = process_command(&mut sheet, &format!("GRAPH (BAR) A1:A2 {}", path)).unwrap();
+        let graph = display_graph(&mut sheet, GraphType::Bar, 0, 0, 1, 0);
+        let written = std::fs::read_to_string(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(written.unwrap(), graph);
+        assert!(message.starts_with(&graph));
+        assert!(message.ends_with(&format!("Graph written to {}", path)));
+
+        // A directory in the current directory cannot be written over
+        let message = process_command(&mut sheet, "GRAPH (BAR) A1:A2 src").unwrap();
+        assert!(message.starts_with("Could not write graph to"));
+
+        // Names that reach outside the current directory are refused before anything is written
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("chart.txt");
+        for name in [outside.to_str().unwrap(), "../chart.txt", "out/chart.txt", ".."] {
+            let message =
+                process_command(&mut sheet, &format!("GRAPH (BAR) A1:A2 {}", name)).unwrap();
+            assert_eq!(
+                message,
+                format!("Invalid file name {}: use a file name without a directory", name)
+            );
+        }
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn test_display_graph_svg() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=10");
+        process_command(&mut sheet, "A2=5");
+        process_command(&mut sheet, "A3=-10");
+
+        // One bar per value, with heights in proportion to the values
+        let svg = crate::sheet::display_graph_svg(&sheet, GraphType::Bar, 0, 0, 2, 0);
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(svg.matches("<rect").count(), 3);
+        assert!(svg.contains(r#"y="20" width="30" height="100""#));
+        assert!(svg.contains(r#"y="70" width="30" height="50""#));
+        // The negative bar hangs below the axis at y = 120
+        assert!(svg.contains(r#"y="120" width="30" height="100""#));
+        assert!(svg.contains("<title>A3: -10</title>"));
+
+        let svg = crate::sheet::display_graph_svg(&sheet, GraphType::Bar, 3, 0, 2, 0);
+        assert!(svg.contains("No data"));
+        assert_eq!(svg.matches("<rect").count(), 0);
+
+        let path = "test_display_graph_svg.svg";
+        let command = format!("GRAPHSVG (BAR) A1:A3 {}", path);
+        assert!(is_valid_command(&mut sheet, &command));
+        let message = process_command(&mut sheet, &command);
+        let written = std::fs::read_to_string(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(message, Some(format!("Graph written to {}", path)));
+        assert_eq!(written.unwrap().matches("<rect").count(), 3);
+
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("chart.svg");
+        let command = format!("GRAPHSVG (BAR) A1:A3 {}", outside.display());
+        assert_eq!(
+            process_command(&mut sheet, &command),
+            Some(format!(
+                "Invalid file name {}: use a file name without a directory",
+                outside.display()
+            ))
+        );
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn test_dependency_graph_dot() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "A2=A1+1");
+        process_command(&mut sheet, "A3=A2*2");
+        process_command(&mut sheet, "B1=SUM(A1:A3)");
+
+        let dot = crate::sheet::dependency_graph_dot(&sheet);
+        assert!(dot.starts_with("digraph dependencies {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("    \"A1\" -> \"A2\";\n"));
+        assert!(dot.contains("    \"A2\" -> \"A3\";\n"));
+        assert!(dot.contains("    \"A1:A3\" [shape=box];\n    \"A1:A3\" -> \"B1\";\n"));
+        assert_eq!(dot.matches("->").count(), 3);
+
+        let path = "test_dependency_graph_dot.dot";
+        let command = format!("DEPGRAPH {}", path);
+        assert!(is_valid_command(&mut sheet, &command));
+        let message = process_command(&mut sheet, &command);
+        let written = std::fs::read_to_string(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(message, Some(format!("Dependency graph written to {}", path)));
+        assert_eq!(written.unwrap(), dot);
+
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("deps.dot");
+        assert_eq!(
+            process_command(&mut sheet, &format!("DEPGRAPH {}", outside.display())),
+            Some(format!(
+                "Invalid file name {}: use a file name without a directory",
+                outside.display()
+            ))
+        );
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn test_resetflags_clears_stale_flags() {
+        let mut sheet = create_test_sheet(5, 5, false);
+        process_command(&mut sheet, "A1=B1+1");
+        process_command(&mut sheet, "B1=A1+1");
+        assert_eq!(sheet.cells[0][1].error_kind, Some(ErrorKind::Circular));
+
+        // Fix the cycle, then leave stale flags behind as an interrupted update would
+        process_command(&mut sheet, "B1=7");
+        sheet.cells[0][1].has_circular = true;
+        sheet.cells[0][1].error_kind = Some(ErrorKind::Circular);
+        sheet.cells[0][0].is_error = true;
+        sheet.circular_dependency_detected = true;
+
+        assert!(is_valid_command(&mut sheet, "RESETFLAGS"));
+        assert_eq!(process_command(&mut sheet, "RESETFLAGS"), None);
+        assert!(!sheet.circular_dependency_detected);
+        assert!(!sheet.cells[0][1].has_circular);
+        assert_eq!(sheet.cells[0][1].error_kind, None);
+        assert!(!sheet.cells[0][0].is_error);
+        assert_eq!(sheet.cells[0][0].value, 8);
+
+        // A formula that is still circular keeps its flag
+        sheet.cells[0][1].formula = Some("A1+1".to_string());
+        process_command(&mut sheet, "RESETFLAGS");
+        assert!(sheet.circular_dependency_detected);
+        assert!(sheet.cells[0][0].has_circular || sheet.cells[0][1].has_circular);
+    }
+
+    #[test]
+    fn test_format_grid_aligns_negative_numbers() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        sheet.cells[0][0].value = -5;
+        sheet.cells[1][0].value = 100;
+
+        let grid = format_grid(&sheet, &[0, 1], &[0], false);
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(lines, vec!["     A   ", "   1  -5 ", "   2 100 "]);
+        assert_eq!(lines[1].len(), lines[2].len());
+    }
+
+    #[test]
+    fn test_format_grid_pads_styled_cells_by_visible_width() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        sheet.cells[0][0].value = 7;
+        sheet.cells[0][0].is_bold = true;
+        sheet.cells[1][0].value = 1234;
+
+        let grid = format_grid(&sheet, &[0, 1], &[0], true);
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(lines[1], "   1    \x1b[1m7\x1b[0m ");
+        assert_eq!(lines[2], "   2 1234 ");
+        assert_eq!(lines[1].replace("\x1b[1m", "").replace("\x1b[0m", ""), "   1    7 ");
+    }
+
+    #[test]
+    fn test_get_reports_value_or_error() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "B1=A1*3");
+        process_command(&mut sheet, "C1=A1/0");
+
+        assert_eq!(
+            process_command(&mut sheet, "GET B1"),
+            Some("Value in cell B1: 12".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "GET C1"),
+            Some("Cell C1 has an error: #DIV/0!".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "GET Z9"));
+    }
+
+    #[test]
+    fn test_undo_steps_and_checkpoints() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        for value in 1..=4 {
+            process_command(&mut sheet, &format!("A1={}", value));
+        }
+        assert_eq!(
+            process_command(&mut sheet, "UNDO 0"),
+            Some("Invalid UNDO format: use UNDO <steps>".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "UNDO 0"));
+        assert_eq!(sheet.cells[0][0].value, 4);
+        assert_eq!(process_command(&mut sheet, "UNDO 2"), None);
+        assert_eq!(sheet.cells[0][0].value, 2);
+        // More steps than recorded undoes everything that is left
+        assert_eq!(process_command(&mut sheet, "UNDO 50"), None);
+        assert_eq!(sheet.cells[0][0].value, 0);
+        assert_eq!(
+            process_command(&mut sheet, "UNDO 1"),
+            Some("Nothing to undo".to_string())
+        );
+
+        process_command(&mut sheet, "A1=7");
+        process_command(&mut sheet, "B1=A1+1");
+        assert_eq!(process_command(&mut sheet, "CHECKPOINT before"), None);
+        process_command(&mut sheet, "A1=100");
+        process_command(&mut sheet, "C1=B1*2");
+        process_command(&mut sheet, "B1=0");
+
+        assert_eq!(process_command(&mut sheet, "RESTORE before"), None);
+        assert_eq!(sheet.cells[0][0].value, 7);
+        assert_eq!(sheet.cells[0][1].value, 8);
+        assert_eq!(sheet.cells[0][2].value, 0);
+        process_command(&mut sheet, "A1=10");
+        assert_eq!(sheet.cells[0][1].value, 11);
+
+        assert_eq!(
+            process_command(&mut sheet, "RESTORE later"),
+            Some("No checkpoint named later".to_string())
+        );
+    }
+
+    #[test]
+    fn test_logical_functions_truth_tables() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        for (a, b, and, or) in [(0, 0, 0, 0), (0, 1, 0, 1), (1, 0, 0, 1), (1, 1, 1, 1)] {
+            process_command(&mut sheet, &format!("A1={}", a * 10));
+            process_command(&mut sheet, &format!("B1={}", b * 10));
+            process_command(&mut sheet, "C1=AND(A1>5, B1>=10)");
+            process_command(&mut sheet, "D1=OR(A1>5, B1<>0)");
+            process_command(&mut sheet, "E1=NOT(A1)");
+            assert_eq!(sheet.cells[0][2].value, and, "AND for {} {}", a, b);
+            assert_eq!(sheet.cells[0][3].value, or, "OR for {} {}", a, b);
+            assert_eq!(sheet.cells[0][4].value, 1 - a, "NOT for {}", a);
+        }
+
+        process_command(&mut sheet, "A2=AND(TRUE, NOT(FALSE))");
+        process_command(&mut sheet, "B2=OR(FALSE, 0)");
+        assert_eq!(sheet.cells[1][0].value, 1);
+        assert_eq!(sheet.cells[1][1].value, 0);
+
+        // Both sides of a comparison are dependencies
+        process_command(&mut sheet, "A3=AND(A1<B1, B1=10)");
+        assert_eq!(sheet.cells[2][0].value, 0);
+        process_command(&mut sheet, "A1=5");
+        assert_eq!(sheet.cells[2][0].value, 1);
+        process_command(&mut sheet, "B1=4");
+        assert_eq!(sheet.cells[2][0].value, 0);
+
+        assert!(!is_valid_command(&mut sheet, "A4=NOT(A1, B1)"));
+        assert!(!is_valid_command(&mut sheet, "A4=A1>5"));
+    }
+
+    #[test]
+    fn test_exportrange_returns_tsv() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "B1=2");
+        process_command(&mut sheet, "C1=A1+B1");
+        process_command(&mut sheet, "A2=-4");
+        process_command(&mut sheet, "C2=A1/0");
+
+        assert_eq!(
+            process_command(&mut sheet, "EXPORTRANGE A1:C2"),
+            Some("1\t2\t3\n-4\t0\t#DIV/0!".to_string())
+        );
+        assert!(!is_valid_command(&mut sheet, "EXPORTRANGE A1:Z9"));
+    }
+
+    #[test]
+    fn test_importrange_writes_block_and_updates_dependents() {
+        let mut sheet = create_sheet(6, 6, true).unwrap();
+        process_command(&mut sheet, "C3=B2*10");
+        process_command(&mut sheet, "E5=SUM(B2:C3)");
+        process_command(&mut sheet, "F1=C3+1");
+
+        assert_eq!(
+            process_command(&mut sheet, "IMPORTRANGE B2 \"1,2\\n3,4\""),
+            None
+        );
+        assert_eq!(sheet.cells[1][1].value, 1);
+        assert_eq!(sheet.cells[1][2].value, 2);
+        assert_eq!(sheet.cells[2][1].value, 3);
+        // The formula that was in C3 is replaced by the imported value
+        assert_eq!(sheet.cells[2][2].value, 4);
+        assert_eq!(sheet.cells[2][2].formula, Some("4".to_string()));
+        assert_eq!(sheet.cells[4][4].value, 10);
+        assert_eq!(sheet.cells[0][5].value, 5);
+
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.cells[1][1].value, 0);
+        assert!(!is_valid_command(&mut sheet, "IMPORTRANGE F6 \"1\\n2\""));
+    }
+
+    #[test]
+    fn test_invalid_command_suggests_closest_command() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        assert_eq!(
+            process_command(&mut sheet, "udno"),
+            Some("Invalid command format (did you mean undo?)".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "COPPY A1:B2"),
+            Some("Invalid command format (did you mean COPY?)".to_string())
+        );
+        assert_eq!(
+            process_command(&mut sheet, "gibberish"),
+            Some("Invalid command format".to_string())
+        );
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_negative_literals_feed_functions_and_arithmetic() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        process_command(&mut sheet, "A1=-5");
+        process_command(&mut sheet, "A2=3");
+        process_command(&mut sheet, "A3=-10");
+        assert_eq!(sheet.cells[0][0].value, -5);
+        assert!(!sheet.cells[0][0].is_error);
+
+        process_command(&mut sheet, "B1=SUM(A1:A3)");
+        process_command(&mut sheet, "B2=A1*-2");
+        process_command(&mut sheet, "B3=-A1-A3");
+        process_command(&mut sheet, "B4=MIN(A1:A3)");
+        assert_eq!(sheet.cells[0][1].value, -12);
+        assert_eq!(sheet.cells[1][1].value, 10);
+        assert_eq!(sheet.cells[2][1].value, 15);
+        assert_eq!(sheet.cells[3][1].value, -10);
+
+        // The leading minus does not hide the references from dependency tracking
+        process_command(&mut sheet, "A1=-1");
+        assert_eq!(sheet.cells[0][1].value, -8);
+        assert_eq!(sheet.cells[1][1].value, 2);
+        assert_eq!(sheet.cells[2][1].value, 11);
+        assert!(has_circular_dependency(&mut sheet, 0, 0, "-B3"));
+    }
+
+    #[test]
+    fn test_formulas_lists_every_formula_cell() {
+        let mut sheet = create_sheet(5, 30, true).unwrap();
+        assert_eq!(
+            process_command(&mut sheet, "FORMULAS"),
+            Some("No formulas stored".to_string())
+        );
+        process_command(&mut sheet, "A1=5");
+        process_command(&mut sheet, "B1=A1+1");
+        process_command(&mut sheet, "AA3=SUM(A1:B1)");
+        process_command(&mut sheet, "C2=B1*2");
+
+        assert_eq!(
+            process_command(&mut sheet, "FORMULAS"),
+            Some("Formulas:\nB1: A1+1\nC2: B1*2\nAA3: SUM(A1:B1)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_offset_reads_relative_cell() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        process_command(&mut sheet, "B3=42");
+        process_command(&mut sheet, "D1=OFFSET(A1, 2, 1)");
+        assert_eq!(sheet.cells[0][3].value, 42);
+        assert!(!sheet.cells[0][3].is_error);
+
+        // The formula depends on the resolved cell, not just the base
+        process_command(&mut sheet, "B3=7");
+        assert_eq!(sheet.cells[0][3].value, 7);
+        process_command(&mut sheet, "E1=OFFSET(C3, -1, -2)+1");
+        assert_eq!(sheet.cells[0][4].value, 1);
+
+        process_command(&mut sheet, "D2=OFFSET(A1, 5, 0)");
+        assert!(sheet.cells[1][3].is_error);
+        assert_eq!(sheet.cells[1][3].error_kind, Some(ErrorKind::BadReference));
+        process_command(&mut sheet, "D3=OFFSET(A1, 0, -1)");
+        assert_eq!(sheet.cells[2][3].error_kind, Some(ErrorKind::BadReference));
+        assert!(!is_valid_command(&mut sheet, "D4=OFFSET(A1:A2, 0, 0)"));
+    }
+
+    #[test]
+    fn test_snapshot_copies_range_values() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        process_command(&mut sheet, "B2=3");
+        process_command(&mut sheet, "C2=B2*4");
+        process_command(&mut sheet, "B3=B2/0");
+
+        let snapshot = sheet.snapshot(1, 1, 2, 2).unwrap();
+        assert_eq!(
+            snapshot.values,
+            vec![vec![Ok(3), Ok(12)], vec![Err(ErrorKind::DivByZero), Ok(0)]]
+        );
+
+        // Later edits do not change a snapshot already taken
+        process_command(&mut sheet, "B2=10");
+        assert_eq!(snapshot.values[0][0], Ok(3));
+        assert!(sheet.snapshot(3, 3, 5, 5).is_none());
+
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = Some(sheet);
+        let client = Client::tracked(rocket::build().mount("/", rocket::routes![crate::values]))
+            .unwrap();
+        let response = client.get("/values/B2:C3").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(response.into_string().unwrap(), r##"[[10,40],["#DIV/0!",0]]"##);
+        assert_eq!(client.get("/values/A1:Z99").dispatch().status(), Status::BadRequest);
+        *SHEET.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_whatif_reports_without_changing_sheet() {
+        let mut sheet = create_sheet(6, 6, true).unwrap();
+        process_command(&mut sheet, "A1=10");
+        process_command(&mut sheet, "B5=A1*2+1");
+        let undo_depth = sheet.undo_stack.len();
+
+        assert_eq!(
+            process_command(&mut sheet, "WHATIF A1=50 : GET B5"),
+            Some("What if A1=50: Value in cell B5: 101".to_string())
+        );
+        assert_eq!(sheet.cells[0][0].value, 10);
+        assert_eq!(sheet.cells[4][1].value, 21);
+        assert_eq!(sheet.undo_stack.len(), undo_depth);
+
+        // The dependency graph is intact after the rollback
+        process_command(&mut sheet, "A1=0");
+        assert_eq!(sheet.cells[4][1].value, 1);
+        assert!(is_valid_command(&mut sheet, "WHATIF A1=B1+1 : GET B5"));
+        assert!(!is_valid_command(&mut sheet, "WHATIF A1=50 : COPY A1:B2"));
+
+        // Numbers drawn during a WHATIF do not move the seeded sequence on
+        let draw = |whatif: bool| {
+            let mut sheet = create_sheet(5, 5, true).unwrap();
+            process_command(&mut sheet, "SEED 42");
+            if whatif {
+                process_command(&mut sheet, "WHATIF C1=RANDBETWEEN(1, 1000000) : GET C1");
+            }
+            process_command(&mut sheet, "C1=RANDBETWEEN(1, 1000000)");
+            process_command(&mut sheet, "C2=RANDBETWEEN(1, 1000000)");
+            (sheet.cells[0][2].value, sheet.cells[1][2].value)
+        };
+        assert_eq!(draw(true), draw(false));
+    }
+
+    #[test]
+    fn test_graph_plots_two_series_side_by_side() {
+        let mut sheet = create_sheet(6, 6, true).unwrap();
+        for (row, (a, b)) in [(3, 1), (1, 2), (2, 2)].into_iter().enumerate() {
+            sheet.cells[row][0].value = a;
+            sheet.cells[row][1].value = b;
+        }
+        sheet.cells[3][1].value = 1;
+
+        let output = process_command(&mut sheet, "GRAPH (BAR) A1:A3 B1:B4").unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[1], "Bar Graph for ranges:");
+        assert_eq!(lines[2], " 3 | █              ");
+        assert_eq!(lines[3], " 2 | █    ▒  █▒     ");
+        assert_eq!(lines[4], " 1 | █▒  █▒  █▒   ▒ ");
+        assert_eq!(lines[6], "   | A1  A2  A3  B4 ");
+        assert_eq!(lines[7], "Legend: █ A1:A3  ▒ B1:B4");
+
+        let scatter = process_command(&mut sheet, "GRAPH (SCATTER) A1:A3 B1:B3").unwrap();
+        assert!(scatter.contains(" 2 |      o  *o \n"));
+        assert!(scatter.contains("Legend: * A1:A3  o B1:B3"));
+    }
+
+    #[test]
+    fn test_sparse_view_blanks_unset_cells() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=0");
+        process_command(&mut sheet, "B1=7");
+        assert_eq!(process_command(&mut sheet, "SPARSEVIEW on"), None);
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "0");
+        assert_eq!(sheet.display_value(&sheet.cells[0][1]), "7");
+        assert_eq!(sheet.display_value(&sheet.cells[0][2]), "");
+        assert_eq!(
+            format_grid(&sheet, &[0, 1], &[0, 1, 2], false),
+            "     A B C \n   1 0 7   \n   2       \n"
+        );
+
+        process_command(&mut sheet, "SPARSEVIEW off");
+        assert_eq!(sheet.display_value(&sheet.cells[0][2]), "0");
+        assert_eq!(
+            process_command(&mut sheet, "SPARSEVIEW maybe"),
+            Some("Invalid SPARSEVIEW format: use SPARSEVIEW on|off".to_string())
+        );
+    }
+
+    #[test]
+    fn test_avgok_skips_error_cells() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "A2=1/0");
+        process_command(&mut sheet, "A3=8");
+        process_command(&mut sheet, "B1=AVG(A1:A3)");
+        process_command(&mut sheet, "B2=AVGOK(A1:A3)");
+        assert!(sheet.cells[0][1].is_error);
+        assert!(!sheet.cells[1][1].is_error);
+        assert_eq!(sheet.cells[1][1].value, 6);
+
+        // Only errors left to average: the error is passed on
+        process_command(&mut sheet, "B3=AVGOK(A2:A2)");
+        assert!(sheet.cells[2][1].is_error);
+        assert_eq!(sheet.cells[2][1].error_kind, Some(ErrorKind::DivByZero));
+
+        // Fixing the error cell brings it back into the average
+        process_command(&mut sheet, "A2=3");
+        assert_eq!(sheet.cells[1][1].value, 5);
+    }
+
+    #[test]
+    fn test_errstr_sets_error_display_text() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=1/0");
+        process_command(&mut sheet, "B1=5");
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "#DIV/0!");
+
+        assert_eq!(process_command(&mut sheet, "ERRSTR \"#ERR\""), None);
+        assert_eq!(
+            format_grid(&sheet, &[0], &[0, 1], false),
+            "     A    B \n   1 #ERR 5 \n"
+        );
+
+        process_command(&mut sheet, "ERRSTR off");
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "#DIV/0!");
+        assert_eq!(
+            process_command(&mut sheet, "ERRSTR #ERR"),
+            Some("Invalid ERRSTR format: use ERRSTR \"<text>\" or ERRSTR off".to_string())
+        );
+    }
+
+    #[test]
+    fn test_load_csv_file_orders_chained_formulas() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        // Each formula reads a formula that comes after it in the file
+        writeln!(temp_file, "5,=C1+1,=B2*2\n1,=SUM(A1:A2),=B1").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        let warnings = load_csv_file(&mut sheet, path, ',').unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(sheet.cells[1][1].value, 6); // B2 = SUM(A1:A2)
+        assert_eq!(sheet.cells[0][2].value, 12); // C1 = B2*2
+        assert_eq!(sheet.cells[0][1].value, 13); // B1 = C1+1
+        assert_eq!(sheet.cells[1][2].value, 13); // C2 = B1
+    }
+
+    #[test]
+    fn test_cycles_lists_each_cycle() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert_eq!(
+            process_command(&mut sheet, "CYCLES"),
+            Some("No circular dependencies".to_string())
+        );
+
+        // A1 -> B1 -> A1, and C2 -> D3 -> C3 -> C2 through a range
+        process_command(&mut sheet, "A1=B1+1");
+        process_command(&mut sheet, "B1=A1");
+        process_command(&mut sheet, "C3=C2*2");
+        process_command(&mut sheet, "D3=SUM(C1:C3)");
+        process_command(&mut sheet, "C2=D3");
+        // E5 only reads a cycle, it is not part of one
+        process_command(&mut sheet, "E5=A1");
+        assert_eq!(
+            process_command(&mut sheet, "CYCLES"),
+            Some("Cycles:\nA1 -> B1 -> A1\nC2 -> D3 -> C2".to_string())
+        );
+
+        process_command(&mut sheet, "B1=7");
+        assert_eq!(
+            process_command(&mut sheet, "CYCLES"),
+            Some("Cycles:\nC2 -> D3 -> C2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_range_statistics_match_full_recompute() {
+        let mut sheet = create_test_sheet(10, 5, true);
+        for (row, value) in [3, 7, 1, 9, 4].iter().enumerate() {
+            update_cell(&mut sheet, row as i32, 0, &value.to_string());
+        }
+        process_command(&mut sheet, "A6=A1*2");
+        process_command(&mut sheet, "B1=STDEV(A1:A6)");
+        process_command(&mut sheet, "B2=AVG(A1:A6)");
+        process_command(&mut sheet, "B3=SUM(A1:A6)");
+        assert!(sheet.cells[0][1].stats.is_some());
+
+        // A1 also changes A6, so each edit updates the statistics for two cells
+        for edit in ["A3=12", "A1=-5", "A5=100", "A3=A4+1", "A1=0"] {
+            process_command(&mut sheet, edit);
+            let incremental = [0, 1, 2].map(|row| sheet.cells[row][1].value);
+            let full = ["STDEV", "AVG", "SUM"]
+                .map(|name| range_function(&mut sheet, name, "A1:A6").unwrap() as i32);
+            assert_eq!(incremental, full, "after {}", edit);
+        }
+
+        // An error in the range stops the statistics until it is fixed
+        process_command(&mut sheet, "A2=1/0");
+        assert!(sheet.cells[2][1].is_error);
+        process_command(&mut sheet, "A2=2");
+        let full = range_function(&mut sheet, "SUM", "A1:A6").unwrap() as i32;
+        assert_eq!(sheet.cells[2][1].value, full);
+        assert!(sheet.cells[2][1].stats.is_some());
+    }
+
+    #[test]
+    fn test_paste_transpose() {
+        let _clipboard = lock_clipboard();
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "B1=2");
+        process_command(&mut sheet, "C1=A1+B1");
+        process_command(&mut sheet, "COPY A1:C1");
+
+        assert_eq!(
+            process_command(&mut sheet, "PASTETRANSPOSE E2"),
+            Some("Pasted from clipboard, transposed".to_string())
+        );
+        assert_eq!(sheet.cells[1][4].value, 1); // E2
+        assert_eq!(sheet.cells[2][4].value, 2); // E3
+        assert_eq!(sheet.cells[3][4].value, 3); // E4
+        assert_eq!(sheet.cells[1][3].value, 0); // D2 is untouched
+
+        // Three rows do not fit below E4, and the refused paste leaves no undo step
+        let undo_depth = sheet.undo_stack.len();
+        assert_eq!(
+            process_command(&mut sheet, "PASTETRANSPOSE E4"),
+            Some("Nothing to paste or the transposed range does not fit".to_string())
+        );
+        assert_eq!(sheet.cells[4][4].value, 0);
+        assert_eq!(sheet.undo_stack.len(), undo_depth);
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.cells[1][4].value, 0);
+    }
+
+    #[test]
+    fn test_references_with_inner_spaces_are_rejected() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        sheet.cells[0][0].value = 7;
+        assert_eq!(
+            parse_cell_reference(&mut sheet, "A 1"),
+            Err(ParseError::BadSyntax)
+        );
+        assert_eq!(parse_range(&mut sheet, "A 1:B2"), None);
+        for formula in ["A 1", "A 1+1", "2*A 1", "SUM(A 1:A3)", "MAX(A1, B 2)"] {
+            assert!(!is_valid_formula(&mut sheet, formula), "{}", formula);
+            assert_eq!(
+                evaluate_expression(&mut sheet, formula, 4, 4),
+                (0, Some(ErrorKind::Invalid)),
+                "{}",
+                formula
+            );
+        }
+        // Spaces around a reference are still fine
+        assert!(is_valid_formula(&mut sheet, " A1 + 1 "));
+        assert_eq!(evaluate_expression(&mut sheet, "A1 + 1", 4, 4), (8, None));
+
+        assert!(!is_valid_command(&mut sheet, "B1=A 1+1"));
+        assert!(!is_valid_command(&mut sheet, "A 1=5"));
+        process_command(&mut sheet, "B1=A 1+1");
+        assert_eq!(sheet.cells[0][1].formula, None);
+    }
+
+    #[test]
+    fn test_sum_over_range_bound_to_counter_cell() {
+        let mut sheet = create_test_sheet(10, 5, true);
+        for row in 1..=6 {
+            process_command(&mut sheet, &format!("A{}={}", row, row * 10));
+        }
+        process_command(&mut sheet, "B1=3");
+        assert!(is_valid_command(&mut sheet, "C1=SUM(A1:A{B1})"));
+        process_command(&mut sheet, "C1=SUM(A1:A{B1})");
+        assert_eq!(sheet.cells[0][2].value, 60); // A1:A3
+
+        process_command(&mut sheet, "B1=5");
+        assert_eq!(sheet.cells[0][2].value, 150); // A1:A5
+        // The cells the range grew over are now tracked, and the ones it left are not
+        process_command(&mut sheet, "A5=1");
+        assert_eq!(sheet.cells[0][2].value, 101);
+        process_command(&mut sheet, "B1=2");
+        process_command(&mut sheet, "A4=1000");
+        assert_eq!(sheet.cells[0][2].value, 30);
+
+        // A row outside the sheet makes the formula an error
+        process_command(&mut sheet, "B1=0");
+        assert!(sheet.cells[0][2].is_error);
+        assert!(!is_valid_command(&mut sheet, "C2=SUM(A1:A{Z99})"));
+        assert!(!is_valid_command(&mut sheet, "C2=SUM(A1:A{B1)"));
+    }
+
+    #[test]
+    fn test_select_route_highlights_cell() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "B2=7");
+
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = Some(sheet);
+        let client = Client::tracked(
+            rocket::build()
+                .mount("/", rocket::routes![index, crate::select])
+                .attach(rocket_dyn_templates::Template::fairing()),
+        )
+        .unwrap();
+        assert_eq!(client.post("/select/B2").dispatch().status(), Status::SeeOther);
+        assert_eq!(SHEET.lock().unwrap().as_ref().unwrap().selected, Some((1, 1)));
+        let body = client.get("/").dispatch().into_string().unwrap();
+        assert!(body.contains(r#"class="selected">7<"#));
+        assert_eq!(body.matches(r#"class="selected""#).count(), 1);
+
+        // An invalid reference leaves the selection alone
+        client.post("/select/Z99").dispatch();
+        assert_eq!(SHEET.lock().unwrap().as_ref().unwrap().selected, Some((1, 1)));
+        *SHEET.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_edit_route_sets_clicked_cell() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "MERGE D1:E1");
+        process_command(&mut sheet, "VALIDATE C1:C5 0 10");
+
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = Some(sheet);
+        *crate::types::COMMAND_QUEUE.lock().unwrap() = Some(crate::start_command_worker());
+        let client = Client::tracked(
+            rocket::build().mount("/", rocket::routes![command, crate::edit]),
+        )
+        .unwrap();
+        let post = |path: &str, body: String| {
+            client
+                .post(path)
+                .header(ContentType::Form)
+                .body(body)
+                .dispatch()
+                .headers()
+                .get_one("Location")
+                .unwrap()
+                .to_string()
+        };
+        // Waits for a queued command and returns its message
+        let finish = |location: &str| {
+            let id = location.strip_prefix("/?pending=").unwrap().parse::<u64>().unwrap();
+            (0..200)
+                .find_map(|_| {
+                    let message = crate::types::COMMAND_MESSAGES.lock().unwrap().get(&id).cloned();
+                    if message.is_none() {
+                        std::thread::sleep(std::time::Duration::from_millis(20));
+                    }
+                    message
+                })
+                .unwrap()
+        };
+
+        // Edits are queued behind commands posted earlier, and do not wait for them
+        let start = std::time::Instant::now();
+        post("/command", "command=A1%3DSLEEP(1)".to_string());
+        let first = post("/edit", "row=0&col=0&value=4".to_string());
+        let second = post("/edit", "row=1&col=0&value=A1%2B5".to_string());
+        assert!(start.elapsed().as_secs_f64() < 0.5);
+        assert_eq!(finish(&first), None);
+        assert_eq!(finish(&second), None);
+        {
+            let sheet = SHEET.lock().unwrap();
+            let sheet = sheet.as_ref().unwrap();
+            assert_eq!(sheet.cells[0][0].value, 4);
+            assert_eq!(sheet.cells[1][0].value, 9);
+        }
+
+        // Cells outside the sheet are reported straight away and change nothing
+        let outside = post("/edit", "row=5&col=0&value=1".to_string());
+        assert!(outside.contains("outside"));
+
+        // The rules of a typed assignment apply: hidden cells and VALIDATE ranges
+        let hidden = post("/edit", "row=0&col=4&value=1".to_string());
+        assert!(finish(&hidden).unwrap().contains("merged"));
+        let refused = post("/edit", "row=0&col=2&value=50".to_string());
+        assert!(finish(&refused).unwrap().contains("outside"));
+        *crate::types::COMMAND_QUEUE.lock().unwrap() = None;
+        let mut sheet = SHEET.lock().unwrap().take().unwrap();
+        assert_eq!(sheet.cells[0][4].value, 0);
+        assert_eq!(sheet.cells[0][2].value, 0);
+        // Refused edits add no undo step
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.cells[1][0].value, 0);
+        assert_eq!(sheet.cells[0][0].value, 4);
+    }
+
+    #[test]
+    fn test_output_commands_report_resulting_state() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        assert!(is_valid_command(&mut sheet, "toggle_output"));
+        assert_eq!(process_command(&mut sheet, "disable_output"), Some("Output disabled".to_string()));
+        assert_eq!(process_command(&mut sheet, "toggle_output"), Some("Output enabled".to_string()));
+        assert!(sheet.output_enabled);
+        assert_eq!(process_command(&mut sheet, "toggle_output"), Some("Output disabled".to_string()));
+        assert!(!sheet.output_enabled);
+        assert_eq!(process_command(&mut sheet, "enable_output"), Some("Output enabled".to_string()));
+        assert!(sheet.output_enabled);
+    }
+
+    #[test]
+    fn test_longestchain_reports_recalculation_depth() {
+        let mut sheet = create_sheet(10, 10, true).unwrap();
+        assert_eq!(process_command(&mut sheet, "LONGESTCHAIN"), Some("No dependencies".to_string()));
+
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "A2=A1+1");
+        process_command(&mut sheet, "A3=A2*2");
+        process_command(&mut sheet, "B1=SUM(A1:A3)");
+        process_command(&mut sheet, "C5=B1-A1");
+        process_command(&mut sheet, "D1=A1");
+        assert!(is_valid_command(&mut sheet, "LONGESTCHAIN"));
+        assert_eq!(
+            process_command(&mut sheet, "LONGESTCHAIN"),
+            Some("Longest dependency chain: 4 (A1 -> A2 -> A3 -> B1 -> C5)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_run_terminal_without_tty_prints_only_messages() {
+        let _guard = lock_global_sheet();
+        let _clipboard = lock_clipboard();
+        *SHEET.lock().unwrap() = Some(create_sheet(3, 3, true).unwrap());
+
+        let mut output = Vec::new();
+        let input = "A1=5\nA2=A1+1\nXYZ\nCOPY A1:A1\nPASTE B1\n";
+        crate::run_terminal(input.as_bytes(), &mut output, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("> "));
+        assert!(!output.contains("     A"));
+        {
+            let sheet_guard = SHEET.lock().unwrap();
+            let sheet = sheet_guard.as_ref().unwrap();
+            assert_eq!(sheet.cells[1][0].value, 6);
+            assert_eq!(sheet.cells[0][1].value, 5);
+            // Piped input leaves the sheet's output setting alone
+            assert!(sheet.output_enabled);
+        }
+        let mut output = Vec::new();
+        crate::run_terminal("toggle_output\n".as_bytes(), &mut output, false).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Output disabled\n");
+
+        // Interactively the grid and a prompt come before every command, and input ends at EOF
+        *SHEET.lock().unwrap() = Some(create_sheet(3, 3, false).unwrap());
+        let mut output = Vec::new();
+        crate::run_terminal("A3=1\n".as_bytes(), &mut output, true).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("(ok)> ").count(), 2);
+        assert!(output.contains("     A"));
+
+        // toggle_output reports the same thing as it does for piped input
+        let mut output = Vec::new();
+        crate::run_terminal("toggle_output\n".as_bytes(), &mut output, true).unwrap();
+        assert!(String::from_utf8(output).unwrap().contains("Output disabled\n"));
+        *SHEET.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_sumdiag_sums_main_diagonal() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        for (cell, value) in [("A1", 1), ("B2", 5), ("C3", 9), ("B1", 100), ("A3", 100)] {
+            process_command(&mut sheet, &format!("{}={}", cell, value));
+        }
+        process_command(&mut sheet, "E1=SUMDIAG(A1:C3)");
+        assert_eq!(sheet.cells[0][4].value, 15);
+
+        // The result follows changes to the diagonal
+        process_command(&mut sheet, "B2=-5");
+        assert_eq!(sheet.cells[0][4].value, 5);
+        process_command(&mut sheet, "C3=1/0");
+        assert_eq!(sheet.cells[0][4].error_kind, Some(ErrorKind::DivByZero));
+
+        // A range that is not square has no main diagonal
+        assert!(!is_valid_command(&mut sheet, "E2=SUMDIAG(A1:C2)"));
+        assert!(!is_valid_command(&mut sheet, "E2=SUMDIAG(A1:A3)"));
+        assert!(is_valid_command(&mut sheet, "E2=SUMDIAG(D4:D4)"));
+        process_command(&mut sheet, "E2=SUMDIAG(A1:C2)");
+        assert_eq!(sheet.cells[1][4].formula, None);
+    }
+
+    #[test]
+    fn test_randbetween_is_reproducible_with_seed() {
+        let draw = |seed: &str| {
+            let mut sheet = create_sheet(5, 5, true).unwrap();
+            process_command(&mut sheet, seed);
+            for cell in ["A1", "A2", "A3", "A4"] {
+                process_command(&mut sheet, &format!("{}=RANDBETWEEN(1, 100)", cell));
+            }
+            (0..4).map(|row| sheet.cells[row][0].value).collect::<Vec<_>>()
+        };
+        let first = draw("SEED 42");
+        assert_eq!(first, draw("SEED 42"));
+        assert_ne!(first, draw("SEED 7"));
+        assert!(first.iter().all(|value| (1..=100).contains(value)));
+
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "B1=5");
+        process_command(&mut sheet, "A1=RANDBETWEEN(B1, B1)");
+        assert_eq!(sheet.cells[0][0].value, 5);
+        process_command(&mut sheet, "A2=RANDBETWEEN(1, 1000000)+B1*0");
+        let drawn = sheet.cells[1][0].value;
+
+        // Changing an input recalculates the cell, but keeps the number drawn for it
+        process_command(&mut sheet, "B1=5");
+        process_command(&mut sheet, "RECOMPUTE");
+        assert_eq!(sheet.cells[1][0].value, drawn);
+
+        // Re-seeding draws again, and the same seed gives the same numbers
+        process_command(&mut sheet, "SEED 3");
+        let seeded = sheet.cells[1][0].value;
+        process_command(&mut sheet, "REFRESH");
+        process_command(&mut sheet, "SEED 3");
+        assert_eq!(sheet.cells[1][0].value, seeded);
+        assert!(!is_valid_command(&mut sheet, "SEED x"));
+        assert!(!is_valid_command(&mut sheet, "A3=RANDBETWEEN(1)"));
+    }
+
+    #[test]
+    fn test_move_range_updates_formulas_reading_moved_cells() {
+        let mut sheet = create_sheet(6, 6, true).unwrap();
+        process_command(&mut sheet, "A1=3");
+        process_command(&mut sheet, "A2=4");
+        process_command(&mut sheet, "A3=5");
+        process_command(&mut sheet, "F1=A1*10");
+        process_command(&mut sheet, "F2=SUM(A1:A2)");
+        process_command(&mut sheet, "F3=SUM(A1:A3)");
+
+        assert_eq!(process_command(&mut sheet, "MOVE A1:A2 D1"), None);
+        assert_eq!(sheet.cells[0][5].formula, Some("D1*10".to_string()));
+        assert_eq!(sheet.cells[0][5].value, 30);
+        assert_eq!(sheet.cells[1][5].formula, Some("SUM(D1:D2)".to_string()));
+        assert_eq!(sheet.cells[1][5].value, 7);
+        // A range reaching outside the moved block keeps its place
+        assert_eq!(sheet.cells[2][5].formula, Some("SUM(A1:A3)".to_string()));
+        assert_eq!(sheet.cells[2][5].value, 5);
+
+        // The dependency graph follows the rewritten formulas
+        process_command(&mut sheet, "D1=100");
+        assert_eq!(sheet.cells[0][5].value, 1000);
+        assert_eq!(sheet.cells[1][5].value, 104);
+        process_command(&mut sheet, "A1=1");
+        assert_eq!(sheet.cells[0][5].value, 1000);
+        assert_eq!(sheet.cells[2][5].value, 6);
+    }
+
+    #[test]
+    fn test_deplist_lists_adjacency() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        assert_eq!(process_command(&mut sheet, "DEPLIST"), Some("No dependencies".to_string()));
+
+        process_command(&mut sheet, "A1=2");
+        process_command(&mut sheet, "A2=A1+1");
+        process_command(&mut sheet, "A3=A2*2");
+        process_command(&mut sheet, "B1=SUM(A1:A2)+A3");
+        assert!(is_valid_command(&mut sheet, "DEPLIST"));
+        assert_eq!(
+            process_command(&mut sheet, "DEPLIST"),
+            Some(
+                "Dependencies:\n\
+                 A1: reads none; read by B1, A2\n\
+                 B1: reads A1:A2, A3; read by none\n\
+                 A2: reads A1; read by B1, A3\n\
+                 A3: reads A2; read by B1"
+                    .to_string()
+            )
+        );
+
+        let path = "test_deplist_lists_adjacency.txt";
+        assert!(is_valid_command(&mut sheet, &format!("DEPLIST {}", path)));
+        let message = process_command(&mut sheet, &format!("DEPLIST {}", path));
+        let written = std::fs::read_to_string(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(message, Some(format!("Dependency list written to {}", path)));
+        assert!(written.unwrap().starts_with("A1: reads none; read by B1, A2\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("deps.txt");
+        assert_eq!(
+            process_command(&mut sheet, &format!("DEPLIST {}", outside.display())),
+            Some(format!(
+                "Invalid file name {}: use a file name without a directory",
+                outside.display()
+            ))
+        );
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn test_recalc_budget_defers_dependents() {
+        let mut sheet = create_sheet(60, 3, true).unwrap();
+        for row in 1..=50 {
+            process_command(&mut sheet, &format!("B{}=A1+{}", row, row));
+        }
+        assert_eq!(process_command(&mut sheet, "BUDGET 10"), None);
+        assert_eq!(
+            process_command(&mut sheet, "A1=100"),
+            Some("Partial recompute - 40 cells deferred, use RECOMPUTE to finish".to_string())
+        );
+        let updated = (0..50).filter(|&row| sheet.cells[row][1].value == 101 + row as i32);
+        assert_eq!(updated.count(), 10);
+
+        assert_eq!(process_command(&mut sheet, "RECOMPUTE"), None);
+        assert!((0..50).all(|row| sheet.cells[row][1].value == 101 + row as i32));
+
+        // Within the budget, or without one, nothing is deferred
+        assert_eq!(process_command(&mut sheet, "C1=B1*2"), None);
+        assert_eq!(process_command(&mut sheet, "BUDGET off"), None);
+        assert_eq!(process_command(&mut sheet, "A1=1"), None);
+        assert_eq!(sheet.cells[49][1].value, 51);
+        assert!(!is_valid_command(&mut sheet, "BUDGET 0"));
+    }
+
+    #[test]
+    fn test_recalc_budget_covers_whole_command() {
+        let mut sheet = create_sheet(12, 3, true).unwrap();
+        for row in 1..=10 {
+            process_command(&mut sheet, &format!("C{}=A1+A2+{}", row, row));
+        }
+        process_command(&mut sheet, "BUDGET 3");
+        // Both changed cells share one budget, and a cell left stale twice is counted once
+        assert_eq!(
+            process_command(&mut sheet, "APPLY A1:A2 =7"),
+            Some("Partial recompute - 10 cells deferred, use RECOMPUTE to finish".to_string())
+        );
+        let updated = (0..10).filter(|&row| sheet.cells[row][2].value == 14 + row as i32 + 1);
+        assert_eq!(updated.count(), 0);
+
+        assert!(crate::parse_config("recalc_budget = -5").is_err());
+        assert!(crate::parse_config("recalc_budget = 0").is_err());
+        assert_eq!(crate::parse_config("recalc_budget = 20").unwrap().recalc_budget, Some(20));
+    }
+
+    #[test]
+    fn test_percent_literal_storage_display_and_arithmetic() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        assert!(is_valid_command(&mut sheet, "A1=50%"));
+        assert_eq!(process_command(&mut sheet, "A1=50%"), None);
+        assert_eq!(sheet.cells[0][0].value, 50);
+        assert_eq!(sheet.cells[0][0].kind, DataKind::Percent);
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "50%");
+
+        // Formulas read the percentage as its number, so scaling by 100 is explicit
+        process_command(&mut sheet, "B1=200");
+        process_command(&mut sheet, "C1=B1*A1/100");
+        assert_eq!(sheet.cells[0][2].value, 100);
+        assert_eq!(sheet.display_value(&sheet.cells[0][2]), "100");
+        process_command(&mut sheet, "A1=25%");
+        assert_eq!(sheet.cells[0][2].value, 50);
+
+        // A plain number clears the percent display, and % is only allowed on a whole value
+        process_command(&mut sheet, "A1=7");
+        assert_eq!(sheet.display_value(&sheet.cells[0][0]), "7");
+        assert!(!is_valid_command(&mut sheet, "A2=50%*200"));
+        assert!(!is_valid_command(&mut sheet, "A2=B1%"));
+    }
+
+    #[test]
+    fn test_clearformat_keeps_values() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=3");
+        process_command(&mut sheet, "B2=A1*2");
+        process_command(&mut sheet, "C3=7");
+        process_command(&mut sheet, "A1=BOLD(A1:C3)");
+        process_command(&mut sheet, "A1=ITALIC(B2)");
+        process_command(&mut sheet, "A1=STRIKE(C3)");
+        process_command(&mut sheet, "A1=COLOR(A1:B2, red)");
+        assert!(sheet.cells[1][1].is_bold && sheet.cells[1][1].is_italic);
+
+        assert!(is_valid_command(&mut sheet, "CLEARFORMAT A1:C3"));
+        assert_eq!(process_command(&mut sheet, "CLEARFORMAT A1:C3"), None);
+        for row in sheet.cells.iter().take(3) {
+            for cell in row.iter().take(3) {
+                assert!(!cell.is_bold && !cell.is_italic && !cell.is_underline && !cell.is_strike);
+                assert_eq!(cell.color, None);
+            }
+        }
+        assert_eq!(sheet.cells[0][0].value, 3);
+        assert_eq!(sheet.cells[1][1].formula, Some("A1*2".to_string()));
+        assert_eq!(sheet.cells[1][1].value, 6);
+        assert_eq!(sheet.cells[2][2].value, 7);
+
+        process_command(&mut sheet, "undo");
+        assert!(sheet.cells[1][1].is_italic);
+        assert_eq!(sheet.cells[0][0].color, Some("red".to_string()));
+    }
+
+    #[test]
+    fn test_load_csv_file_skips_comments_and_blank_lines() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "# prices for March\n1,2\n\n  # totals follow\n3,=A1+A2\n   \n5,6").unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        assert_eq!(load_csv_file(&mut sheet, path, ','), Ok(Vec::new()));
+        assert_eq!(sheet.cells[0][0].value, 1);
+        assert_eq!(sheet.cells[1][0].value, 3);
+        assert_eq!(sheet.cells[1][1].value, 4);
+        assert_eq!(sheet.cells[2][1].value, 6);
+        assert!(sheet.cells[3].iter().all(|cell| !cell.is_set));
+    }
+
+    #[test]
+    fn test_getrow_and_getcol_return_comma_separated_values() {
+        let mut sheet = create_sheet(3, 4, true).unwrap();
+        process_command(&mut sheet, "A3=1");
+        process_command(&mut sheet, "B3=A3+1");
+        process_command(&mut sheet, "D3=B3/0");
+        process_command(&mut sheet, "B1=-5");
+        assert_eq!(process_command(&mut sheet, "GETROW 3"), Some("1,2,0,#DIV/0!".to_string()));
+        assert_eq!(process_command(&mut sheet, "GETCOL B"), Some("-5,0,2".to_string()));
+
+        assert!(is_valid_command(&mut sheet, "GETROW 1"));
+        assert!(!is_valid_command(&mut sheet, "GETROW 4"));
+        assert!(!is_valid_command(&mut sheet, "GETCOL E"));
+        assert_eq!(
+            process_command(&mut sheet, "GETCOL E"),
+            Some("Invalid GETCOL format: use GETCOL <column>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_argmax_and_argmin_locate_extremes() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=4");
+        process_command(&mut sheet, "B2=9");
+        process_command(&mut sheet, "C3=9");
+        process_command(&mut sheet, "C1=-3");
+        process_command(&mut sheet, "A3=1/0");
+        process_command(&mut sheet, "B3=-3");
+        assert_eq!(process_command(&mut sheet, "ARGMAX A1:C3"), Some("Maximum 9 at B2".to_string()));
+        assert_eq!(process_command(&mut sheet, "ARGMIN A1:C3"), Some("Minimum -3 at C1".to_string()));
+        assert_eq!(process_command(&mut sheet, "ARGMAX C2:C3"), Some("Maximum 9 at C3".to_string()));
+        assert_eq!(process_command(&mut sheet, "ARGMIN A3:A3"), Some("No values in A3:A3".to_string()));
+
+        assert!(is_valid_command(&mut sheet, "ARGMIN A1:C3"));
+        assert!(!is_valid_command(&mut sheet, "ARGMAX A1:Z9"));
+    }
+
+    #[test]
+    fn test_index_marks_circular_cells() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=B1+1");
+        process_command(&mut sheet, "B1=A1");
+        assert!(sheet.cells[0][1].has_circular);
+        // An unrelated edit clears the flag, but the cycle and its error remain
+        process_command(&mut sheet, "C3=1");
+        assert!(!sheet.cells[0][1].has_circular);
+
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = Some(sheet);
+        let client = Client::tracked(
+            rocket::build()
+                .mount("/", rocket::routes![index])
+                .attach(rocket_dyn_templates::Template::fairing()),
+        )
+        .unwrap();
+        let body = client.get("/").dispatch().into_string().unwrap();
+        assert!(body.contains(r#"<td class="circular">#CIRC!</td>"#));
+        assert_eq!(body.matches("#CIRC!").count(), 1);
+        *SHEET.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_apply_fills_relative_formula() {
+        let mut sheet = create_sheet(10, 5, true).unwrap();
+        for i in 0..10 {
+            update_cell(&mut sheet, i, 0, &(i + 1).to_string());
+        }
+        assert_eq!(process_command(&mut sheet, "APPLY B1:B10 =A1*2"), None);
+        assert_eq!(sheet.cells[2][1].formula.as_deref(), Some("A3*2"));
+        assert_eq!(sheet.cells[2][1].value, 6);
+        assert_eq!(sheet.cells[9][1].value, 20);
+        update_cell(&mut sheet, 2, 0, "7");
+        assert_eq!(sheet.cells[2][1].value, 14);
+
+        // One snapshot covers the whole fill
+        undo(&mut sheet);
+        assert_eq!(sheet.cells[2][1].formula, None);
+        assert!(process_command(&mut sheet, "APPLY B1:B10 =A2")
+            .unwrap()
+            .contains("outside the sheet"));
+
+        // A locked cell in the range stops the whole command
+        process_command(&mut sheet, "LOCK B4");
+        assert_eq!(
+            process_command(&mut sheet, "APPLY B1:B10 =A1+1"),
+            Some("Cannot apply a formula to locked cells".to_string())
+        );
+        assert_eq!(sheet.cells[0][1].formula, None);
+    }
+
+    #[test]
+    fn test_dangling_operators_are_rejected() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        update_cell(&mut sheet, 0, 0, "3");
+        for formula in ["A1++B1", "A1 + + B1", "A1+", "A1 + ", "+A1", "+5", "+5%", "(A1*)"] {
+            assert!(!is_valid_formula(&mut sheet, formula), "{} should be rejected", formula);
+            assert_eq!(
+                evaluate_expression(&mut sheet, formula, 0, 2),
+                (0, Some(ErrorKind::Invalid))
+            );
+            process_command(&mut sheet, &format!("C1={}", formula));
+            assert_eq!(sheet.cells[0][2].formula, None);
+        }
+        // Unary minus is still accepted
+        assert!(is_valid_formula(&mut sheet, "A1+-B1"));
+        assert!(is_valid_formula(&mut sheet, "-A1"));
+    }
+
+    #[test]
+    fn test_index_sends_capped_column_widths() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=12345");
+        let text = "a very long piece of text that runs far past any column";
+        assert!(text.len() > 40);
+        process_command(&mut sheet, &format!(r#"B1="{}""#, text));
+
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = Some(sheet);
+        let client = Client::tracked(
+            rocket::build()
+                .mount("/", rocket::routes![index])
+                .attach(rocket_dyn_templates::Template::fairing()),
+        )
+        .unwrap();
+        let body = client.get("/").dispatch().into_string().unwrap();
+        assert_eq!(body.matches(r#"<col style="width: "#).count(), 5);
+        assert!(body.contains(r#"<col style="width: 5ch">"#));
+        // The long text is wider than the cap, so its column is held at 40
+        assert!(body.contains(r#"<col style="width: 40ch">"#));
+        assert_eq!(body.matches(r#"<col style="width: 1ch">"#).count(), 3);
+        *SHEET.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_eval_reports_without_storing() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=7");
+        assert_eq!(process_command(&mut sheet, "EVAL 2+3*4"), Some("14".to_string()));
+        assert_eq!(process_command(&mut sheet, "EVAL A1"), Some("7".to_string()));
+        assert_eq!(process_command(&mut sheet, "EVAL A1/0"), Some("#DIV/0!".to_string()));
+        assert!(process_command(&mut sheet, "EVAL A1+").unwrap().starts_with("Invalid formula"));
+        // Nothing was written to any cell
+        assert_eq!(sheet.cells.iter().flatten().filter(|cell| cell.is_set).count(), 1);
+        assert!(sheet.dependency_graph.is_empty());
+    }
+
+    #[test]
+    fn test_routes_without_sheet_do_not_panic() {
+        let _guard = lock_global_sheet();
+        *SHEET.lock().unwrap() = None;
+        let client = Client::tracked(
+            rocket::build()
+                .mount("/", rocket::routes![index, crate::scroll, crate::values])
+                .attach(rocket_dyn_templates::Template::fairing()),
+        )
+        .unwrap();
+        let response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.into_string().unwrap(), "No spreadsheet is loaded");
+        assert_eq!(client.post("/scroll/s").dispatch().status(), Status::SeeOther);
+        assert_eq!(client.get("/values/A1:B2").dispatch().status(), Status::ServiceUnavailable);
+    }
+
+    #[test]
+    fn test_quoted_text_is_not_a_dependency() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, r#"A1="see A1""#);
+        assert!(!sheet.cells[0][0].has_circular);
+        assert!(!sheet.circular_dependency_detected);
+        assert_eq!(formula_dependencies(&mut sheet, r#""x, C2""#), vec![]);
+        assert_eq!(
+            formula_dependencies(&mut sheet, r#"IF(A1>"B2", C1, SUM(D1:D3))"#),
+            vec![
+                DependencyType::Single { row: 0, col: 0 },
+                DependencyType::Single { row: 0, col: 2 },
+                DependencyType::Range { start_row: 0, start_col: 3, end_row: 2, end_col: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_range_stats_follow_direct_writes() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=5");
+        process_command(&mut sheet, "A2=10");
+        process_command(&mut sheet, "B1=SUM(A1:A2)");
+        process_command(&mut sheet, "A3=A1*3");
+        process_command(&mut sheet, "COPY A3:A3");
+        process_command(&mut sheet, "PASTE A2");
+        assert_eq!(sheet.cells[0][1].value, 20);
+
+        // Plain values pasted over a cell of the range
+        process_command(&mut sheet, "D1=1");
+        process_command(&mut sheet, "COPY D1:D1");
+        process_command(&mut sheet, "PASTE A1");
+        assert_eq!(sheet.cells[0][1].value, 1 + 3);
+
+        // Sorting, filling and cutting write cells directly
+        process_command(&mut sheet, "D2=7");
+        process_command(&mut sheet, "D3=2");
+        process_command(&mut sheet, "D4=9");
+        process_command(&mut sheet, "E1=SUM(D2:D3)");
+        process_command(&mut sheet, "D2=SORTD(D2:D4)");
+        assert_eq!(sheet.cells[0][4].value, 9 + 7);
+        process_command(&mut sheet, "C1=2");
+        process_command(&mut sheet, "C2=4");
+        process_command(&mut sheet, "C3=6");
+        process_command(&mut sheet, "E2=SUM(C4:C5)");
+        process_command(&mut sheet, "C4=AUTOFILL(C4:C5)");
+        assert_eq!(sheet.cells[1][4].value, 8 + 10);
+        process_command(&mut sheet, "CUT C4:C4");
+        assert_eq!(sheet.cells[1][4].value, 10);
+    }
+
+    #[test]
+    fn test_offset_targets_follow_their_offsets() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "D1=OFFSET(A1,0,3)");
+        assert!(sheet.cells[0][3].has_circular);
+
+        process_command(&mut sheet, "A2=10");
+        process_command(&mut sheet, "A3=20");
+        process_command(&mut sheet, "E1=1");
+        process_command(&mut sheet, "C1=OFFSET(A1,E1,0)");
+        assert_eq!(sheet.cells[0][2].value, 10);
+        // The offset moves the target to A3, and later changes to A3 reach C1
+        process_command(&mut sheet, "E1=2");
+        assert_eq!(sheet.cells[0][2].value, 20);
+        process_command(&mut sheet, "A3=30");
+        assert_eq!(sheet.cells[0][2].value, 30);
+        process_command(&mut sheet, "A2=99");
+        assert_eq!(sheet.cells[0][2].value, 30);
+    }
+
+    #[test]
+    fn test_linked_sheets_refresh_through_chains() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=3");
+        process_command(&mut sheet, "NEWSHEET Sheet2");
+        process_command(&mut sheet, "NEWSHEET Sheet3");
+        process_command(&mut sheet, "SHEET Sheet2");
+        process_command(&mut sheet, "A1=Sheet1!A1*2");
+        process_command(&mut sheet, "SHEET Sheet3");
+        process_command(&mut sheet, "A1=Sheet2!A1+1");
+        assert_eq!(sheet.cells[0][0].value, 7);
+
+        process_command(&mut sheet, "SHEET Sheet1");
+        process_command(&mut sheet, "A1=10");
+        assert_eq!(sheet.workbook.sheets["Sheet2"].cells[0][0].value, 20);
+        assert_eq!(sheet.workbook.sheets["Sheet3"].cells[0][0].value, 21);
+
+        // Scrolling and reading commands leave the other sheets alone
+        sheet.cells[0][0].value = 50;
+        process_command(&mut sheet, "s");
+        process_command(&mut sheet, "GET A1");
+        assert_eq!(sheet.workbook.sheets["Sheet3"].cells[0][0].value, 21);
+        process_command(&mut sheet, "B1=1");
+        assert_eq!(sheet.workbook.sheets["Sheet3"].cells[0][0].value, 101);
+    }
+
+    #[test]
+    fn test_import_skips_non_numeric_fields() {
+        let mut sheet = create_sheet(5, 5, true).unwrap();
+        process_command(&mut sheet, "A1=2");
+        process_command(&mut sheet, "B1=A1*10");
+        let message = process_command(&mut sheet, "IMPORTRANGE B1 \"abc\"").unwrap();
+        assert!(message.contains("skipped non-numeric value"));
+        // The formula is left in place and still follows A1
+        assert_eq!(sheet.cells[0][1].value, 20);
+        process_command(&mut sheet, "A1=3");
+        assert_eq!(sheet.cells[0][1].value, 30);
+    }
+
+    #[test]
+    fn test_format_grid_pads_by_display_width() {
+        let mut sheet = create_sheet(5, 5, false).unwrap();
+        sheet.column_headers = vec![Some("名前".to_string())];
+        sheet.error_text = Some("e\u{301}".to_string());
+        sheet.cells[0][0].value = -5;
+        sheet.cells[1][0].is_error = true;
+
+        // The wide name covers eight terminal columns and the accented "e" just one
+        let grid = format_grid(&sheet, &[0, 1], &[0], false);
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(
+            lines,
+            vec!["     A (名前) ", "   1       -5 ", "   2        e\u{301} "]
+        );
+        assert_eq!(column_widths(&sheet, &[0, 1], &[0]), vec![8]);
+
+        let styled = format_grid(&sheet, &[1], &[0], true);
+        assert!(styled.ends_with("   2        e\u{301} \n"));
+    }
+
+    #[test]
+    fn test_validate_rules_follow_structural_edits() {
+        let mut sheet = create_test_sheet(6, 5, true);
+        process_command(&mut sheet, "VALIDATE A2:A3 0 100");
+        process_command(&mut sheet, "VALIDATE C1 0 10");
+        process_command(&mut sheet, "VALIDATE D1:D2 0 10");
+
+        // Inserting a row above the rule moves it down with its cells
+        assert_eq!(process_command(&mut sheet, "INSERTROW 1"), None);
+        assert_eq!(sheet.validation_at(1, 0), None);
+        assert_eq!(sheet.validation_at(3, 0), Some((0, 100)));
+        assert_eq!(sheet.validation_at(1, 2), Some((0, 10)));
+
+        // Deleting cells above it shifts it back up; a rule whose cells are deleted goes
+        assert_eq!(process_command(&mut sheet, "DELETERANGE A1:C1 up"), None);
+        assert_eq!(sheet.validation_at(2, 0), Some((0, 100)));
+        assert_eq!(sheet.validation_at(3, 0), None);
+        assert_eq!(sheet.validation_at(0, 2), Some((0, 10)));
+        assert_eq!(process_command(&mut sheet, "DELETERANGE C1 up"), None);
+        assert_eq!(sheet.validation_at(0, 2), None);
+
+        // Moving the cells carries the rule along
+        assert_eq!(process_command(&mut sheet, "MOVE A2:A3 B4"), None);
+        assert_eq!(sheet.validation_at(1, 0), None);
+        assert_eq!(sheet.validation_at(4, 1), Some((0, 100)));
+        assert_eq!(
+            process_command(&mut sheet, "B5=200"),
+            Some("Value 200 for B5 is outside the allowed range 0 to 100".to_string())
+        );
+
+        // Deleting a column drops a rule that only covered it
+        process_command(&mut sheet, "COLDEL D");
+        assert_eq!(sheet.validation_at(1, 3), None);
+        process_command(&mut sheet, "ROWDEL 5");
+        assert_eq!(sheet.validation_at(3, 1), Some((0, 100)));
+    }
+
+    #[test]
+    fn test_undo_restores_merged_regions() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "MERGE A1:B1");
+        process_command(&mut sheet, "MERGE A3:C3");
+        assert_eq!(process_command(&mut sheet, "UNMERGE A1"), None);
+        assert_eq!(sheet.merged_regions, vec![(2, 0, 2, 2)]);
+
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.merged_regions, vec![(0, 0, 0, 1), (2, 0, 2, 2)]);
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.merged_regions, vec![(0, 0, 0, 1)]);
+        process_command(&mut sheet, "redo");
+        assert_eq!(sheet.merged_regions, vec![(0, 0, 0, 1), (2, 0, 2, 2)]);
+
+        // Checkpoints keep the regions too
+        process_command(&mut sheet, "CHECKPOINT merged");
+        process_command(&mut sheet, "UNMERGE A1:C3");
+        assert!(sheet.merged_regions.is_empty());
+        process_command(&mut sheet, "RESTORE merged");
+        assert_eq!(sheet.merged_regions, vec![(0, 0, 0, 1), (2, 0, 2, 2)]);
+
+        // A refused UNMERGE adds no undo step
+        process_command(&mut sheet, "UNMERGE E5");
+        process_command(&mut sheet, "undo");
+        assert!(sheet.merged_regions.is_empty());
+    }
+
+    #[test]
+    fn test_undo_insert_row_restores_merged_regions() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A2=3");
+        process_command(&mut sheet, "MERGE A2:B2");
+        assert_eq!(process_command(&mut sheet, "INSERTROW 1"), None);
+        assert_eq!(sheet.merged_regions, vec![(2, 0, 2, 1)]);
+        assert_eq!(sheet.cells[2][0].value, 3);
+
+        // The region goes back to the row its value returns to
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.merged_regions, vec![(1, 0, 1, 1)]);
+        assert_eq!(sheet.cells[1][0].value, 3);
+        process_command(&mut sheet, "redo");
+        assert_eq!(sheet.merged_regions, vec![(2, 0, 2, 1)]);
+    }
+
+    #[test]
+    fn test_undo_restores_validate_rules() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=50");
+        process_command(&mut sheet, "VALIDATE A1:A2 0 10");
+        assert!(sheet.cells[0][0].is_error);
+
+        // Undoing INSERTROW puts the rule back on the cells it covered
+        process_command(&mut sheet, "INSERTROW 1");
+        assert_eq!(sheet.validation_at(1, 0), Some((0, 10)));
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.validation_at(0, 0), Some((0, 10)));
+        assert_eq!(sheet.validation_at(2, 0), None);
+
+        // Undoing VALIDATE removes the rule and the error it flagged
+        process_command(&mut sheet, "undo");
+        assert_eq!(sheet.validation_at(0, 0), None);
+        assert!(!sheet.cells[0][0].is_error);
+        assert_eq!(sheet.cells[0][0].value, 50);
+    }
+
+    #[test]
+    fn test_sumif_overflow_is_an_error() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=2000000000");
+        process_command(&mut sheet, "A2=2000000000");
+        assert_eq!(sumif(&mut sheet, "A1:A2, >0"), Ok(4_000_000_000));
+
+        process_command(&mut sheet, "B1=SUMIF(A1:A2, >0)");
+        assert!(sheet.cells[0][1].is_error);
+        assert_eq!(sheet.cells[0][1].error_kind, Some(ErrorKind::Overflow));
+
+        // Once the sum fits again, so does the cell
+        process_command(&mut sheet, "A2=5");
+        assert!(!sheet.cells[0][1].is_error);
+        assert_eq!(sheet.cells[0][1].value, 2_000_000_005);
+    }
+
+    #[test]
+    fn test_locked_cells_refuse_every_write() {
+        let mut sheet = create_test_sheet(6, 4, true);
+        for (cell, value) in [("A1", 3), ("A2", 1), ("A3", 2), ("B2", 5)] {
+            process_command(&mut sheet, &format!("{}={}", cell, value));
+        }
+        process_command(&mut sheet, "LOCK A2");
+        let undo_steps = sheet.undo_stack.len();
+        let refused = [
+            ("B1=SORTA(A1:A3)", "Cannot change locked cells"),
+            ("B1=SORTD(A1:A3)", "Cannot change locked cells"),
+            ("B1=AUTOFILL(A2:A3)", "Cannot change locked cells"),
+            ("B1=BOLD(A1:A3)", "Cannot change locked cells"),
+            ("B1=ITALIC(A2)", "Cannot change locked cells"),
+            ("B1=UNDERLINE(A1:B2)", "Cannot change locked cells"),
+            ("B1=STRIKE(A2)", "Cannot change locked cells"),
+            ("B1=COLOR(A1:A2, red)", "Cannot change locked cells"),
+            ("CLEARFORMAT A2", "Cannot change locked cells"),
+            ("ROWDEL 2", "Cannot delete locked cells"),
+            ("COLDEL A", "Cannot delete locked cells"),
+            ("DELETERANGE A1:A1 up", "Cannot delete A1:A1: it would change locked cells"),
+            ("INSERTROW 1", "Cannot insert a row: it would move locked cells"),
+        ];
+        for (command, message) in refused {
+            assert_eq!(process_command(&mut sheet, command), Some(message.to_string()), "{}", command);
+        }
+        assert_eq!(sheet.cells[1][0].value, 1);
+        assert!(!sheet.cells[1][0].is_bold);
+        assert!(sheet.cells[1][0].is_locked);
+        assert_eq!(sheet.cells[0][0].value, 3);
+        assert_eq!(sheet.undo_stack.len(), undo_steps);
+
+        // Writes that leave the locked cell alone still work
+        assert_eq!(process_command(&mut sheet, "B1=BOLD(B1:B2)"), None);
+        assert!(sheet.cells[1][1].is_bold);
+        assert_eq!(process_command(&mut sheet, "ROWDEL 1"), None);
+        assert_eq!(sheet.cells[0][0].value, 0);
+        assert_eq!(process_command(&mut sheet, "DELETERANGE B1:B2 up"), None);
+        assert_eq!(process_command(&mut sheet, "INSERTROW 4"), None);
+        assert_eq!(sheet.cells[1][0].value, 1);
+    }
+
+    #[test]
+    fn test_cell_history_follows_moved_cells() {
+        let history = |sheet: &mut Sheet, cell: &str| process_command(sheet, &format!("HISTORY {}", cell));
+        let mut sheet = create_test_sheet(6, 6, true);
+        process_command(&mut sheet, "A1=1");
+        process_command(&mut sheet, "A2=2");
+
+        // Inserting a row above A1 takes A1's history down to A2 and leaves none behind
+        assert_eq!(process_command(&mut sheet, "INSERTROW 1"), None);
+        assert_eq!(history(&mut sheet, "A1"), Some("No history recorded for cell A1".to_string()));
+        assert_eq!(history(&mut sheet, "A2"), Some("History of cell A2: 1 = 1".to_string()));
+        assert_eq!(history(&mut sheet, "A3"), Some("History of cell A3: 2 = 2".to_string()));
+
+        // Deleting A2 drops its history and moves A3's up
+        assert_eq!(process_command(&mut sheet, "DELETERANGE A2 up"), None);
+        assert_eq!(history(&mut sheet, "A2"), Some("History of cell A2: 2 = 2".to_string()));
+        assert_eq!(history(&mut sheet, "A3"), Some("No history recorded for cell A3".to_string()));
+
+        // Moving A2 over C1 replaces C1's history with A2's
+        process_command(&mut sheet, "C1=9");
+        assert_eq!(process_command(&mut sheet, "MOVE A2 C1"), None);
+        assert_eq!(history(&mut sheet, "C1"), Some("History of cell C1: 2 = 2".to_string()));
+        assert_eq!(history(&mut sheet, "A2"), Some("No history recorded for cell A2".to_string()));
+
+        // Swapping exchanges the two histories
+        process_command(&mut sheet, "E1=5");
+        assert_eq!(process_command(&mut sheet, "SWAP C1 E1"), None);
+        assert_eq!(history(&mut sheet, "C1"), Some("History of cell C1: 5 = 5".to_string()));
+        assert_eq!(history(&mut sheet, "E1"), Some("History of cell E1: 2 = 2".to_string()));
+    }
+
+    #[test]
+    fn test_sumif_skips_text_cells() {
+        let mut sheet = create_test_sheet(5, 5, true);
+        process_command(&mut sheet, "A1=3");
+        process_command(&mut sheet, "A2=\"abc\"");
+        process_command(&mut sheet, "A3=8");
+        process_command(&mut sheet, "B1=10");
+        process_command(&mut sheet, "B2=20");
+        process_command(&mut sheet, "B3=40");
+        assert_eq!(sheet.cells[1][0].kind, DataKind::Text);
+
+        // Text holds no number, so it never meets a criterion, even one its stored 0 would meet
+        process_command(&mut sheet, "C1=SUMIF(A1:A3, <5, B1:B3)");
+        assert_eq!(sheet.cells[0][2].value, 10);
+        assert_eq!(sumif(&mut sheet, "A1:A3, 0, B1:B3"), Ok(0));
+        assert_eq!(sumif(&mut sheet, "A1:A3, >=0"), Ok(11));
+        assert!(!is_valid_formula(&mut sheet, "SUMIF(A1:A3, B1)"));
+    }
+
+    #[test]
+    fn test_command_table_flags_view_only_commands() {
+        let view_only = |keyword: &str| {
+            crate::utils::COMMANDS
+                .iter()
+                .find(|&&(name, _)| name == keyword)
+                .map(|&(_, view_only)| view_only)
+        };
+        for keyword in ["STATS", "COMPARE", "EVAL", "SCINOTATION", "GET", "w", "GRAPH"] {
+            assert_eq!(view_only(keyword), Some(true), "{}", keyword);
+        }
+        for keyword in ["undo", "PASTE", "SEED", "RECOMPUTE", "IMPORTRANGE"] {
+            assert_eq!(view_only(keyword), Some(false), "{}", keyword);
+        }
+        let mut names: Vec<&str> = crate::utils::COMMANDS.iter().map(|&(name, _)| name).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), crate::utils::COMMANDS.len());
+
+        // Suggestions come from the same table
+        let mut sheet = create_test_sheet(5, 5, true);
+        assert_eq!(
+            process_command(&mut sheet, "STAT A1:A2"),
+            Some("Invalid command format (did you mean STATS?)".to_string())
+        );
+    }
+}
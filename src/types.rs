@@ -1,6 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::mpsc::Sender;
 use std::sync::Mutex;
 
+/// Largest number of rows a sheet may have.
+pub const MAX_ROWS: i32 = 999;
+/// Largest number of columns a sheet may have (column ZZZ).
+pub const MAX_COLS: i32 = 18278;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum DependencyType {
     Single {
@@ -32,6 +38,38 @@ pub enum PatternType {
     Unknown,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DataKind {
+    #[default]
+    Number,
+    Date,    // Value holds days since 1970-01-01
+    Text,    // Shown as text, see `cell_text`
+    Percent, // Value holds the percentage, e.g. 50 for 50%
+}
+
+/// Why a cell's formula could not be evaluated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    DivByZero,    // Division by zero
+    BadReference, // A reference outside the sheet or range, or to a missing sheet
+    Circular,     // The formula depends on its own cell
+    Overflow,     // The result does not fit in a cell
+    Invalid,      // Any other failure, e.g. a malformed formula or a bad function argument
+}
+
+impl ErrorKind {
+    /// The code shown in place of the value, as in common spreadsheet programs.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::DivByZero => "#DIV/0!",
+            ErrorKind::BadReference => "#REF!",
+            ErrorKind::Circular => "#CIRC!",
+            ErrorKind::Overflow => "#NUM!",
+            ErrorKind::Invalid => "#VALUE!",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Cell {
     pub value: i32,
@@ -42,6 +80,13 @@ pub struct Cell {
     pub is_bold: bool,
     pub is_italic: bool,
     pub is_underline: bool,
+    pub is_strike: bool,
+    pub color: Option<String>,
+    pub is_locked: bool,
+    pub kind: DataKind,
+    pub error_kind: Option<ErrorKind>, // Why the formula failed, for an error or circular cell
+    pub is_set: bool, // Whether the cell was ever given a value, so an explicit 0 can be told apart
+    pub stats: Option<Box<RangeStats>>, // Running totals of a SUM, AVG or STDEV over one range
 }
 
 impl Cell {
@@ -55,14 +100,54 @@ impl Cell {
             is_bold: false,
             is_italic: false,
             is_underline: false,
+            is_strike: false,
+            color: None,
+            is_locked: false,
+            kind: DataKind::Number,
+            error_kind: None,
+            is_set: false,
+            stats: None,
         }
     }
+
+    /// Whether the cell holds a formula that computes its value, rather than a plain number.
+    pub fn is_computed(&self) -> bool {
+        self.formula.as_deref().is_some_and(|formula| {
+            let formula = formula.trim();
+            let number = formula.strip_suffix('%').unwrap_or(formula);
+            number.trim().parse::<i32>().is_err()
+        })
+    }
+}
+
+/// Running statistics of the values in a range.
+///
+/// A cell whose formula is SUM, AVG or STDEV over a single range keeps these, so that when one
+/// cell of the range changes its result is updated in constant time instead of reading the whole
+/// range again; see `recalculate_after_change`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeStats {
+    pub count: usize,
+    pub sum: i64,
+    pub mean: f64,
+    pub m2: f64, // Sum of squared differences from the mean, as in Welford's algorithm
+}
+
+/// A read-only copy of the values in a range, see `Sheet::snapshot`.
+///
+/// A snapshot owns its data, so it can be read after the lock on the global sheet is released.
+/// Each value is `Ok` with the cell's value, or `Err` with the kind of error the cell holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SheetSnapshot {
+    pub values: Vec<Vec<Result<i32, ErrorKind>>>, // One line of values per row of the range
 }
 
 #[derive(Clone)]
 pub struct SheetState {
     pub cells: Vec<Vec<Cell>>,
     pub dependency_graph: HashMap<(i32, i32), CellDependencies>,
+    pub merged_regions: Vec<(i32, i32, i32, i32)>,
+    pub validation_rules: Vec<(i32, i32, i32, i32, i32, i32)>,
 }
 
 #[derive(Default)]
@@ -75,10 +160,50 @@ pub struct Sheet {
     pub output_enabled: bool,
     pub circular_dependency_detected: bool,
     pub extension_enabled: bool,
+    pub command_history: Vec<String>,
+    pub command_position: usize, // Where the next `!-N` counts back from in command_history
     pub max_history_size: usize,
     pub dependency_graph: HashMap<(i32, i32), CellDependencies>,
     pub undo_stack: Vec<SheetState>,
     pub redo_stack: Vec<SheetState>,
+    pub merged_regions: Vec<(i32, i32, i32, i32)>, // (start_row, start_col, end_row, end_col)
+    pub column_headers: Vec<Option<String>>,       // Column names read from a CSV header row
+    pub frozen_rows: i32,                          // Leading rows kept visible while scrolling
+    pub frozen_cols: i32,                          // Leading columns kept visible while scrolling
+    pub scientific_notation: bool,                 // Show large values as e.g. 1.2e6
+    pub show_formulas: bool, // Show the formula text of computed cells instead of their values
+    pub cell_history: HashMap<(i32, i32), Vec<(i32, String)>>, // Recent (value, formula) per cell
+    pub last_elapsed: f64,   // Seconds taken by the last command, read by ELAPSED()
+    pub sleep_cache: HashMap<(i32, i32), i32>, // Duration each SLEEP cell last slept for
+    pub recalculating: bool, // Set while dependents are re-evaluated, so SLEEP can reuse its value
+    pub workbook: Workbook,  // This sheet's name and the other worksheets
+    pub macros: HashMap<String, Vec<String>>, // Commands recorded under a name by ALIAS or MACRO
+    pub validation_rules: Vec<(i32, i32, i32, i32, i32, i32)>, // VALIDATE ranges with (min, max)
+    pub view_history: Vec<(i32, i32)>, // Views scrolled away from since the last data change
+    pub view_size: Option<i32>, // Rows and columns shown at once, if not the default
+    pub checkpoints: HashMap<String, SheetState>, // Snapshots saved by CHECKPOINT, by name
+    pub sparse_view: bool,   // Show cells that were never set as blank instead of 0
+    pub error_text: Option<String>, // Shown for every error cell instead of its error code
+    pub selected: Option<(i32, i32)>, // Cell highlighted in the web interface, set by /select
+    pub rng_state: u64,      // State of the generator behind RANDBETWEEN, set by SEED
+    pub random_cache: HashMap<(i32, i32), (i32, i32, i32)>, // (low, high, value) last drawn per cell
+    pub recalc_budget: Option<usize>, // Most dependents recalculated by one command, see BUDGET
+    pub recalc_remaining: Option<usize>, // What is left of the budget during the current command
+    pub deferred_cells: HashSet<(i32, i32)>, // Dependents the budget left stale in this command
+}
+
+/// A range as `(sheet, start_row, start_col, end_row, end_col)`, where `sheet` names another
+/// worksheet of the workbook or is `None` for the active sheet.
+pub type SheetRange = (Option<String>, i32, i32, i32, i32);
+
+/// The worksheets of a workbook, as seen from the active sheet.
+///
+/// The active sheet owns the workbook, so `sheets` holds every worksheet except the active one.
+/// Switching sheets moves the workbook over to the newly active sheet.
+#[derive(Default)]
+pub struct Workbook {
+    pub active: String,                 // Name of the sheet that owns this workbook
+    pub sheets: HashMap<String, Sheet>, // The other worksheets, by name
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -87,6 +212,13 @@ pub enum GraphType {
     Scatter,
 }
 
+/// Why a cell reference could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseError {
+    BadSyntax,   // Not a column letters followed by a row number, e.g. "1A" or "A1B"
+    OutOfBounds, // Well formed, but outside the sheet, e.g. "A0" or a row past the last one
+}
+
 #[derive(Clone, Debug)]
 pub struct Clipboard {
     pub contents: Vec<Vec<Cell>>,
@@ -96,4 +228,9 @@ pub struct Clipboard {
 lazy_static::lazy_static! {
     pub static ref SHEET: Mutex<Option<Sheet>> = Mutex::new(None);
     pub static ref CLIPBOARD: Mutex<Option<Clipboard>> = Mutex::new(None);
+    // Commands posted from the web interface, with their ids, waiting for the command worker
+    pub static ref COMMAND_QUEUE: Mutex<Option<Sender<(u64, String)>>> = Mutex::new(None);
+    // Messages from the most recent queued commands by id, shown to the client that posted them
+    pub static ref COMMAND_MESSAGES: Mutex<BTreeMap<u64, Option<String>>> =
+        Mutex::new(BTreeMap::new());
 }
@@ -1,41 +1,55 @@
-use crate::types::{PatternType, Sheet};
+use crate::parser::{parse, Expr};
+use crate::types::{Cell, DataKind, ParseError, PatternType, Sheet, SheetRange, MAX_COLS};
+use std::collections::HashMap;
 use std::str::FromStr;
 
 /// Parses a cell reference string into row and column indices.
 ///
 /// This function converts a cell reference (e.g., "A1") into zero-based row and column indices.
 /// It splits the input string into alphabetic (column) and numeric (row) parts, decodes the column
-/// letters to a number, and adjusts the row number to zero-based indexing.
+/// letters to a number, and adjusts the row number to zero-based indexing. Whitespace around the
+/// reference is ignored, but whitespace inside it (e.g., "A 1") is a syntax error; the formula
+/// parser and validator reject such references the same way.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet (for bounds checking).
 /// * `ref_str` - The cell reference string (e.g., "A1").
 ///
 /// # Returns
-/// An `Option<(i32, i32)>` containing the row and column indices, or `None` if the reference is invalid.
+/// A `Result<(i32, i32), ParseError>` containing the row and column indices. The error is
+/// `ParseError::BadSyntax` if the string is not column letters followed by a row number, and
+/// `ParseError::OutOfBounds` if the reference is well formed but outside the sheet (including
+/// row 0).
 ///
 /// # Example
 /// ```
 /// let mut sheet = create_sheet(10, 10, false).unwrap();
-/// let result = parse_cell_reference(&mut sheet, "B2");
-/// assert_eq!(result, Some((1, 1))); // Row 1, Col 1
+/// assert_eq!(parse_cell_reference(&mut sheet, "B2"), Ok((1, 1))); // Row 1, Col 1
+/// assert_eq!(parse_cell_reference(&mut sheet, "B0"), Err(ParseError::OutOfBounds));
+/// assert_eq!(parse_cell_reference(&mut sheet, "2B"), Err(ParseError::BadSyntax));
+/// assert_eq!(parse_cell_reference(&mut sheet, "B 2"), Err(ParseError::BadSyntax));
 /// ```
-pub fn parse_cell_reference(sheet: &mut Sheet, ref_str: &str) -> Option<(i32, i32)> {
+pub fn parse_cell_reference(sheet: &mut Sheet, ref_str: &str) -> Result<(i32, i32), ParseError> {
     let ref_str = ref_str.trim();
-    let num_start = ref_str.chars().position(|c| c.is_ascii_digit())?;
+    let num_start = ref_str
+        .chars()
+        .position(|c| c.is_ascii_digit())
+        .ok_or(ParseError::BadSyntax)?;
     let (col_str, row_str) = ref_str.split_at(num_start);
 
-    if col_str.is_empty() {
-        return None;
+    if col_str.is_empty()
+        || !col_str.chars().all(|c| c.is_ascii_alphabetic())
+        || !row_str.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(ParseError::BadSyntax);
     }
-
-    let col = decode_column(col_str);
-    let row = i32::from_str(row_str).ok()? - 1;
+    let col = decode_column(col_str).ok_or(ParseError::OutOfBounds)?;
+    let row = i32::from_str(row_str).map_err(|_| ParseError::OutOfBounds)? - 1;
 
     if row >= 0 && row < sheet.rows && col >= 0 && col < sheet.cols {
-        Some((row, col))
+        Ok((row, col))
     } else {
-        None
+        Err(ParseError::OutOfBounds)
     }
 }
 
@@ -60,8 +74,8 @@ pub fn parse_cell_reference(sheet: &mut Sheet, ref_str: &str) -> Option<(i32, i3
 /// ```
 pub fn parse_range(sheet: &mut Sheet, range: &str) -> Option<(i32, i32, i32, i32)> {
     let (start, end) = range.split_once(':')?;
-    let (start_row, start_col) = parse_cell_reference(sheet, start)?;
-    let (end_row, end_col) = parse_cell_reference(sheet, end)?;
+    let (start_row, start_col) = parse_cell_reference(sheet, start).ok()?;
+    let (end_row, end_col) = parse_cell_reference(sheet, end).ok()?;
     if start_row <= end_row && start_col <= end_col {
         Some((start_row, start_col, end_row, end_col))
     } else {
@@ -69,16 +83,297 @@ pub fn parse_range(sheet: &mut Sheet, range: &str) -> Option<(i32, i32, i32, i32
     }
 }
 
+/// Replaces each `{...}` row bound in a formula.
+///
+/// A formula may give the row of a reference as a cell in braces, as in `SUM(A1:A{B1})`, to
+/// make the row follow the value of that cell. This function calls `replace` with the text
+/// inside each pair of braces (trimmed) and puts the returned text in place of the braces.
+///
+/// # Arguments
+/// * `formula` - The formula text.
+/// * `replace` - Gives the text for a bound, or `None` if the bound is invalid.
+///
+/// # Returns
+/// An `Option<String>` with the formula after replacement, or `None` if a brace is unmatched or
+/// `replace` returned `None`.
+///
+/// # Example
+/// ```
+/// let replaced = replace_dynamic_bounds("SUM(A1:A{B1})", |bound| Some(format!("[{}]", bound)));
+/// assert_eq!(replaced, Some("SUM(A1:A[B1])".to_string()));
+/// assert_eq!(replace_dynamic_bounds("A{B1", |_| Some(String::new())), None);
+/// ```
+pub fn replace_dynamic_bounds(
+    formula: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> Option<String> {
+    let mut result = String::new();
+    let mut rest = formula;
+    while let Some(open) = rest.find(['{', '}']) {
+        if rest[open..].starts_with('}') {
+            return None;
+        }
+        let close = open + rest[open..].find('}')?;
+        let bound = &rest[open + 1..close];
+        if bound.contains('{') {
+            return None;
+        }
+        result.push_str(&rest[..open]);
+        result.push_str(&replace(bound.trim())?);
+        rest = &rest[close + 1..];
+    }
+    result.push_str(rest);
+    Some(result)
+}
+
+/// Resolves the `{...}` row bounds of a formula to the current values of their cells.
+///
+/// See `replace_dynamic_bounds`. Each bound must name a cell of the sheet that does not hold an
+/// error; the cell's value is used as the row number.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `formula` - The formula text.
+///
+/// # Returns
+/// An `Option<String>` with the resolved formula, or `None` if a bound is invalid.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// sheet.cells[0][1].value = 3;
+/// assert_eq!(resolve_dynamic_bounds(&mut sheet, "SUM(A1:A{B1})"), Some("SUM(A1:A3)".to_string()));
+/// ```
+pub fn resolve_dynamic_bounds(sheet: &mut Sheet, formula: &str) -> Option<String> {
+    replace_dynamic_bounds(formula, |bound| {
+        let (row, col) = parse_cell_reference(sheet, bound).ok()?;
+        let cell = &sheet.cells[row as usize][col as usize];
+        (!cell.is_error).then(|| cell.value.to_string())
+    })
+}
+
+/// Parses either a single cell reference or a range.
+///
+/// A single cell is returned as a one-cell range, so callers that act on a rectangle of cells
+/// (formatting, locking) can accept both `A1` and `A1:C3`.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `target` - The cell reference or range string.
+///
+/// # Returns
+/// An `Option<(i32, i32, i32, i32)>` containing `(start_row, start_col, end_row, end_col)`, or
+/// `None` if the target is neither a valid cell nor a valid range.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// assert_eq!(parse_cell_or_range(&mut sheet, "B2"), Some((1, 1, 1, 1)));
+/// assert_eq!(parse_cell_or_range(&mut sheet, "A1:B2"), Some((0, 0, 1, 1)));
+/// ```
+pub fn parse_cell_or_range(sheet: &mut Sheet, target: &str) -> Option<(i32, i32, i32, i32)> {
+    parse_range(sheet, target).or_else(|| {
+        parse_cell_reference(sheet, target)
+            .ok()
+            .map(|(row, col)| (row, col, row, col))
+    })
+}
+
+/// Parses a reference into another worksheet of the workbook.
+///
+/// The reference is a sheet name, an exclamation mark, and a cell or range on that sheet
+/// (e.g., "Sheet2!A1" or "Sheet2!A1:A5"). Bounds are checked against the named sheet, which must
+/// be one of the other sheets held in `sheet.workbook`. Plain references are left to
+/// `parse_cell_reference`, which rejects the `!`, so a reference into another sheet is never
+/// mistaken for a cell of the active one.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the active spreadsheet.
+/// * `reference` - The qualified reference string (e.g., "Sheet2!A1:A5").
+///
+/// # Returns
+/// An `Option<(String, i32, i32, i32, i32)>` containing the sheet name and
+/// `(start_row, start_col, end_row, end_col)` on that sheet, or `None` if the sheet does not
+/// exist or the reference is invalid there.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, true).unwrap();
+/// process_command(&mut sheet, "NEWSHEET Sheet2");
+/// assert_eq!(
+///     parse_external_reference(&mut sheet, "Sheet2!B1:B3"),
+///     Some(("Sheet2".to_string(), 0, 1, 2, 1))
+/// );
+/// assert_eq!(parse_external_reference(&mut sheet, "Sheet3!A1"), None);
+/// ```
+pub fn parse_external_reference(
+    sheet: &mut Sheet,
+    reference: &str,
+) -> Option<(String, i32, i32, i32, i32)> {
+    let (name, local) = reference.trim().split_once('!')?;
+    let target = sheet.workbook.sheets.get_mut(name)?;
+    let (start_row, start_col, end_row, end_col) = parse_cell_or_range(target, local)?;
+    Some((name.to_string(), start_row, start_col, end_row, end_col))
+}
+
+/// Checks whether a name can be used for a worksheet.
+///
+/// Sheet names are written in front of references in formulas (e.g., `Sheet2!A1`), so they
+/// must start with a letter and contain only letters and digits.
+///
+/// # Arguments
+/// * `name` - The proposed sheet name.
+///
+/// # Returns
+/// A boolean indicating whether the name is valid.
+///
+/// # Example
+/// ```
+/// assert!(is_valid_sheet_name("Sheet2"));
+/// assert!(!is_valid_sheet_name("2024 data"));
+/// ```
+pub fn is_valid_sheet_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Checks whether a name given to a file-writing command names a file in the current directory.
+///
+/// Commands such as `GRAPH` can be sent by any web client, so the file they write must not be
+/// able to reach outside the working directory: path separators, `.`/`..` and absolute paths
+/// are refused.
+///
+/// # Arguments
+/// * `name` - The file name given to the command.
+///
+/// # Returns
+/// A boolean indicating whether the name is a plain file name.
+///
+/// # Example
+/// ```
+/// assert!(is_plain_file_name("chart.txt"));
+/// assert!(!is_plain_file_name("../chart.txt"));
+/// ```
+pub fn is_plain_file_name(name: &str) -> bool {
+    !name.contains(['/', '\\'])
+        && std::path::Path::new(name).file_name() == Some(std::ffi::OsStr::new(name))
+}
+
+/// Checks whether a name can be used for a macro defined with `ALIAS` or `MACRO`.
+///
+/// Macros are run by typing their name as the first word of a command, so the name must not
+/// shadow anything else: it must be made of lowercase letters and digits (built-in keywords
+/// are uppercase), start with a letter, and be neither a cell reference nor one of the
+/// lowercase commands.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `name` - The proposed macro name.
+///
+/// # Returns
+/// A boolean indicating whether the name is valid.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(5, 5, true).unwrap();
+/// assert!(is_valid_macro_name(&mut sheet, "fmt"));
+/// assert!(!is_valid_macro_name(&mut sheet, "BOLD"));
+/// assert!(!is_valid_macro_name(&mut sheet, "undo"));
+/// ```
+pub fn is_valid_macro_name(sheet: &mut Sheet, name: &str) -> bool {
+    name.len() > 1
+        && name.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && !["undo", "redo"].contains(&name)
+        && parse_cell_reference(sheet, name).is_err()
+}
+
+/// Parses a comma-separated list of ranges into their start and end indices.
+///
+/// This function splits the input on commas and parses each part with `parse_cell_or_range`,
+/// so that functions like `SUM(A1:A5, C1:C5)` can aggregate over several disjoint ranges. A
+/// single cell (e.g., "B1") is returned as a one-cell range. A part may name another sheet (e.g., "Sheet2!A1:A5"), in which case it is parsed with
+/// `parse_external_reference` and tagged with that sheet's name.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet (for bounds checking).
+/// * `ranges` - The range list string (e.g., "A1:A5, C1:C5").
+///
+/// # Returns
+/// An `Option<Vec<SheetRange>>` containing each parsed range with the sheet it belongs to
+/// (`None` for the active sheet), or `None` if any part is invalid.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let result = parse_range_list(&mut sheet, "A1:A2, C1:C2");
+/// assert_eq!(result, Some(vec![(None, 0, 0, 1, 0), (None, 0, 2, 1, 2)]));
+/// assert_eq!(parse_range_list(&mut sheet, "B1"), Some(vec![(None, 0, 1, 0, 1)]));
+/// ```
+pub fn parse_range_list(sheet: &mut Sheet, ranges: &str) -> Option<Vec<SheetRange>> {
+    ranges
+        .split(',')
+        .map(|range| {
+            if range.contains('!') {
+                parse_external_reference(sheet, range).map(
+                    |(name, start_row, start_col, end_row, end_col)| {
+                        (Some(name), start_row, start_col, end_row, end_col)
+                    },
+                )
+            } else {
+                parse_cell_or_range(sheet, range.trim()).map(
+                    |(start_row, start_col, end_row, end_col)| {
+                        (None, start_row, start_col, end_row, end_col)
+                    },
+                )
+            }
+        })
+        .collect()
+}
+
+/// Resolves a function argument that names a cell or a range, possibly on another sheet.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `arg` - The parsed argument (e.g., the `A1:B2` of `SUM(A1:B2)`).
+///
+/// # Returns
+/// An `Option<SheetRange>` with the sheet name, if any, and the bounds of the range, or `None` if
+/// the argument is not a valid cell or range.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let Some(Expr::Call { args, .. }) = parse("SUM(B2)") else { unreachable!() };
+/// assert_eq!(reference_arg(&mut sheet, &args[0]), Some((None, 1, 1, 1, 1)));
+/// ```
+pub fn reference_arg(sheet: &mut Sheet, arg: &Expr) -> Option<SheetRange> {
+    match arg {
+        Expr::CellRef(_) | Expr::Range(..) => parse_range_list(sheet, &arg.to_string())?.pop(),
+        _ => None,
+    }
+}
+
 /// Calculates the result of a range-based function (e.g., SUM, AVG) over a specified range.
 ///
 /// This function applies a mathematical function (e.g., SUM, AVG, MIN, MAX, STDEV) to the values
 /// in the specified range. It supports error handling for invalid ranges or erroneous cells.
-/// For STDEV, it uses Welford's online algorithm to compute the standard deviation.
+/// Several ranges may be given, in which case the function aggregates over all
+/// of them; cells covered by overlapping ranges are counted once per range. A range may also
+/// name another sheet of the workbook (e.g., "Sheet2!A1:A5"). Single cells and integer literals
+/// may be mixed in with the ranges, as in `SUM(A1, 5, B1:B3)`. The arguments are taken as the
+/// formula parser produced them, so each one is read on its own.
+/// For STDEV, it uses Welford's online algorithm to compute the standard deviation. MODE returns
+/// the most frequent value; when several values are equally frequent (including when all values
+/// are distinct), the smallest of them is returned. AVGOK averages like AVG, but skips error
+/// cells instead of failing; it only fails when every cell in the ranges is an error.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
 /// * `function` - The function name (e.g., "SUM", "AVG").
-/// * `range` - The range string (e.g., "A1:B2").
+/// * `args` - The parsed arguments of the call (e.g., those of `SUM(A1:A5, C1:C5)`).
 ///
 /// # Returns
 /// A `Result<f64, ()>` containing the calculated result or an error if the operation fails.
@@ -88,14 +383,22 @@ pub fn parse_range(sheet: &mut Sheet, range: &str) -> Option<(i32, i32, i32, i32
 /// let mut sheet = create_sheet(10, 10, false).unwrap();
 /// sheet.cells[0][0].value = 10;
 /// sheet.cells[0][1].value = 20;
-/// let result = calculate_range_function(&mut sheet, "SUM", "A1:B1");
-/// assert_eq!(result, Ok(30.0));
+/// let Some(Expr::Call { args, .. }) = parse("AVG(A1, 5, B1)") else { unreachable!() };
+/// assert_eq!(calculate_range_function(&mut sheet, "AVG", &args), Ok(35.0 / 3.0));
 /// ```
-pub fn calculate_range_function(sheet: &mut Sheet, function: &str, range: &str) -> Result<f64, ()> {
-    let (start_row, start_col, end_row, end_col) = match parse_range(sheet, range) {
-        Some(range) => range,
-        None => return Err(()),
-    };
+pub fn calculate_range_function(
+    sheet: &mut Sheet,
+    function: &str,
+    args: &[Expr],
+) -> Result<f64, ()> {
+    let mut literals = Vec::new();
+    let mut ranges = Vec::new();
+    for arg in args {
+        match arg {
+            Expr::Number(value) => literals.push(*value),
+            _ => ranges.push(reference_arg(sheet, arg).ok_or(())?),
+        }
+    }
 
     let function = function.to_uppercase();
     let mut count: usize = 0;
@@ -105,27 +408,51 @@ pub fn calculate_range_function(sheet: &mut Sheet, function: &str, range: &str)
     // For STDEV: Welford's online algorithm variables
     let mut mean: f64 = 0.0;
     let mut m2: f64 = 0.0;
+    // For MODE: how often each value occurs
+    let mut frequencies: HashMap<i32, usize> = HashMap::new();
 
-    for i in start_row..=end_row {
-        for j in start_col..=end_col {
-            let cell = &sheet.cells[i as usize][j as usize];
-            if cell.is_error {
-                return Err(());
-            }
-            let value = cell.value as f64;
-            count += 1;
-
-            // Update aggregates
-            sum += value;
-            min = min.min(value);
-            max = max.max(value);
-
-            // Welford's algorithm for variance
-            if function == "STDEV" {
-                let delta = value - mean;
-                mean += delta / count as f64;
-                let delta2 = value - mean;
-                m2 += delta * delta2;
+    let mut add = |raw: i32| {
+        let value = raw as f64;
+        count += 1;
+
+        // Update aggregates
+        sum += value;
+        min = min.min(value);
+        max = max.max(value);
+
+        // Welford's algorithm for variance
+        if function == "STDEV" {
+            let delta = value - mean;
+            mean += delta / count as f64;
+            let delta2 = value - mean;
+            m2 += delta * delta2;
+        }
+        if function == "MODE" {
+            *frequencies.entry(raw).or_insert(0) += 1;
+        }
+    };
+
+    for literal in literals {
+        add(literal);
+    }
+    for (sheet_name, start_row, start_col, end_row, end_col) in ranges {
+        let cells = match &sheet_name {
+            Some(name) => &sheet.workbook.sheets[name].cells,
+            None => &sheet.cells,
+        };
+        for i in start_row..=end_row {
+            for j in start_col..=end_col {
+                let cell = &cells[i as usize][j as usize];
+                if cell.is_error {
+                    if function == "AVGOK" {
+                        continue;
+                    }
+                    return Err(());
+                }
+                // Text is skipped, as in other spreadsheet programs
+                if cell.kind != DataKind::Text {
+                    add(cell.value);
+                }
             }
         }
     }
@@ -136,7 +463,7 @@ pub fn calculate_range_function(sheet: &mut Sheet, function: &str, range: &str)
 
     match function.as_str() {
         "SUM" => Ok(sum),
-        "AVG" => Ok(sum / count as f64),
+        "AVG" | "AVGOK" => Ok(sum / count as f64),
         "MIN" => Ok(min),
         "MAX" => Ok(max),
         "STDEV" => {
@@ -147,81 +474,188 @@ pub fn calculate_range_function(sheet: &mut Sheet, function: &str, range: &str)
                 Ok(variance.sqrt().round())
             }
         }
+        "MODE" => frequencies
+            .into_iter()
+            .max_by(|(value_a, count_a), (value_b, count_b)| {
+                count_a.cmp(count_b).then(value_b.cmp(value_a))
+            })
+            .map(|(value, _)| value as f64)
+            .ok_or(()),
         _ => Err(()),
     }
 }
 
-/// Evaluates a simple arithmetic expression.
+/// Parses a comparison criterion such as `>5`, `<=10` or `<>0`.
 ///
-/// This function parses a whitespace-separated arithmetic expression (e.g., "1 + 2 * 3")
-/// and computes the result. It supports addition, subtraction, multiplication, and division.
-/// Division by zero sets the `is_error` flag to true.
+/// This function splits a criterion into its comparison operator and integer operand. A bare
+/// number (e.g., `5`) is treated as an equality test. Supported operators are `=`, `<>`, `<`,
+/// `<=`, `>` and `>=`.
 ///
 /// # Arguments
-/// * `expr` - The arithmetic expression to evaluate.
-/// * `is_error` - A mutable boolean flag to indicate if an error (e.g., division by zero) occurs.
+/// * `criterion` - The criterion string (e.g., ">5").
 ///
 /// # Returns
-/// The computed result as an `i32`.
+/// An `Option<(&str, i32)>` containing the operator and operand, or `None` if the criterion is invalid.
 ///
 /// # Example
 /// ```
-/// let mut is_error = false;
-/// let result = evaluate_arithmetic("2 + 3 * 4", &mut is_error);
-/// assert_eq!(result, 14);
-/// assert!(!is_error);
+/// assert_eq!(parse_criterion(">=5"), Some((">=", 5)));
+/// assert_eq!(parse_criterion("7"), Some(("=", 7)));
 /// ```
-pub fn evaluate_arithmetic(expr: &str, is_error: &mut bool) -> i32 {
-    let tokens: Vec<&str> = expr.split_whitespace().collect();
-    if tokens.len() == 1 {
-        return tokens[0].parse().unwrap_or(0);
+pub fn parse_criterion(criterion: &str) -> Option<(&str, i32)> {
+    let criterion = criterion.trim();
+    let op_len = criterion.chars().take_while(|c| "<>=".contains(*c)).count();
+    let (op, operand) = criterion.split_at(op_len);
+    let op = match op {
+        "" => "=",
+        "=" | "<>" | "<" | "<=" | ">" | ">=" => op,
+        _ => return None,
+    };
+    let operand = operand.trim().parse::<i32>().ok()?;
+    Some((op, operand))
+}
+
+/// Checks whether a value satisfies a comparison parsed by `parse_criterion`.
+///
+/// # Arguments
+/// * `value` - The value to test.
+/// * `op` - The comparison operator (e.g., ">").
+/// * `operand` - The value to compare against.
+///
+/// # Returns
+/// A boolean indicating whether the comparison holds.
+///
+/// # Example
+/// ```
+/// assert!(matches_criterion(6, ">", 5));
+/// assert!(!matches_criterion(5, "<>", 5));
+/// ```
+pub fn matches_criterion(value: i32, op: &str, operand: i32) -> bool {
+    match op {
+        "=" => value == operand,
+        "<>" => value != operand,
+        "<" => value < operand,
+        "<=" => value <= operand,
+        ">" => value > operand,
+        ">=" => value >= operand,
+        _ => false,
+    }
+}
+
+/// Calculates a conditional sum over a range (the SUMIF function).
+///
+/// This function evaluates the arguments of `SUMIF(range, criterion[, sum_range])`. Each cell in
+/// `range` is tested against the criterion; for matching cells, the cell itself is summed or, when
+/// `sum_range` is given, the cell at the same offset in `sum_range`. Both ranges must have the
+/// same shape. Text cells are skipped, as in `calculate_range_function`.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `args` - The parsed arguments of the call (e.g., those of `SUMIF(A1:A10, >5, B1:B10)`).
+///
+/// # Returns
+/// A `Result<i64, ()>` containing the sum or an error if the arguments are invalid or a
+/// relevant cell holds an error. The sum is kept in an `i64` so that the caller can tell when it
+/// does not fit in a cell.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// sheet.cells[0][0].value = 3;
+/// sheet.cells[1][0].value = 8;
+/// let Some(Expr::Call { args, .. }) = parse("SUMIF(A1:A2, >5)") else { unreachable!() };
+/// assert_eq!(calculate_sumif(&mut sheet, &args), Ok(8));
+/// ```
+pub fn calculate_sumif(sheet: &mut Sheet, args: &[Expr]) -> Result<i64, ()> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(());
     }
 
-    let mut result = tokens[0].parse::<i32>().unwrap_or(0);
-    let mut i = 1;
-    while i < tokens.len() - 1 {
-        let op = tokens[i];
-        let b = tokens[i + 1].parse::<i32>().unwrap_or(0);
-        match op {
-            "+" => result += b,
-            "-" => result -= b,
-            "*" => result *= b,
-            "/" => {
-                if b == 0 {
-                    *is_error = true;
-                    return 0;
+    let (start_row, start_col, end_row, end_col) = sumif_range(sheet, &args[0]).ok_or(())?;
+    let criterion = args[1].to_string();
+    let (op, operand) = sumif_criterion(&args[1], &criterion).ok_or(())?;
+    let (sum_row, sum_col) = if args.len() == 3 {
+        let (s_row, s_col, e_row, e_col) = sumif_range(sheet, &args[2]).ok_or(())?;
+        if e_row - s_row != end_row - start_row || e_col - s_col != end_col - start_col {
+            return Err(());
+        }
+        (s_row, s_col)
+    } else {
+        (start_row, start_col)
+    };
+
+    let mut sum: i64 = 0;
+    for i in start_row..=end_row {
+        for j in start_col..=end_col {
+            let cell = &sheet.cells[i as usize][j as usize];
+            if cell.is_error {
+                return Err(());
+            }
+            if cell.kind != DataKind::Text && matches_criterion(cell.value, op, operand) {
+                let target = &sheet.cells[(sum_row + i - start_row) as usize]
+                    [(sum_col + j - start_col) as usize];
+                if target.is_error {
+                    return Err(());
+                }
+                if target.kind != DataKind::Text {
+                    sum += target.value as i64;
                 }
-                result /= b;
             }
-            _ => {}
         }
-        i += 2;
     }
-    result
+    Ok(sum)
+}
+
+/// Reads a range argument of SUMIF, which must be a range on the active sheet.
+fn sumif_range(sheet: &mut Sheet, arg: &Expr) -> Option<(i32, i32, i32, i32)> {
+    match arg {
+        Expr::Range(..) => parse_range(sheet, &arg.to_string()),
+        _ => None,
+    }
+}
+
+/// Reads the criterion argument of SUMIF, a number or a comparison such as `>5`; `text` is the
+/// argument written out, which the returned operator borrows from.
+fn sumif_criterion<'a>(arg: &Expr, text: &'a str) -> Option<(&'a str, i32)> {
+    match arg {
+        Expr::Number(_) | Expr::Criterion { .. } => parse_criterion(text),
+        _ => None,
+    }
 }
 
 /// Decodes a column string into a zero-based column index.
 ///
 /// This function converts a column reference (e.g., "A", "AA") into a zero-based index
-/// using a base-26 system where 'A' is 1, 'B' is 2, etc.
+/// using a base-26 system where 'A' is 1, 'B' is 2, etc. Decoding stops as soon as the
+/// column passes `MAX_COLS`, so arbitrarily long input cannot overflow.
 ///
 /// # Arguments
 /// * `col_str` - The column string (e.g., "A", "AA").
 ///
 /// # Returns
-/// The zero-based column index as an `i32`.
+/// An `Option<i32>` containing the zero-based column index, or `None` if the string is empty,
+/// contains non-alphabetic characters, or names a column beyond `MAX_COLS`.
 ///
 /// # Example
 /// ```
-/// let col_index = decode_column("B");
-/// assert_eq!(col_index, 1); // Column B is index 1
+/// assert_eq!(decode_column("B"), Some(1)); // Column B is index 1
+/// assert_eq!(decode_column("ZZZZZ"), None); // Beyond MAX_COLS
 /// ```
-pub fn decode_column(col_str: &str) -> i32 {
+pub fn decode_column(col_str: &str) -> Option<i32> {
+    if col_str.is_empty() {
+        return None;
+    }
     let mut result = 0;
     for c in col_str.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
         result = result * 26 + (c.to_ascii_uppercase() as i32 - 'A' as i32 + 1);
+        if result > MAX_COLS {
+            return None;
+        }
     }
-    result - 1
+    Some(result - 1)
 }
 
 /// Encodes a zero-based column index into a column string.
@@ -292,6 +726,427 @@ pub fn triangular(n: i32) -> i32 {
     n * (n + 1) / 2
 }
 
+/// Converts a calendar date to the number of days since 1970-01-01.
+///
+/// This function uses the proleptic Gregorian calendar, so dates before 1970 produce negative
+/// day counts. The month and day are validated, including leap years.
+///
+/// # Arguments
+/// * `year` - The year (e.g., 2024).
+/// * `month` - The month, from 1 to 12.
+/// * `day` - The day of the month, starting at 1.
+///
+/// # Returns
+/// An `Option<i32>` containing the day count, or `None` if the date does not exist.
+///
+/// # Example
+/// ```
+/// assert_eq!(days_from_civil(1970, 1, 2), Some(1));
+/// assert_eq!(days_from_civil(2023, 2, 29), None);
+/// ```
+pub fn days_from_civil(year: i32, month: i32, day: i32) -> Option<i32> {
+    let is_leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if day < 1 || day > days_in_month {
+        return None;
+    }
+
+    let y = i64::from(if month <= 2 { year - 1 } else { year });
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let month_index = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    i32::try_from(era * 146097 + day_of_era - 719468).ok()
+}
+
+/// Converts a number of days since 1970-01-01 back to a calendar date.
+///
+/// This is the inverse of `days_from_civil`.
+///
+/// # Arguments
+/// * `days` - The number of days since 1970-01-01.
+///
+/// # Returns
+/// A tuple `(year, month, day)`.
+///
+/// # Example
+/// ```
+/// assert_eq!(civil_from_days(0), (1970, 1, 1));
+/// ```
+pub fn civil_from_days(days: i32) -> (i32, i32, i32) {
+    let z = i64::from(days) + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year as i32, month as i32, day as i32)
+}
+
+/// Formats a cell's value for display.
+///
+/// Error cells (other than circular ones) are shown by their error code, such as `#DIV/0!` or
+/// `#REF!` (or `err` if the kind of error is unknown), date cells as `YYYY-MM-DD`, text cells
+/// by their text, percent cells with a `%` sign, and everything else as a plain integer.
+///
+/// # Arguments
+/// * `cell` - The cell to format.
+///
+/// # Returns
+/// A `String` with the display text of the cell.
+///
+/// # Example
+/// ```
+/// let mut cell = Cell::new();
+/// cell.value = 19723;
+/// cell.kind = DataKind::Date;
+/// assert_eq!(format_cell_value(&cell), "2024-01-01");
+/// ```
+pub fn format_cell_value(cell: &Cell) -> String {
+    if cell.is_error && !cell.has_circular {
+        return cell
+            .error_kind
+            .map_or("err", |kind| kind.code())
+            .to_string();
+    }
+    match cell.kind {
+        DataKind::Number => cell.value.to_string(),
+        DataKind::Percent => format!("{}%", cell.value),
+        DataKind::Date => {
+            let (year, month, day) = civil_from_days(cell.value);
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        DataKind::Text => cell_text(cell).unwrap_or_default(),
+    }
+}
+
+/// Reads a percentage literal such as `50%`.
+///
+/// A cell assigned a whole number followed by `%` holds that number and is shown with the `%`
+/// sign. Formulas read the cell as the number itself, so `A1*200/100` takes 50% of 200 when A1 is
+/// `50%`. A `%` inside a larger formula is not accepted.
+///
+/// # Arguments
+/// * `formula` - The formula string.
+///
+/// # Returns
+/// An `Option<i32>` with the percentage, or `None` if the formula is not a percentage literal.
+///
+/// # Example
+/// ```
+/// assert_eq!(percent_literal("50%"), Some(50));
+/// assert_eq!(percent_literal("-5 %"), Some(-5));
+/// assert_eq!(percent_literal("A1%"), None);
+/// ```
+pub fn percent_literal(formula: &str) -> Option<i32> {
+    let number = formula.trim().strip_suffix('%')?.trim();
+    // As in other formulas, a leading `+` is not a sign
+    number.parse().ok().filter(|_| !number.starts_with('+'))
+}
+
+/// Finds the text held by a text cell.
+///
+/// A text cell is assigned either quoted text (e.g., `A1="abc"`) or a number formatted with
+/// `TEXT(value, "000")`, which pads the value with leading zeros to the width of the pattern.
+///
+/// # Arguments
+/// * `cell` - The cell to read.
+///
+/// # Returns
+/// An `Option<String>` containing the cell's text, or `None` if the cell does not hold text.
+///
+/// # Example
+/// ```
+/// let mut cell = Cell::new();
+/// cell.formula = Some("TEXT(A1, \"000\")".to_string());
+/// cell.value = 7;
+/// assert_eq!(cell_text(&cell), Some("007".to_string()));
+/// ```
+pub fn cell_text(cell: &Cell) -> Option<String> {
+    match parse(cell.formula.as_deref()?)? {
+        Expr::Text(text) => Some(text),
+        Expr::Call { name, args } if name == "TEXT" => match args.get(1) {
+            Some(Expr::Text(pattern)) => {
+                Some(format!("{:0width$}", cell.value, width = pattern.len()))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Maps a text color name to its ANSI foreground color code.
+///
+/// The supported names are the eight standard terminal colors: black, red, green, yellow, blue,
+/// magenta, cyan and white. Matching is case-insensitive.
+///
+/// # Arguments
+/// * `color` - The color name.
+///
+/// # Returns
+/// An `Option<u8>` containing the ANSI code (30-37), or `None` for an unknown color.
+///
+/// # Example
+/// ```
+/// assert_eq!(ansi_color_code("Red"), Some(31));
+/// assert_eq!(ansi_color_code("orange"), None);
+/// ```
+pub fn ansi_color_code(color: &str) -> Option<u8> {
+    match color.to_lowercase().as_str() {
+        "black" => Some(30),
+        "red" => Some(31),
+        "green" => Some(32),
+        "yellow" => Some(33),
+        "blue" => Some(34),
+        "magenta" => Some(35),
+        "cyan" => Some(36),
+        "white" => Some(37),
+        _ => None,
+    }
+}
+
+/// Every command keyword, with whether the command only moves the view or reports on the sheet,
+/// leaving every cell value as it is.
+///
+/// `suggest_command` picks from these keywords, and after a command that is not view-only the
+/// sheets that read the active sheet are refreshed (see `process_command`). A new command is
+/// added here once, with its flag.
+pub const COMMANDS: [(&str, bool); 68] = [
+    ("w", true),
+    ("a", true),
+    ("s", true),
+    ("d", true),
+    ("undo", false),
+    ("redo", false),
+    ("disable_output", true),
+    ("enable_output", true),
+    ("toggle_output", true),
+    ("scroll_to", true),
+    ("FORMULA", true),
+    ("FORMULAS", true),
+    ("CYCLES", true),
+    ("LONGESTCHAIN", true),
+    ("GET", true),
+    ("GETROW", true),
+    ("GETCOL", true),
+    ("ARGMAX", true),
+    ("ARGMIN", true),
+    ("APPLY", false),
+    ("EVAL", true),
+    ("HISTORY", true),
+    ("STATS", true),
+    ("RECOMPUTE", false),
+    ("RESETFLAGS", false),
+    ("ROWDEL", false),
+    ("COLDEL", false),
+    ("INSERTROW", false),
+    ("DELETERANGE", false),
+    ("COPY", true),
+    ("CUT", false),
+    ("PASTE", false),
+    ("PASTETRANSPOSE", false),
+    ("MOVE", false),
+    ("SWAP", false),
+    ("MERGE", false),
+    ("UNMERGE", false),
+    ("CLEARFORMAT", false),
+    ("FREEZE", true),
+    ("SHOW", true),
+    ("SERIES", false),
+    ("TOTALROW", false),
+    ("TOTALCOL", false),
+    ("COMPARE", true),
+    ("LOCK", false),
+    ("UNLOCK", false),
+    ("GRAPH", true),
+    ("GRAPHSVG", true),
+    ("DEPGRAPH", true),
+    ("DEPLIST", true),
+    ("EXPORTRANGE", true),
+    ("IMPORTRANGE", false),
+    ("UNDO", false),
+    ("WHATIF", false),
+    ("CHECKPOINT", false),
+    ("RESTORE", false),
+    ("NEWSHEET", false),
+    ("SHEET", false),
+    ("ALIAS", false),
+    ("MACRO", false),
+    ("VALIDATE", false),
+    ("SCINOTATION", true),
+    ("SHOWFORMULAS", true),
+    ("SPARSEVIEW", true),
+    ("ERRSTR", true),
+    ("SEED", false),
+    ("REFRESH", false),
+    ("BUDGET", false),
+];
+
+/// Computes the edit distance between two strings.
+///
+/// The distance is the least number of single-character insertions, deletions and substitutions
+/// that turn `a` into `b` (the Levenshtein distance).
+///
+/// # Arguments
+/// * `a` - The first string.
+/// * `b` - The second string.
+///
+/// # Returns
+/// The edit distance as a `usize`.
+///
+/// # Example
+/// ```
+/// assert_eq!(levenshtein("udno", "undo"), 2);
+/// assert_eq!(levenshtein("COPY", "COPY"), 0);
+/// ```
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Finds the known command closest to a mistyped one.
+///
+/// Only the first word of the input is compared, so arguments do not affect the match. A command
+/// is suggested if it is at most two edits away, fewer edits than it has characters, and no
+/// other command is just as close.
+///
+/// # Arguments
+/// * `input` - The command that could not be run.
+///
+/// # Returns
+/// An `Option<&str>` with the suggested command keyword, or `None` if there is no clear match.
+///
+/// # Example
+/// ```
+/// assert_eq!(suggest_command("udno"), Some("undo"));
+/// assert_eq!(suggest_command("COPPY A1:B2"), Some("COPY"));
+/// assert_eq!(suggest_command("hello"), None);
+/// ```
+pub fn suggest_command(input: &str) -> Option<&'static str> {
+    let word = input.split_whitespace().next()?;
+    let mut best: Option<(usize, &'static str)> = None;
+    let mut tied = false;
+    for (command, _) in COMMANDS {
+        let distance = levenshtein(word, command);
+        if distance > 2 || distance >= command.len() {
+            continue;
+        }
+        match best {
+            Some((best_distance, _)) if distance > best_distance => {}
+            Some((best_distance, _)) if distance == best_distance => tied = true,
+            _ => {
+                best = Some((distance, command));
+                tied = false;
+            }
+        }
+    }
+    best.filter(|_| !tied).map(|(_, command)| command)
+}
+
+/// Splits one line of a CSV file into fields.
+///
+/// Fields may be wrapped in double quotes, in which case the delimiter can appear inside them and
+/// a doubled quote (`""`) stands for a literal quote. The surrounding quotes are not part of the
+/// returned field.
+///
+/// # Arguments
+/// * `line` - A single line of the file, without the trailing newline.
+/// * `delimiter` - The character separating fields.
+///
+/// # Returns
+/// A `Vec<String>` containing the unquoted fields in order.
+///
+/// # Example
+/// ```
+/// let fields = split_csv_line(r#"1,"2,3","say ""hi""""#, ',');
+/// assert_eq!(fields, vec!["1", "2,3", "say \"hi\""]);
+/// ```
+pub fn split_csv_line(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c != '"' {
+                field.push(c);
+            } else if chars.peek() == Some(&'"') {
+                field.push('"');
+                chars.next();
+            } else {
+                in_quotes = false;
+            }
+        } else if c == '"' {
+            in_quotes = true;
+        } else if c == delimiter {
+            fields.push(std::mem::take(&mut field));
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+/// Splits the quoted text of an `IMPORTRANGE` command into rows of fields.
+///
+/// Commands are typed on one line, so `\n` in the text starts a new row and `\t` stands for a
+/// tab. Rows are tab-separated if the text contains a tab, and comma-separated otherwise; each
+/// row is split with `split_csv_line`.
+///
+/// # Arguments
+/// * `text` - The text as typed, including the surrounding double quotes.
+///
+/// # Returns
+/// An `Option<Vec<Vec<String>>>` with the fields of each row, or `None` if the text is not
+/// quoted or holds no rows.
+///
+/// # Example
+/// ```
+/// let rows = split_import_text("\"1,2\\n3,4\"").unwrap();
+/// assert_eq!(rows, vec![vec!["1", "2"], vec!["3", "4"]]);
+/// ```
+pub fn split_import_text(text: &str) -> Option<Vec<Vec<String>>> {
+    let text = text.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let text = text.replace("\\n", "\n").replace("\\t", "\t");
+    let delimiter = if text.contains('\t') { '\t' } else { ',' };
+    let rows: Vec<Vec<String>> = text
+        .lines()
+        .map(|line| split_csv_line(line, delimiter))
+        .collect();
+    if rows.is_empty() {
+        None
+    } else {
+        Some(rows)
+    }
+}
+
 /// Checks if a sequence of values follows a factorial pattern.
 ///
 /// This function determines if the given sequence of values matches a factorial sequence
@@ -502,7 +1357,9 @@ pub fn detect_pattern(
 ///
 /// This function checks if the provided formula is syntactically correct and supported by the
 /// spreadsheet. It supports numeric literals, cell references, arithmetic expressions, and specific
-/// functions like SUM, AVG, SLEEP, etc., depending on whether extensions are enabled.
+/// functions like SUM, AVG, SLEEP, etc., depending on whether extensions are enabled. A row given
+/// as a cell in braces (e.g., `SUM(A1:A{B1})`) is accepted if the cell is valid, whatever its
+/// current value.
 ///
 /// # Arguments
 /// * `sheet` - A mutable reference to the spreadsheet.
@@ -519,57 +1376,245 @@ pub fn detect_pattern(
 /// ```
 pub fn is_valid_formula(sheet: &mut Sheet, formula: &str) -> bool {
     let formula = formula.trim();
+    if percent_literal(formula).is_some() {
+        return true;
+    }
+    // A `{cell}` row bound may take any row, so the formula must be valid with the bounds at the
+    // first or at the last row
+    if formula.contains(['{', '}']) {
+        let rows = sheet.rows;
+        return [1, rows].into_iter().any(|row| {
+            replace_dynamic_bounds(formula, |bound| {
+                parse_cell_reference(sheet, bound)
+                    .ok()
+                    .map(|_| row.to_string())
+            })
+            .is_some_and(|resolved| is_valid_formula(sheet, &resolved))
+        });
+    }
     if sheet.extension_enabled {
         if let Some((func_name, args)) = formula.split_once('(') {
             if let Some(args) = args.strip_suffix(')') {
                 let func_name = func_name.trim().to_uppercase();
                 match func_name.as_str() {
-                    "SUM" | "AVG" | "MAX" | "MIN" | "STDEV" | "SORTA" | "SORTD" | "AUTOFILL" => {
+                    "SORTA" | "SORTD" | "AUTOFILL" => {
                         return parse_range(sheet, args.trim()).is_some();
                     }
-                    "SLEEP" => {
-                        return args.parse::<i32>().is_ok()
-                            || parse_cell_reference(sheet, args.trim()).is_some();
+                    "BOLD" | "ITALIC" | "UNDERLINE" | "STRIKE" => {
+                        return parse_cell_or_range(sheet, args.trim()).is_some();
                     }
-                    "BOLD" | "ITALIC" | "UNDERLINE" => {
-                        return parse_cell_reference(sheet, args.trim()).is_some();
+                    "COLOR" => {
+                        return args.split_once(',').is_some_and(|(target, color)| {
+                            parse_cell_or_range(sheet, target.trim()).is_some()
+                                && ansi_color_code(color.trim()).is_some()
+                        });
                     }
-                    _ => return false,
+                    _ => {}
                 }
             }
         }
-    } else if let Some((func_name, args)) = formula.split_once('(') {
-        if let Some(args) = args.strip_suffix(')') {
-            let func_name = func_name.trim().to_uppercase();
-            match func_name.as_str() {
-                "SUM" | "AVG" | "MAX" | "MIN" | "STDEV" => {
-                    return parse_range(sheet, args.trim()).is_some();
+    }
+
+    parse(formula).is_some_and(|expr| is_valid_expr(sheet, &expr))
+}
+
+/// Validates a parsed formula expression against the spreadsheet.
+///
+/// This function walks the expression tree, checking that every cell reference and range lies
+/// within the sheet and that every function call names a supported function with suitable
+/// arguments. Function calls may appear anywhere inside arithmetic, so formulas like
+/// `SUM(A1:A2)+1` are accepted. Ranges and criteria are only valid as function arguments.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `expr` - The parsed expression to validate.
+///
+/// # Returns
+/// A boolean indicating whether the expression is valid.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let expr = parse("SUM(A1:A2)+1").unwrap();
+/// assert!(is_valid_expr(&mut sheet, &expr));
+/// ```
+pub fn is_valid_expr(sheet: &mut Sheet, expr: &Expr) -> bool {
+    match expr {
+        Expr::Number(_) | Expr::Bool(_) | Expr::Text(_) => true,
+        Expr::CellRef(cell_ref) => {
+            parse_cell_reference(sheet, cell_ref).is_ok()
+                || parse_external_reference(sheet, cell_ref).is_some()
+        }
+        Expr::Range(..) | Expr::Criterion { .. } | Expr::Comparison { .. } => false,
+        Expr::Neg(inner) => is_valid_expr(sheet, inner),
+        Expr::Binary { left, right, .. } => {
+            is_valid_expr(sheet, left) && is_valid_expr(sheet, right)
+        }
+        Expr::Call { name, args } => match name.as_str() {
+            "SUM" | "AVG" | "AVGOK" | "MAX" | "MIN" | "STDEV" | "MODE" => {
+                !args.is_empty() && args.iter().all(|arg| is_valid_list_arg(sheet, arg))
+            }
+            "SUMIF" => is_valid_sumif_args(sheet, args),
+            "AND" | "OR" => {
+                !args.is_empty() && args.iter().all(|arg| is_valid_logical_arg(sheet, arg))
+            }
+            "NOT" => args.len() == 1 && is_valid_logical_arg(sheet, &args[0]),
+            "SLEEP" => args.len() == 1 && is_valid_expr(sheet, &args[0]),
+            "DATE" => args.len() == 3 && args.iter().all(|arg| is_valid_expr(sheet, arg)),
+            "DATEDIFF" => args.len() == 2 && args.iter().all(|arg| is_valid_expr(sheet, arg)),
+            "TODAY" => args.is_empty(),
+            "RANDBETWEEN" => args.len() == 2 && args.iter().all(|arg| is_valid_expr(sheet, arg)),
+            "ELAPSED" => args.is_empty() && sheet.extension_enabled,
+            "VLOOKUP" => {
+                args.len() == 3
+                    && is_valid_expr(sheet, &args[0])
+                    && is_valid_range_arg(sheet, &args[1])
+                    && is_valid_expr(sheet, &args[2])
+            }
+            "INDEX" => {
+                args.len() == 3
+                    && is_valid_range_arg(sheet, &args[0])
+                    && is_valid_expr(sheet, &args[1])
+                    && is_valid_expr(sheet, &args[2])
+            }
+            "SUMDIAG" => match args.as_slice() {
+                [Expr::Range(start, end)] => parse_range(sheet, &format!("{}:{}", start, end))
+                    .is_some_and(|(start_row, start_col, end_row, end_col)| {
+                        end_row - start_row == end_col - start_col
+                    }),
+                _ => false,
+            },
+            "OFFSET" => match args.as_slice() {
+                [Expr::CellRef(base), row_offset, col_offset] => {
+                    parse_cell_reference(sheet, base).is_ok()
+                        && is_valid_expr(sheet, row_offset)
+                        && is_valid_expr(sheet, col_offset)
                 }
-                "SLEEP" => {
-                    return args.parse::<i32>().is_ok()
-                        || parse_cell_reference(sheet, args.trim()).is_some();
+                _ => false,
+            },
+            "VALUE" => args.len() == 1 && is_valid_expr(sheet, &args[0]),
+            "TEXT" => match args.as_slice() {
+                [value, Expr::Text(pattern)] => {
+                    !pattern.is_empty()
+                        && pattern.chars().all(|c| c == '0')
+                        && is_valid_expr(sheet, value)
                 }
-                _ => return false,
-            }
+                _ => false,
+            },
+            _ => false,
+        },
+    }
+}
+
+/// Checks whether a function argument is a range that lies within the spreadsheet.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `arg` - The parsed function argument.
+///
+/// # Returns
+/// A boolean indicating whether the argument is a valid range.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let expr = parse("SUM(A1:B2)").unwrap();
+/// if let Expr::Call { args, .. } = expr {
+///     assert!(is_valid_range_arg(&mut sheet, &args[0]));
+/// }
+/// ```
+pub fn is_valid_range_arg(sheet: &mut Sheet, arg: &Expr) -> bool {
+    match arg {
+        Expr::Range(start, end) => {
+            let range = format!("{}:{}", start, end);
+            parse_range(sheet, &range).is_some()
+                || parse_external_reference(sheet, &range).is_some()
         }
+        _ => false,
     }
+}
 
-    if formula.contains('+')
-        || formula.contains('-')
-        || formula.contains('*')
-        || formula.contains('/')
-    {
-        let parts: Vec<&str> = formula
-            .split(|c| "+-*/".contains(c))
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .collect();
-        return parts.iter().all(|part| {
-            part.parse::<i32>().is_ok() || parse_cell_reference(sheet, part).is_some()
-        });
+/// Checks whether an argument can be tested by a logical function like AND, OR or NOT.
+///
+/// Besides plain expressions, which count as true when they are not zero, logical functions
+/// accept comparisons such as `A1>5` whose two sides are valid expressions.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `arg` - The parsed function argument.
+///
+/// # Returns
+/// A boolean indicating whether the argument is a valid expression or comparison.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let expr = parse("AND(A1>5, TRUE)").unwrap();
+/// if let Expr::Call { args, .. } = expr {
+///     assert!(args.iter().all(|arg| is_valid_logical_arg(&mut sheet, arg)));
+/// }
+/// ```
+pub fn is_valid_logical_arg(sheet: &mut Sheet, arg: &Expr) -> bool {
+    match arg {
+        Expr::Comparison { left, right, .. } => {
+            is_valid_expr(sheet, left) && is_valid_expr(sheet, right)
+        }
+        _ => is_valid_expr(sheet, arg),
     }
+}
 
-    parse_cell_reference(sheet, formula).is_some() || formula.parse::<i32>().is_ok()
+/// Checks whether an argument can be aggregated by a function like SUM or AVG.
+///
+/// Besides ranges, these functions accept single cell references and integer literals, so
+/// that `SUM(1, 2, 3)` and `AVG(A1, 5, B1:B3)` work.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `arg` - The parsed function argument.
+///
+/// # Returns
+/// A boolean indicating whether the argument is a valid range, cell reference or literal.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let expr = parse("SUM(A1, -5, B1:B2)").unwrap();
+/// if let Expr::Call { args, .. } = expr {
+///     assert!(args.iter().all(|arg| is_valid_list_arg(&mut sheet, arg)));
+/// }
+/// ```
+pub fn is_valid_list_arg(sheet: &mut Sheet, arg: &Expr) -> bool {
+    match arg {
+        Expr::Number(_) | Expr::CellRef(_) => is_valid_expr(sheet, arg),
+        Expr::Neg(inner) => matches!(**inner, Expr::Number(_)),
+        _ => is_valid_range_arg(sheet, arg),
+    }
+}
+
+/// Validates the argument list of a SUMIF formula.
+///
+/// Accepts `range, criterion` or `range, criterion, sum_range`, where both ranges are valid and
+/// the criterion parses with `parse_criterion`.
+///
+/// # Arguments
+/// * `sheet` - A mutable reference to the spreadsheet.
+/// * `args` - The parsed arguments of the call.
+///
+/// # Returns
+/// A boolean indicating whether the arguments are valid.
+///
+/// # Example
+/// ```
+/// let mut sheet = create_sheet(10, 10, false).unwrap();
+/// let Some(Expr::Call { args, .. }) = parse("SUMIF(A1:A5, >2, B1:B5)") else { unreachable!() };
+/// assert!(is_valid_sumif_args(&mut sheet, &args));
+/// assert!(!is_valid_sumif_args(&mut sheet, &args[..1]));
+/// ```
+pub fn is_valid_sumif_args(sheet: &mut Sheet, args: &[Expr]) -> bool {
+    (args.len() == 2 || args.len() == 3)
+        && sumif_range(sheet, &args[0]).is_some()
+        && sumif_criterion(&args[1], &args[1].to_string()).is_some()
+        && (args.len() == 2 || sumif_range(sheet, &args[2]).is_some())
 }
 
 /// Validates whether a command is valid for the spreadsheet.
@@ -595,65 +1640,295 @@ pub fn is_valid_command(sheet: &mut Sheet, command: &str) -> bool {
     if command.len() == 1 && "wasdq".contains(command) {
         return true;
     }
-    if command == "disable_output" || command == "enable_output" {
+    if [
+        "disable_output",
+        "enable_output",
+        "toggle_output",
+        "RECOMPUTE",
+        "RESETFLAGS",
+    ]
+    .contains(&command)
+    {
         return true;
     }
-    if sheet.extension_enabled && (command == "undo" || command == "redo") {
-        return true;
+    if let Some(stripped) = command.strip_prefix("scroll_to ") {
+        return parse_cell_reference(sheet, stripped).is_ok();
     }
+
+    // Extension keywords are matched by prefix; anything else falls through to assignment
     if sheet.extension_enabled {
-        if let Some(stripped) = command.strip_prefix("FORMULA ") {
-            return parse_cell_reference(sheet, stripped.trim()).is_some();
+        if [
+            "undo",
+            "redo",
+            "STATS",
+            "FORMULAS",
+            "CYCLES",
+            "LONGESTCHAIN",
+            "REFRESH",
+            "DEPLIST",
+        ]
+        .contains(&command)
+        {
+            return true;
         }
-    }
-    if sheet.extension_enabled {
-        if let Some(stripped) = command.strip_prefix("ROWDEL ") {
+        if let Some(stripped) = command.strip_prefix("UNDO ") {
+            return stripped
+                .trim()
+                .parse::<usize>()
+                .is_ok_and(|steps| steps >= 1);
+        }
+        if let Some(stripped) = command.strip_prefix("CHECKPOINT ") {
+            return is_valid_sheet_name(stripped.trim());
+        }
+        if let Some(stripped) = command.strip_prefix("RESTORE ") {
+            return sheet.checkpoints.contains_key(stripped.trim());
+        }
+        if let Some(stripped) = command
+            .strip_prefix("ALIAS ")
+            .or_else(|| command.strip_prefix("MACRO "))
+        {
+            return stripped.trim().split_once(' ').is_some_and(|(name, body)| {
+                is_valid_macro_name(sheet, name) && !body.replace(';', "").trim().is_empty()
+            });
+        }
+        let invocation = command.strip_prefix("RUN ").unwrap_or(command);
+        let name = invocation.split_whitespace().next().unwrap_or("");
+        if sheet.macros.contains_key(name) {
+            return true;
+        }
+        if let Some(stripped) = command.strip_prefix("NEWSHEET ") {
+            return is_valid_sheet_name(stripped.trim());
+        }
+        if let Some(stripped) = command.strip_prefix("SHEET ") {
+            let name = stripped.trim();
+            return name == sheet.workbook.active || sheet.workbook.sheets.contains_key(name);
+        }
+        if let Some(stripped) = command
+            .strip_prefix("FORMULA ")
+            .or_else(|| command.strip_prefix("GET "))
+            .or_else(|| command.strip_prefix("HISTORY "))
+        {
+            return parse_cell_reference(sheet, stripped.trim()).is_ok();
+        }
+        if let Some(stripped) = command
+            .strip_prefix("ROWDEL ")
+            .or_else(|| command.strip_prefix("GETROW "))
+        {
             return stripped
                 .trim()
                 .parse::<i32>()
                 .is_ok_and(|r| r >= 1 && r <= sheet.rows);
         }
-    }
-    if sheet.extension_enabled {
-        if let Some(stripped) = command.strip_prefix("COLDEL ") {
-            return stripped.trim().chars().all(|c| c.is_ascii_alphabetic());
+        if let Some(stripped) = command
+            .strip_prefix("COLDEL ")
+            .or_else(|| command.strip_prefix("GETCOL "))
+        {
+            return decode_column(stripped.trim()).is_some_and(|col| col < sheet.cols);
+        }
+        if let Some(stripped) = command
+            .strip_prefix("LOCK ")
+            .or_else(|| command.strip_prefix("UNLOCK "))
+            .or_else(|| command.strip_prefix("SHOW "))
+        {
+            return parse_cell_or_range(sheet, stripped.trim()).is_some();
+        }
+        if let Some(stripped) = command.strip_prefix("MERGE ") {
+            return parse_range(sheet, stripped.trim()).is_some();
+        }
+        if let Some(stripped) = command.strip_prefix("SERIES ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return parts.len() == 3
+                && parse_cell_or_range(sheet, parts[0]).is_some()
+                && parts[1].parse::<i32>().is_ok()
+                && parts[2].parse::<i32>().is_ok();
+        }
+        if let Some(stripped) = command.strip_prefix("SEED ") {
+            return stripped.trim().parse::<i64>().is_ok();
+        }
+        if let Some(stripped) = command.strip_prefix("BUDGET ") {
+            let stripped = stripped.trim();
+            return stripped.eq_ignore_ascii_case("off")
+                || stripped.parse::<usize>().is_ok_and(|budget| budget > 0);
+        }
+        if let Some(stripped) = command.strip_prefix("ERRSTR ") {
+            let stripped = stripped.trim();
+            return stripped.eq_ignore_ascii_case("off")
+                || stripped.len() > 2 && stripped.starts_with('"') && stripped.ends_with('"');
+        }
+        if let Some(stripped) = command
+            .strip_prefix("SCINOTATION ")
+            .or_else(|| command.strip_prefix("SHOWFORMULAS "))
+            .or_else(|| command.strip_prefix("SPARSEVIEW "))
+        {
+            return ["on", "off"].contains(&stripped.trim().to_lowercase().as_str());
+        }
+        if let Some(stripped) = command.strip_prefix("FREEZE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return match parts.as_slice() {
+                [rows, cols] => {
+                    rows.parse::<i32>()
+                        .is_ok_and(|r| (0..=sheet.rows).contains(&r))
+                        && cols
+                            .parse::<i32>()
+                            .is_ok_and(|c| (0..=sheet.cols).contains(&c))
+                }
+                _ => false,
+            };
+        }
+        if let Some(stripped) = command.strip_prefix("INSERTROW ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return match parts.as_slice() {
+                [row] | [row, "EXTEND"] => {
+                    row.parse::<i32>().is_ok_and(|r| r >= 1 && r <= sheet.rows)
+                }
+                _ => false,
+            };
+        }
+        if let Some(stripped) = command.strip_prefix("DELETERANGE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return match parts.as_slice() {
+                [range, direction] => {
+                    direction.eq_ignore_ascii_case("up")
+                        && parse_cell_or_range(sheet, range).is_some()
+                }
+                _ => false,
+            };
+        }
+        if let Some(stripped) = command
+            .strip_prefix("TOTALCOL ")
+            .or_else(|| command.strip_prefix("TOTALROW "))
+        {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return parts.len() == 2
+                && parse_cell_or_range(sheet, parts[0]).is_some()
+                && parse_cell_reference(sheet, parts[1]).is_ok();
+        }
+        if let Some(stripped) = command.strip_prefix("COMPARE ") {
+            let targets: Vec<&str> = stripped.split_whitespace().collect();
+            return targets.len() == 2
+                && parse_range(sheet, targets[0]).is_some()
+                && parse_range(sheet, targets[1]).is_some();
+        }
+        if let Some(stripped) = command.strip_prefix("SWAP ") {
+            let targets: Vec<&str> = stripped.split_whitespace().collect();
+            return targets.len() == 2
+                && parse_cell_or_range(sheet, targets[0]).is_some()
+                && parse_cell_or_range(sheet, targets[1]).is_some();
+        }
+        if let Some(stripped) = command.strip_prefix("VALIDATE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return match parts.as_slice() {
+                [range, min, max] => {
+                    parse_cell_or_range(sheet, range).is_some()
+                        && min
+                            .parse::<i32>()
+                            .ok()
+                            .zip(max.parse::<i32>().ok())
+                            .is_some_and(|(min, max)| min <= max)
+                }
+                _ => false,
+            };
+        }
+        if let Some(stripped) = command
+            .strip_prefix("UNMERGE ")
+            .or_else(|| command.strip_prefix("CLEARFORMAT "))
+        {
+            return parse_cell_or_range(sheet, stripped.trim()).is_some();
+        }
+        if let Some(stripped) = command
+            .strip_prefix("DEPGRAPH ")
+            .or_else(|| command.strip_prefix("DEPLIST "))
+        {
+            return !stripped.trim().is_empty();
+        }
+        if command.starts_with("GRAPHSVG ") {
+            let parts: Vec<&str> = command.split_whitespace().collect();
+            return parts.len() == 4
+                && ["(BAR)", "(SCATTER)"].contains(&parts[1].to_uppercase().as_str())
+                && parse_range(sheet, parts[2]).is_some();
+        }
+        if command.starts_with("GRAPH ") {
+            let parts: Vec<&str> = command.split_whitespace().collect();
+            // With five parts the fourth is a second range, followed by a file name
+            return (3..=5).contains(&parts.len())
+                && ["(BAR)", "(SCATTER)"].contains(&parts[1].to_uppercase().as_str())
+                && parse_range(sheet, parts[2]).is_some()
+                && (parts.len() < 5 || parse_range(sheet, parts[3]).is_some());
+        }
+        if let Some(range) = command
+            .strip_prefix("COPY ")
+            .or_else(|| command.strip_prefix("CUT "))
+        {
+            return parse_range(sheet, range).is_some();
+        }
+        if let Some(expr) = command.strip_prefix("EVAL ") {
+            return is_valid_formula(sheet, expr.trim());
+        }
+        if let Some(stripped) = command.strip_prefix("APPLY ") {
+            return stripped
+                .trim()
+                .split_once(' ')
+                .is_some_and(|(range, formula)| {
+                    let formula = formula.trim();
+                    parse_range(sheet, range).is_some()
+                        && is_valid_formula(sheet, formula.strip_prefix('=').unwrap_or(formula))
+                });
+        }
+        if let Some(range) = command
+            .strip_prefix("EXPORTRANGE ")
+            .or_else(|| command.strip_prefix("ARGMAX "))
+            .or_else(|| command.strip_prefix("ARGMIN "))
+        {
+            return parse_range(sheet, range.trim()).is_some();
+        }
+        if let Some(cell_ref) = command
+            .strip_prefix("PASTE ")
+            .or_else(|| command.strip_prefix("PASTETRANSPOSE "))
+        {
+            return parse_cell_reference(sheet, cell_ref).is_ok();
+        }
+        if let Some(stripped) = command.strip_prefix("WHATIF ") {
+            return stripped
+                .split_once(" : ")
+                .is_some_and(|(assignment, query)| {
+                    let (assignment, query) = (assignment.trim(), query.trim());
+                    assignment.contains('=')
+                        && is_valid_command(sheet, assignment)
+                        && query.starts_with("GET ")
+                        && is_valid_command(sheet, query)
+                });
+        }
+        if let Some(stripped) = command.strip_prefix("IMPORTRANGE ") {
+            return stripped
+                .trim()
+                .split_once(' ')
+                .is_some_and(|(cell_ref, text)| {
+                    parse_cell_reference(sheet, cell_ref).is_ok_and(|(row, _)| {
+                        split_import_text(text)
+                            .is_some_and(|rows| rows.len() as i32 <= sheet.rows - row)
+                    })
+                });
+        }
+        if let Some(stripped) = command.strip_prefix("MOVE ") {
+            let parts: Vec<&str> = stripped.split_whitespace().collect();
+            return match parts.as_slice() {
+                [range, target] => {
+                    let source = parse_cell_or_range(sheet, range);
+                    let dest = parse_cell_reference(sheet, target).ok();
+                    source.zip(dest).is_some_and(
+                        |((start_row, start_col, end_row, end_col), (dest_row, dest_col))| {
+                            dest_row + end_row - start_row < sheet.rows
+                                && dest_col + end_col - start_col < sheet.cols
+                        },
+                    )
+                }
+                _ => false,
+            };
         }
     }
-    if let Some(stripped) = command.strip_prefix("scroll_to ") {
-        return parse_cell_reference(sheet, stripped).is_some();
-    }
-    if sheet.extension_enabled && command.starts_with("GRAPH ") {
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        return parts.len() == 3
-            && ["(BAR)", "(SCATTER)"].contains(&parts[1].to_uppercase().as_str())
-            && parse_range(sheet, parts[2]).is_some();
-    }
-    if sheet.extension_enabled {
-        let range = if let Some(stripped) = command.strip_prefix("COPY ") {
-            stripped
-        } else {
-            &command[4..]
-        };
-        return parse_range(sheet, range).is_some();
-    }
-    if sheet.extension_enabled {
-        let range = if let Some(stripped) = command.strip_prefix("CUT ") {
-            stripped
-        } else {
-            &command[3..]
-        };
-        return parse_range(sheet, range).is_some();
-    }
-    if sheet.extension_enabled {
-        let cell_ref = if let Some(stripped) = command.strip_prefix("PASTE ") {
-            stripped
-        } else {
-            &command[5..]
-        };
-        return parse_cell_reference(sheet, cell_ref).is_some();
-    }
+
     command.split_once('=').is_some_and(|(ref_str, formula)| {
-        parse_cell_reference(sheet, ref_str.trim()).is_some()
+        parse_cell_reference(sheet, ref_str.trim()).is_ok()
             && is_valid_formula(sheet, formula.trim())
     })
 }